@@ -0,0 +1,70 @@
+/**********************************************************************************
+Benchmarks of the parsing of WHO files, over a sample file of 150 rows from a range
+of registries, with dates in the various formats found and text fields that include
+html tags and entities. 'process_records' reads, deserialises and processes each row
+as the import's workers do (without writing anything), while 'clean_text' and
+'parse_dates' time the text cleaning and date conversion of the sample's fields.
+Run with 'cargo bench'.
+***********************************************************************************/
+
+use std::hint::black_box;
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use csv::{ByteRecord, ReaderBuilder};
+use dl_who::bench_support::{process_record, DateExtensions, StringExtensions};
+
+const SAMPLE_FILE: &str = "benches/data/ICTRP_sample.csv";
+const TEXT_FIELDS: [usize; 6] = [3, 4, 30, 34, 35, 36];
+const DATE_FIELDS: [usize; 7] = [1, 21, 22, 46, 47, 55, 58];
+
+
+fn read_sample() -> (Vec<u8>, Vec<ByteRecord>) {
+    let data = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join(SAMPLE_FILE)).unwrap();
+    let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(data.as_slice());
+    let records = rdr.byte_records().map(|r| r.unwrap()).collect();
+    (data, records)
+}
+
+
+fn get_fields(records: &[ByteRecord], field_nums: &[usize]) -> Vec<String> {
+    records.iter()
+        .flat_map(|r| field_nums.iter().map(|i| String::from_utf8_lossy(&r[*i]).into_owned()))
+        .collect()
+}
+
+
+fn bench_parsing(c: &mut Criterion) {
+
+    let (data, records) = read_sample();
+
+    let mut group = c.benchmark_group("parsing");
+    group.throughput(Throughput::Elements(records.len() as u64));
+    group.bench_function("process_records", |b| b.iter(|| {
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(data.as_slice());
+        let mut record = ByteRecord::new();
+        while rdr.read_byte_record(&mut record).unwrap() {
+            black_box(process_record(&record));
+        }
+    }));
+
+    let texts = get_fields(&records, &TEXT_FIELDS);
+    group.bench_function("clean_text", |b| b.iter(|| {
+        for t in &texts {
+            black_box(t.tidy());
+            black_box(t.replace_unicodes());
+            black_box(t.replace_tags_and_unicodes());
+        }
+    }));
+
+    let dates = get_fields(&records, &DATE_FIELDS);
+    group.bench_function("parse_dates", |b| b.iter(|| {
+        for d in &dates {
+            black_box(d.as_iso_date());
+        }
+    }));
+    group.finish();
+}
+
+
+criterion_group!(benches, bench_parsing);
+criterion_main!(benches);
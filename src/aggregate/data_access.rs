@@ -1,4 +1,4 @@
-use sqlx::{Pool, Postgres};
+use sqlx::{Pool, Postgres, Row};
 use crate::AppError;
 
 use super::structs::BasTable;
@@ -245,20 +245,20 @@ pub async fn set_up_data_grids (pool: &Pool<Postgres>) -> Result<(), AppError> {
         order by ss.id;"#;
     execute_sql(sql, pool).await?;
 
-
-    // Need to translate countries into continents before aggregation
-
-    set_up_data_grid(pool, "continent_numbers").await?;
-
     Ok(())
 }
 
 
 pub async fn fetch_table_list(pool: &Pool<Postgres>) -> Result<Vec<BasTable>, AppError> {
   
-  let sql = r#"select table_name
-        from met.tables
-        order by table_name;"#;
+  // The dat tables are partitioned by year - only the 
+  // parent (partitioned) table of each registry is listed.
+
+  let sql = r#"select c.relname::varchar as table_name
+        from pg_class c
+        inner join pg_namespace n on n.oid = c.relnamespace
+        where n.nspname = 'dat' and c.relkind = 'p'
+        order by c.relname;"#;
 
   sqlx::query_as(&sql).fetch_all(pool).await
              .map_err(|e| AppError::SqlxError(e, sql.to_string()))
@@ -266,6 +266,54 @@ pub async fn fetch_table_list(pool: &Pool<Postgres>) -> Result<Vec<BasTable>, Ap
 }
 
 
+pub async fn fetch_grid_names(pool: &Pool<Postgres>) -> Result<Vec<String>, AppError> {
+
+    let sql = r#"select table_name::varchar
+        from information_schema.tables
+        where table_schema = 'der' and table_name like 'grid\_%'
+        order by table_name;"#;
+
+    sqlx::query_scalar(sql).fetch_all(pool).await
+             .map_err(|e| AppError::SqlxError(e, sql.to_string()))
+}
+
+
+pub async fn fetch_grid_columns(grid_name: &str, pool: &Pool<Postgres>) -> Result<Vec<String>, AppError> {
+
+    let sql = r#"select column_name::varchar
+        from information_schema.columns
+        where table_schema = 'der' and table_name = $1
+        order by ordinal_position;"#;
+
+    sqlx::query_scalar(sql).bind(grid_name).fetch_all(pool).await
+             .map_err(|e| AppError::SqlxError(e, sql.to_string()))
+}
+
+
+// All values are returned as text, so that any grid can be written out
+// without knowing the types of its columns.
+
+pub async fn fetch_grid_rows(grid_name: &str, columns: &[String], pool: &Pool<Postgres>) -> Result<Vec<Vec<Option<String>>>, AppError> {
+
+    let col_list = columns.iter().map(|c| format!("\"{}\"::text", c)).collect::<Vec<String>>().join(", ");
+    let sql = format!(r#"select {} from der.{} order by 1;"#, col_list, grid_name);
+
+    let rows = sqlx::query(&sql).fetch_all(pool).await
+             .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+
+    let mut values = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut row_values = Vec::with_capacity(columns.len());
+        for i in 0..columns.len() {
+            row_values.push(row.try_get::<Option<String>, _>(i)
+                    .map_err(|e| AppError::SqlxError(e, sql.clone()))?);
+        }
+        values.push(row_values);
+    }
+    Ok(values)
+}
+
+
 
 // Get the possible other matches using sponsor name and sponsor id
 // Only get pairings - groups of more than 2 very likely to be funding grant ids
@@ -47,21 +47,26 @@ pub async fn set_up_init_sec_id_tables (pool: &Pool<Postgres>) -> Result<u64, Ap
 }
 
 
-pub async fn process_sec_ids(entry: &BasTable, pool: &Pool<Postgres>) -> Result<(u64, u64), AppError> {
+pub async fn process_sec_ids(entry: &BasTable, exclude_missing: bool, pool: &Pool<Postgres>) -> Result<(u64, u64), AppError> {
+
+    let missing_clause = match exclude_missing {
+        true => "and not not_in_full_export",
+        false => "",
+    };
 
     let sql = format!(r#"insert into sec.initial_tr_sec_ids (pri_sid_type, pri_sid, sec_sid_type, sec_sid)
         select sid_type_id, sd_sid, SPLIT_PART(unnest(reg_sec_ids), '::', 1)::int4, SPLIT_PART(unnest(reg_sec_ids), '::', 2)
         from dat.{} 
-        where reg_sec_ids is not null
-        order by sd_sid;"#, entry.table_name);
+        where reg_sec_ids is not null {}
+        order by sd_sid;"#, entry.table_name, missing_clause);
 
     let tr = execute_sql(&sql, pool).await?;
         
     let sql = format!(r#"insert into sec.other_sec_ids (pri_sid_type, pri_sid, sponsor, sec_id)
         select sid_type_id, sd_sid, sponsor_processed, unnest(oth_sec_ids) 
         from dat.{}
-        where oth_sec_ids is not null
-        order by sd_sid;"#, entry.table_name);
+        where oth_sec_ids is not null {}
+        order by sd_sid;"#, entry.table_name, missing_clause);
 
     let oth = execute_sql(&sql, pool).await?;
 
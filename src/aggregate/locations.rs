@@ -0,0 +1,217 @@
+/**********************************************************************************
+The numbers of studies registered in each continent, WHO region and World Bank
+income group, for each source and registration year. The country dictionary
+bundled with the program (see download/countries.rs) is stored as der.country_regions,
+and the country codes of each study (as resolved when the study was imported) are
+unnested into der.study_locations, with the fraction of the study given to each of
+its countries (1 / the number of countries). Studies with no resolved countries are
+included once, with no country. Each grouping is then summarised in two grids:
+der.grid_<grouping>_numbers counts each study once in every category with any of its
+countries ('any-country' counting, so that the categories can sum to more than the
+number of studies), while der.grid_<grouping>_fractions divides each study between
+the categories of its countries (fractional counting, so that the categories sum to
+the number of studies). Studies with no countries, and countries with no value for
+the grouping (e.g. Antarctica, or a territory the World Bank does not classify), are
+given in a 'not given' category (category_id 0). If required, the studies flagged as
+not in the latest full export are left out.
+***********************************************************************************/
+
+use sqlx::{Pool, Postgres};
+use crate::AppError;
+use crate::download::countries::{all_countries, Country, CONTINENTS, INCOME_GROUPS, WHO_REGIONS};
+use super::structs::BasTable;
+
+
+// The groupings, each with its column in der.country_regions and its categories.
+
+struct Grouping {
+    name: &'static str,
+    categories: &'static [(&'static str, &'static str)],
+}
+
+const GROUPINGS: [Grouping; 3] = [
+    Grouping { name: "continent", categories: &CONTINENTS },
+    Grouping { name: "who_region", categories: &WHO_REGIONS },
+    Grouping { name: "income_group", categories: &INCOME_GROUPS },
+];
+
+
+async fn execute_sql(sql: &str, pool: &Pool<Postgres>) -> Result<u64, AppError> {
+
+    let res = sqlx::raw_sql(sql).execute(pool)
+            .await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    Ok(res.rows_affected())
+}
+
+
+pub async fn set_up_location_tables(pool: &Pool<Postgres>) -> Result<u64, AppError> {
+
+    let sql = r#"drop table if exists der.country_regions;
+    create table der.country_regions (
+          alpha_3  varchar  not null primary key
+        , name     varchar  not null
+        , continent_id  int4  null
+        , continent  varchar  null
+        , who_region_id  int4  null
+        , who_region  varchar  null
+        , income_group_id  int4  null
+        , income_group  varchar  null
+    );
+    drop table if exists der.study_locations;
+    create table der.study_locations (
+          sid_type_id  int4
+        , sid_type_name varchar
+        , reg_year int4
+        , sd_sid varchar
+        , alpha_3 varchar
+        , fraction float8
+    );"#;
+
+    execute_sql(sql, pool).await?;
+
+    // The category ids are the positions of the categories in their lists, from 1.
+
+    let countries = all_countries();
+    let category = |value: &Option<String>, categories: &[(&str, &str)]| {
+        value.as_ref().and_then(|v| categories.iter().position(|(c, _)| c == v))
+            .map(|i| ((i + 1) as i32, categories[i].1.to_string()))
+    };
+    let column = |value: fn(&Country) -> &Option<String>, categories: &[(&str, &str)]| -> (Vec<Option<i32>>, Vec<Option<String>>) {
+        countries.iter().map(|c| category(value(c), categories)).map(|c| c.unzip()).unzip()
+    };
+    let (continent_ids, continents) = column(|c| &c.continent, &CONTINENTS);
+    let (who_region_ids, who_regions) = column(|c| &c.who_region, &WHO_REGIONS);
+    let (income_group_ids, income_groups) = column(|c| &c.income_group, &INCOME_GROUPS);
+
+    let sql = r#"insert into der.country_regions (alpha_3, name, continent_id, continent,
+                    who_region_id, who_region, income_group_id, income_group)
+                select * from UNNEST($1::varchar[], $2::varchar[], $3::int[], $4::varchar[],
+                    $5::int[], $6::varchar[], $7::int[], $8::varchar[])"#;
+    let res = sqlx::query(sql)
+        .bind(countries.iter().map(|c| c.alpha_3.clone()).collect::<Vec<String>>())
+        .bind(countries.iter().map(|c| c.name.clone()).collect::<Vec<String>>())
+        .bind(continent_ids).bind(continents)
+        .bind(who_region_ids).bind(who_regions)
+        .bind(income_group_ids).bind(income_groups)
+        .execute(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    Ok(res.rows_affected())
+}
+
+
+pub async fn store_study_locations(entry: &BasTable, exclude_missing: bool, pool: &Pool<Postgres>) -> Result<u64, AppError> {
+
+    let missing_clause = match exclude_missing {
+        true => "and not d.not_in_full_export",
+        false => "",
+    };
+
+    let sql = format!(r#"Insert into der.study_locations (sid_type_id, sid_type_name, reg_year, sd_sid, alpha_3, fraction)
+        select d.sid_type_id, '{}', d.reg_year, d.sd_sid, c.alpha_3, 1.0 / cardinality(d.country_codes)
+        from dat.{} d
+        cross join unnest(d.country_codes) as c(alpha_3)
+        where cardinality(d.country_codes) > 0 {};
+        Insert into der.study_locations (sid_type_id, sid_type_name, reg_year, sd_sid, alpha_3, fraction)
+        select d.sid_type_id, '{}', d.reg_year, d.sd_sid, null, 1.0
+        from dat.{} d
+        where coalesce(cardinality(d.country_codes), 0) = 0 {}"#,
+        entry.table_name, entry.table_name, missing_clause, entry.table_name, entry.table_name, missing_clause);
+
+    execute_sql(&sql, pool).await
+}
+
+
+pub async fn create_location_grids(pool: &Pool<Postgres>) -> Result<usize, AppError> {
+
+    let mut num_grids = 0;
+    for g in &GROUPINGS {
+        create_location_grid(&format!("{}_numbers", g.name), g, "int4", "count(distinct s.sd_sid)", pool).await?;
+        create_location_grid(&format!("{}_fractions", g.name), g, "numeric(12,2)", "sum(s.fraction)", pool).await?;
+        num_grids += 2;
+    }
+    Ok(num_grids)
+}
+
+
+async fn create_location_grid(grid_name: &str, g: &Grouping, value_type: &str, value: &str,
+                    pool: &Pool<Postgres>) -> Result<u64, AppError> {
+
+    // The grids have the same columns as the other categorised grids, (see
+    // data_access::set_up_categorised_data_grid), but are filled in a single
+    // statement, with a row for each source and category found, and a
+    // 'column_total' row (sid_type_id 800000) for each category.
+
+    let year_filters = get_year_filters();
+    let columns: Vec<&str> = year_filters.iter().map(|(c, _)| c.as_str()).collect();
+    let column_defs: Vec<String> = columns.iter()
+        .map(|c| format!(", {:<11} {} not null default(0)", c, value_type)).collect();
+
+    let sql = format!(r#"drop table if exists der.grid_{grid};
+    create table der.grid_{grid}
+    (
+          sid_type_id   int4    not null
+        , sid_type_name varchar not null
+        , category_id int4    not null
+        , category    varchar not null
+        {}
+    );
+    create index grid_{grid}_src_id on der.grid_{grid}(sid_type_id);"#,
+    column_defs.join("\n        "), grid = grid_name);
+
+    execute_sql(&sql, pool).await?;
+
+    let values: Vec<String> = year_filters.iter()
+        .map(|(_, filter)| match filter {
+            Some(f) => format!("coalesce({} filter (where {}), 0)", value, f),
+            None => value.to_string(),
+        }).collect();
+    let totals: Vec<String> = columns.iter().map(|c| format!("sum({})", c)).collect();
+
+    let sql = format!(r#"Insert into der.grid_{grid} (sid_type_id, sid_type_name, category_id, category, {cols})
+        select s.sid_type_id, s.sid_type_name, coalesce(r.{g}_id, 0), coalesce(r.{g}, 'not given'),
+            {vals}
+        from der.study_locations s
+        left join der.country_regions r on r.alpha_3 = s.alpha_3
+        group by s.sid_type_id, s.sid_type_name, coalesce(r.{g}_id, 0), coalesce(r.{g}, 'not given')
+        order by s.sid_type_id, coalesce(r.{g}_id, 0);
+        Insert into der.grid_{grid} (sid_type_id, sid_type_name, category_id, category, {cols})
+        select 800000, 'column_total', category_id, category,
+            {tots}
+        from der.grid_{grid}
+        group by category_id, category
+        order by category_id;"#,
+        grid = grid_name, g = g.name, cols = columns.join(", "),
+        vals = values.join(",\n            "), tots = totals.join(", "));
+
+    // The categories with no studies at all are listed in the column total rows.
+
+    let category_list: Vec<String> = g.categories.iter().enumerate()
+        .map(|(i, (_, name))| format!("({}, '{}')", i + 1, name)).collect();
+    let sql = format!(r#"{}
+        Insert into der.grid_{grid} (sid_type_id, sid_type_name, category_id, category)
+        select 800000, 'column_total', c.id, c.name
+        from (values {}) as c(id, name)
+        where not exists (select 1 from der.grid_{grid} g
+                          where g.sid_type_id = 800000 and g.category_id = c.id);"#,
+        sql, category_list.join(", "), grid = grid_name);
+
+    execute_sql(&sql, pool).await
+}
+
+
+// The value columns of the grids, each with the condition on the registration year
+// of the studies counted in it. The line total has no condition.
+
+fn get_year_filters() -> Vec<(String, Option<String>)> {
+    let mut filters = vec![
+        ("not_given".to_string(), Some("s.reg_year = 0 or s.reg_year > 2030".to_string())),
+        ("pre_2000".to_string(), Some("s.reg_year > 0 and s.reg_year < 2000".to_string())),
+    ];
+    for y in 2000..2031 {
+        filters.push((format!("y{}", y), Some(format!("s.reg_year = {}", y))));
+    }
+    filters.push(("line_total".to_string(), None));
+    filters
+}
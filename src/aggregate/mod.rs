@@ -2,15 +2,19 @@ mod data_access;
 mod dedup;
 mod structs;
 mod ftw;
+mod locations;
 
 use sqlx::{Pool, Postgres};
 use crate::AppError;
 use log::info;
+use std::fs;
+use std::path::Path;
 
 
-pub async fn identify_linked_studies(pool: &Pool<Postgres>) -> Result<(), AppError> {
+pub async fn identify_linked_studies(exclude_missing: bool, pool: &Pool<Postgres>) -> Result<(), AppError> {
  
-    // First get the list of source tables
+    // First get the list of source tables. If exclude_missing is true the studies 
+    // flagged as not in the latest full export are left out of the linking.
 
     let tables = data_access::fetch_table_list(pool).await?;
 
@@ -22,7 +26,7 @@ pub async fn identify_linked_studies(pool: &Pool<Postgres>) -> Result<(), AppErr
     let mut tr_ids_total = 0;
     let mut oth_ids_total = 0;
     for entry in &tables {
-        let (tr, oth) = dedup::process_sec_ids(entry, pool).await?;
+        let (tr, oth) = dedup::process_sec_ids(entry, exclude_missing, pool).await?;
         tr_ids_total += tr;
         oth_ids_total += oth;
     }
@@ -175,8 +179,10 @@ pub async fn identify_linked_studies(pool: &Pool<Postgres>) -> Result<(), AppErr
 
 
 
-pub async fn aggregate_who_data(pool: &Pool<Postgres>) -> Result<(), AppError> {
+pub async fn aggregate_who_data(exclude_missing: bool, pool: &Pool<Postgres>) -> Result<(), AppError> {
 
+    // If exclude_missing is true the studies flagged as not in the 
+    // latest full export are left out of the counts, as of the linking.
     
     // Then set up tables to hold data,
     // then read list of soures / tables into a vector of structs
@@ -218,7 +224,6 @@ pub async fn aggregate_who_data(pool: &Pool<Postgres>) -> Result<(), AppError> {
     
 
     //ftw::set_up_schema("cxt.lups", pool).await?;
-    ftw::set_up_schema("cxt.locs", pool).await?;
     
     data_access::set_up_data_grids(pool).await?;
 
@@ -229,7 +234,20 @@ pub async fn aggregate_who_data(pool: &Pool<Postgres>) -> Result<(), AppError> {
 
 
 
-    // summarise number of studies registered for each continent, for each source / year
+    // summarise number of studies registered for each continent, WHO region and 
+    // income group, for each source / year, using the country codes of the studies
+    // and the country dictionary bundled with the program (see locations.rs).
+
+    let tables = data_access::fetch_table_list(pool).await?;
+    let n = locations::set_up_location_tables(pool).await?;
+    info!("{} countries and their regions stored", n);
+    let mut total = 0;
+    for entry in &tables {
+        total += locations::store_study_locations(entry, exclude_missing, pool).await?;
+    }
+    info!("{} study locations stored", total);
+    let n = locations::create_location_grids(pool).await?;
+    info!("{} grids of study numbers by location created", n);
 
 
     // summarise number of studies starting in each continent, 2004 onwards
@@ -241,9 +259,37 @@ pub async fn aggregate_who_data(pool: &Pool<Postgres>) -> Result<(), AppError> {
     // summarise number of studies with results, 2000 onwards
 
     ftw::drop_schema("ctx_lups", pool).await?;
-    ftw::drop_schema("ctx_locs", pool).await?;
 
     Ok(())
 }
 
 
+pub async fn export_data_grids(export_folder: &Path, pool: &Pool<Postgres>) -> Result<usize, AppError> {
+
+    // Each der.grid_ table created by the aggregation process is written
+    // to a csv file of the same name in the export folder.
+
+    fs::create_dir_all(export_folder)
+            .map_err(|e| AppError::IoWriteErrorWithPath(e, export_folder.to_owned()))?;
+
+    let grid_names = data_access::fetch_grid_names(pool).await?;
+    for grid_name in &grid_names {
+        let columns = data_access::fetch_grid_columns(grid_name, pool).await?;
+        let rows = data_access::fetch_grid_rows(grid_name, &columns, pool).await?;
+
+        let file_path = export_folder.join(format!("{}.csv", grid_name));
+        let mut wtr = csv::Writer::from_path(&file_path)
+                .map_err(|e| AppError::CsvError(e, file_path.display().to_string()))?;
+        wtr.write_record(&columns)
+                .map_err(|e| AppError::CsvError(e, file_path.display().to_string()))?;
+        for row in &rows {
+            wtr.write_record(row.iter().map(|v| v.as_deref().unwrap_or("")))
+                .map_err(|e| AppError::CsvError(e, file_path.display().to_string()))?;
+        }
+        wtr.flush()
+                .map_err(|e| AppError::IoWriteErrorWithPath(e, file_path.clone()))?;
+        info!("{} rows exported from der.{}", rows.len(), grid_name);
+    }
+
+    Ok(grid_names.len())
+}
@@ -0,0 +1,391 @@
+/**********************************************************************************
+The age eligibility of a study, from the age_min and age_max fields of the WHO data
+(or, for the EU CTR, from the age groups listed at the end of the inclusion criteria).
+Registries give ages in many ways - '18 Years', '0.5 years', '≥18', '18+', 'N/A',
+'No limit', a range such as '18-65 years' in a single field, or just a number. Each
+limit is parsed into its value and unit, whether the limit itself is included (it is
+not in '>18' or 'under 65'), and the age in days and in years, so that limits given
+in different units can be compared. A limit that is missing is distinguished from one
+explicitly said to have no limit. The range of ages is then classified by the
+populations it includes - paediatric (under 18), adult (18 to 64) and elderly
+(65 and over), the age groups used by the EU CTR - so that studies can be
+aggregated by population, using the age_groups column of the dat tables.
+***********************************************************************************/
+
+use std::sync::LazyLock;
+use regex::Regex;
+
+const DAYS_PER_YEAR: f64 = 365.25;
+const ADULT_DAYS: f64 = 18.0 * DAYS_PER_YEAR;
+const ELDERLY_DAYS: f64 = 65.0 * DAYS_PER_YEAR;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgeUnit {
+    Years,
+    Months,
+    Weeks,
+    Days,
+    Hours,
+    Minutes,
+}
+
+impl AgeUnit {
+
+    // The unit's name as used in the agemin_units and agemax_units fields.
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgeUnit::Years => "Years",
+            AgeUnit::Months => "Months",
+            AgeUnit::Weeks => "Weeks",
+            AgeUnit::Days => "Days",
+            AgeUnit::Hours => "Hours",
+            AgeUnit::Minutes => "Minutes",
+        }
+    }
+
+    fn days(&self) -> f64 {
+        match self {
+            AgeUnit::Years => DAYS_PER_YEAR,
+            AgeUnit::Months => DAYS_PER_YEAR / 12.0,
+            AgeUnit::Weeks => 7.0,
+            AgeUnit::Days => 1.0,
+            AgeUnit::Hours => 1.0 / 24.0,
+            AgeUnit::Minutes => 1.0 / 1440.0,
+        }
+    }
+
+    fn from_word(word: &str) -> Option<AgeUnit> {
+        match word {
+            "y" | "yr" | "yrs" | "year" | "years" => Some(AgeUnit::Years),
+            "m" | "mo" | "mos" | "month" | "months" => Some(AgeUnit::Months),
+            "w" | "wk" | "wks" | "week" | "weeks" => Some(AgeUnit::Weeks),
+            "d" | "day" | "days" => Some(AgeUnit::Days),
+            "h" | "hr" | "hrs" | "hour" | "hours" => Some(AgeUnit::Hours),
+            "min" | "mins" | "minute" | "minutes" => Some(AgeUnit::Minutes),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct AgeLimit {
+    pub value: f64,
+    pub unit: AgeUnit,
+    pub inclusive: bool,
+    pub days: f64,
+    pub years: f64,
+}
+
+impl AgeLimit {
+
+    pub fn new(value: f64, unit: AgeUnit, inclusive: bool) -> Self {
+        let days = value * unit.days();
+        AgeLimit { value, unit, inclusive, days: round_to(days, 2), years: round_to(days / DAYS_PER_YEAR, 4) }
+    }
+
+    // The value as given, without a decimal point if it is a whole number.
+
+    pub fn value_string(&self) -> String {
+        match self.value.fract() == 0.0 {
+            true => format!("{}", self.value as i64),
+            false => self.value.to_string(),
+        }
+    }
+
+    // The age in days at which the range starts or ends. A whole number of units runs
+    // to the start of the next, i.e. an exclusive minimum of '> 17 years' starts at 18
+    // years and an inclusive maximum of '17 years' ends (exclusively) at 18 years.
+
+    fn bound_days(&self, to_next_unit: bool) -> f64 {
+        match to_next_unit && self.value.fract() == 0.0 {
+            true => (self.value + 1.0) * self.unit.days(),
+            false => self.value * self.unit.days(),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgeGroup {
+    Paediatric,
+    Adult,
+    Elderly,
+}
+
+impl AgeGroup {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgeGroup::Paediatric => "paediatric",
+            AgeGroup::Adult => "adult",
+            AgeGroup::Elderly => "elderly",
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct AgeEligibility {
+    pub min: Option<AgeLimit>,
+    pub max: Option<AgeLimit>,
+    pub no_min_limit: bool,
+    pub no_max_limit: bool,
+    pub age_groups: Vec<AgeGroup>,
+}
+
+impl AgeEligibility {
+
+    // The age groups as stored in the dat tables, or None if there are none.
+
+    pub fn age_group_names(&self) -> Option<Vec<String>> {
+        match self.age_groups.is_empty() {
+            true => None,
+            false => Some(self.age_groups.iter().map(|g| g.name().to_string()).collect()),
+        }
+    }
+
+    // A missing limit is treated as no limit, so a study with only a minimum
+    // age of 18 years is taken to include both adults and the elderly.
+
+    fn set_age_groups(&mut self) {
+        let start = self.min.as_ref().map_or(0.0, |m| m.bound_days(!m.inclusive));
+        let end = self.max.as_ref().map_or(f64::INFINITY, |m| m.bound_days(m.inclusive));
+        let groups = [(AgeGroup::Paediatric, 0.0, ADULT_DAYS), (AgeGroup::Adult, ADULT_DAYS, ELDERLY_DAYS),
+                      (AgeGroup::Elderly, ELDERLY_DAYS, f64::INFINITY)];
+        self.age_groups = groups.iter()
+                    .filter(|(_, from, to)| start < *to && end > *from)
+                    .map(|(g, _, _)| *g)
+                    .collect();
+    }
+}
+
+
+// An age field, as parsed. A single age without a comparison ('18 years') is a
+// limit of whichever field it is in, while one with a comparison ('>= 18', 'under
+// 65') is taken as a minimum or maximum even if it appears in the other field.
+
+#[derive(Debug, PartialEq)]
+enum ParsedAge {
+    NoLimit,
+    Value(AgeLimit),
+    Lower(AgeLimit),
+    Upper(AgeLimit),
+    Range(AgeLimit, AgeLimit),
+}
+
+
+static NO_LIMIT: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(r"\b(?:n/?a|none|not applicable|unlimited|no\b.*\blimits?|no (?:max|min)(?:imum)?)\b").unwrap());
+static AGE_RANGE: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(r"^(?:between\s*)?(\d+(?:[.,]\d+)?)\s*([a-z]*)\s*(?:-|–|~|to|and)\s*(\d+(?:[.,]\d+)?)\s*([a-z]*)").unwrap());
+static AGE_VALUE: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(r"(\d+(?:[.,]\d+)?)\s*(\+)?\s*([a-z]*)").unwrap());
+static UNIT_WORD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[a-z]+").unwrap());
+
+// Phrases giving the direction of a single age, and whether it is inclusive. Those
+// that contain others (e.g. 'or over' contains 'over') are checked first.
+
+const AGE_COMPARISONS: [(&str, bool, bool); 24] = [
+    (">=", true, true), ("at least", true, true), ("or more", true, true), ("or older", true, true),
+    ("or over", true, true), ("or above", true, true), ("and over", true, true), ("and older", true, true),
+    ("and above", true, true), ("<=", false, true), ("or less", false, true), ("or younger", false, true),
+    ("or under", false, true), ("and under", false, true), ("and younger", false, true), ("up to", false, true),
+    (">", true, false), ("older than", true, false), ("more than", true, false), ("over", true, false),
+    ("above", true, false), ("<", false, false), ("younger than", false, false), ("under", false, false)];
+
+
+pub fn get_age_eligibility(age_min: &str, age_max: &str) -> Option<AgeEligibility> {
+
+    let parsed_min = parse_age(age_min);
+    let parsed_max = parse_age(age_max);
+    if parsed_min.is_none() && parsed_max.is_none() {
+        return None;
+    }
+
+    let mut ages = AgeEligibility::default();
+    match parsed_min {
+        Some(ParsedAge::NoLimit) => ages.no_min_limit = true,
+        Some(ParsedAge::Value(a)) | Some(ParsedAge::Lower(a)) => ages.min = Some(a),
+        Some(ParsedAge::Upper(a)) => ages.max = Some(a),
+        Some(ParsedAge::Range(from, to)) => (ages.min, ages.max) = (Some(from), Some(to)),
+        None => {},
+    }
+    match parsed_max {
+        Some(ParsedAge::NoLimit) => ages.no_max_limit = ages.max.is_none(),
+        Some(ParsedAge::Value(a)) | Some(ParsedAge::Upper(a)) => ages.max = Some(a),
+        Some(ParsedAge::Lower(a)) => { ages.min.get_or_insert(a); },
+        Some(ParsedAge::Range(from, to)) => {
+            ages.min.get_or_insert(from);
+            ages.max = Some(to);
+        },
+        None => {},
+    }
+    ages.set_age_groups();
+    Some(ages)
+}
+
+
+// The EU CTR gives the age groups included rather than ages. The minimum and maximum
+// are those implied by the groups (e.g. 18 to 64 years for adults only), when they
+// are contiguous.
+
+pub fn get_euro_age_eligibility(age_string: &str) -> AgeEligibility {
+
+    let children = age_string.contains("Are the trial subjects under 18? yes");
+    let adult = age_string.contains("F.1.2 Adults (18-64 years) yes");
+    let aged = age_string.contains("F.1.3 Elderly (>=65 years) yes");
+
+    let years = |value: f64| Some(AgeLimit::new(value, AgeUnit::Years, true));
+    let mut ages = AgeEligibility::default();
+    if !children {
+        ages.min = if adult { years(18.0) } else if aged { years(65.0) } else { None };
+    }
+    if !aged {
+        ages.max = if adult { years(64.0) } else if children { years(17.0) } else { None };
+    }
+    let groups = [(children, AgeGroup::Paediatric), (adult, AgeGroup::Adult), (aged, AgeGroup::Elderly)];
+    ages.age_groups = groups.iter().filter(|(included, _)| *included).map(|(_, g)| *g).collect();
+    ages
+}
+
+
+fn parse_age(age: &str) -> Option<ParsedAge> {
+
+    let text = age.trim().trim_end_matches('.').to_lowercase()
+                .replace('≥', ">=").replace('≤', "<=").replace("=>", ">=").replace("=<", "<=");
+    if text.is_empty() || text == "null" {
+        return None;
+    }
+    if NO_LIMIT.is_match(&text) && !text.contains(|c: char| c.is_ascii_digit()) {
+        return Some(ParsedAge::NoLimit);
+    }
+
+    if let Some(caps) = AGE_RANGE.captures(&text) {
+        let from_unit = AgeUnit::from_word(&caps[2]);
+        let to_unit = AgeUnit::from_word(&caps[4]).or(from_unit).unwrap_or(AgeUnit::Years);
+        let from = AgeLimit::new(get_value(&caps[1]), from_unit.unwrap_or(to_unit), true);
+        let to = AgeLimit::new(get_value(&caps[3]), to_unit, true);
+        return Some(ParsedAge::Range(from, to));
+    }
+
+    // A unit given elsewhere in the field (e.g. 'Years: 18') is used
+    // if there is none after the number.
+
+    let caps = AGE_VALUE.captures(&text)?;
+    let unit = AgeUnit::from_word(&caps[3])
+                .or_else(|| UNIT_WORD.find_iter(&text).find_map(|m| AgeUnit::from_word(m.as_str())))
+                .unwrap_or(AgeUnit::Years);
+    let value = get_value(&caps[1]);
+    if caps.get(2).is_some() {
+        return Some(ParsedAge::Lower(AgeLimit::new(value, unit, true)));    // as in '18+'
+    }
+    match AGE_COMPARISONS.iter().find(|(phrase, _, _)| text.contains(phrase)) {
+        Some((_, true, inclusive)) => Some(ParsedAge::Lower(AgeLimit::new(value, unit, *inclusive))),
+        Some((_, false, inclusive)) => Some(ParsedAge::Upper(AgeLimit::new(value, unit, *inclusive))),
+        None => Some(ParsedAge::Value(AgeLimit::new(value, unit, true))),
+    }
+}
+
+
+fn get_value(number: &str) -> f64 {
+    number.replace(',', ".").parse().unwrap_or_default()
+}
+
+
+fn round_to(value: f64, places: i32) -> f64 {
+    let factor = 10f64.powi(places);
+    (value * factor).round() / factor
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn years(value: f64, inclusive: bool) -> Option<AgeLimit> {
+        Some(AgeLimit::new(value, AgeUnit::Years, inclusive))
+    }
+
+    #[test]
+    fn check_single_ages_parsed() {
+        assert_eq!(parse_age("18 Years"), Some(ParsedAge::Value(years(18.0, true).unwrap())));
+        assert_eq!(parse_age("18"), Some(ParsedAge::Value(years(18.0, true).unwrap())));
+        assert_eq!(parse_age("0.5 years"), Some(ParsedAge::Value(years(0.5, true).unwrap())));
+        assert_eq!(parse_age("≥18"), Some(ParsedAge::Lower(years(18.0, true).unwrap())));
+        assert_eq!(parse_age("18+ years"), Some(ParsedAge::Lower(years(18.0, true).unwrap())));
+        assert_eq!(parse_age(">17 years"), Some(ParsedAge::Lower(years(17.0, false).unwrap())));
+        assert_eq!(parse_age("18 years and over"), Some(ParsedAge::Lower(years(18.0, true).unwrap())));
+        assert_eq!(parse_age("under 65"), Some(ParsedAge::Upper(years(65.0, false).unwrap())));
+        assert_eq!(parse_age("6 Months"), Some(ParsedAge::Value(AgeLimit::new(6.0, AgeUnit::Months, true))));
+        assert_eq!(parse_age("28 days"), Some(ParsedAge::Value(AgeLimit::new(28.0, AgeUnit::Days, true))));
+        assert_eq!(parse_age("Not stated"), None);
+        assert_eq!(parse_age("NULL"), None);
+        assert_eq!(parse_age(""), None);
+    }
+
+    #[test]
+    fn check_no_limits_and_ranges_parsed() {
+        for no_limit in ["N/A", "no limit", "No age limit", "None", "Not applicable", "no maximum age", "N/A (No limit)"] {
+            assert_eq!(parse_age(no_limit), Some(ParsedAge::NoLimit), "{}", no_limit);
+        }
+        assert_eq!(parse_age("18-65 years"), Some(ParsedAge::Range(years(18.0, true).unwrap(), years(65.0, true).unwrap())));
+        assert_eq!(parse_age("between 6 months and 2 years"),
+                Some(ParsedAge::Range(AgeLimit::new(6.0, AgeUnit::Months, true), years(2.0, true).unwrap())));
+    }
+
+    #[test]
+    fn check_ages_normalised_to_days_and_years() {
+        let a = AgeLimit::new(6.0, AgeUnit::Months, true);
+        assert_eq!(a.days, 182.63);
+        assert_eq!(a.years, 0.5);
+        let a = AgeLimit::new(2.0, AgeUnit::Weeks, true);
+        assert_eq!(a.days, 14.0);
+        assert_eq!(a.years, 0.0383);
+        assert_eq!(AgeLimit::new(18.0, AgeUnit::Years, true).value_string(), "18");
+        assert_eq!(AgeLimit::new(0.5, AgeUnit::Years, true).value_string(), "0.5");
+    }
+
+    #[test]
+    fn check_eligibility_and_age_groups() {
+        let ages = get_age_eligibility("18 Years", "N/A").unwrap();
+        assert_eq!(ages.min, years(18.0, true));
+        assert_eq!(ages.max, None);
+        assert!(!ages.no_min_limit && ages.no_max_limit);
+        assert_eq!(ages.age_groups, vec![AgeGroup::Adult, AgeGroup::Elderly]);
+
+        let ages = get_age_eligibility("18-64 years", "").unwrap();
+        assert_eq!(ages.max, years(64.0, true));
+        assert_eq!(ages.age_groups, vec![AgeGroup::Adult]);
+
+        let ages = get_age_eligibility("no limit", "<18").unwrap();
+        assert!(ages.no_min_limit);
+        assert_eq!(ages.age_groups, vec![AgeGroup::Paediatric]);
+
+        let ages = get_age_eligibility(">17", "17 years").unwrap();
+        assert!(ages.age_groups.is_empty());
+        assert_eq!(ages.age_group_names(), None);
+
+        let ages = get_age_eligibility("216 months", "65").unwrap();
+        assert_eq!(ages.age_group_names(), Some(vec!["adult".to_string(), "elderly".to_string()]));
+
+        assert_eq!(get_age_eligibility("", "Not stated"), None);
+    }
+
+    #[test]
+    fn check_euro_age_groups() {
+        let text = "Are the trial subjects under 18? no F.1.2 Adults (18-64 years) yes F.1.3 Elderly (>=65 years) no";
+        let ages = get_euro_age_eligibility(text);
+        assert_eq!((ages.min, ages.max), (years(18.0, true), years(64.0, true)));
+        assert_eq!(ages.age_groups, vec![AgeGroup::Adult]);
+
+        let text = "Are the trial subjects under 18? yes F.1.2 Adults (18-64 years) no F.1.3 Elderly (>=65 years) yes";
+        let ages = get_euro_age_eligibility(text);
+        assert_eq!((ages.min, ages.max), (None, None));
+        assert_eq!(ages.age_groups, vec![AgeGroup::Paediatric, AgeGroup::Elderly]);
+    }
+}
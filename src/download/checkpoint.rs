@@ -0,0 +1,160 @@
+/**********************************************************************************
+Importing a full export (DL type 502) takes several hours, and a run that stops
+part way through would otherwise have to start again from the beginning. After
+each chunk of the export is committed a checkpoint is saved, in evs.dl_checkpoints
+in the monitoring database, giving the position in the file of the first record
+after that chunk (as a record count, a line number and a byte offset) and the
+running totals of the import. 'import full --resume <dl_id>' then skips the
+records already processed and continues from the next chunk, as part of the same
+import - the same dl_id and evs.dl_events record - so that the final totals are
+those of the whole export. The size of the export file is recorded so that a
+checkpoint cannot be applied to a different file.
+***********************************************************************************/
+
+use std::fs;
+use std::path::Path;
+use csv::Position;
+use sqlx::{Pool, Postgres};
+use crate::{AppError, DownloadResult};
+use super::data_access::{fetch_checkpoint, store_checkpoint};
+
+
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub dl_id: i32,
+    pub file_path: String,
+    pub file_size: i64,
+    pub chunk_num: i32,
+    pub record_offset: i64,
+    pub line_offset: i64,
+    pub byte_offset: i64,
+    pub totals: DownloadResult,
+    pub completed: bool,
+}
+
+impl Checkpoint {
+
+    pub fn new(dl_id: i32, file_path: &Path) -> Result<Self, AppError> {
+        Ok(Checkpoint {
+            dl_id,
+            file_path: get_path_string(file_path),
+            file_size: get_file_size(file_path)?,
+            chunk_num: 0,
+            record_offset: 0,
+            line_offset: 1,
+            byte_offset: 0,
+            totals: DownloadResult::new(),
+            completed: false,
+        })
+    }
+
+    // Moves the checkpoint on past a committed chunk, the position
+    // being that of the reader after the chunk's last record.
+
+    pub fn advance(&mut self, chunk_res: &DownloadResult, position: &Position) {
+        self.chunk_num += 1;
+        self.record_offset += chunk_res.num_checked as i64;
+        self.line_offset = position.line() as i64;
+        self.byte_offset = position.byte() as i64;
+        self.totals = self.totals.add(chunk_res.clone());
+    }
+
+    pub fn check_resumable(&self, file_path: &Path) -> Result<(), AppError> {
+
+        if self.completed {
+            return Result::Err(AppError::InconsistentProgramParameter(
+                        format!("Import {} has already been completed, and cannot be resumed", self.dl_id)));
+        }
+        if self.file_path != get_path_string(file_path) {
+            return Result::Err(AppError::InconsistentProgramParameter(
+                        format!("Import {} was of {}, not {:?}", self.dl_id, self.file_path, file_path)));
+        }
+        if self.file_size != get_file_size(file_path)? {
+            return Result::Err(AppError::InconsistentProgramParameter(
+                        format!("The full export file {:?} has changed since import {} was started", file_path, self.dl_id)));
+        }
+        Ok(())
+    }
+
+    pub async fn save(&self, mon_pool: &Pool<Postgres>) -> Result<(), AppError> {
+        store_checkpoint(self, mon_pool).await
+    }
+}
+
+
+pub async fn get_checkpoint_to_resume(dl_id: i32, file_path: &Path, mon_pool: &Pool<Postgres>) -> Result<Checkpoint, AppError> {
+
+    let checkpoint = match fetch_checkpoint(dl_id, mon_pool).await? {
+        Some(c) => c,
+        None => return Result::Err(AppError::InconsistentProgramParameter(
+                        format!("No checkpoint has been saved for import {} - only full export imports can be resumed", dl_id))),
+    };
+    checkpoint.check_resumable(file_path)?;
+    Ok(checkpoint)
+}
+
+
+fn get_path_string(file_path: &Path) -> String {
+    file_path.to_string_lossy().replace("\\", "/")   // to support Windows
+}
+
+
+fn get_file_size(file_path: &Path) -> Result<i64, AppError> {
+    let metadata = fs::metadata(file_path)
+                .map_err(|e| AppError::IoReadErrorWithPath(e, file_path.to_owned()))?;
+    Ok(metadata.len() as i64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_result(num_checked: i32, num_added: i32, num_rejected: i32) -> DownloadResult {
+        DownloadResult { num_checked, num_downloaded: num_checked - num_rejected, num_added,
+                         num_rejected, ..DownloadResult::new() }
+    }
+
+    #[test]
+    fn check_checkpoint_advanced_by_chunks() {
+        let folder = std::env::temp_dir().join("dl_who_checkpoint_test");
+        fs::create_dir_all(&folder).unwrap();
+        let file_path = folder.join("ICTRPFullExport.csv");
+        fs::write(&file_path, "a,b\nc,d\n").unwrap();
+
+        let mut checkpoint = Checkpoint::new(7, &file_path).unwrap();
+        assert_eq!(checkpoint.file_size, 8);
+
+        let mut position = Position::new();
+        position.set_byte(1000).set_line(51).set_record(50);
+        checkpoint.advance(&chunk_result(50, 40, 2), &position);
+        position.set_byte(1900).set_line(112).set_record(100);
+        checkpoint.advance(&chunk_result(50, 10, 0), &position);
+
+        assert_eq!(checkpoint.chunk_num, 2);
+        assert_eq!(checkpoint.record_offset, 100);
+        assert_eq!(checkpoint.line_offset, 112);
+        assert_eq!(checkpoint.byte_offset, 1900);
+        assert_eq!(checkpoint.totals.num_checked, 100);
+        assert_eq!(checkpoint.totals.num_downloaded, 98);
+        assert_eq!(checkpoint.totals.num_added, 50);
+        assert_eq!(checkpoint.totals.num_rejected, 2);
+        assert!(checkpoint.check_resumable(&file_path).is_ok());
+    }
+
+    #[test]
+    fn check_checkpoint_not_resumed_for_other_files() {
+        let folder = std::env::temp_dir().join("dl_who_checkpoint_file_test");
+        fs::create_dir_all(&folder).unwrap();
+        let file_path = folder.join("ICTRPFullExport.csv");
+        fs::write(&file_path, "a,b\nc,d\n").unwrap();
+        let checkpoint = Checkpoint::new(7, &file_path).unwrap();
+
+        assert!(checkpoint.check_resumable(&folder.join("Other.csv")).is_err());
+        fs::write(&file_path, "a,b\nc,d\ne,f\n").unwrap();
+        assert!(checkpoint.check_resumable(&file_path).is_err());
+
+        let completed = Checkpoint { completed: true, ..Checkpoint::new(7, &file_path).unwrap() };
+        assert!(completed.check_resumable(&file_path).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+/**********************************************************************************
+The countries of a study. The countries field of the WHO data lists the countries
+in each registry's own spellings - 'Korea, Republic of', 'Korea (South)', 'South
+Korea', 'Iran (Islamic Republic of)', 'Viet Nam', 'Turkey', 'Swaziland', 'UK',
+'England' - and in any case. Each country named is looked up in a bundled dictionary
+(countries.csv), with the ISO 3166 two and three letter code of each country, the
+name used for it here and its ISO and common alternative names, including former
+names (Burma, Zaire, Swaziland, East Timor) of a country that still exists. The names
+of countries that no longer exist and have more than one successor (the USSR,
+Yugoslavia, Czechoslovakia) are not resolved. Names are compared after conversion to
+lower case and the removal of punctuation and accents, and the codes only if in upper
+case. The resolved names (country_list) and three letter codes (country_codes) are
+stored in the dat tables and the json files. Values that cannot be resolved are listed
+(as countries_unresolved) in the json files, and are counted in the report of
+unclassified values logged after each file (or full export) is processed. The
+dictionary also gives the WHO region, continent and World Bank income group of each
+country, used when aggregating the studies by location (see aggregate/locations.rs).
+Territories are given the WHO region of their geographical area, and the income
+groups are those of the World Bank's FY2025 classification (which does not classify
+Venezuela, or many small territories).
+***********************************************************************************/
+
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use super::who_helper::split_and_dedup_countries;
+
+const COUNTRY_DATA: &str = include_str!("countries.csv");
+
+// Two letter codes that are more likely to be a (negative) answer than a country.
+const NOT_CODES: [&str; 2] = ["NA", "NO"];
+
+// The values of the region, continent and income group columns
+// of the dictionary, with the names used for them.
+
+pub const WHO_REGIONS: [(&str, &str); 6] = [("AFR", "African Region"), ("AMR", "Region of the Americas"),
+                ("SEAR", "South-East Asia Region"), ("EUR", "European Region"),
+                ("EMR", "Eastern Mediterranean Region"), ("WPR", "Western Pacific Region")];
+pub const CONTINENTS: [(&str, &str); 7] = [("Africa", "Africa"), ("Asia", "Asia"), ("Europe", "Europe"),
+                ("North America", "North America"), ("South America", "South America"),
+                ("Oceania", "Oceania"), ("Antarctica", "Antarctica")];
+pub const INCOME_GROUPS: [(&str, &str); 4] = [("L", "Low income"), ("LM", "Lower middle income"),
+                ("UM", "Upper middle income"), ("H", "High income")];
+
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Country {
+    pub alpha_2: String,
+    pub alpha_3: String,
+    pub name: String,
+    pub who_region: Option<String>,
+    pub continent: Option<String>,
+    pub income_group: Option<String>,
+    aliases: String,         // separated by '|'
+}
+
+
+struct CountryDictionary {
+    countries: Vec<Country>,
+    names: HashMap<String, usize>,     // normalised names and aliases
+    codes: HashMap<String, usize>,     // two and three letter codes
+}
+
+impl CountryDictionary {
+
+    fn bundled() -> Self {
+        let mut rdr = csv::Reader::from_reader(COUNTRY_DATA.as_bytes());
+        let countries: Vec<Country> = rdr.deserialize().map(|r| r.expect("bundled country data is valid")).collect();
+        let mut names = HashMap::new();
+        let mut codes = HashMap::new();
+        for (i, c) in countries.iter().enumerate() {
+            for name in std::iter::once(c.name.as_str()).chain(c.aliases.split('|').filter(|a| !a.is_empty())) {
+                names.insert(normalise(name), i);
+            }
+            codes.insert(c.alpha_2.clone(), i);
+            codes.insert(c.alpha_3.clone(), i);
+        }
+        CountryDictionary { countries, names, codes }
+    }
+}
+
+static DICTIONARY: LazyLock<CountryDictionary> = LazyLock::new(CountryDictionary::bundled);
+
+
+pub fn all_countries() -> &'static [Country] {
+    &DICTIONARY.countries
+}
+
+
+// The countries of a study, with the values that could not be resolved.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StudyCountries {
+    pub names: Vec<String>,
+    pub codes: Vec<String>,
+    pub unresolved: Vec<String>,
+}
+
+
+pub fn get_country(text: &str) -> Option<&'static Country> {
+    let text = text.trim();
+    let i = match DICTIONARY.names.get(&normalise(text)) {
+        Some(i) => Some(i),
+        None if !NOT_CODES.contains(&text) => DICTIONARY.codes.get(text),
+        None => None,
+    };
+    i.map(|i| &DICTIONARY.countries[*i])
+}
+
+
+// The country list is known to be non-null and already 'tidied'.
+
+pub fn get_study_countries(sid_type_id: i32, country_list: &str) -> Option<StudyCountries> {
+
+    // The names are returned from the split in lower case, so codes are found
+    // by comparison with those in upper case in the original list.
+
+    let upper_case: Vec<&str> = country_list.split([';', ',']).map(|s| s.trim())
+        .filter(|s| s.len() <= 3 && s.chars().all(|c| c.is_ascii_uppercase())).collect();
+
+    let mut countries = StudyCountries::default();
+    for c in split_and_dedup_countries(sid_type_id, country_list) {
+        let code = upper_case.iter().find(|u| u.to_lowercase() == c);
+        match get_country(&c).or_else(|| code.and_then(|u| get_country(u))) {
+            Some(country) if !countries.codes.contains(&country.alpha_3) => {
+                countries.names.push(country.name.clone());
+                countries.codes.push(country.alpha_3.clone());
+            },
+            Some(_) => {},      // a second name for a country already listed
+            None => countries.unresolved.push(c),
+        }
+    }
+    match countries == StudyCountries::default() {
+        true => None,
+        false => Some(countries),
+    }
+}
+
+
+// Lower case, without apostrophes, full stops or accents, and
+// with any other punctuation (e.g. ' - ', ', ' or brackets) as a space.
+
+fn normalise(name: &str) -> String {
+    let mut key = String::with_capacity(name.len());
+    for c in name.to_lowercase().chars().filter(|c| !['\'', '’', '.'].contains(c)) {
+        match fold_accent(c) {
+            c if c.is_alphanumeric() => key.push(c),
+            _ if key.ends_with(' ') || key.is_empty() => {},
+            _ => key.push(' '),
+        }
+    }
+    key.trim_end().to_string()
+}
+
+
+fn fold_accent(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        _ => c,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(text: &str) -> Option<&str> {
+        get_country(text).map(|c| c.alpha_3.as_str())
+    }
+
+    #[test]
+    fn check_dictionary_names_unique() {
+        let mut rdr = csv::Reader::from_reader(COUNTRY_DATA.as_bytes());
+        let countries: Vec<Country> = rdr.deserialize().map(|r| r.unwrap()).collect();
+        let num_names: usize = countries.iter().map(|c| 1 + c.aliases.split('|').filter(|a| !a.is_empty()).count()).sum();
+        let mut keys: Vec<String> = countries.iter()
+            .flat_map(|c| std::iter::once(c.name.as_str()).chain(c.aliases.split('|').filter(|a| !a.is_empty())))
+            .map(normalise).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), num_names);
+        assert_eq!(DICTIONARY.codes.len(), 2 * countries.len());
+    }
+
+    #[test]
+    fn check_region_values_known() {
+        let known = |v: &Option<String>, values: &[(&str, &str)]| v.as_ref().is_none_or(|v| values.iter().any(|(c, _)| c == v));
+        for c in all_countries() {
+            assert!(known(&c.who_region, &WHO_REGIONS), "{}", c.name);
+            assert!(known(&c.continent, &CONTINENTS) && c.continent.is_some(), "{}", c.name);
+            assert!(known(&c.income_group, &INCOME_GROUPS), "{}", c.name);
+        }
+        let c = get_country("Korea, Republic of").unwrap();
+        assert_eq!((c.who_region.as_deref(), c.continent.as_deref(), c.income_group.as_deref()),
+                   (Some("WPR"), Some("Asia"), Some("H")));
+        assert_eq!(get_country("Venezuela").unwrap().income_group, None);
+    }
+
+    #[test]
+    fn check_variant_spellings_resolved() {
+        assert_eq!(code("Korea, Republic of"), Some("KOR"));
+        assert_eq!(code("korea - republic of"), Some("KOR"));
+        assert_eq!(code("Korea (South)"), Some("KOR"));
+        assert_eq!(code("South Korea"), Some("KOR"));
+        assert_eq!(code("Iran (Islamic Republic of)"), Some("IRN"));
+        assert_eq!(code("iran - islamic republic of"), Some("IRN"));
+        assert_eq!(code("Viet Nam"), Some("VNM"));
+        assert_eq!(code("cote d'ivoire"), Some("CIV"));
+        assert_eq!(code("Côte d’Ivoire"), Some("CIV"));
+        assert_eq!(code("united states of america"), Some("USA"));
+        assert_eq!(code("U.S.A."), Some("USA"));
+        assert_eq!(code("england"), Some("GBR"));
+        assert_eq!(code("Taiwan, Province of China"), Some("TWN"));
+        assert_eq!(code("Hong Kong SAR, China"), Some("HKG"));
+        assert_eq!(code("congo - the democratic republic of the"), Some("COD"));
+        assert_eq!(code("Congo"), Some("COG"));
+    }
+
+    #[test]
+    fn check_codes_and_historic_names_resolved() {
+        assert_eq!(code("DE"), Some("DEU"));
+        assert_eq!(code("GBR"), Some("GBR"));
+        assert_eq!(code("NO"), None);
+        assert_eq!(code("Burma"), Some("MMR"));
+        assert_eq!(code("Zaire"), Some("COD"));
+        assert_eq!(code("Swaziland"), Some("SWZ"));
+        assert_eq!(code("East Timor"), Some("TLS"));
+        assert_eq!(code("Turkey"), Some("TUR"));
+        assert_eq!(code("Macedonia, the former Yugoslav Republic of"), Some("MKD"));
+        assert_eq!(code("Yugoslavia"), None);
+        assert_eq!(code("USSR"), None);
+        assert_eq!(code("asia"), None);
+        assert_eq!(get_country("Holland").unwrap().name, "Netherlands");
+    }
+
+    #[test]
+    fn check_study_countries_resolved_and_deduplicated() {
+        let c = get_study_countries(100, "United States;USA;Korea, Republic of;Yugoslavia").unwrap();
+        assert_eq!(c.names, vec!["United States", "South Korea"]);
+        assert_eq!(c.codes, vec!["USA", "KOR"]);
+        assert_eq!(c.unresolved, vec!["yugoslavia"]);
+
+        let c = get_study_countries(100, "DE;FRA;gb").unwrap();
+        assert_eq!((c.codes, c.unresolved), (vec!["DEU".to_string(), "FRA".to_string()], vec!["gb".to_string()]));
+
+        let c = get_study_countries(130, "India, Sri Lanka, Bonaire, Sint Eustatius and Saba").unwrap();
+        assert_eq!(c.codes, vec!["IND", "LKA", "BES"]);
+        let c = get_study_countries(127, "Japan;Asia(except Japan)").unwrap();
+        assert_eq!((c.codes, c.unresolved), (vec!["JPN".to_string()], vec!["asia".to_string()]));
+        assert_eq!(get_study_countries(100, ";"), None);
+    }
+}
@@ -1,13 +1,19 @@
-use sqlx::{Pool, Postgres};
+use sqlx::{Pool, Postgres, PgConnection};
 use std::path::PathBuf;
-use chrono::{Utc};
+use chrono::{NaiveDate, Utc};
 use std::collections::HashMap;
 use crate::{err::AppError, DownloadResult};
-use crate:: download::file_models::{WHOSummary};
+use crate:: download::summary_buffer::{StoredSummary, StoredHash, StoreCounts};
+use crate:: download::rejects::RejectedRow;
+use crate:: download::registries::{catalogue, Registry, YearPartition};
+use crate:: download::versions::{StudyVersion, VersionRow};
+use crate:: download::missing::MissingStudy;
+use crate:: download::checkpoint::Checkpoint;
+use log::{info, warn};
 
 pub async fn get_next_download_id(pool: &Pool<Postgres>) -> Result<i32, AppError>{
 
-    let sql = "select max(id) from evs.dl_events ";
+    let sql = "select coalesce(max(id), 0) from evs.dl_events ";   // 0 if no events yet
     let last_id: i32 = sqlx::query_scalar(sql).fetch_one(pool)
                       .await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
     let new_id = last_id + 1;
@@ -30,30 +36,217 @@ pub async fn update_dl_event_record (dl_id: i32, type_id: i32, dl_res: DownloadR
              num_records_downloaded = $2,
              num_records_added = $3,
              time_ended = $4,
-             type_id = $5
-             where id = $6"#;
+             type_id = $5,
+             num_records_not_in_export = $6
+             where id = $7"#;
     let res = sqlx::query(sql).bind(dl_res.num_checked).bind(dl_res.num_downloaded).bind(dl_res.num_added)
-          .bind(now).bind(type_id).bind(dl_id).execute(pool)
+          .bind(now).bind(type_id).bind(dl_res.num_not_in_export).bind(dl_id).execute(pool)
              .await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?; 
     Ok(res.rows_affected() == 1)
 }
 
 
-pub async fn add_new_single_file_record(dl_id: i32, file_path: &PathBuf, file_res: &DownloadResult, pool: &Pool<Postgres>) -> Result<bool, AppError> {
+// The columns of the dat study tables, as listed when copying
+// data between them (e.g. from tables created by earlier versions).
+
+const DAT_COLUMNS: &str = r#"sid_type_id, sd_sid, title, study_type, study_type_id, 
+                    study_status, study_status_id, sponsor_name, sponsor_processed, 
+                    reg_sec_ids, oth_sec_ids, reg_year, enrol_year, results_yes_no, 
+                    country_list, last_revised_in_who, remote_url, 
+                    last_who_dl_id, last_edited_in_sys, local_path"#;
+
+
+pub async fn prepare_dat_tables(pool: &Pool<Postgres>) -> Result<(), AppError> {
+
+    // Ensures each registry in the catalogue has a dat table, partitioned by reg_year.
+    // Each registry's changes are made in a separate transaction. The dat schema
+    // itself, and met.tables, are created by the source database migrations.
+
+    for registry in &catalogue().registries {
+        let mut tx = pool.begin().await
+                    .map_err(|e| AppError::SqlxError(e, "begin transaction".to_string()))?;
+        prepare_dat_table(registry, &mut tx).await?;
+        tx.commit().await
+                    .map_err(|e| AppError::SqlxError(e, "commit transaction".to_string()))?;
+    }
+    Ok(())
+}
+
+
+async fn prepare_dat_table(registry: &Registry, conn: &mut PgConnection) -> Result<(), AppError> {
+
+    // Tables created by earlier versions of the program - a single unpartitioned
+    // table with the registry's name, or the year based tables listed in the 
+    // catalogue - are renamed (with an '_unpartitioned' suffix) so that their names 
+    // can be used by the new table and its partitions, and their data is copied 
+    // into the new table. The renamed tables are left in place to be checked and dropped.
+
+    let mut old_tables: Vec<String> = Vec::new();
+    let parent = &registry.name;
+    let mut parent_exists = false;
+
+    match get_relation_kind(parent, conn).await? {
+        Some((kind, _)) if kind == "p" => parent_exists = true,
+        Some((kind, false)) if kind == "r" => old_tables.push(rename_as_unpartitioned(parent, conn).await?),
+        Some(_) => return Result::Err(AppError::InconsistentProgramParameter(
+                    format!("dat.{} exists but is not a table that can be partitioned", parent))),
+        None => {},
+    }
+    for t in &registry.legacy_tables {
+        if let Some((kind, false)) = get_relation_kind(t, conn).await? && kind == "r" {
+            old_tables.push(rename_as_unpartitioned(t, conn).await?);
+        }
+    }
+
+    if !parent_exists {
+        let sql = format!(r#"create table dat.{} (
+                      sid_type_id           int           not null
+                    , sd_sid                varchar       not null
+                    , title                 varchar       null
+                    , study_type            varchar       null
+                    , study_type_id         int           null
+                    , study_status          varchar       null
+                    , study_status_id       int           null
+                    , sponsor_name          varchar       null
+                    , sponsor_processed     varchar       null
+                    , reg_sec_ids           varchar[]     null
+                    , oth_sec_ids           varchar[]     null
+                    , reg_year              int           not null
+                    , enrol_year            int           null
+                    , results_yes_no        varchar       null
+                    , country_list          varchar[]     null
+                    , last_revised_in_who   date          null
+                    , remote_url            varchar       null
+                    , last_who_dl_id        int           null
+                    , last_edited_in_sys    timestamptz   null
+                    , local_path            varchar       null
+                    , content_hash          varchar       null
+                    , last_seen_dl_id       int           null
+                    , not_in_full_export    boolean       not null default false
+                    , age_groups            varchar[]     null
+                    , anticipated_enrolment int           null
+                    , actual_enrolment      int           null
+                    , country_codes         varchar[]     null
+                    , primary key (sd_sid, reg_year)
+                ) partition by range (reg_year)"#, parent);
+        sqlx::query(&sql).execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+        create_partition(parent, &registry.partition_for_year(0), conn).await?;
+        info!("Partitioned table dat.{} created", parent);
+    }
+    else {
+        let sql = format!(r#"alter table dat.{} add column if not exists content_hash varchar null,
+                    add column if not exists last_seen_dl_id int null,
+                    add column if not exists not_in_full_export boolean not null default false,
+                    add column if not exists age_groups varchar[] null,
+                    add column if not exists anticipated_enrolment int null,
+                    add column if not exists actual_enrolment int null,
+                    add column if not exists country_codes varchar[] null"#, parent);
+        sqlx::query(&sql).execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    }
+
+    // Ensure the registry is listed in met.tables, used when aggregating.
+
+    let sql = r#"insert into met.tables (sid_type_id, sid_type_name, table_name)
+                 select $1, $2, $2
+                 where not exists (select 1 from met.tables where sid_type_id = $1)"#;
+    sqlx::query(sql).bind(registry.sid_type_id).bind(parent).execute(&mut *conn).await
+                    .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    for old in old_tables {
+        let sql = format!("select distinct coalesce(reg_year, 0) from dat.{}", old);
+        let years: Vec<i32> = sqlx::query_scalar(&sql).fetch_all(&mut *conn).await
+                    .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+        for year in years {
+            create_partition(parent, &registry.partition_for_year(year), conn).await?;
+        }
+
+        let select_cols = DAT_COLUMNS.replace("reg_year,", "coalesce(reg_year, 0),");
+        let sql = format!(r#"insert into dat.{} ({}) 
+                    select {} from dat.{} 
+                    on conflict (sd_sid, reg_year) do nothing"#, parent, DAT_COLUMNS, select_cols, old);
+        let res = sqlx::query(&sql).execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+        warn!("{} records copied from dat.{} into dat.{} - dat.{} can be dropped once the copy has been checked", 
+                    res.rows_affected(), old, parent, old);
+    }
+    Ok(())
+}
+
+
+pub async fn create_partition(parent: &str, partition: &YearPartition, conn: &mut PgConnection) -> Result<(), AppError> {
+
+    let from_year = match partition.from_year {
+        Some(y) => y.to_string(),
+        None => "MINVALUE".to_string(),
+    };
+    let sql = format!("create table if not exists dat.{} partition of dat.{} for values from ({}) to ({})", 
+                    partition.name, parent, from_year, partition.to_year);
+    sqlx::query(&sql).execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    Ok(())
+}
+
+
+async fn get_relation_kind(table_name: &str, conn: &mut PgConnection) -> Result<Option<(String, bool)>, AppError> {
+
+    // Returns the kind of the relation ('r' for a table, 'p' for a 
+    // partitioned table) and whether it is itself a partition.
+
+    let sql = r#"select c.relkind::text, c.relispartition
+                 from pg_class c inner join pg_namespace n on n.oid = c.relnamespace
+                 where n.nspname = 'dat' and c.relname = $1"#;
+    sqlx::query_as(sql).bind(table_name).fetch_optional(&mut *conn).await
+                    .map_err(|e| AppError::SqlxError(e, sql.to_string()))
+}
+
+
+async fn rename_as_unpartitioned(table_name: &str, conn: &mut PgConnection) -> Result<String, AppError> {
+
+    let new_name = format!("{}_unpartitioned", table_name);
+    let sql = format!("alter table dat.{} rename to {}", table_name, new_name);
+    sqlx::query(&sql).execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    Ok(new_name)
+}
+
+
+pub async fn get_imported_files(pool: &Pool<Postgres>) -> Result<HashMap<String, Option<String>>, AppError> {
+
+    // Returns the name (without the folder) and content hash of each file already imported.
+    // The chunks of a full export are recorded with the id of their import in their names
+    // (see splitter::get_chunk_file_name), so are never taken for a file of the same name.
+
+    let sql = "select file_path, file_hash from der.who_file_dls order by dl_id";
+    let rows: Vec<(String, Option<String>)> = sqlx::query_as(sql).fetch_all(pool).await
+                    .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    Ok(rows.into_iter()
+        .map(|(path, hash)| (path.rsplit('/').next().unwrap_or_default().to_string(), hash))
+        .collect())
+}
+
+
+pub async fn add_new_single_file_record(dl_id: i32, file_path: &PathBuf, file_hash: Option<&str>, file_res: &DownloadResult, 
+                            conn: &mut PgConnection) -> Result<bool, AppError> {
 
     let source_path = file_path.to_str().unwrap().replace("\\\\", "/").replace("\\", "/");     // assumes utf-8 characters
     let date_dl = Utc::now().date_naive();
-    let sql = r#"Insert into der.who_file_dls(dl_id, file_path, date_dl, 
-                num_checked, num_downloaded, num_added) 
-                values($1, $2, $3, $4, $5, $6)"#;
-    let res = sqlx::query(sql).bind(dl_id).bind(source_path).bind(date_dl)
+
+    // Any record of an earlier import of the same file is replaced.
+
+    let sql = "delete from der.who_file_dls where file_path = $1";
+    sqlx::query(sql).bind(&source_path)
+            .execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    let sql = r#"Insert into der.who_file_dls(dl_id, file_path, file_hash, date_dl, 
+                num_checked, num_downloaded, num_added, num_updated, num_unchanged) 
+                values($1, $2, $3, $4, $5, $6, $7, $8, $9)"#;
+    let res = sqlx::query(sql).bind(dl_id).bind(source_path).bind(file_hash).bind(date_dl)
                 .bind(file_res.num_checked).bind(file_res.num_downloaded).bind(file_res.num_added)
-                .execute(pool).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?; 
+                .bind(file_res.num_updated).bind(file_res.num_unchanged)
+                .execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?; 
     Ok(res.rows_affected() == 1)
 }
 
 
-pub async fn add_contents_record(file_path: &PathBuf, source_tots: &mut HashMap<i32, i32>, pool: &Pool<Postgres>) -> Result<u64, AppError> {
+pub async fn add_contents_record(file_path: &PathBuf, source_tots: &mut HashMap<i32, i32>, conn: &mut PgConnection) -> Result<u64, AppError> {
 
     let source_path = file_path.to_str().unwrap().replace("\\\\", "/").replace("\\", "/");   
     let mut source_ids = Vec::<i32>::new();
@@ -63,9 +256,11 @@ pub async fn add_contents_record(file_path: &PathBuf, source_tots: &mut HashMap<
         tots.push(v)
     }
 
-    // ?? delete existing first ?? 
     // Use the file path to delete previous records relating to this file.
-    // let sql ...
+
+    let sql = "delete from der.who_file_contents where file_path = $1";
+    sqlx::query(sql).bind(&source_path)
+            .execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
 
     let sql = r#"Insert into der.who_file_contents (file_path, source_id, num_found)
                  select $1, a.*
@@ -73,70 +268,450 @@ pub async fn add_contents_record(file_path: &PathBuf, source_tots: &mut HashMap<
                     (select * from UNNEST($2::int[], $3::int[])) as a"#;
                let res = sqlx::query(sql).bind(source_path)
                .bind(source_ids).bind(tots)
-               .execute(pool).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?; 
+               .execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?; 
 
     Ok(res.rows_affected())
 }
 
 
-pub async fn store_who_summary(rec: WHOSummary, full_path: PathBuf, pool: &Pool<Postgres>) -> Result<bool, AppError> {
+pub async fn add_rejects_records(dl_id: i32, file_path: &PathBuf, rows: Vec<RejectedRow>, conn: &mut PgConnection) -> Result<u64, AppError> {
 
-    // WHO summary data needs to be modified before storage in db.
-  
-    let now = Utc::now();
-    let local_path = if full_path == PathBuf::from("") {
-        None
-    }   
-    else {
-        Some(full_path.to_str().unwrap().replace("\\\\", "/").replace("\\", "/"))   // to support Windows
-    };   
-        
-    let mut sql = format!("SELECT EXISTS(SELECT 1 from dat.{} where sd_sid = '{}')", rec.table_name, rec.sd_sid); 
-    let record_exists = sqlx::query_scalar(&sql).fetch_one(pool).await
-                        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
-
-    if record_exists {
-            
-        // Update with new details.
-        
-        sql = "Update dat.".to_string() + &rec.table_name + 
-                        r#" SET sid_type_id = $1, title = $3, 
-                        study_type = $4, study_type_id = $5, study_status = $6, study_status_id = $7, 
-                        sponsor_name = $8, sponsor_processed = $9,
-                        reg_sec_ids = $10, oth_sec_ids = $11, 
-                        reg_year = $12, enrol_year = $13, results_yes_no = $14, 
-                        country_list = $15, last_revised_in_who = $16, remote_url = $17, 
-                        last_who_dl_id = $18, last_edited_in_sys = $19, local_path = $20
-                        where sd_sid = $2"#;
+    let source_path = file_path.to_str().unwrap().replace("\\\\", "/").replace("\\", "/");   
+
+    // Any rejects recorded against this file by an earlier run are replaced.
+
+    let sql = "delete from der.who_file_rejects where file_path = $1";
+    sqlx::query(sql).bind(&source_path)
+            .execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    if rows.is_empty() {
+        return Ok(0);
     }
-    else {
-            
-        // Create as a new record.
 
-        sql = "Insert into dat.".to_string() + &rec.table_name + r#"(sid_type_id, sd_sid, title, 
+    let mut line_numbers = Vec::<i64>::new();
+    let mut byte_offsets = Vec::<i64>::new();
+    let mut reason_codes = Vec::<String>::new();
+    let mut error_texts = Vec::<String>::new();
+    let mut raw_texts = Vec::<String>::new();
+    for r in rows {
+        line_numbers.push(r.line_number);
+        byte_offsets.push(r.byte_offset);
+        reason_codes.push(r.reason_code);
+        error_texts.push(r.error_text);
+        raw_texts.push(r.raw_text);
+    }
+
+    let sql = r#"Insert into der.who_file_rejects (dl_id, file_path, line_number, byte_offset, 
+                    reason_code, error_text, raw_text)
+                 select $1, $2, a.*
+                    from
+                    (select * from UNNEST($3::bigint[], $4::bigint[], $5::varchar[], $6::varchar[], $7::varchar[])) as a"#;
+    let res = sqlx::query(sql).bind(dl_id).bind(source_path)
+               .bind(line_numbers).bind(byte_offsets).bind(reason_codes).bind(error_texts).bind(raw_texts)
+               .execute(&mut *conn).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?; 
+
+    Ok(res.rows_affected())
+}
+
+
+pub async fn delete_rejects_records_from(dl_id: i32, line_offset: i64, pool: &Pool<Postgres>) -> Result<u64, AppError> {
+
+    // Used when an import is resumed, to remove any rejects stored from the chunk
+    // that failed (outside its transaction), as that chunk is processed again.
+
+    let sql = "delete from der.who_file_rejects where dl_id = $1 and line_number >= $2";
+    let res = sqlx::query(sql).bind(dl_id).bind(line_offset)
+            .execute(pool).await.map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    Ok(res.rows_affected())
+}
+
+
+pub async fn get_content_hashes(table_name: &str, sd_sids: &[String], conn: &mut PgConnection) 
+                        -> Result<HashMap<String, StoredHash>, AppError> {
+
+    // Returns the stored content hash of each of the studies that are already in the table,
+    // and whether the study is flagged as not in the last full export.
+
+    let sql = format!(r#"select sd_sid, content_hash, not_in_full_export from dat.{} 
+                where sd_sid = any($1::varchar[])"#, table_name);
+    let rows: Vec<(String, Option<String>, bool)> = sqlx::query_as(&sql).bind(sd_sids)
+        .fetch_all(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    Ok(rows.into_iter()
+        .map(|(sd_sid, content_hash, not_in_full_export)| (sd_sid, StoredHash { content_hash, not_in_full_export }))
+        .collect())
+}
+
+
+pub async fn store_who_summaries(table_name: &str, recs: Vec<StoredSummary>, conn: &mut PgConnection) -> Result<StoreCounts, AppError> {
+
+    // WHO summary data is stored as a batch, all destined for the same (parent) table, as a 
+    // single upsert over a set of unnested arrays. Postgres arrays cannot be 'ragged',
+    // so the array fields (sec ids, countries, age groups) are passed as strings joined by the unit 
+    // separator character (chr(31)) and split back into arrays within the statement.
+    // Storing a study also records that it was seen in the download (see mark_studies_seen).
+
+    let mut sid_type_ids = Vec::<i32>::new();
+    let mut sd_sids = Vec::<String>::new();
+    let mut titles = Vec::<Option<String>>::new();
+    let mut study_types = Vec::<Option<String>>::new();
+    let mut study_type_ids = Vec::<i32>::new();
+    let mut study_statuses = Vec::<Option<String>>::new();
+    let mut study_status_ids = Vec::<i32>::new();
+    let mut sponsor_names = Vec::<Option<String>>::new();
+    let mut sponsors_processed = Vec::<Option<String>>::new();
+    let mut reg_sec_ids = Vec::<Option<String>>::new();
+    let mut oth_sec_ids = Vec::<Option<String>>::new();
+    let mut reg_years = Vec::<i32>::new();
+    let mut enrol_years = Vec::<i32>::new();
+    let mut results_yes_nos = Vec::<Option<String>>::new();
+    let mut country_lists = Vec::<Option<String>>::new();
+    let mut age_groups = Vec::<Option<String>>::new();
+    let mut anticipated_enrolments = Vec::<Option<i32>>::new();
+    let mut actual_enrolments = Vec::<Option<i32>>::new();
+    let mut country_codes = Vec::<Option<String>>::new();
+    let mut dates_last_rev = Vec::<Option<NaiveDate>>::new();
+    let mut remote_urls = Vec::<Option<String>>::new();
+    let mut dl_ids = Vec::<i32>::new();
+    let mut local_paths = Vec::<Option<String>>::new();
+    let mut content_hashes = Vec::<String>::new();
+
+    for s in recs {
+        let rec = s.rec;
+        sid_type_ids.push(rec.sid_type_id);
+        sd_sids.push(rec.sd_sid);
+        titles.push(rec.title);
+        study_types.push(rec.study_type);
+        study_type_ids.push(rec.study_type_id);
+        study_statuses.push(rec.study_status);
+        study_status_ids.push(rec.study_status_id);
+        sponsor_names.push(rec.sponsor_name);
+        sponsors_processed.push(rec.sponsor_processed);
+        reg_sec_ids.push(rec.reg_sec_ids.map(|v| v.join("\u{1f}")));
+        oth_sec_ids.push(rec.oth_sec_ids.map(|v| v.join("\u{1f}")));
+        reg_years.push(rec.reg_year);
+        enrol_years.push(rec.enrol_year);
+        results_yes_nos.push(rec.results_yes_no);
+        country_lists.push(rec.country_list.map(|v| v.join("\u{1f}")));
+        age_groups.push(rec.age_eligibility.and_then(|a| a.age_group_names()).map(|v| v.join("\u{1f}")));
+        anticipated_enrolments.push(rec.enrolment.as_ref().and_then(|e| e.anticipated));
+        actual_enrolments.push(rec.enrolment.as_ref().and_then(|e| e.actual));
+        country_codes.push(rec.country_codes.map(|v| v.join("\u{1f}")));
+        dates_last_rev.push(rec.date_last_rev_in_who);
+        remote_urls.push(rec.remote_url);
+        dl_ids.push(rec.dl_id);
+        local_paths.push(s.local_path);
+        content_hashes.push(s.content_hash);
+    }
+
+    // The studies already stored are counted first, as updates. (The system columns
+    // that would otherwise distinguish inserts from updates in the upsert's
+    // RETURNING clause are not available for partitioned tables.)
+
+    let sql = format!(r#"select count(*) from dat.{} d
+                where d.sd_sid = any($1::varchar[])"#, table_name);
+    let existing: i64 = sqlx::query_scalar(&sql).bind(&sd_sids)
+        .fetch_one(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+
+    // The tables are partitioned by reg_year, which is part of the primary key, so a study
+    // whose registration year has changed since it was stored would otherwise be duplicated.
+    // Any such earlier versions are deleted first.
+
+    let sql = format!(r#"delete from dat.{} d
+                using UNNEST($1::varchar[], $2::int[]) as n(sd_sid, reg_year)
+                where d.sd_sid = n.sd_sid and d.reg_year <> n.reg_year"#, table_name);
+    sqlx::query(&sql).bind(&sd_sids).bind(&reg_years)
+        .execute(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+
+    let sql = format!(r#"Insert into dat.{} (sid_type_id, sd_sid, title, 
+                    study_type, study_type_id, study_status, study_status_id, 
+                    sponsor_name, sponsor_processed, 
+                    reg_sec_ids, oth_sec_ids, reg_year, enrol_year, results_yes_no, 
+                    country_list, last_revised_in_who, remote_url, 
+                    last_who_dl_id, last_edited_in_sys, local_path, content_hash, 
+                    last_seen_dl_id, not_in_full_export, age_groups, 
+                    anticipated_enrolment, actual_enrolment, country_codes)
+                select a.sid_type_id, a.sd_sid, a.title, 
+                    a.study_type, a.study_type_id, a.study_status, a.study_status_id, 
+                    a.sponsor_name, a.sponsor_processed, 
+                    string_to_array(a.reg_sec_ids, chr(31)), string_to_array(a.oth_sec_ids, chr(31)), 
+                    a.reg_year, a.enrol_year, a.results_yes_no, 
+                    string_to_array(a.country_list, chr(31)), a.last_revised_in_who, a.remote_url, 
+                    a.last_who_dl_id, now(), a.local_path, a.content_hash, 
+                    a.last_who_dl_id, false, string_to_array(a.age_groups, chr(31)), 
+                    a.anticipated_enrolment, a.actual_enrolment, string_to_array(a.country_codes, chr(31))
+                from UNNEST($1::int[], $2::varchar[], $3::varchar[], 
+                    $4::varchar[], $5::int[], $6::varchar[], $7::int[], 
+                    $8::varchar[], $9::varchar[], 
+                    $10::varchar[], $11::varchar[], $12::int[], $13::int[], $14::varchar[], 
+                    $15::varchar[], $16::date[], $17::varchar[], 
+                    $18::int[], $19::varchar[], $20::varchar[], $21::varchar[], 
+                    $22::int[], $23::int[], $24::varchar[]) 
+                as a(sid_type_id, sd_sid, title, 
                     study_type, study_type_id, study_status, study_status_id, 
                     sponsor_name, sponsor_processed, 
                     reg_sec_ids, oth_sec_ids, reg_year, enrol_year, results_yes_no, 
                     country_list, last_revised_in_who, remote_url, 
-                    last_who_dl_id, last_edited_in_sys, local_path)
-                VALUES
-                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, 
-                $11, $12, $13, $14, $15, $16, $17, $18, $19, $20)"#;
+                    last_who_dl_id, local_path, content_hash, age_groups, 
+                    anticipated_enrolment, actual_enrolment, country_codes)
+                ON CONFLICT (sd_sid, reg_year) DO UPDATE SET 
+                    sid_type_id = EXCLUDED.sid_type_id, title = EXCLUDED.title, 
+                    study_type = EXCLUDED.study_type, study_type_id = EXCLUDED.study_type_id, 
+                    study_status = EXCLUDED.study_status, study_status_id = EXCLUDED.study_status_id, 
+                    sponsor_name = EXCLUDED.sponsor_name, sponsor_processed = EXCLUDED.sponsor_processed,
+                    reg_sec_ids = EXCLUDED.reg_sec_ids, oth_sec_ids = EXCLUDED.oth_sec_ids, 
+                    enrol_year = EXCLUDED.enrol_year, results_yes_no = EXCLUDED.results_yes_no, 
+                    country_list = EXCLUDED.country_list, last_revised_in_who = EXCLUDED.last_revised_in_who, 
+                    remote_url = EXCLUDED.remote_url, last_who_dl_id = EXCLUDED.last_who_dl_id, 
+                    last_edited_in_sys = EXCLUDED.last_edited_in_sys, local_path = EXCLUDED.local_path, 
+                    content_hash = EXCLUDED.content_hash, last_seen_dl_id = EXCLUDED.last_seen_dl_id, 
+                    not_in_full_export = false, age_groups = EXCLUDED.age_groups, 
+                    anticipated_enrolment = EXCLUDED.anticipated_enrolment, 
+                    actual_enrolment = EXCLUDED.actual_enrolment, 
+                    country_codes = EXCLUDED.country_codes"#, table_name);
+
+    let res = sqlx::query(&sql)
+        .bind(sid_type_ids).bind(sd_sids).bind(titles)
+        .bind(study_types).bind(study_type_ids).bind(study_statuses).bind(study_status_ids)
+        .bind(sponsor_names).bind(sponsors_processed)
+        .bind(reg_sec_ids).bind(oth_sec_ids)
+        .bind(reg_years).bind(enrol_years).bind(results_yes_nos)
+        .bind(country_lists).bind(dates_last_rev).bind(remote_urls)
+        .bind(dl_ids).bind(local_paths).bind(content_hashes).bind(age_groups)
+        .bind(anticipated_enrolments).bind(actual_enrolments).bind(country_codes)
+        .execute(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+
+    let updated = existing as i32;
+    Ok(StoreCounts {
+        added: res.rows_affected() as i32 - updated,
+        updated,
+        unchanged: 0,
+    })
+}
+
+
+pub async fn mark_studies_seen(table_name: &str, sd_sids: &[String], dl_id: i32, conn: &mut PgConnection) -> Result<u64, AppError> {
+
+    // Records that the studies (which have not changed, and so are not 
+    // rewritten) were in the download. A study flagged as not in an earlier 
+    // full export is therefore no longer flagged once it reappears.
+
+    let sql = format!(r#"update dat.{} 
+                set last_seen_dl_id = $2, not_in_full_export = false
+                where sd_sid = any($1::varchar[])"#, table_name);
+    let res = sqlx::query(&sql).bind(sd_sids).bind(dl_id)
+        .execute(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    Ok(res.rows_affected())
+}
+
+
+pub async fn store_study_downloads(sd_sids: &[String], dl_id: i32, conn: &mut PgConnection) -> Result<u64, AppError> {
+
+    // Records that the studies were in the download, whether or not they had changed.
+    // A study already recorded (from a chunk or file being processed again) is ignored.
+
+    let sql = r#"Insert into dat.study_downloads (dl_id, sd_sid)
+                select $1, a.sd_sid from UNNEST($2::varchar[]) as a(sd_sid)
+                ON CONFLICT (dl_id, sd_sid) DO NOTHING"#;
+    let res = sqlx::query(sql).bind(dl_id).bind(sd_sids)
+        .execute(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+    Ok(res.rows_affected())
+}
+
+
+pub async fn flag_studies_not_seen(table_name: &str, dl_id: i32, conn: &mut PgConnection) -> Result<Vec<MissingStudy>, AppError> {
+
+    // Flags, and returns, the studies in the table that were not seen in the (full
+    // export) download, including any that were also missing from earlier exports.
+
+    let sql = format!(r#"update dat.{} 
+                set not_in_full_export = true
+                where coalesce(last_seen_dl_id, 0) < $1
+                returning sd_sid, sid_type_id, last_seen_dl_id, local_path"#, table_name);
+    let rows: Vec<(String, i32, Option<i32>, Option<String>)> = sqlx::query_as(&sql).bind(dl_id)
+        .fetch_all(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    Ok(rows.into_iter()
+        .map(|(sd_sid, sid_type_id, last_seen_dl_id, local_path)| 
+                MissingStudy { sd_sid, sid_type_id, last_seen_dl_id, local_path, archive_path: None })
+        .collect())
+}
+
+
+pub async fn store_studies_not_in_export(dl_id: i32, studies: &[MissingStudy], pool: &Pool<Postgres>) -> Result<u64, AppError> {
+
+    let sd_sids: Vec<&str> = studies.iter().map(|s| s.sd_sid.as_str()).collect();
+    let sid_type_ids: Vec<i32> = studies.iter().map(|s| s.sid_type_id).collect();
+    let last_seen_dl_ids: Vec<Option<i32>> = studies.iter().map(|s| s.last_seen_dl_id).collect();
+    let archive_paths: Vec<Option<String>> = studies.iter().map(|s| s.archive_path.clone()).collect();
+
+    let sql = r#"Insert into evs.studies_not_in_export (dl_id, sd_sid, sid_type_id, last_seen_dl_id, json_archive_path)
+                select $1, a.sd_sid, a.sid_type_id, a.last_seen_dl_id, a.json_archive_path
+                from UNNEST($2::varchar[], $3::int[], $4::int[], $5::varchar[]) 
+                as a(sd_sid, sid_type_id, last_seen_dl_id, json_archive_path)
+                ON CONFLICT (dl_id, sd_sid) DO UPDATE SET 
+                    last_seen_dl_id = EXCLUDED.last_seen_dl_id, json_archive_path = EXCLUDED.json_archive_path"#;
+    let res = sqlx::query(sql).bind(dl_id)
+        .bind(sd_sids).bind(sid_type_ids).bind(last_seen_dl_ids).bind(archive_paths)
+        .execute(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+    Ok(res.rows_affected())
+}
+
+
+pub async fn store_checkpoint(checkpoint: &Checkpoint, pool: &Pool<Postgres>) -> Result<(), AppError> {
+
+    let now = Utc::now();
+    let c = checkpoint;
+    let sql = r#"Insert into evs.dl_checkpoints (dl_id, file_path, file_size, chunk_num, record_offset, 
+                    line_offset, byte_offset, num_checked, num_downloaded, num_added, num_updated, 
+                    num_unchanged, num_rejected, completed, time_saved)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+                ON CONFLICT (dl_id) DO UPDATE SET 
+                    chunk_num = EXCLUDED.chunk_num, record_offset = EXCLUDED.record_offset,
+                    line_offset = EXCLUDED.line_offset, byte_offset = EXCLUDED.byte_offset,
+                    num_checked = EXCLUDED.num_checked, num_downloaded = EXCLUDED.num_downloaded,
+                    num_added = EXCLUDED.num_added, num_updated = EXCLUDED.num_updated,
+                    num_unchanged = EXCLUDED.num_unchanged, num_rejected = EXCLUDED.num_rejected,
+                    completed = EXCLUDED.completed, time_saved = EXCLUDED.time_saved"#;
+    sqlx::query(sql).bind(c.dl_id).bind(&c.file_path).bind(c.file_size).bind(c.chunk_num)
+        .bind(c.record_offset).bind(c.line_offset).bind(c.byte_offset)
+        .bind(c.totals.num_checked).bind(c.totals.num_downloaded).bind(c.totals.num_added)
+        .bind(c.totals.num_updated).bind(c.totals.num_unchanged).bind(c.totals.num_rejected)
+        .bind(c.completed).bind(now)
+        .execute(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+    Ok(())
+}
+
+
+// file_path, file_size, the chunk number and positions, and the running totals
+
+type CheckpointRow = (String, i64, i32, i64, i64, i64, i32, i32, i32, i32, i32, i32, bool);
+
+pub async fn fetch_checkpoint(dl_id: i32, pool: &Pool<Postgres>) -> Result<Option<Checkpoint>, AppError> {
+
+    let sql = r#"select file_path, file_size, chunk_num, record_offset, line_offset, byte_offset, 
+                    num_checked, num_downloaded, num_added, num_updated, num_unchanged, num_rejected, completed
+                from evs.dl_checkpoints where dl_id = $1"#;
+    let row: Option<CheckpointRow> = sqlx::query_as(sql)
+        .bind(dl_id).fetch_optional(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    Ok(row.map(|(file_path, file_size, chunk_num, record_offset, line_offset, byte_offset,
+                 num_checked, num_downloaded, num_added, num_updated, num_unchanged, num_rejected, completed)| 
+        Checkpoint {
+            dl_id, file_path, file_size, chunk_num, record_offset, line_offset, byte_offset,
+            totals: DownloadResult { num_checked, num_downloaded, num_added, num_updated, num_unchanged, 
+                                     num_rejected, num_not_in_export: 0 },
+            completed,
+        }))
+}
+
+
+pub async fn get_version_changes(sd_sids: &[String], conn: &mut PgConnection) -> Result<Vec<(String, String)>, AppError> {
+
+    // Returns the changes (as json strings) recorded in each version of the 
+    // studies, in the order in which they were recorded.
+
+    let sql = r#"select sd_sid, changes::text from dat.study_versions 
+                where sd_sid = any($1::varchar[])
+                order by sd_sid, dl_id"#;
+    sqlx::query_as(sql).bind(sd_sids).fetch_all(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))
+}
+
+
+pub async fn store_study_versions(rows: Vec<VersionRow>, conn: &mut PgConnection) -> Result<u64, AppError> {
+
+    // As for the summaries, the changed field names are joined using chr(31). If the study 
+    // already has a version from the same download (i.e. it was in an earlier file of 
+    // the same run) the two are merged, with the later values taking precedence.
+
+    let mut sd_sids = Vec::<String>::new();
+    let mut sid_type_ids = Vec::<i32>::new();
+    let mut dl_ids = Vec::<i32>::new();
+    let mut record_dates = Vec::<Option<NaiveDate>>::new();
+    let mut content_hashes = Vec::<String>::new();
+    let mut changed_fields = Vec::<String>::new();
+    let mut changes = Vec::<String>::new();
+    let mut source_files = Vec::<String>::new();
+
+    for r in rows {
+        sd_sids.push(r.sd_sid);
+        sid_type_ids.push(r.sid_type_id);
+        dl_ids.push(r.dl_id);
+        record_dates.push(r.record_date);
+        content_hashes.push(r.content_hash);
+        changed_fields.push(r.changed_fields.join("\u{1f}"));
+        changes.push(r.changes);
+        source_files.push(r.source_file);
     }
 
-    sqlx::query(&sql)
-    .bind(rec.sid_type_id).bind(rec.sd_sid).bind(rec.title)
-    .bind(rec.study_type).bind(rec.study_type_id).bind(rec.study_status).bind(rec.study_status_id)
-    .bind(rec.sponsor_name).bind(rec.sponsor_processed)
-    .bind(rec.reg_sec_ids).bind(rec.oth_sec_ids)
-    .bind(rec.reg_year).bind(rec.enrol_year).bind(rec.results_yes_no)
-    .bind(rec.country_list).bind(rec.date_last_rev_in_who).bind(rec.remote_url)
-    .bind(rec.dl_id).bind(now).bind(local_path)
-    .execute(pool).await
-    .map_err(|e| AppError::SqlxError(e, sql))?;
+    let sql = r#"Insert into dat.study_versions (sd_sid, sid_type_id, dl_id, record_date, 
+                    content_hash, changed_fields, changes, source_file)
+                select a.sd_sid, a.sid_type_id, a.dl_id, a.record_date, 
+                    a.content_hash, string_to_array(a.changed_fields, chr(31)), a.changes::jsonb, a.source_file
+                from UNNEST($1::varchar[], $2::int[], $3::int[], $4::date[], 
+                    $5::varchar[], $6::varchar[], $7::text[], $8::varchar[])
+                as a(sd_sid, sid_type_id, dl_id, record_date, content_hash, changed_fields, changes, source_file)
+                ON CONFLICT (sd_sid, dl_id) DO UPDATE SET 
+                    record_date = EXCLUDED.record_date, content_hash = EXCLUDED.content_hash, 
+                    changed_fields = array(select distinct unnest(study_versions.changed_fields || EXCLUDED.changed_fields)), 
+                    changes = study_versions.changes || EXCLUDED.changes, 
+                    source_file = EXCLUDED.source_file, recorded_at = now()"#;
+
+    let res = sqlx::query(sql)
+        .bind(sd_sids).bind(sid_type_ids).bind(dl_ids).bind(record_dates)
+        .bind(content_hashes).bind(changed_fields).bind(changes).bind(source_files)
+        .execute(&mut *conn).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+    Ok(res.rows_affected())
+}
+
+
+pub async fn fetch_versions_to_download(dl_id: i32, pool: &Pool<Postgres>) -> Result<Vec<(String, i32, String)>, AppError> {
 
-    Ok(!record_exists)   // return whether record needed to be added or not
+    // Returns the changes recorded in each version of each study in the given
+    // download, up to and including that download, ordered by study and then download.
 
+    let sql = r#"select v.sd_sid, v.sid_type_id, v.changes::text from dat.study_versions v
+                inner join dat.study_downloads d on d.sd_sid = v.sd_sid and d.dl_id = $1
+                where v.dl_id <= $1
+                order by v.sd_sid, v.dl_id"#;
+    sqlx::query_as(sql).bind(dl_id).fetch_all(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))
+}
+
+
+pub async fn download_has_studies(dl_id: i32, pool: &Pool<Postgres>) -> Result<bool, AppError> {
+
+    let sql = "select exists (select 1 from dat.study_downloads where dl_id = $1)";
+    sqlx::query_scalar(sql).bind(dl_id).fetch_one(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))
+}
+
+
+pub async fn fetch_study_versions(sd_sid: &str, pool: &Pool<Postgres>) -> Result<Vec<StudyVersion>, AppError> {
+
+    let sql = r#"select dl_id, record_date, source_file, changes::text from dat.study_versions 
+                where sd_sid = $1
+                order by dl_id"#;
+    let rows: Vec<(i32, Option<NaiveDate>, Option<String>, String)> = sqlx::query_as(sql).bind(sd_sid)
+        .fetch_all(pool).await
+        .map_err(|e| AppError::SqlxError(e, sql.to_string()))?;
+
+    let mut versions = Vec::with_capacity(rows.len());
+    for (dl_id, record_date, source_file, changes) in rows {
+        versions.push(StudyVersion { dl_id, record_date, source_file, changes: serde_json::from_str(&changes)? });
+    }
+    Ok(versions)
 }
  
 /* 
@@ -192,4 +767,4 @@ pub fn is_who_test_study() -> bool {
     false
 }
 
-*/
\ No newline at end of file
+*/
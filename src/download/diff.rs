@@ -0,0 +1,477 @@
+/**********************************************************************************
+The diff subcommand compares two sources of WHO data - each either a WHO csv (or
+zip) file, or the data stored after an earlier import, identified by its dl_id -
+and reports the trial ids added or removed and, for ids in both, the fields of the
+full WHO record that have changed. File rows pass through the same deserialisation,
+summarise_line and process_line functions as an import, so the comparison is made
+on the normalised values rather than the raw text, and the volatile fields (the
+date the record was last updated) are ignored. The data stored after an import is
+rebuilt from the versions (see versions.rs) of the studies in that import, so that
+studies missing from the later of two imports are reported as removed. So that two
+full exports can be compared each study is held only as a hash of each of its fields. The totals for
+each registry, and the numbers of studies in which each field changed, are written
+to the log, and the individual studies to a csv file in a 'diffs' sub-folder of the
+log folder.
+***********************************************************************************/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use csv::{ByteRecord, Reader, ReaderBuilder, Writer};
+use log::{info, warn};
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use crate::err::AppError;
+use super::data_access::{download_has_studies, fetch_versions_to_download};
+use super::processor;
+use super::read_next_line;
+use super::source_file::open_source_reader;
+use super::validator::{log_tally, IssueTally};
+use super::versions::{apply_changes, FieldMap};
+use super::who_helper::get_db_name;
+
+const NULL_HASH: u64 = 0;    // a field that is missing or null
+
+
+pub enum DiffSource {
+    File(PathBuf),
+    Download(i32),
+}
+
+impl DiffSource {
+
+    // An argument that is a whole number is taken as a dl_id, anything else as a
+    // file, named within the csv data folder or as a full path.
+
+    pub fn from_arg(arg: &str, csv_data_path: &Path) -> Self {
+        match arg.trim().parse::<i32>() {
+            Ok(dl_id) => DiffSource::Download(dl_id),
+            Err(_) => DiffSource::File(csv_data_path.join(arg)),
+        }
+    }
+
+    pub fn needs_db(&self) -> bool {
+        matches!(self, DiffSource::Download(_))
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            DiffSource::File(p) => p.file_stem().map_or("file".to_string(), |s| s.to_string_lossy().to_string()),
+            DiffSource::Download(dl_id) => format!("dl {}", dl_id),
+        }
+    }
+}
+
+
+// The names of the fields, in the order in which their
+// hashes are held, shared by the two sources compared.
+
+#[derive(Default)]
+pub struct FieldIndex {
+    names: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl FieldIndex {
+    fn position(&mut self, name: &str) -> usize {
+        match self.positions.get(name) {
+            Some(&i) => i,
+            None => {
+                self.names.push(name.to_string());
+                self.positions.insert(name.to_string(), self.names.len() - 1);
+                self.names.len() - 1
+            },
+        }
+    }
+}
+
+
+struct StudyDigest {
+    sid_type_id: i32,
+    field_hashes: Vec<u64>,
+}
+
+#[derive(Default)]
+pub struct SourceDigest {
+    studies: HashMap<String, StudyDigest>,
+    pub num_rejected: i32,
+}
+
+impl SourceDigest {
+
+    // A study appearing more than once is represented by its
+    // last record, as it would be after an import.
+
+    fn add(&mut self, sd_sid: String, sid_type_id: i32, fields: &FieldMap, index: &mut FieldIndex) {
+        let mut field_hashes = vec![NULL_HASH; index.names.len()];
+        for (k, v) in fields {
+            if v.is_null() {
+                continue;
+            }
+            let i = index.position(k);
+            if i >= field_hashes.len() {
+                field_hashes.resize(i + 1, NULL_HASH);
+            }
+            field_hashes[i] = hash_value(v);
+        }
+        self.studies.insert(sd_sid, StudyDigest { sid_type_id, field_hashes });
+    }
+}
+
+
+fn hash_value(value: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl DiffKind {
+    fn name(&self) -> &'static str {
+        match self {
+            DiffKind::Added => "added",
+            DiffKind::Removed => "removed",
+            DiffKind::Changed => "changed",
+        }
+    }
+}
+
+
+pub struct StudyDiff {
+    pub sd_sid: String,
+    pub sid_type_id: i32,
+    pub kind: DiffKind,
+    pub fields: Vec<String>,     // the fields changed
+}
+
+
+#[derive(Debug, Default)]
+pub struct RegistryDiff {
+    pub added: IssueTally,
+    pub removed: IssueTally,
+    pub changed: i32,
+    pub unchanged: i32,
+    pub field_changes: BTreeMap<String, i32>,
+}
+
+
+#[derive(Default)]
+pub struct DiffReport {
+    pub registries: BTreeMap<i32, RegistryDiff>,
+    pub studies: Vec<StudyDiff>,
+}
+
+impl DiffReport {
+
+    pub fn log_report(&self, from: &DiffSource, to: &DiffSource) {
+
+        info!("");
+        info!("Comparison of {} with {}", from.label(), to.label());
+        let mut totals = RegistryDiff::default();
+        for (sid_type_id, r) in &self.registries {
+            info!("");
+            info!("{} ({}): {} added, {} removed, {} changed, {} unchanged", get_db_name(*sid_type_id), sid_type_id,
+                        r.added.count, r.removed.count, r.changed, r.unchanged);
+            log_tally("    Trial ids added", &r.added);
+            log_tally("    Trial ids removed", &r.removed);
+            for (field, n) in &r.field_changes {
+                info!("    {}: changed in {}", field, n);
+                *totals.field_changes.entry(field.clone()).or_default() += n;
+            }
+            totals.added.count += r.added.count;
+            totals.removed.count += r.removed.count;
+            totals.changed += r.changed;
+            totals.unchanged += r.unchanged;
+        }
+        info!("");
+        info!("In total: {} added, {} removed, {} changed, {} unchanged",
+                    totals.added.count, totals.removed.count, totals.changed, totals.unchanged);
+        for (field, n) in &totals.field_changes {
+            info!("    {}: changed in {}", field, n);
+        }
+        info!("---------------------------------------------------");
+    }
+
+    // Writes the studies added, removed or changed to a csv file, replacing any
+    // earlier comparison of the same sources. Returns the path of the file.
+
+    pub fn write_studies(&self, folder: &Path, from: &DiffSource, to: &DiffSource) -> Result<PathBuf, AppError> {
+
+        fs::create_dir_all(folder)?;
+        let file_path = folder.join(format!("{} vs {}.csv", from.label(), to.label()));
+        let file = File::create(&file_path)
+            .map_err(|e| AppError::IoWriteErrorWithPath(e, file_path.clone()))?;
+        let mut w = Writer::from_writer(file);
+        w.write_record(["sd_sid", "sid_type_id", "change", "fields_changed"])
+            .map_err(|e| AppError::CsvError(e, "diff file header".to_string()))?;
+        for s in &self.studies {
+            w.write_record([&s.sd_sid, &s.sid_type_id.to_string(), s.kind.name(), &s.fields.join("; ")])
+                .map_err(|e| AppError::CsvError(e, s.sd_sid.clone()))?;
+        }
+        w.flush()?;
+        Ok(file_path)
+    }
+}
+
+
+pub async fn compare_sources(from: &DiffSource, to: &DiffSource, pool: Option<&Pool<Postgres>>) -> Result<DiffReport, AppError> {
+
+    let mut index = FieldIndex::default();
+    let old = digest_source(from, &mut index, pool).await?;
+    let new = digest_source(to, &mut index, pool).await?;
+    Ok(compare_digests(&old, &new, &index))
+}
+
+
+async fn digest_source(source: &DiffSource, index: &mut FieldIndex, pool: Option<&Pool<Postgres>>) -> Result<SourceDigest, AppError> {
+
+    let digest = match (source, pool) {
+        (DiffSource::File(file_path), _) => {
+            let source_reader = open_source_reader(file_path)?;
+            let mut csv_rdr = ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(source_reader);
+            info!("Reading file {:?}", file_path);
+            digest_records(&mut csv_rdr, index)?
+        },
+        (DiffSource::Download(dl_id), Some(pool)) => {
+            info!("Reading the data stored after download {}", dl_id);
+            digest_download(*dl_id, index, pool).await?
+        },
+        (DiffSource::Download(_), None) => {
+            return Result::Err(AppError::InconsistentProgramParameter(
+                    "A database connection is needed to compare downloads".to_string()));
+        },
+    };
+    info!("{} studies read from {}", digest.studies.len(), source.label());
+    if digest.num_rejected > 0 {
+        warn!("{} rows rejected from {}", digest.num_rejected, source.label());
+    }
+    Ok(digest)
+}
+
+
+fn digest_records<R: Read>(csv_rdr: &mut Reader<R>, index: &mut FieldIndex) -> Result<SourceDigest, AppError> {
+
+    let mut digest = SourceDigest::default();
+    let mut record = ByteRecord::new();
+    let mut num_checked = 0;
+
+    while let Some(line_result) = read_next_line(csv_rdr, &mut record, num_checked + 1)? {
+        num_checked += 1;
+        if num_checked % 10000 == 0 {
+            info!("{} records read", num_checked);
+        }
+
+        let who_line = match line_result {
+            Ok(w) => w,
+            Err(_) => {
+                digest.num_rejected += 1;
+                continue;
+            }
+        };
+        let summ = match processor::summarise_line(&who_line, 0) {
+            Ok(s) => s,
+            Err(_) => {
+                digest.num_rejected += 1;
+                continue;
+            }
+        };
+        if let Some(rec) = processor::process_line(who_line, &summ) {
+            digest.add(summ.sd_sid, summ.sid_type_id, &rec.content_fields(), index);
+        }
+    }
+    Ok(digest)
+}
+
+
+async fn digest_download(dl_id: i32, index: &mut FieldIndex, pool: &Pool<Postgres>) -> Result<SourceDigest, AppError> {
+
+    // Only the studies in the download are included (so that those missing from a later
+    // download are reported as removed), each as it was after that download.
+
+    if !download_has_studies(dl_id, pool).await? {
+        return Result::Err(AppError::InconsistentProgramParameter(
+                format!("The studies in download {} have not been recorded (it may have been imported before they were) - compare its file instead", dl_id)));
+    }
+    let versions = fetch_versions_to_download(dl_id, pool).await?;
+    digest_versions(versions, index)
+}
+
+
+fn digest_versions(versions: Vec<(String, i32, String)>, index: &mut FieldIndex) -> Result<SourceDigest, AppError> {
+
+    // Each study's state is built up from its versions, which are returned in order.
+
+    let mut digest = SourceDigest::default();
+    let mut current: Option<(String, i32, FieldMap)> = None;
+
+    for (sd_sid, sid_type_id, changes) in versions {
+        let changes: FieldMap = serde_json::from_str(&changes)?;
+        match current.as_mut() {
+            Some((sid, type_id, state)) if *sid == sd_sid => {
+                *type_id = sid_type_id;
+                apply_changes(state, &changes);
+            },
+            _ => {
+                if let Some((sid, type_id, state)) = current.take() {
+                    digest.add(sid, type_id, &state, index);
+                }
+                let mut state = FieldMap::new();
+                apply_changes(&mut state, &changes);
+                current = Some((sd_sid, sid_type_id, state));
+            },
+        }
+    }
+    if let Some((sid, type_id, state)) = current {
+        digest.add(sid, type_id, &state, index);
+    }
+    Ok(digest)
+}
+
+
+fn compare_digests(old: &SourceDigest, new: &SourceDigest, index: &FieldIndex) -> DiffReport {
+
+    let mut report = DiffReport::default();
+
+    for (sd_sid, o) in &old.studies {
+        match new.studies.get(sd_sid) {
+            None => {
+                report.registries.entry(o.sid_type_id).or_default().removed.add(sd_sid.clone());
+                report.studies.push(StudyDiff { sd_sid: sd_sid.clone(), sid_type_id: o.sid_type_id,
+                                                kind: DiffKind::Removed, fields: vec![] });
+            },
+            Some(n) => {
+                let registry = report.registries.entry(n.sid_type_id).or_default();
+                let num_fields = o.field_hashes.len().max(n.field_hashes.len());
+                let fields: Vec<String> = (0..num_fields)
+                    .filter(|&i| o.field_hashes.get(i).unwrap_or(&NULL_HASH) != n.field_hashes.get(i).unwrap_or(&NULL_HASH))
+                    .map(|i| index.names[i].clone())
+                    .collect();
+                if fields.is_empty() {
+                    registry.unchanged += 1;
+                } else {
+                    registry.changed += 1;
+                    for f in &fields {
+                        *registry.field_changes.entry(f.clone()).or_default() += 1;
+                    }
+                    report.studies.push(StudyDiff { sd_sid: sd_sid.clone(), sid_type_id: n.sid_type_id,
+                                                    kind: DiffKind::Changed, fields });
+                }
+            },
+        }
+    }
+
+    for (sd_sid, n) in &new.studies {
+        if !old.studies.contains_key(sd_sid) {
+            report.registries.entry(n.sid_type_id).or_default().added.add(sd_sid.clone());
+            report.studies.push(StudyDiff { sd_sid: sd_sid.clone(), sid_type_id: n.sid_type_id,
+                                            kind: DiffKind::Added, fields: vec![] });
+        }
+    }
+
+    report.studies.sort_by(|a, b| a.sd_sid.cmp(&b.sd_sid));
+    report
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support::make_row;
+
+    fn digest_rows(rows: &[String], index: &mut FieldIndex) -> SourceDigest {
+        let data = rows.join("\n") + "\n";
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(data.as_bytes());
+        digest_records(&mut rdr, index).unwrap()
+    }
+
+    #[test]
+    fn check_files_compared_on_normalised_values() {
+        let mut index = FieldIndex::default();
+        let old = digest_rows(&[
+            make_row(&[(0, "ACTRN12624000001"), (1, "2025-01-06"), (21, "2024-01-15"), (24, "Recruiting")]),
+            make_row(&[(0, "ACTRN12624000002"), (21, "2024-01-15"), (24, "Recruiting")]),
+            make_row(&[(0, "NCT01234567"), (3, "A study"), (21, "2024-01-15")]),
+            make_row(&[(0, "ChiCTR2400000001"), (3, "To go")]),
+        ], &mut index);
+        let new = digest_rows(&[
+            make_row(&[(0, "ACTRN12624000001"), (1, "2025-02-24"), (21, "15/01/2024"), (24, "Completed")]),
+            make_row(&[(0, "ACTRN12624000002"), (21, "2024-01-15"), (24, "Recruiting")]),
+            make_row(&[(0, "NCT01234567"), (3, "A revised study"), (21, "2024-01-15")]),
+            make_row(&[(0, "NCT07654321")]),
+            "too,few,fields".to_string(),
+        ], &mut index);
+        assert_eq!(new.num_rejected, 1);
+
+        let report = compare_digests(&old, &new, &index);
+        let anzctr = &report.registries[&116];
+        assert_eq!(anzctr.changed, 1);
+        assert_eq!(anzctr.unchanged, 1);    // the last updated date, and the form of the date, are ignored
+        assert_eq!(anzctr.field_changes.keys().collect::<Vec<&String>>(), vec!["study_status_id", "study_status_orig"]);
+
+        let ctg = &report.registries[&120];
+        assert_eq!(ctg.added.samples, vec!["NCT07654321".to_string()]);
+        assert_eq!(ctg.field_changes.get("pub_title"), Some(&1));
+        assert_eq!(report.registries[&118].removed.count, 1);
+
+        let kinds: Vec<(&str, DiffKind)> = report.studies.iter().map(|s| (s.sd_sid.as_str(), s.kind)).collect();
+        assert_eq!(kinds, vec![("ACTRN12624000001", DiffKind::Changed), ("ChiCTR2400000001", DiffKind::Removed),
+                               ("NCT01234567", DiffKind::Changed), ("NCT07654321", DiffKind::Added)]);
+    }
+
+    #[test]
+    fn check_missing_and_null_fields_equivalent() {
+        let mut index = FieldIndex::default();
+        let field_map = |v: Value| match v { Value::Object(m) => m, _ => FieldMap::new() };
+        let mut old = SourceDigest::default();
+        old.add("A1".to_string(), 100, &field_map(serde_json::json!({"a": "x", "b": null})), &mut index);
+        old.add("A2".to_string(), 100, &field_map(serde_json::json!({"a": "x"})), &mut index);
+        let mut new = SourceDigest::default();
+        new.add("A1".to_string(), 100, &field_map(serde_json::json!({"a": "x", "c": null})), &mut index);
+        new.add("A2".to_string(), 100, &field_map(serde_json::json!({"a": "x", "c": ["y"]})), &mut index);
+
+        let report = compare_digests(&old, &new, &index);
+        assert_eq!(report.registries[&100].unchanged, 1);
+        assert_eq!(report.studies.len(), 1);
+        assert_eq!(report.studies[0].fields, vec!["c".to_string()]);
+    }
+
+    #[test]
+    fn check_study_missing_from_later_download_removed() {
+
+        // The versions of the studies in each download, up to that download - B was
+        // in download 1 but not download 2, while A changed and C was new in 2.
+
+        let version = |sd_sid: &str, changes: &str| (sd_sid.to_string(), 116, changes.to_string());
+        let mut index = FieldIndex::default();
+        let old = digest_versions(vec![version("A", r#"{"a": "x"}"#), version("B", r#"{"a": "y"}"#)], &mut index).unwrap();
+        let new = digest_versions(vec![version("A", r#"{"a": "x"}"#), version("A", r#"{"a": "z"}"#),
+                                       version("C", r#"{"a": "y"}"#)], &mut index).unwrap();
+
+        let report = compare_digests(&old, &new, &index);
+        let kinds: Vec<(&str, DiffKind)> = report.studies.iter().map(|s| (s.sd_sid.as_str(), s.kind)).collect();
+        assert_eq!(kinds, vec![("A", DiffKind::Changed), ("B", DiffKind::Removed), ("C", DiffKind::Added)]);
+        assert_eq!(report.registries[&116].removed.samples, vec!["B".to_string()]);
+    }
+
+    #[test]
+    fn check_sources_identified_from_arguments() {
+        let folder = Path::new("/data/who");
+        assert!(matches!(DiffSource::from_arg("12", folder), DiffSource::Download(12)));
+        let file = DiffSource::from_arg("20250224 ICTRP.csv", folder);
+        assert!(matches!(&file, DiffSource::File(p) if p == Path::new("/data/who/20250224 ICTRP.csv")));
+        assert_eq!(file.label(), "20250224 ICTRP");
+        assert!(matches!(DiffSource::from_arg("/full/ICTRPFullExport.zip", folder), DiffSource::File(p) if p == Path::new("/full/ICTRPFullExport.zip")));
+        assert_eq!(DiffSource::Download(7).label(), "dl 7");
+    }
+}
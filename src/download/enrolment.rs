@@ -0,0 +1,211 @@
+/**********************************************************************************
+The planned and actual enrolment of a study. The target_size field of the WHO data
+is free text - '120', 'Total: 120', '120 participants', 'Group A: 50; Group B: 50',
+'实验组:30;对照组:30;', or (in CTRI) 'Sample Size from India = 100 ;Final Enrollment
+numbers achieved (Total)= 98'. Each part of the text is taken as a total (if it is
+unlabelled, or labelled as a total or as the sample size), an enrolment achieved, or
+the size of a group. The target total is the labelled total, or else the sum of the
+groups. It is then combined with type_enrolment, which says whether the target is
+the actual enrolment (as in CTG once a study has completed) or the anticipated one,
+and with results_actual_enrollment, to give the anticipated and the actual enrolment.
+These are stored in the dat tables (anticipated_enrolment, actual_enrolment), so that
+the distribution of study sizes by registry and registration year can be queried.
+***********************************************************************************/
+
+use std::sync::LazyLock;
+use regex::Regex;
+
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct EnrolmentGroup {
+    pub name: String,
+    pub size: i32,
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct Enrolment {
+    pub target_total: Option<i32>,
+    pub target_groups: Vec<EnrolmentGroup>,
+    pub anticipated: Option<i32>,
+    pub actual: Option<i32>,
+}
+
+
+// The target size as parsed, before it is combined with the other fields.
+
+#[derive(Debug, Default, PartialEq)]
+struct TargetSize {
+    total: Option<i32>,
+    achieved: Option<i32>,
+    groups: Vec<EnrolmentGroup>,
+}
+
+
+#[derive(Debug, PartialEq)]
+enum SizeLabel {
+    Total,
+    Achieved,
+    Group(String),
+}
+
+
+static THOUSANDS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d)[,.](\d{3})\b").unwrap());
+static NUMBER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+").unwrap());
+
+const TOTAL_LABELS: [&str; 9] = ["total", "overall", "planned", "target", "sample size", "number of participants",
+                                 "总", "合计", "共"];
+const ACHIEVED_LABELS: [&str; 3] = ["achieved", "actual", "enrolled"];
+
+
+pub fn get_enrolment(target_size: &str, type_enrolment: &str, actual_enrollment: &str) -> Option<Enrolment> {
+
+    let target = parse_target_size(target_size);
+    let results_actual = get_number(actual_enrollment).filter(|n| *n > 0);
+    let target_is_actual = type_enrolment.trim().to_lowercase().starts_with("actual");
+
+    let enrolment = Enrolment {
+        anticipated: if target_is_actual { None } else { target.total },
+        actual: results_actual.or(target.achieved).or(if target_is_actual { target.total } else { None }),
+        target_total: target.total,
+        target_groups: target.groups,
+    };
+    match enrolment == Enrolment::default() {
+        true => None,
+        false => Some(enrolment),
+    }
+}
+
+
+fn parse_target_size(target_size: &str) -> TargetSize {
+
+    // Thousands separators are removed first, so that the text
+    // can then be split into its parts at any commas.
+
+    let text = THOUSANDS.replace_all(target_size.trim(), "$1$2");
+    let mut target = TargetSize::default();
+    let mut totals: Vec<(bool, i32)> = Vec::new();   // (labelled 'total', size)
+
+    for part in text.split([';', '；', ',', '，', '\n']) {
+        match parse_part(part) {
+            Some((SizeLabel::Total, size)) => totals.push((is_total(part), size)),
+            Some((SizeLabel::Achieved, size)) if size > 0 => { target.achieved.get_or_insert(size); },
+            Some((SizeLabel::Achieved, _)) => {},      // 0 in CTRI until recruitment is complete
+            Some((SizeLabel::Group(name), size)) => target.groups.push(EnrolmentGroup { name, size }),
+            None => {},
+        }
+    }
+
+    // A total explicitly labelled as such is preferred (e.g. to the sample size in
+    // one country). Otherwise the groups, if there are any, are added together.
+
+    target.total = match totals.iter().find(|(total, _)| *total).or(totals.first()) {
+        Some((_, size)) => Some(*size),
+        None if !target.groups.is_empty() => target.groups.iter().map(|g| g.size).try_fold(0, i32::checked_add),
+        None => None,
+    };
+    target
+}
+
+
+fn parse_part(part: &str) -> Option<(SizeLabel, i32)> {
+
+    // The size is the first number after the last colon or equals sign, if
+    // there is one, and the label the text before it. Otherwise the size is
+    // the first number, and the part is a total (e.g. '120 participants').
+
+    let (label, size) = match part.rfind([':', '：', '=']) {
+        Some(pos) => {
+            let sep_len = part[pos..].chars().next().map_or(1, |c| c.len_utf8());
+            (part[..pos].trim(), get_number(&part[pos + sep_len..])?)
+        },
+        None => ("", get_number(part)?),
+    };
+    let llabel = label.to_lowercase();
+    if llabel.is_empty() || TOTAL_LABELS.iter().any(|l| llabel.contains(l)) && !is_achieved(&llabel) {
+        Some((SizeLabel::Total, size))
+    }
+    else if is_achieved(&llabel) {
+        Some((SizeLabel::Achieved, size))
+    }
+    else {
+        Some((SizeLabel::Group(label.to_string()), size))
+    }
+}
+
+
+fn is_total(part: &str) -> bool {
+    let lpart = part.to_lowercase();
+    ["total", "总", "合计"].iter().any(|l| lpart.contains(l))
+}
+
+
+fn is_achieved(label: &str) -> bool {
+    ACHIEVED_LABELS.iter().any(|l| label.contains(l))
+}
+
+
+fn get_number(text: &str) -> Option<i32> {
+    let text = THOUSANDS.replace_all(text, "$1$2");
+    NUMBER.find(&text).and_then(|m| m.as_str().parse().ok())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(name: &str, size: i32) -> EnrolmentGroup {
+        EnrolmentGroup { name: name.to_string(), size }
+    }
+
+    #[test]
+    fn check_totals_parsed() {
+        assert_eq!(parse_target_size("120").total, Some(120));
+        assert_eq!(parse_target_size("Total: 120").total, Some(120));
+        assert_eq!(parse_target_size("120 participants").total, Some(120));
+        assert_eq!(parse_target_size("1,200").total, Some(1200));
+        assert_eq!(parse_target_size("总样本量：1,200").total, Some(1200));
+        assert_eq!(parse_target_size("Planned Sample Size: 450; UK Sample Size: 150").total, Some(450));
+        assert_eq!(parse_target_size("").total, None);
+        assert_eq!(parse_target_size("Not stated").total, None);
+    }
+
+    #[test]
+    fn check_groups_parsed_and_added() {
+        let target = parse_target_size("Group A: 50; Group B: 50");
+        assert_eq!(target.total, Some(100));
+        assert_eq!(target.groups, vec![group("Group A", 50), group("Group B", 50)]);
+
+        let target = parse_target_size("实验组:30;对照组:30;");
+        assert_eq!(target.total, Some(60));
+        assert_eq!(target.groups, vec![group("实验组", 30), group("对照组", 30)]);
+
+        let target = parse_target_size("Experimental group 1:40;Control group:20;Total:60;");
+        assert_eq!(target.total, Some(60));
+        assert_eq!(target.groups.len(), 2);
+    }
+
+    #[test]
+    fn check_achieved_enrolment_parsed() {
+        let target = parse_target_size("Sample Size from India = 100 ;Total Sample Size=500 ;Final Enrollment numbers achieved (Total)= 480 ;Final Enrollment numbers achieved (India)=95");
+        assert_eq!(target.total, Some(500));
+        assert_eq!(target.achieved, Some(480));
+        let target = parse_target_size("Sample Size from India = 100 ;Final Enrollment numbers achieved (Total)= 0");
+        assert_eq!((target.total, target.achieved), (Some(100), None));
+    }
+
+    #[test]
+    fn check_anticipated_and_actual_enrolment() {
+        let e = get_enrolment("120", "Anticipated", "").unwrap();
+        assert_eq!((e.anticipated, e.actual), (Some(120), None));
+        let e = get_enrolment("118", "Actual", "").unwrap();
+        assert_eq!((e.anticipated, e.actual), (None, Some(118)));
+        let e = get_enrolment("120", "", "97").unwrap();
+        assert_eq!((e.anticipated, e.actual), (Some(120), Some(97)));
+        let e = get_enrolment("", "", "97").unwrap();
+        assert_eq!((e.target_total, e.anticipated, e.actual), (None, None, Some(97)));
+        assert_eq!(get_enrolment("", "Anticipated", ""), None);
+        assert_eq!(get_enrolment("", "", "0"), None);
+    }
+}
@@ -1,82 +1,90 @@
 use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+use super::gen_helper::DatePrecision;
+use super::ages::AgeEligibility;
+use super::genders::GenderEligibility;
+use super::enrolment::Enrolment;
+
+// The fields of a row of a WHO file, borrowed from the csv record (which the
+// reader reuses), so that only the values kept in a WHORecord are copied.
 
 #[allow(dead_code)]
 #[derive(serde::Deserialize)]
-pub struct WHOLine
+pub struct WHOLine<'a>
 {
-    pub trial_id: String,                           // 0
-    pub last_updated: String,                       // 1
-    pub sec_ids: String,                            // 2
-    pub pub_title: String,                          // 3
-    pub scientific_title: String,                   // 4
-    pub url: String,                                // 5
-
-    pub pub_contact_first_name: String,             // 6
-    pub pub_contact_last_name: String,              // 7
-    pub pub_contact_address: String,                // 8
-    pub pub_contact_email: String,                  // 9
-    pub pub_contact_tel: String,                    // 10
-    pub pub_contact_affiliation: String,            // 11
-
-    pub sci_contact_first_name: String,             // 12
-    pub sci_contact_last_name: String,              // 13
-    pub sci_contact_address: String,                // 14
-    pub sci_contact_email: String,                  // 15
-    pub sci_contact_tel: String,                    // 16
-    pub sci_contact_affiliation: String,            // 17
-
-    pub study_type: String,                         // 18
-    pub study_design: String,                       // 19
-    pub phase: String,                              // 20
-    pub date_registration: String,                  // 21
-    pub date_enrollement: String,                   // 22
-    pub target_size: String,                        // 23
-    pub recruitment_status:String,                  // 24
-
-    pub primary_sponsor: String,                    // 25
-    pub secondary_sponsors: String,                 // 26
-    pub source_support: String,                     // 27
-    pub countries: String,                          // 28
-    pub conditions: String,                         // 29
-    pub interventions: String,                      // 30
-
-    pub age_min: String,                            // 31
-    pub age_max: String,                            // 32
-    pub gender: String,                             // 33
-    pub inclusion_criteria: String,                 // 34
-    pub exclusion_criteria: String,                 // 35
-
-    pub primary_outcome: String,                    // 36
-    pub secondary_outcomes: String,                 // 37
-
-    pub bridging_flag: String,                      // 38
-    pub bridged_type: String,                       // 39
-    pub childs: String,                             // 40
-    pub type_enrolment: String,                     // 41
-    pub retrospective_flag: String,                 // 42
-
-    pub results_actual_enrollment: String,          // 43
-    pub results_url_link: String,                   // 44
-    pub results_summary: String,                    // 45
-    pub results_date_posted: String,                // 46
-    pub results_date_first_pub: String,             // 47
-    pub results_baseline_char: String,              // 48
-    pub results_participant_flow: String,           // 49
-    pub results_adverse_events: String,             // 50
-    pub results_outcome_measures: String,           // 51
-    pub results_url_protocol: String,               // 52
-
-    pub results_ipd_plan: String,                   // 53
-    pub results_ipd_description: String,            // 54
-    pub results_date_completed: String,             // 55
-    pub results_yes_no: String,                     // 56
-
-    pub ethics_status: String,                      // 57
-    pub ethics_approval_date: String,               // 58
-    pub ethics_contact_name: String,                // 59
-    pub ethics_contact_address:String,              // 60
-    pub ethics_contact_phone: String,               // 61
-    pub ethics_contact_email: String,               // 62
+    pub trial_id: &'a str,                        // 0
+    pub last_updated: &'a str,                    // 1
+    pub sec_ids: &'a str,                         // 2
+    pub pub_title: &'a str,                       // 3
+    pub scientific_title: &'a str,                // 4
+    pub url: &'a str,                             // 5
+
+    pub pub_contact_first_name: &'a str,          // 6
+    pub pub_contact_last_name: &'a str,           // 7
+    pub pub_contact_address: &'a str,             // 8
+    pub pub_contact_email: &'a str,               // 9
+    pub pub_contact_tel: &'a str,                 // 10
+    pub pub_contact_affiliation: &'a str,         // 11
+
+    pub sci_contact_first_name: &'a str,          // 12
+    pub sci_contact_last_name: &'a str,           // 13
+    pub sci_contact_address: &'a str,             // 14
+    pub sci_contact_email: &'a str,               // 15
+    pub sci_contact_tel: &'a str,                 // 16
+    pub sci_contact_affiliation: &'a str,         // 17
+
+    pub study_type: &'a str,                      // 18
+    pub study_design: &'a str,                    // 19
+    pub phase: &'a str,                           // 20
+    pub date_registration: &'a str,               // 21
+    pub date_enrollement: &'a str,                // 22
+    pub target_size: &'a str,                     // 23
+    pub recruitment_status: &'a str,             // 24
+
+    pub primary_sponsor: &'a str,                 // 25
+    pub secondary_sponsors: &'a str,              // 26
+    pub source_support: &'a str,                  // 27
+    pub countries: &'a str,                       // 28
+    pub conditions: &'a str,                      // 29
+    pub interventions: &'a str,                   // 30
+
+    pub age_min: &'a str,                         // 31
+    pub age_max: &'a str,                         // 32
+    pub gender: &'a str,                          // 33
+    pub inclusion_criteria: &'a str,              // 34
+    pub exclusion_criteria: &'a str,              // 35
+
+    pub primary_outcome: &'a str,                 // 36
+    pub secondary_outcomes: &'a str,              // 37
+
+    pub bridging_flag: &'a str,                   // 38
+    pub bridged_type: &'a str,                    // 39
+    pub childs: &'a str,                          // 40
+    pub type_enrolment: &'a str,                  // 41
+    pub retrospective_flag: &'a str,              // 42
+
+    pub results_actual_enrollment: &'a str,       // 43
+    pub results_url_link: &'a str,                // 44
+    pub results_summary: &'a str,                 // 45
+    pub results_date_posted: &'a str,             // 46
+    pub results_date_first_pub: &'a str,          // 47
+    pub results_baseline_char: &'a str,           // 48
+    pub results_participant_flow: &'a str,        // 49
+    pub results_adverse_events: &'a str,          // 50
+    pub results_outcome_measures: &'a str,        // 51
+    pub results_url_protocol: &'a str,            // 52
+
+    pub results_ipd_plan: &'a str,                // 53
+    pub results_ipd_description: &'a str,         // 54
+    pub results_date_completed: &'a str,          // 55
+    pub results_yes_no: &'a str,                  // 56
+
+    pub ethics_status: &'a str,                   // 57
+    pub ethics_approval_date: &'a str,            // 58
+    pub ethics_contact_name: &'a str,             // 59
+    pub ethics_contact_address: &'a str,            // 60
+    pub ethics_contact_phone: &'a str,            // 61
+    pub ethics_contact_email: &'a str,            // 62
 
 }
 
@@ -103,8 +111,11 @@ pub struct WHORecord
     pub study_status_orig: Option<String>,
     pub study_status_id: i32,
     pub date_registration: Option<String>,
+    pub date_registration_precision: Option<DatePrecision>,
     pub date_enrolment: Option<String>,
+    pub date_enrolment_precision: Option<DatePrecision>,
     pub target_size: Option<String>,
+    pub enrolment: Option<Enrolment>,
     pub primary_sponsor: Option<String>,
     pub secondary_sponsors: Option<String>,
     pub source_support: Option<String>,
@@ -113,7 +124,9 @@ pub struct WHORecord
     pub agemin_units: Option<String>,
     pub agemax: Option<String>,
     pub agemax_units: Option<String>,
-    pub gender: Option<String>,
+    pub age_eligibility: Option<AgeEligibility>,
+    pub gender_orig: Option<String>,
+    pub gender: Option<GenderEligibility>,
     pub inclusion_criteria: Option<String>,
     pub exclusion_criteria: Option<String>,
     pub primary_outcome: Option<String>,
@@ -127,23 +140,87 @@ pub struct WHORecord
     pub results_url_link: Option<String>,
     pub results_summary: Option<String>,
     pub results_date_posted: Option<String>,
+    pub results_date_posted_precision: Option<DatePrecision>,
     pub results_date_first_pub: Option<String>,
+    pub results_date_first_pub_precision: Option<DatePrecision>,
     pub results_url_protocol: Option<String>,
     pub ipd_plan: Option<String>,
     pub ipd_description: Option<String>,
     pub results_date_completed: Option<String>,
+    pub results_date_completed_precision: Option<DatePrecision>,
     pub results_yes_no: Option<String>,
 
     pub design_string: Option<String>,
     pub phase_string: Option<String>,
 
     pub country_list: Option<Vec<String>>,
+    pub country_codes: Option<Vec<String>>,
+    pub countries_unresolved: Option<Vec<String>>,
     pub secondary_ids: Option<Vec<SecondaryId>>,
     pub study_features: Option<Vec<WhoStudyFeature>>,
     pub condition_list: Option<Vec<String>>,
     pub meddra_condition_list: Option<Vec<MeddraCondition>>,
 }
 
+// Fields of the WHO record that can change without any change to the study itself
+// (the date the record was last refreshed in the WHO database), and which are
+// therefore excluded when checking whether a study has changed.
+
+const VOLATILE_FIELDS: [&str; 1] = ["record_date"];
+
+// Fields derived entirely from others in the record (the precision of each date,
+// given by the length of its ISO string), which can only change if those do, and
+// are also excluded.
+
+const DERIVED_FIELDS: [&str; 5] = ["date_registration_precision", "date_enrolment_precision", 
+            "results_date_posted_precision", "results_date_first_pub_precision", "results_date_completed_precision"];
+
+// Fields holding values parsed or classified from those in the WHO data (the age
+// limits as structured values, the gender eligibility as classified, the
+// anticipated and actual enrolment, and the codes of the countries listed with
+// the names that could not be resolved), which are excluded as well, so that the
+// hash covers only the WHO source content of a study and does not change when the
+// parsing of it does. The gender value as given (gender_orig) is source content.
+
+const PARSED_FIELDS: [&str; 5] = ["age_eligibility", "gender", "enrolment",
+            "country_codes", "countries_unresolved"];
+
+impl WHORecord {
+
+    // The record's fields, other than the volatile, derived and parsed ones, keyed
+    // (and so ordered alphabetically) by field name.
+
+    pub fn content_fields(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(m)) => m,
+            _ => serde_json::Map::new(),
+        };
+        for f in VOLATILE_FIELDS.iter().chain(DERIVED_FIELDS.iter()).chain(PARSED_FIELDS.iter()) {
+            fields.remove(*f);
+        }
+        fields
+    }
+
+    // The original gender value, if it could not be classified.
+
+    pub fn unclassified_gender(&self) -> Option<&str> {
+        match self.gender {
+            Some(_) => None,
+            None => self.gender_orig.as_deref(),
+        }
+    }
+}
+
+
+// A hash of a record's content fields, as a lower case hex string. As the fields are 
+// serialised in a fixed order the hash only changes if their values do.
+
+pub fn hash_content(fields: &serde_json::Map<String, serde_json::Value>) -> String {
+    let json_string = serde_json::to_string(fields).unwrap_or_default();
+    format!("{:x}", Sha256::digest(json_string.as_bytes()))
+}
+
+
 #[derive(Debug, Clone, serde::Serialize)]
 #[allow(dead_code)]
 pub struct SecondaryId
@@ -287,6 +364,10 @@ pub struct WHOSummary
     pub enrol_year: i32,
     pub results_yes_no: Option<String>,
     pub country_list: Option<Vec<String>>,
+    pub country_codes: Option<Vec<String>>,
+    pub countries_unresolved: Option<Vec<String>>,
+    pub age_eligibility: Option<AgeEligibility>,
+    pub enrolment: Option<Enrolment>,
 
     pub table_name: String,
 
@@ -1,4 +1,6 @@
-use regex::Regex;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+use regex::{Captures, Regex};
 
 pub trait StringExtensions {
     fn tidy(&self) -> Option<String>;
@@ -7,323 +9,367 @@ pub trait StringExtensions {
 }
 
 pub trait DateExtensions {
+    fn as_partial_date(&self, order: DateOrder) -> Option<PartialDate>;
     fn as_iso_date(&self) -> Option<String>;
-    fn get_time_units(&self) -> Option<String>;
 }
 
 
-impl StringExtensions for String {
-    
-    fn tidy(&self) -> Option<String> {
+// How precisely a date is known. Some registries give only the month, or
+// just the year, of some dates, e.g. the expected start of enrolment.
 
-        if self == "NULL" ||  self == "null" ||  self == "\"NULL\"" ||  self == "\"null\""
-        ||  self.trim() == ""
-        {
-            None
-        }
-        else {
-            let trimmed: &str;
-            if self.starts_with("\"") {
-                let complex_trim = |c| c == ' ' || c == ';' || c == '"';
-                trimmed = self.trim_matches(complex_trim);
-            }
-            else {
-                let complex_trim = |c| c == ' ' || c == ';';
-                trimmed = self.trim_matches(complex_trim);
-            }
-            if trimmed == "" {
-                None
-            }
-            else {
-                Some(trimmed.to_owned())
-            }
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatePrecision {
+    Day,
+    Month,
+    Year,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialDate {
+    pub year: i32,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+    pub precision: DatePrecision,
+}
+
+impl PartialDate {
+
+    pub fn day(year: i32, month: u32, day: u32) -> Self {
+        PartialDate { year, month: Some(month), day: Some(day), precision: DatePrecision::Day }
+    }
+
+    pub fn month(year: i32, month: u32) -> Self {
+        PartialDate { year, month: Some(month), day: None, precision: DatePrecision::Month }
+    }
+
+    pub fn year(year: i32) -> Self {
+        PartialDate { year, month: None, day: None, precision: DatePrecision::Year }
+    }
+
+    // The date in ISO 8601 form, only as precise as it is known, 
+    // i.e. as 2020-03-05, 2020-03 or 2020.
+
+    pub fn as_iso(&self) -> String {
+        match (self.month, self.day) {
+            (Some(m), Some(d)) => format!("{}-{:02}-{:02}", self.year, m, d),
+            (Some(m), None) => format!("{}-{:02}", self.year, m),
+            _ => self.year.to_string(),
         }
     }
+}
+
+
+// The order of the day and month in a numeric date such as 05/03/2020, when both are 12 
+// or less (and so either could be the month). The registries in the WHO data put the day
+// first, but a registry known to use US style dates can be given date_order = "month_first"
+// in the registry catalogue.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateOrder {
+    #[default]
+    DayFirst,
+    MonthFirst,
+}
 
 
+// The html entities replaced in text fields, in order of precedence, and the tags 
+// replaced by line breaks in the longer text fields. All start with an ascii 
+// character, so the text can be scanned for them byte by byte.
+
+const ENTITIES: [(&str, &str); 10] = [("&#32;", " "), ("&#37;", "%"), ("#gt;", ">"), ("#lt;", "<"), 
+                                      ("&#39;", "’"), ("&rsquo;", "’"), ("&quot;", "'"), 
+                                      ("&gt;", ">"), ("&lt;", "<"), ("&amp;", "&")];
+const BREAK_TAGS: [&str; 4] = ["<p>", "<br>", "<br/>", "<br />"];
+const END_PARA_TAG: &str = "</p>";
+
+
+impl StringExtensions for str {
+    
+    fn tidy(&self) -> Option<String> {
+        get_trimmed(self).map(|s| s.to_owned())
+    }
+
     fn replace_unicodes(&self) -> Option<String> {
-        if self == "NULL" ||  self == "null" ||  self == "\"NULL\"" ||  self == "\"null\""
-        ||  self.trim() == ""
-        {
-            None
-        }
-        else {
-            let trimmed: &str;
-            if self.starts_with("\"") {
-                let complex_trim = |c| c == ' ' || c == ';' || c == '"';
-                trimmed = self.trim_matches(complex_trim);
-            }
-            else {
-                let complex_trim = |c| c == ' ' || c == ';';
-                trimmed = self.trim_matches(complex_trim);
-            }
-            if trimmed == "" {
-                None
-            }
-            else {
-                let mut output = trimmed.to_owned();
-                output = output.replace("&#32;", " ").replace("&#37;", "%");
-                output = output.replace("#gt;", ">").replace("#lt;", "<");       
-                output = output.replace("&#39;", "’").replace("&rsquo;", "’");
-                output = output.replace("&quot;", "'");
-                output = output.replace("&gt;", ">").replace("&lt;", "<");
-                output = output.replace("&amp;", "&");
-                Some(output)
-            }
+        get_trimmed(self).map(|s| clean_text(s, false))
+    }
+
+    fn replace_tags_and_unicodes(&self) -> Option<String> {
+        get_trimmed(self).map(|s| clean_text(s, true))
+    }
+}
+
+
+// The field's text without any enclosing spaces and semicolons (and double quotes, if 
+// it starts with one), or None if that leaves nothing or the field is a 'null'.
+
+fn get_trimmed(s: &str) -> Option<&str> {
+
+    if s == "NULL" ||  s == "null" ||  s == "\"NULL\"" ||  s == "\"null\"" || s.trim().is_empty() {
+        return None;
+    }
+    let trimmed = match s.starts_with('"') {
+        true => s.trim_matches([' ', ';', '"']),
+        false => s.trim_matches([' ', ';']),
+    };
+    if trimmed.is_empty() { None } else { Some(trimmed) }
+}
+
+
+// Where the text written so far ends, in terms of the line breaks that may still be 
+// merged: a "\n \n" is reduced to a single line break, but a line break that has 
+// already been merged with the one before it cannot be merged again.
+
+#[derive(PartialEq)]
+enum TextEnd {
+    LineBreak,
+    LineBreakSpace,
+    Other,
+}
+
+
+struct CleanText {
+    text: String,
+    breaks: usize,          // line breaks read but not yet written
+    end: TextEnd,
+    after_comma: bool,      // the last character written was an unpaired comma
+}
+
+impl CleanText {
+
+    fn write(&mut self, s: &str) {
+        self.write_breaks();
+        self.end = match self.end == TextEnd::LineBreak && s == " " {
+            true => TextEnd::LineBreakSpace,
+            false => TextEnd::Other,
+        };
+        self.after_comma = false;
+        self.text.push_str(s);
+    }
+
+    fn write_comma(&mut self) {
+        self.write_breaks();
+        if !self.after_comma {
+            self.text.push(',');
         }
+        self.end = TextEnd::Other;
+        self.after_comma = !self.after_comma;
     }
 
+    fn add_break(&mut self) {
+        self.breaks += 1;
+        self.after_comma = false;
+    }
 
-    fn replace_tags_and_unicodes(&self) -> Option<String> {
-        if self == "NULL" ||  self == "null" ||  self == "\"NULL\"" ||  self == "\"null\""
-        ||  self.trim() == ""
-        {
-            None
+    fn write_breaks(&mut self) {
+
+        // Each pair of consecutive line breaks becomes one, and the first
+        // of them also absorbs a preceding line break and space.
+
+        let mut n = self.breaks.div_ceil(2);
+        self.breaks = 0;
+        if n == 0 {
+            return;
         }
-        else {
-            let trimmed: &str;
-            if self.starts_with("\"") {
-                let complex_trim = |c| c == ' ' || c == ';' || c == '"';
-                trimmed = self.trim_matches(complex_trim);
-            }
-            else {
-                let complex_trim = |c| c == ' ' || c == ';';
-                trimmed = self.trim_matches(complex_trim);
-            }
-            if trimmed == "" {
-                None
-            }
-            else {
-                let mut output = trimmed.to_owned();
-                
-                output = output.replace("<p>", "\n");
-                output = output.replace("<br>", "\n");
-                output = output.replace("<br/>", "\n");
-                output = output.replace("<br />", "\n");
-                output = output.replace("\n\n", "\n").replace("\n \n", "\n");
-                output = output.replace(",,", ",");
-                output = output.replace("</p>", "");
-
-                output = output.replace("&#32;", " ").replace("&#37;", "%");
-                output = output.replace("#gt;", ">").replace("#lt;", "<");       
-                output = output.replace("&#39;", "’").replace("&rsquo;", "’");
-                output = output.replace("&quot;", "'");
-                output = output.replace("&gt;", ">").replace("&lt;", "<");
-                output = output.replace("&amp;", "&");
-                Some(output)
-            }
+        if self.end == TextEnd::LineBreakSpace {
+            self.text.pop();
+            n -= 1;
         }
+        for _ in 0..n {
+            self.text.push('\n');
+        }
+        self.end = if n > 0 { TextEnd::LineBreak } else { TextEnd::Other };
+    }
+}
+
+
+// Replaces the html entities in the text and, if replace_tags is true, the paragraph and 
+// line break tags, in a single pass. Consecutive line breaks (and a line break, space and
+// line break) are merged, as are pairs of commas, and closing paragraph tags removed.
+
+fn clean_text(text: &str, replace_tags: bool) -> String {
+
+    let bytes = text.as_bytes();
+    let is_special = |b: &u8| *b == b'&' || *b == b'#' 
+                        || (replace_tags && (*b == b'<' || *b == b'\n' || *b == b','));
+    let mut clean = CleanText { text: String::with_capacity(text.len()), breaks: 0, 
+                                end: TextEnd::Other, after_comma: false };
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &text[i..];
+        let literal_len = match bytes[i..].iter().position(is_special) {
+            Some(0) => 0,
+            Some(n) => n,
+            None => rest.len(),
+        };
+        if literal_len > 0 {
+            clean.write(&rest[..literal_len]);
+            i += literal_len;
+            continue;
+        }
+
+        let mut matched_len = 1;
+        match bytes[i] {
+            b'\n' => clean.add_break(),
+            b',' => clean.write_comma(),
+            b'<' => {
+                if let Some(tag) = BREAK_TAGS.iter().find(|t| rest.starts_with(**t)) {
+                    clean.add_break();
+                    matched_len = tag.len();
+                }
+                else if rest.starts_with(END_PARA_TAG) {
+                    clean.write("");
+                    matched_len = END_PARA_TAG.len();
+                }
+                else {
+                    clean.write("<");
+                }
+            },
+            _ => {
+                match ENTITIES.iter().find(|(e, _)| rest.starts_with(*e)) {
+                    Some((entity, replacement)) => {
+                        clean.write(replacement);
+                        clean.end = TextEnd::Other;
+                        matched_len = entity.len();
+                    },
+                    None => clean.write(&rest[..1]),
+                }
+            },
+        }
+        i += matched_len;
     }
+    clean.write_breaks();
+    clean.text
 }
 
-impl DateExtensions for String {
+
+// The date formats recognised, compiled once, with their days, months and years 
+// captured. A month may be named in full or, in some formats, by its first three letters.
+
+const DAY: &str = r"(0?[1-9]|1\d|2\d|3[0-1])";
+const MONTH: &str = r"(0?[1-9]|1[0-2])";
+const MONTH_ABBREV: &str = r"(Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)";
+const MONTH_NAME: &str = r"(January|February|March|April|May|June|July|August|September|October|November|December)";
+const YEAR: &str = r"((?:19|20)\d{2})";
+
+static YEAR_MONTH_DAY: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[YEAR, "-", MONTH, "-", DAY]));
+static NUMERIC_DATE: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[r"(\d{1,2})-(\d{1,2})-", YEAR]));
+static DAY_MONTH_ABBREV_YEAR: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[DAY, " ", MONTH_ABBREV, " ", YEAR]));
+static DAY_MONTH_NAME_YEAR: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[DAY, " ", MONTH_NAME, " ", YEAR]));
+static MONTH_NAME_DAY_YEAR: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[MONTH_NAME, " ", DAY, " ", YEAR]));
+static MONTH_NAME_YEAR: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[MONTH_NAME, " ", YEAR]));
+static MONTH_ABBREV_YEAR: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[MONTH_ABBREV, " ", YEAR]));
+static YEAR_MONTH: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[YEAR, "-", MONTH]));
+static MONTH_YEAR: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[MONTH, "-", YEAR]));
+static YEAR_ONLY: LazyLock<Regex> = LazyLock::new(|| get_date_regex(&[YEAR]));
+
+fn get_date_regex(parts: &[&str]) -> Regex {
+    Regex::new(&format!("^{}$", parts.concat())).unwrap()
+}
+
+
+impl DateExtensions for str {
     
-    fn as_iso_date(&self) -> Option<String> {
+    fn as_partial_date(&self, order: DateOrder) -> Option<PartialDate> {
 
-        if self == "NULL" ||  self == "null" ||  self == "\"NULL\"" ||  self == "\"null\""
-        ||  self.trim() == ""
-        {
-            return None
-        }
+        get_trimmed(self)?;    // a null or empty field
         
         if self == "1900-01-01" || self == "01/01/1900" || self == "Jan  1 1900" || self == "Jan  1 1900 12:00AM"
         {
             return None
         }
-            
-        let mut date_string: String;
-        let mut iso_date: String;
 
-        date_string = self.trim_matches('"').to_string();
-        date_string = date_string.replace("/", "-").replace(".", "-").replace(",", "");   // regularise delimiters
+        // Delimiters are regularised, which only needs a copy 
+        // of the string if it contains any that are not dashes.
 
-        if date_string.trim() == ""  // check again after changes
-        {
-            return None
-        }
+        let unquoted = self.trim_matches('"');
+        let date_string: Cow<str> = match unquoted.contains(['/', '.', ',']) {
+            true => Cow::Owned(unquoted.chars().filter(|c| *c != ',')
+                                       .map(|c| if c == '/' || c == '.' { '-' } else { c }).collect()),
+            false => Cow::Borrowed(unquoted),
+        };
 
-        iso_date =  date_string.clone();   // as the initial default
-
-        let p1 = r#"^(19|20)\d{2}-(0?[1-9]|1[0-2])-(0?[1-9]|1\d|2\d|3[0-1])$"#;
-        let re1 = Regex::new(p1).unwrap();
-        let p2 = r#"^(0?[1-9]|1\d|2\d|3[0-1])-(0?[1-9]|1[0-2])-(19|20)\d{2}$"#;
-        let re2 = Regex::new(p2).unwrap();
-        let p3 = r#"^(0?[1-9]|1\d|2\d|3[0-1]) (Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) (19|20)\d{2}$"#;
-        let re3 = Regex::new(p3).unwrap();
-        let p4 = r#"^(0?[1-9]|1\d|2\d|3[0-1]) (January|February|March|April|May|June|July|August|September|October|November|December) (19|20)\d{2}$"#;
-        let re4 = Regex::new(p4).unwrap();
-        let p5 = r#"^(January|February|March|April|May|June|July|August|September|October|November|December) (0?[1-9]|1\d|2\d|3[0-1]) (19|20)\d{2}$"#;
-        let re5 = Regex::new(p5).unwrap();
-        let p6 = r#"^(January|February|March|April|May|June|July|August|September|October|November|December) (19|20)\d{2}$"#;
-        let re6 = Regex::new(p6).unwrap();
-        let py = r#"(19|20)\d{2}$"#;
-        let re_yr = Regex::new(py).unwrap();
-        
-        if re1.is_match(&date_string) {   // yyyy_mm_dd
-            if date_string.len() == 10
-            {
-                iso_date = date_string;  // already OK
-            }
-            else {
-                let dash1 = &date_string.find('-').unwrap();
-                let dash2 = &date_string.rfind('-').unwrap();
-                let year_s = &date_string[..4];
-                let month_s = format!("{:0>2}", &date_string[(dash1 + 1)..(dash2 - dash1 - 1)]);
-                let day_s =  format!("{:0>2}", &date_string[(dash2 + 1)..]);
-
-                iso_date = format!("{}-{}-{}", year_s, month_s, day_s);
-            }
-        }
-        else if re2.is_match(&date_string) {   // dd_mm_yyyy
-            let dash1 = &date_string.find('-').unwrap();
-            let dash2 = &date_string.rfind('-').unwrap();
-            if re_yr.is_match(&date_string) {
-                let caps = re_yr.captures(&date_string).unwrap();
-                let year_s = &caps[0];
-                let month_s = format!("{:0>2}", &date_string[(dash1 + 1)..*dash2]);
-                let day_s =  format!("{:0>2}", &date_string[..*dash1]);
-                iso_date = format!("{}-{}-{}", year_s, month_s, day_s);
-            }
-        }
-        else if re3.is_match(&date_string) {        // dd_MMM_yyyy 
-            let dash1 = &date_string.find(' ').unwrap();
-            let dash2 = &date_string.rfind(' ').unwrap();
-            if re_yr.is_match(&date_string) {
-                let caps = re_yr.captures(&date_string).unwrap();
-                let year_s = &caps[0];
-                let month = &date_string[(dash1 + 1)..*dash2];
-                let month_s = match month{
-                    "Jan" => "01",
-                    "Feb" => "02",
-                    "Mar" => "03",
-                    "Apr" => "04",
-                    "May" => "05",
-                    "Jun" => "06",
-                    "Jul" => "07",
-                    "Aug" => "08",
-                    "Sep" => "09",
-                    "Oct" => "10",
-                    "Nov" => "11",
-                    "Dec" => "12",
-                        _  => "00",
-                };
-                let day_s =  format!("{:0>2}", &date_string[..*dash1]);
-                iso_date = format!("{}-{}-{}", year_s, month_s, day_s);
-            }
-        }
-        else if re4.is_match(&date_string) {   // dd_MMMM_yyyy
-            let dash1 = &date_string.find(' ').unwrap();
-            let dash2 = &date_string.rfind(' ').unwrap();
-            if re_yr.is_match(&date_string) {
-                let caps = re_yr.captures(&date_string).unwrap();
-                let year_s = &caps[0];
-                let month = &date_string[(dash1 + 1)..*dash2];
-                let month_s = match month{
-                    "January" => "01",
-                    "February" => "02",
-                    "March" => "03",
-                    "April" => "04",
-                    "May" => "05",
-                    "June" => "06",
-                    "July" => "07",
-                    "August" => "08",
-                    "September" => "09",
-                    "October" => "10",
-                    "November" => "11",
-                    "December" => "12",
-                    _ => "00",
-                };
-                let day_s =  format!("{:0>2}", &date_string[..*dash1]);
-                iso_date = format!("{}-{}-{}", year_s, month_s, day_s);
-            }
-        }
-        else if re5.is_match(&date_string) {   // MMMM_dd_yyyy
-            let dash1 = &date_string.find(' ').unwrap();
-            let dash2 = &date_string.rfind(' ').unwrap();
-            if re_yr.is_match(&date_string) {
-                let caps = re_yr.captures(&date_string).unwrap();
-                let year_s = &caps[0];
-                let month = &date_string[..*dash1];
-                let month_s = match month{
-                    "January" => "01",
-                    "February" => "02",
-                    "March" => "03",
-                    "April" => "04",
-                    "May" => "05",
-                    "June" => "06",
-                    "July" => "07",
-                    "August" => "08",
-                    "September" => "09",
-                    "October" => "10",
-                    "November" => "11",
-                    "December" => "12",
-                    _ => "00",
-                };
-                let day_s =  format!("{:0>2}", &date_string[(dash1 + 1)..*dash2]);
-                iso_date = format!("{}-{}-{}", year_s, month_s, day_s);
-            }
-        }
-        else if re6.is_match(&date_string) {   // MMMM_yyyy
-            let dash1 = &date_string.find(' ').unwrap();
-            if re_yr.is_match(&date_string) {
-                let caps = re_yr.captures(&date_string).unwrap();
-                let year_s = &caps[0];
-                let month = &date_string[..*dash1];
-                let month_s = match month{
-                    "January" => "01",
-                    "February" => "02",
-                    "March" => "03",
-                    "April" => "04",
-                    "May" => "05",
-                    "June" => "06",
-                    "July" => "07",
-                    "August" => "08",
-                    "September" => "09",
-                    "October" => "10",
-                    "November" => "11",
-                    "December" => "12",
-                    _ => "00",
-                };
-                let day_s =  "15".to_string();          // for now **** Need to handle partial dates!
-                iso_date = format!("{}-{}-{}", year_s, month_s, day_s);
-            }
+        let caps = |re: &Regex| re.captures(&date_string);
+        let num = |c: &Captures, i: usize| c[i].parse::<u32>().unwrap_or(0);
+        let year = |c: &Captures, i: usize| c[i].parse::<i32>().unwrap_or(0);
+        let month = |c: &Captures, i: usize| get_month_number(&c[i]);
+
+        if let Some(c) = caps(&YEAR_MONTH_DAY) {
+            Some(PartialDate::day(year(&c, 1), num(&c, 2), num(&c, 3)))
         }
-        
-        // Final check in case none of the options above have worked
-        
-        if re1.is_match(&iso_date) && iso_date.len() == 10 { 
-             Some(iso_date)
+        else if let Some(c) = caps(&NUMERIC_DATE) {
+            get_numeric_date(year(&c, 3), num(&c, 1), num(&c, 2), order)
+        }
+        else if let Some(c) = caps(&DAY_MONTH_ABBREV_YEAR).or_else(|| caps(&DAY_MONTH_NAME_YEAR)) {
+            Some(PartialDate::day(year(&c, 3), month(&c, 2), num(&c, 1)))
+        }
+        else if let Some(c) = caps(&MONTH_NAME_DAY_YEAR) {
+            Some(PartialDate::day(year(&c, 3), month(&c, 1), num(&c, 2)))
+        }
+        else if let Some(c) = caps(&MONTH_NAME_YEAR).or_else(|| caps(&MONTH_ABBREV_YEAR)) {
+            Some(PartialDate::month(year(&c, 2), month(&c, 1)))
+        }
+        else if let Some(c) = caps(&YEAR_MONTH) {
+            Some(PartialDate::month(year(&c, 1), num(&c, 2)))
+        }
+        else if let Some(c) = caps(&MONTH_YEAR) {
+            Some(PartialDate::month(year(&c, 2), num(&c, 1)))
         }
         else {
-            None
+            caps(&YEAR_ONLY).map(|c| PartialDate::year(year(&c, 1)))
         }
     }
 
 
-    fn get_time_units(&self) -> Option<String> {
-        
-        if self.trim() == ""
-        {
-            return None;
-        }
+    // The date as an ISO date string, but only if it is a full date.
 
-        let time_string = self.to_lowercase();
-        let units = match time_string  
-        { 
-            _ if time_string.contains("year") => "Years",
-            _ if time_string.contains("month") => "Months",
-            _ if time_string.contains("week") => "Weeks",
-            _ if time_string.contains("day") => "Days",
-            _ if time_string.contains("hour") => "Hours",
-            _ if time_string.contains("min") => "Minutes",
-            _ => &("Other (".to_string() + &time_string + ")"),
-        };
-        return Some(units.to_string())
+    fn as_iso_date(&self) -> Option<String> {
+        self.as_partial_date(DateOrder::DayFirst)
+            .filter(|d| d.precision == DatePrecision::Day)
+            .map(|d| d.as_iso())
     }
 
 }
 
 
+// A numeric date, with the day and month in either order. The registry's usual
+// order is only needed if both are 12 or less.
+
+fn get_numeric_date(year: i32, first: u32, second: u32, order: DateOrder) -> Option<PartialDate> {
+    let (day, month) = match order {
+        _ if first > 12 => (first, second),
+        _ if second > 12 => (second, first),
+        DateOrder::DayFirst => (first, second),
+        DateOrder::MonthFirst => (second, first),
+    };
+    match (1..=31).contains(&day) && (1..=12).contains(&month) {
+        true => Some(PartialDate::day(year, month, day)),
+        false => None,
+    }
+}
+
+
+fn get_month_number(month: &str) -> u32 {
+    match &month[..3] {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => 0,
+    }
+}
+
+
 
     /* 
 
@@ -574,4 +620,86 @@ public class SplitDate
     }
 }
 
-*/
\ No newline at end of file
+*/
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_fields_tidied() {
+        assert_eq!("NULL".tidy(), None);
+        assert_eq!("\"null\"".tidy(), None);
+        assert_eq!(" ; ".tidy(), None);
+        assert_eq!("  ;Phase 2; ".tidy(), Some("Phase 2".to_string()));
+        assert_eq!("\"Recruiting\";".tidy(), Some("Recruiting".to_string()));
+        assert_eq!("Both \"sexes\"".tidy(), Some("Both \"sexes\"".to_string()));
+    }
+
+    #[test]
+    fn check_entities_replaced() {
+        let text = "Drug A &amp; drug B &#37; &lt;5 mg&gt; #gt;&#32;patient&#39;s &quot;own&quot; &rsquo; &nbsp; dose";
+        assert_eq!(text.replace_unicodes(),
+                   Some("Drug A & drug B % <5 mg> > patient’s 'own' ’ &nbsp; dose".to_string()));
+        assert_eq!("&amp;lt; <br>".replace_unicodes(), Some("&lt; <br>".to_string()));
+        assert_eq!("  ".replace_unicodes(), None);
+    }
+
+    #[test]
+    fn check_tags_replaced() {
+        let text = "<p>Inclusion:</p><p>Age &gt;= 18<br><br>Signed consent<br />\n \nNo prior treatment,, or surgery</p>";
+        assert_eq!(text.replace_tags_and_unicodes(),
+                   Some("\nInclusion:\nAge >= 18\nSigned consent\nNo prior treatment, or surgery".to_string()));
+        assert_eq!("&lt;p&gt; <b>bold</b>".replace_tags_and_unicodes(), Some("<p> <b>bold</b>".to_string()));
+    }
+
+    #[test]
+    fn check_line_breaks_merged_as_before() {
+
+        // Runs of line breaks (and commas) are halved, as they were by the replacements
+        // previously applied in turn, so that the cleaned text of the studies already
+        // stored, and so their content hashes, are unchanged.
+
+        assert_eq!("a\n\n\nb<br> <br> <br>c,,,d".replace_tags_and_unicodes(), Some("a\n\nb\n \nc,,d".to_string()));
+        assert_eq!("a\n \n\n \nb".replace_tags_and_unicodes(), Some("a\n \nb".to_string()));
+        assert_eq!("a<br></p><br>b".replace_tags_and_unicodes(), Some("a\n\nb".to_string()));
+    }
+
+    #[test]
+    fn check_dates_converted() {
+        let dates = [("2024-03-15", Some("2024-03-15")), ("2024/3/5", Some("2024-03-05")), ("2024-1-05", Some("2024-01-05")),
+                     ("15/03/2024", Some("2024-03-15")), ("5.3.2024", Some("2024-03-05")), ("\"15/03/2024\"", Some("2024-03-15")),
+                     ("5 Mar 2024", Some("2024-03-05")), ("05 March 2024", Some("2024-03-05")), ("March 5, 2024", Some("2024-03-05")),
+                     ("03/25/2024", Some("2024-03-25")), ("Mar 5 2024", None), ("2024-13-01", None), ("32/01/2024", None), 
+                     ("13/13/2024", None), ("1900-01-01", None), ("NULL", None), (" ", None), ("March 2024", None), ("2024", None)];
+        for (date, iso_date) in dates {
+            assert_eq!(date.as_iso_date().as_deref(), iso_date, "{}", date);
+        }
+    }
+
+    #[test]
+    fn check_partial_dates_keep_their_precision() {
+        let date = |s: &str| s.as_partial_date(DateOrder::DayFirst);
+        assert_eq!(date("March 2020"), Some(PartialDate { year: 2020, month: Some(3), day: None, precision: DatePrecision::Month }));
+        assert_eq!(date("Mar 2020").unwrap().as_iso(), "2020-03");
+        assert_eq!(date("2020-3").unwrap().as_iso(), "2020-03");
+        assert_eq!(date("03/2020").unwrap().as_iso(), "2020-03");
+        assert_eq!(date("2020"), Some(PartialDate { year: 2020, month: None, day: None, precision: DatePrecision::Year }));
+        assert_eq!(date("\"2020\"").unwrap().as_iso(), "2020");
+        assert_eq!(date("5 March 2020").unwrap().precision, DatePrecision::Day);
+        assert_eq!(date("2100"), None);
+        assert_eq!(date("13-2020"), None);
+    }
+
+    #[test]
+    fn check_numeric_dates_use_registry_order() {
+        let iso = |s: &str, order: DateOrder| s.as_partial_date(order).map(|d| d.as_iso());
+        assert_eq!(iso("05/03/2020", DateOrder::DayFirst).as_deref(), Some("2020-03-05"));
+        assert_eq!(iso("05/03/2020", DateOrder::MonthFirst).as_deref(), Some("2020-05-03"));
+        assert_eq!(iso("25/03/2020", DateOrder::MonthFirst).as_deref(), Some("2020-03-25"));
+        assert_eq!(iso("03/25/2020", DateOrder::DayFirst).as_deref(), Some("2020-03-25"));
+        assert_eq!(iso("00/03/2020", DateOrder::DayFirst), None);
+        assert_eq!(iso("2020-05-03", DateOrder::MonthFirst).as_deref(), Some("2020-05-03"));
+    }
+}
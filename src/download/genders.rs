@@ -0,0 +1,124 @@
+/**********************************************************************************
+The gender eligibility of a study, classified from the free text gender field of
+the WHO data as all, female, male, not stated or other. The original value is kept
+(as gender_orig in the json files). Values are first looked up in the registry's own
+spellings, given as gender_values in the registry catalogue (e.g. '男性' or '女性'
+in the Chinese and Japanese registries), and are otherwise classified by the words
+they contain, or by the yes / no lists of some registries ('Female: yes Male: no').
+A value that cannot be classified leaves the gender empty, and is counted in the
+report of unclassified values (see unclassified.rs) logged once each file (or full
+export) is processed, so that the registry's spelling can be added to the catalogue.
+***********************************************************************************/
+
+use std::sync::LazyLock;
+use regex::Regex;
+use super::registries::Registry;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GenderEligibility {
+    All,
+    Female,
+    Male,
+    NotStated,
+    Other,
+}
+
+
+static YES_NO: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(female|male)\s*:\s*(yes|no)\b").unwrap());
+
+const NOT_STATED: [&str; 9] = ["-", "n/a", "na", "none", "not stated", "not specified", "not applicable",
+                               "not reported", "unknown"];
+const ALL_WORDS: [&str; 5] = ["both", "all", "any", "either", "everyone"];
+const FEMALE_WORDS: [&str; 7] = ["female", "females", "woman", "women", "f", "girl", "girls"];
+const MALE_WORDS: [&str; 7] = ["male", "males", "man", "men", "m", "boy", "boys"];
+const OTHER_PHRASES: [&str; 6] = ["other", "intersex", "transgender", "non-binary", "nonbinary", "diverse"];
+
+
+// Returns None if the (non empty) value cannot be classified.
+
+pub fn get_gender_eligibility(gender: &str, registry: Option<&Registry>) -> Option<GenderEligibility> {
+
+    if let Some(g) = registry.and_then(|r| r.get_gender_value(gender)) {
+        return Some(g);
+    }
+
+    let text = gender.trim().trim_end_matches('.').to_lowercase();
+    if NOT_STATED.contains(&text.as_str()) {
+        return Some(GenderEligibility::NotStated);
+    }
+
+    // Words are compared whole, so that (for instance) 'women' is not taken to include 'men'.
+
+    let (female, male) = match YES_NO.is_match(&text) {
+        true => {
+            let yes = |sex: &str| YES_NO.captures_iter(&text).any(|c| &c[1] == sex && &c[2] == "yes");
+            (yes("female"), yes("male"))
+        },
+        false => {
+            let words: Vec<&str> = text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).collect();
+            if words.iter().any(|w| ALL_WORDS.contains(w)) {
+                return Some(GenderEligibility::All);
+            }
+            (words.iter().any(|w| FEMALE_WORDS.contains(w)), words.iter().any(|w| MALE_WORDS.contains(w)))
+        },
+    };
+
+    match (female, male) {
+        (true, true) => Some(GenderEligibility::All),
+        (true, false) => Some(GenderEligibility::Female),
+        (false, true) => Some(GenderEligibility::Male),
+        _ if OTHER_PHRASES.iter().any(|p| text.contains(p)) => Some(GenderEligibility::Other),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::registries::RegistryCatalogue;
+    use GenderEligibility::*;
+
+    #[test]
+    fn check_genders_classified_by_words() {
+        let g = |s: &str| get_gender_eligibility(s, None);
+        assert_eq!(g("Both"), Some(All));
+        assert_eq!(g("All"), Some(All));
+        assert_eq!(g("Both, male and female"), Some(All));
+        assert_eq!(g("Male and Female"), Some(All));
+        assert_eq!(g("Female"), Some(Female));
+        assert_eq!(g("Women"), Some(Female));
+        assert_eq!(g("Pregnant women"), Some(Female));
+        assert_eq!(g("M"), Some(Male));
+        assert_eq!(g("Men only"), Some(Male));
+        assert_eq!(g("Not stated"), Some(NotStated));
+        assert_eq!(g("-"), Some(NotStated));
+        assert_eq!(g("Intersex"), Some(Other));
+        assert_eq!(g("Healthy volunteers"), None);
+    }
+
+    #[test]
+    fn check_yes_no_lists_classified() {
+        let g = |s: &str| get_gender_eligibility(s, None);
+        assert_eq!(g("<br>Female: yes<br>Male: yes<br>"), Some(All));
+        assert_eq!(g("<br>Female: yes<br>Male: no<br>"), Some(Female));
+        assert_eq!(g("Female: no Male: yes"), Some(Male));
+        assert_eq!(g("Female: no Male: no"), None);
+    }
+
+    #[test]
+    fn check_registry_spellings_used() {
+        let cat = RegistryCatalogue::bundled();
+        let g = |s: &str, id: i32| get_gender_eligibility(s, cat.get(id));
+        assert_eq!(g("男性", 118), Some(Male));
+        assert_eq!(g("男女均可", 118), Some(All));
+        assert_eq!(g("女性", 127), Some(Female));
+        assert_eq!(g("男女両方", 127), Some(All));
+        assert_eq!(g("زن", 125), Some(Female));
+        assert_eq!(g("هر دو", 125), Some(All));
+        assert_eq!(g("男性", 116), None);
+        assert_eq!(g("Female", 118), Some(Female));
+    }
+}
@@ -0,0 +1,255 @@
+/**********************************************************************************
+The json files of the studies stored are written by a small pool of writer
+threads rather than by the task writing to the database, which only queues them.
+Each writer has its own bounded queue, and a file is always queued to the same
+writer (chosen from its path) so that two versions of a study within the same
+file are written in the order they were queued. A writer that fails to write a
+file stops, and the error is returned when the next file is queued for it, or
+when the pool is finished - which waits for all the queued files to be written.
+Files are written to a temporary (staged) file alongside the study's json file,
+and finishing the pool returns the files staged. These are only promoted, i.e.
+renamed to replace the existing files, once the file's (or chunk's) transaction
+has been committed, so a transaction that is rolled back leaves the json files
+as they were, and consistent with the content hashes in the database.
+***********************************************************************************/
+
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use serde_json::to_string_pretty;
+use crate::err::AppError;
+use super::file_models::WHORecord;
+use super::summary_buffer::JsonPaths;
+use super::{stage_json_file, promote_json_file};
+
+pub const JSON_WRITER_COUNT: usize = 4;
+const JSON_QUEUE_SIZE: usize = 250;
+
+
+// The json file for a study, written if the study is new or has changed.
+
+pub struct JsonFile {
+    pub paths: JsonPaths,
+    pub rec: WHORecord,
+}
+
+
+struct JsonJob {
+    file: JsonFile,
+    only_if_missing: bool,
+}
+
+
+// The json files staged by the writers, each listed once
+// however many versions of it were written.
+
+#[derive(Default)]
+pub struct StagedJsonFiles {
+    paths: HashSet<PathBuf>,
+}
+
+impl StagedJsonFiles {
+
+    // Replaces the existing json files with those staged,
+    // returning the number promoted.
+
+    pub fn promote(self) -> Result<usize, AppError> {
+        for path in self.paths.iter() {
+            promote_json_file(path)?;
+        }
+        Ok(self.paths.len())
+    }
+}
+
+
+pub struct JsonWriter {
+    queues: Vec<SyncSender<JsonJob>>,
+    writers: Vec<Option<JoinHandle<Result<StagedJsonFiles, AppError>>>>,
+}
+
+impl JsonWriter {
+
+    pub fn new(num_writers: usize) -> Self {
+        let mut queues = Vec::new();
+        let mut writers = Vec::new();
+        for _ in 0..num_writers.max(1) {
+            let (queue, jobs) = sync_channel(JSON_QUEUE_SIZE);
+            queues.push(queue);
+            writers.push(Some(thread::spawn(move || write_files(jobs))));
+        }
+        JsonWriter { queues, writers }
+    }
+
+    // Queues the file to be written. If only_if_missing is true (the study
+    // being unchanged) the file is only written if it does not exist, and
+    // no snapshot is written.
+
+    pub fn write(&mut self, file: JsonFile, only_if_missing: bool) -> Result<(), AppError> {
+        let i = get_writer_index(&file.paths.path, self.queues.len());
+        match self.queues[i].send(JsonJob { file, only_if_missing }) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(self.stopped_writer_error(i)),
+        }
+    }
+
+    // Waits for all the files queued to be written, and
+    // returns the files staged (or the first error).
+
+    pub fn finish(mut self) -> Result<StagedJsonFiles, AppError> {
+        self.queues.clear();
+        let mut staged = StagedJsonFiles::default();
+        for writer in self.writers.iter_mut() {
+            if let Some(handle) = writer.take() {
+                staged.paths.extend(join_writer(handle)?.paths);
+            }
+        }
+        Ok(staged)
+    }
+
+    fn stopped_writer_error(&mut self, i: usize) -> AppError {
+        match self.writers[i].take().map(join_writer) {
+            Some(Err(e)) => e,
+            _ => AppError::ProcessingThreadError("json writer".to_string(), "The writer's queue has closed".to_string()),
+        }
+    }
+}
+
+
+fn get_writer_index(path: &Path, num_writers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    (hasher.finish() % num_writers as u64) as usize
+}
+
+
+fn join_writer(handle: JoinHandle<Result<StagedJsonFiles, AppError>>) -> Result<StagedJsonFiles, AppError> {
+    handle.join().unwrap_or_else(|_| Err(AppError::ProcessingThreadError("json writer".to_string(),
+                                                    "The writer thread panicked".to_string())))
+}
+
+
+fn write_files(jobs: Receiver<JsonJob>) -> Result<StagedJsonFiles, AppError> {
+    let mut staged = StagedJsonFiles::default();
+    for job in jobs {
+        write_file(&job, &mut staged)?;
+    }
+    Ok(staged)
+}
+
+
+fn write_file(job: &JsonJob, staged: &mut StagedJsonFiles) -> Result<bool, AppError> {
+    let paths = &job.file.paths;
+    if job.only_if_missing && paths.path.exists() {
+        return Ok(false);
+    }
+    let json_string = to_string_pretty(&job.file.rec)?;
+    stage_json_file(&paths.path, &json_string)?;
+    staged.paths.insert(paths.path.clone());
+    if !job.only_if_missing && let Some(snapshot_path) = &paths.snapshot_path {
+        stage_json_file(snapshot_path, &json_string)?;
+        staged.paths.insert(snapshot_path.clone());
+    }
+    Ok(true)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use super::super::test_support::make_record;
+
+    fn json_file(path: &Path, snapshot_path: Option<PathBuf>, title: &str) -> JsonFile {
+        JsonFile { paths: JsonPaths { path: path.to_owned(), snapshot_path },
+                   rec: make_record(&[(0, "ACTRN12624000001"), (3, title)]) }
+    }
+
+    #[test]
+    fn check_versions_of_a_file_written_in_order() {
+        let folder = std::env::temp_dir().join("dl_who_json_writer_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("ACTRN12624000001.json");
+        let snapshot_path = folder.join("ACTRN12624000001_2025-02-24.json");
+
+        let mut writer = JsonWriter::new(3);
+        for i in 0..20 {
+            writer.write(json_file(&path, None, &format!("title {}", i)), false).unwrap();
+        }
+        writer.write(json_file(&path, Some(snapshot_path.clone()), "final title"), false).unwrap();
+        for i in 0..10 {
+            writer.write(json_file(&folder.join(format!("other {}.json", i)), None, "other"), false).unwrap();
+        }
+        let staged = writer.finish().unwrap();
+        assert!(!path.exists());
+        assert_eq!(staged.promote().unwrap(), 12);
+
+        assert!(fs::read_to_string(&path).unwrap().contains("final title"));
+        assert!(fs::read_to_string(&snapshot_path).unwrap().contains("final title"));
+        assert_eq!(fs::read_dir(&folder).unwrap().count(), 12);
+    }
+
+    #[test]
+    fn check_missing_json_file_rewritten_when_unchanged() {
+        let folder = std::env::temp_dir().join("dl_who_unchanged_json_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("ACTRN12624000001.json");
+        let snapshot_path = folder.join("ACTRN12624000001_2025-02-24.json");
+        let job = |only_if_missing: bool| JsonJob { file: json_file(&path, Some(snapshot_path.clone()), "a"), only_if_missing };
+        let write = |only_if_missing: bool| {
+            let mut staged = StagedJsonFiles::default();
+            let written = write_file(&job(only_if_missing), &mut staged).unwrap();
+            staged.promote().unwrap();
+            written
+        };
+
+        assert!(write(true));
+        assert!(path.exists());
+        assert!(!snapshot_path.exists());
+        fs::write(&path, "kept").unwrap();
+        assert!(!write(true));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+        assert!(write(false));
+        assert_ne!(fs::read_to_string(&path).unwrap(), "kept");
+        assert!(snapshot_path.exists());
+    }
+
+    #[test]
+    fn check_files_unchanged_until_promoted() {
+        let folder = std::env::temp_dir().join("dl_who_json_staging_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("ACTRN12624000001.json");
+        fs::write(&path, "committed").unwrap();
+
+        // Staged files that are never promoted (as when the transaction
+        // is rolled back) leave the existing file as it was.
+
+        let mut writer = JsonWriter::new(2);
+        writer.write(json_file(&path, None, "rolled back"), false).unwrap();
+        drop(writer.finish().unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "committed");
+
+        let mut writer = JsonWriter::new(2);
+        writer.write(json_file(&path, None, "new title"), false).unwrap();
+        let staged = writer.finish().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "committed");
+        staged.promote().unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("new title"));
+        assert!(!folder.join("ACTRN12624000001.json.tmp").exists());
+    }
+
+    #[test]
+    fn check_write_error_returned() {
+        let folder = std::env::temp_dir().join("dl_who_json_writer_error_test");
+        let _ = fs::remove_dir_all(&folder);
+        let mut writer = JsonWriter::new(1);
+        writer.write(json_file(&folder.join("no folder").join("x.json"), None, "a"), false).unwrap();
+        assert!(writer.finish().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+/**********************************************************************************
+A full export (DL type 502) should include every study in the WHO data, so a study
+already stored but absent from the export has probably been deleted or withdrawn
+from its registry, or merged with another. Each study stored, or found unchanged,
+during an import has its last_seen_dl_id set to the import's dl_id. Once all of a
+full export has been processed the studies in the dat tables that were not seen
+are flagged (not_in_full_export), and listed, with the import in which they were
+last seen, in evs.studies_not_in_export in the monitoring database. If a json
+archive folder has been given their json files are also moved there, keeping
+the same registry (and year) sub-folders. A flagged study that appears in a later
+import is treated as changed, even if its content is not (see summary_buffer.rs),
+so it is unflagged, its local_path reset and its json file rewritten in the
+usual place.
+***********************************************************************************/
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use sqlx::{Pool, Postgres};
+use log::{info, warn};
+use crate::err::AppError;
+use super::data_access::{flag_studies_not_seen, store_studies_not_in_export};
+use super::registries::catalogue;
+use super::who_helper::get_db_name;
+
+
+pub struct MissingStudy {
+    pub sd_sid: String,
+    pub sid_type_id: i32,
+    pub last_seen_dl_id: Option<i32>,
+    pub local_path: Option<String>,
+    pub archive_path: Option<String>,
+}
+
+
+pub async fn flag_studies_not_in_export(dl_id: i32, json_path: &Path, archive_folder: &Path,
+                    src_pool: &Pool<Postgres>, mon_pool: &Pool<Postgres>) -> Result<i32, AppError> {
+
+    // The studies of all the registries are flagged in a single transaction.
+
+    let mut tx = src_pool.begin().await
+                .map_err(|e| AppError::SqlxError(e, "begin transaction".to_string()))?;
+    let mut studies = Vec::new();
+    for registry in &catalogue().registries {
+        studies.extend(flag_studies_not_seen(&registry.name, dl_id, &mut tx).await?);
+    }
+    tx.commit().await
+                .map_err(|e| AppError::SqlxError(e, "commit transaction".to_string()))?;
+
+    if !archive_folder.as_os_str().is_empty() {
+        let mut num_archived = 0;
+        for s in studies.iter_mut() {
+            if let Some(local_path) = &s.local_path && Path::new(local_path).exists() {
+                let archive_path = get_archive_path(Path::new(local_path), json_path, archive_folder);
+                move_file(Path::new(local_path), &archive_path)?;
+                s.archive_path = Some(archive_path.to_string_lossy().replace("\\", "/"));   // to support Windows
+                num_archived += 1;
+            }
+        }
+        info!("{} json files of studies not in the full export moved to {:?}", num_archived, archive_folder);
+    }
+
+    store_studies_not_in_export(dl_id, &studies, mon_pool).await?;
+
+    let mut registry_tots: BTreeMap<i32, i32> = BTreeMap::new();
+    for s in &studies {
+        *registry_tots.entry(s.sid_type_id).or_default() += 1;
+    }
+    for (sid_type_id, n) in &registry_tots {
+        warn!("{} studies in {} not in the full export", n, get_db_name(*sid_type_id));
+    }
+    info!("{} studies in total not in the full export", studies.len());
+    Ok(studies.len() as i32)
+}
+
+
+// The file keeps its path relative to the json data folder, e.g. <json>/chictr/x.json
+// becomes <archive>/chictr/x.json. A file outside that folder is put in the archive
+// folder itself.
+
+fn get_archive_path(local_path: &Path, json_path: &Path, archive_folder: &Path) -> PathBuf {
+    match local_path.strip_prefix(json_path) {
+        Ok(relative_path) => archive_folder.join(relative_path),
+        Err(_) => archive_folder.join(local_path.file_name().unwrap_or_default()),
+    }
+}
+
+
+fn move_file(from: &Path, to: &Path) -> Result<(), AppError> {
+
+    // A rename fails if the archive is on a different file system,
+    // in which case the file is copied and the original removed.
+
+    if let Some(folder) = to.parent() {
+        fs::create_dir_all(folder)?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to).map_err(|e| AppError::IoWriteErrorWithPath(e, to.to_owned()))?;
+        fs::remove_file(from).map_err(|e| AppError::IoWriteErrorWithPath(e, from.to_owned()))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_archive_paths_keep_sub_folders() {
+        let json_path = Path::new("/data/json");
+        let archive = Path::new("/data/archive");
+        assert_eq!(get_archive_path(Path::new("/data/json/chictr/ChiCTR2400000001.json"), json_path, archive),
+                    PathBuf::from("/data/archive/chictr/ChiCTR2400000001.json"));
+        assert_eq!(get_archive_path(Path::new("/data/json/dutch/2024/NL-OMON12345.json"), json_path, archive),
+                    PathBuf::from("/data/archive/dutch/2024/NL-OMON12345.json"));
+        assert_eq!(get_archive_path(Path::new("/elsewhere/anzctr/ACTRN12624000001.json"), json_path, archive),
+                    PathBuf::from("/data/archive/ACTRN12624000001.json"));
+    }
+
+    #[test]
+    fn check_json_file_moved_to_archive() {
+        let folder = std::env::temp_dir().join("dl_who_archive_test");
+        let _ = fs::remove_dir_all(&folder);
+        let from = folder.join("json").join("anzctr").join("ACTRN12624000001.json");
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, "{}").unwrap();
+
+        let to = get_archive_path(&from, &folder.join("json"), &folder.join("archive"));
+        move_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(folder.join("archive").join("anzctr").join("ACTRN12624000001.json")).unwrap(), "{}");
+    }
+}
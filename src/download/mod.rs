@@ -3,109 +3,379 @@ mod processor;
 pub mod data_access;
 pub mod who_helper;
 pub mod gen_helper;
+pub mod source_file;
+pub mod splitter;
+pub mod rejects;
+pub mod summary_buffer;
+pub mod registries;
+pub mod validator;
+pub mod versions;
+pub mod diff;
+pub mod missing;
+pub mod checkpoint;
+pub mod json_writer;
+pub mod pipeline;
+pub mod ages;
+pub mod genders;
+pub mod enrolment;
+pub mod countries;
+pub mod unclassified;
+#[cfg(test)]
+pub mod test_support;
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use crate::{AppError, DownloadResult};
-use data_access::{add_new_single_file_record, add_contents_record, store_who_summary};
+use data_access::{add_new_single_file_record, add_contents_record, add_rejects_records, delete_rejects_records_from};
 use who_helper::{get_db_name, split_by_year};
 use file_models::{WHOLine, WHOSummary};
+use source_file::open_source_reader;
+use rejects::{RejectPars, RejectReason, RejectsLog};
+use summary_buffer::{SummaryBuffer, JsonPars, SUMMARY_BATCH_SIZE};
+use json_writer::StagedJsonFiles;
+use checkpoint::Checkpoint;
+use unclassified::UnclassifiedValues;
+use pipeline::{LinePipeline, LineOutcome, ProcessedStudy, get_worker_count};
 use std::fs;
-use std::io::BufReader;
 use std::fs::File;
-use csv::ReaderBuilder;
-use std::io::Write;
-use serde_json::to_string_pretty;
-use sqlx::{Pool, Postgres};
-use log::info;
+use csv::{ByteRecord, ErrorKind, Reader, ReaderBuilder};
+use std::io::{Read, Write};
+use sqlx::{Pool, Postgres, PgConnection, Transaction};
+use log::{info, warn};
 
+// The number of records in each chunk of a full export file,
+// as in the files previously generated by splitting the export.
+pub const FULL_EXPORT_CHUNK_SIZE: usize = 50000;
 
-pub async fn process_single_file(file_path: &PathBuf, json_path: &PathBuf, dl_id: i32, 
-                src_pool: &Pool<Postgres>) -> Result<DownloadResult, AppError> {
 
-    // Set up source file, csv reader, counters, hash table.
+pub async fn process_single_file(file_path: &PathBuf, file_hash: &str, json_pars: &JsonPars, reject_pars: &RejectPars, 
+                dl_id: i32, src_pool: &Pool<Postgres>) -> Result<DownloadResult, AppError> {
 
-    let file = File::open(file_path)?;
-    let buf_reader = BufReader::new(file);
+    // Set up source file and csv reader.
+    // The source may be a csv file or a zip archive containing one.
+
+    let source_reader = open_source_reader(file_path)?;
+    let csv_rdr = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(source_reader);
+    info!("");
+    info!("Processing file {:?}", file_path);
+
+    // The records are read and processed by a pipeline of threads (see pipeline.rs).
+
+    let mut lines = LinePipeline::start(csv_rdr, json_pars, dl_id, get_worker_count());
+
+    // All the file's database changes are made within a single transaction, so that
+    // a failure part way through the file leaves the database as it was beforehand.
+    // Its json files only replace the existing ones once the transaction is committed.
+
+    let mut tx = begin_transaction(src_pool).await?;
+    let mut rejects = RejectsLog::new(file_path, reject_pars);
+    let mut unclassified = UnclassifiedValues::new();
+    let (file_res, mut source_tots, json_files) = match process_records(&mut lines, usize::MAX, &mut rejects,
+                                                      &mut unclassified, file_path, &mut tx).await {
+        Ok(r) => r,
+        Err(e) => return Err(store_rejects_after_failure(e, dl_id, file_path, &mut rejects, src_pool).await),
+    };
+
+    info!("{} records checked in total for this file, {} added, {} updated and {} unchanged", 
+                file_res.num_checked, file_res.num_added, file_res.num_updated, file_res.num_unchanged);
+    if file_res.num_rejected > 0 {
+        warn!("{} records rejected from this file", file_res.num_rejected);
+    }
+    unclassified.log("this file");
+    info!("---------------------------------------------------");
+
+    // Update database with single file details and 
+    // return the aggregate figures in the res struct ... 
+
+    add_new_single_file_record(dl_id, file_path, Some(file_hash), &file_res, &mut tx).await?;
+    add_contents_record(file_path, &mut source_tots, &mut tx).await?;
+    add_rejects_records(dl_id, file_path, rejects.take_unstored(), &mut tx).await?;
+    commit_transaction(tx).await?;
+    json_files.promote()?;
+
+    Ok(file_res)
+}
+
+
+pub async fn process_full_export_file(file_path: &PathBuf, chunk_stem: &str, json_pars: &JsonPars, reject_pars: &RejectPars, 
+                checkpoint: &mut Checkpoint, src_pool: &Pool<Postgres>, mon_pool: &Pool<Postgres>) -> Result<DownloadResult, AppError> {
+
+    // The full export file (over 5 GB, and possibly still zipped) is streamed 
+    // through in chunks of FULL_EXPORT_CHUNK_SIZE records. Each chunk is recorded 
+    // in the database as if it were one of the numbered files that splitting the 
+    // export would have generated, with the id of the import added so that the
+    // chunks of different exports are not confused, i.e. as '<chunk stem>001 dl<id>.csv'
+    // etc., within the folder holding the full export file. Each chunk's database
+    // changes are committed as a separate transaction, after which the
+    // chunk's json files are promoted and the checkpoint is moved on and
+    // saved (see checkpoint.rs). If the import is being resumed the chunks
+    // already committed are skipped.
+
+    let source_reader = open_source_reader(file_path)?;
     let mut csv_rdr = ReaderBuilder::new()
         .has_headers(false)
-        .from_reader(buf_reader);
+        .from_reader(source_reader);
+    let source_folder = file_path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    let dl_id = checkpoint.dl_id;
+    let mut rejects = RejectsLog::new(file_path, reject_pars);   // one rejects file, and threshold, for the whole export
+    let mut unclassified = UnclassifiedValues::new();
+    info!("");
+    info!("Processing full export file {:?}", file_path);
+
+    if checkpoint.record_offset > 0 {
+        skip_processed_records(&mut csv_rdr, checkpoint)?;
+        rejects.continue_from(checkpoint.totals.num_rejected, checkpoint.line_offset)?;
+        delete_rejects_records_from(dl_id, checkpoint.line_offset, src_pool).await?;
+        info!("Import {} resumed after chunk {}, from line {} ({} records already processed)", 
+                    dl_id, checkpoint.chunk_num, checkpoint.line_offset, checkpoint.record_offset);
+    }
+    checkpoint.save(mon_pool).await?;
+    let mut lines = LinePipeline::start(csv_rdr, json_pars, dl_id, get_worker_count());
+
+    let mut chunk_num = checkpoint.chunk_num as usize;
+    loop {
+        chunk_num += 1;
+        let chunk_path = source_folder.join(splitter::get_chunk_file_name(chunk_stem, dl_id, chunk_num));
+        info!("");
+        info!("Processing chunk {:?}", chunk_path);
+
+        let mut tx = begin_transaction(src_pool).await?;
+        let (chunk_res, mut source_tots, json_files) = match process_records(&mut lines, FULL_EXPORT_CHUNK_SIZE, &mut rejects,
+                                                           &mut unclassified, &chunk_path, &mut tx).await {
+            Ok(r) => r,
+            Err(e) => return Err(store_rejects_after_failure(e, dl_id, &chunk_path, &mut rejects, src_pool).await),
+        };
+        if chunk_res.num_checked == 0 {
+            break;   // end of the file reached (transaction is empty and is simply dropped)
+        }
+
+        info!("{} records checked in total for this chunk", chunk_res.num_checked);
+        info!("---------------------------------------------------");
+
+        add_new_single_file_record(dl_id, &chunk_path, None, &chunk_res, &mut tx).await?;
+        add_contents_record(&chunk_path, &mut source_tots, &mut tx).await?;
+        add_rejects_records(dl_id, &chunk_path, rejects.take_unstored(), &mut tx).await?;
+        commit_transaction(tx).await?;
+        json_files.promote()?;
+
+        // The checkpoint is in the monitoring database, so is saved once the chunk is 
+        // committed. Should the run stop in between, the chunk is processed again 
+        // when the import is resumed.
+
+        checkpoint.advance(&chunk_res, lines.position());
+        checkpoint.save(mon_pool).await?;
+    }
+
+    let dl_res = checkpoint.totals.clone();
+    info!("{} records checked in total for the full export, in {} chunks, {} added, {} updated and {} unchanged", 
+                dl_res.num_checked, checkpoint.chunk_num, dl_res.num_added, dl_res.num_updated, dl_res.num_unchanged);
+    if dl_res.num_rejected > 0 {
+        warn!("{} records rejected from the full export", dl_res.num_rejected);
+    }
+    unclassified.log("the full export");
+    info!("---------------------------------------------------");
+
+    Ok(dl_res)
+}
+
+
+async fn process_records(lines: &mut LinePipeline, max_records: usize, rejects: &mut RejectsLog,
+                unclassified: &mut UnclassifiedValues, source_path: &Path, tx: &mut PgConnection) 
+                -> Result<(DownloadResult, HashMap<i32, i32>, StagedJsonFiles), AppError> {
+
+    // Processes the records returned by the pipeline, until either the end of the
+    // data or max_records have been returned, leaving any further records in the 
+    // pipeline. Returns the counts and the numbers of records from each source.
+    // The source path (of the file, or of the chunk) is recorded with each study version.
+    // Rows that cannot be used are quarantined (see rejects.rs), and gender and country
+    // values that could not be classified are added to the unclassified values report.
+    // Summary records are written using the transaction (tx) of the calling function.
+    // The json files are returned staged, to be promoted once tx has been committed.
+
     let mut file_res = DownloadResult::new();
     let mut source_tots: HashMap<i32, i32> = HashMap::new();
-    info!("");
-    info!("Processing file {:?}", file_path);
+    let mut summaries = SummaryBuffer::new(SUMMARY_BATCH_SIZE, &source_path.to_string_lossy());
+
+    for _ in 0..max_records {
 
-    for result in csv_rdr.deserialize() {
+        let outcome = match lines.next().await {
+            Some(r) => r?,
+            None => break,   // end of the data reached
+        };
 
         file_res.num_checked +=1;
         if file_res.num_checked % 1000 == 0 {
             info!("{} records checked", file_res.num_checked);
         }
 
-        // Obtain the full record from deserialisation
-
-        let who_line: WHOLine = match result {
-             Ok(w) => w,
-             Err(e) => return Err(AppError::CsvError(e, file_res.num_checked.to_string())),
+        let study = match outcome {
+            LineOutcome::Study(s) => s,
+            LineOutcome::Rejected(record, reason) => {
+                file_res.num_rejected +=1;
+                rejects.add(&record, reason)?;
+                continue;
+            },
+            LineOutcome::Unprocessed => continue,
         };
-               
-        // Construct the summary record
-        
-        let rec_summ = match processor::summarise_line(&who_line, dl_id, file_res.num_checked)
-        {
-            Some(r) => r,
-            None => continue,   // some sort of problem occured - should have been logged
-        };
-
-        let mut file_path = PathBuf::from("");     
-        if rec_summ.sid_type_id != 120  && rec_summ.sid_type_id != 126 {   // file production not necessary for these sources
-            
-            // Process the whole line to get a full WHO record for storage.
-
-            match processor::process_line(who_line, &rec_summ)
-            {
-                Some (rec) => {
-                    
-                    // Write the JSON string to a file.
-
-                    file_path = get_file_path(json_path, &rec_summ)?;
-                    let json_string = to_string_pretty(&rec).unwrap();
-                    let mut file = File::create(&file_path)?;
-                    file.write_all(json_string.as_bytes())?;
-                },
-
-                None => continue,  // some sort of problem occured - should have been logged
-            }
-        }
 
         // Adjust running source totals.
 
-        let sid_type_id = rec_summ.sid_type_id;
+        let sid_type_id = study.rec_summ.sid_type_id;
         source_tots.entry(sid_type_id).and_modify(|n| *n += 1).or_insert(1);
 
         // Store the WHO summary record in the database (whether a file was produced or not).
+        // Records are buffered and written in batches, so the counts returned are those
+        // of any batch written as a result of adding this record.
 
-        let added = store_who_summary(rec_summ, file_path, src_pool).await?;           
+        let ProcessedStudy { rec_summ, rec, json } = *study;
+        if let Some(g) = rec.unclassified_gender() {
+            unclassified.add(unclassified::GENDER, sid_type_id, g);
+        }
+        for c in rec.countries_unresolved.iter().flatten() {
+            unclassified.add(unclassified::COUNTRY, sid_type_id, c);
+        }
+        let counts = summaries.add(rec_summ, rec, json, &mut *tx).await?;           
 
         // Update the Download summary struct.
 
         file_res.num_downloaded +=1;
-        if added {
-            file_res.num_added +=1;
-        } 
+        file_res.num_added += counts.added;
+        file_res.num_updated += counts.updated;
+        file_res.num_unchanged += counts.unchanged;
     }
 
-    info!("{} records checked in total for this file", file_res.num_checked);
-    info!("---------------------------------------------------");
+    // Write any records still buffered (and wait for their json files to be staged).
 
-    // Update database with single file details and 
-    // return the aggregate figures in the res struct ... 
+    let (counts, json_files) = summaries.finish(&mut *tx).await?;
+    file_res.num_added += counts.added;
+    file_res.num_updated += counts.updated;
+    file_res.num_unchanged += counts.unchanged;
 
-    add_new_single_file_record(dl_id, file_path, &file_res, src_pool).await?;
-    add_contents_record(file_path, &mut source_tots, src_pool).await?;
+    Ok((file_res, source_tots, json_files))
+}
 
-    Ok(file_res)
 
+fn read_next_line<'r, R: Read>(csv_rdr: &mut Reader<R>, record: &'r mut ByteRecord, record_num: i32) 
+                -> Result<Option<Result<WHOLine<'r>, RejectReason>>, AppError> {
+
+    // Reads the record as bytes first, so that the raw text of 
+    // a row that cannot be deserialised is still available.
+    // The record is reused, the line borrowing its fields. 
+    // Returns None at the end of the data.
+
+    match csv_rdr.read_byte_record(record) {
+        Ok(false) => Ok(None),
+        Ok(true) => Ok(Some(deserialise_line(record))),
+        Err(e) if matches!(e.kind(), ErrorKind::UnequalLengths { .. }) => Ok(Some(Err(RejectReason::ParseError(e.to_string())))),
+        Err(e) => Err(AppError::CsvError(e, record_num.to_string())),
+    }
+}
+
+
+fn skip_processed_records<R: Read>(csv_rdr: &mut Reader<R>, checkpoint: &Checkpoint) -> Result<(), AppError> {
+
+    // The records of the chunks already committed are read but not processed. The 
+    // reader should then be at the position saved in the checkpoint, unless the
+    // file has been changed (its size having been checked already).
+
+    let mut record = ByteRecord::new();
+    for _ in 0..checkpoint.record_offset {
+        match csv_rdr.read_byte_record(&mut record) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::UnequalLengths { .. }) => {},   // a rejected row
+            Err(e) => return Err(AppError::CsvError(e, "skipping records already processed".to_string())),
+        }
+    }
+    if csv_rdr.position().byte() as i64 != checkpoint.byte_offset {
+        return Result::Err(AppError::InconsistentProgramParameter(
+                    format!("The full export file does not match the checkpoint of import {} (expected to resume at byte {}, not {})", 
+                            checkpoint.dl_id, checkpoint.byte_offset, csv_rdr.position().byte())));
+    }
+    Ok(())
+}
+
+
+fn deserialise_line(record: &ByteRecord) -> Result<WHOLine<'_>, RejectReason> {
+    record.deserialize(None).map_err(|e| RejectReason::ParseError(e.to_string()))
+}
+
+
+// Deserialises, summarises and processes a record as the pipeline's workers do, but
+// without working out where its json file goes, returning true if a WHO record
+// was produced. Used by the benchmarks.
+
+pub fn process_record(record: &ByteRecord) -> bool {
+    let who_line = match deserialise_line(record) {
+        Ok(w) => w,
+        Err(_) => return false,
+    };
+    match processor::summarise_line(&who_line, 0) {
+        Ok(summ) => processor::process_line(who_line, &summ).is_some(),
+        Err(_) => false,
+    }
+}
+
+
+async fn store_rejects_after_failure(e: AppError, dl_id: i32, source_path: &PathBuf, rejects: &mut RejectsLog, 
+                src_pool: &Pool<Postgres>) -> AppError {
+
+    // If processing failed, e.g. because too many rows have been rejected, any rejects 
+    // not yet stored are added to the database before the error is returned. This is done 
+    // outside the file's transaction (which will be rolled back) so that the reasons 
+    // for the failure are retained.
+
+    let unstored = rejects.take_unstored();
+    if !unstored.is_empty() {
+        let stored = match src_pool.acquire().await {
+            Ok(mut conn) => add_rejects_records(dl_id, source_path, unstored, &mut conn).await.map(|_| ()),
+            Err(e) => Err(AppError::SqlxError(e, "acquiring connection".to_string())),
+        };
+        if stored.is_err() {
+            warn!("Unable to store the rejected rows in the database - see the rejects file");
+        }
+    }
+    e
+}
+
+
+async fn begin_transaction(src_pool: &Pool<Postgres>) -> Result<Transaction<'static, Postgres>, AppError> {
+    src_pool.begin().await
+        .map_err(|e| AppError::SqlxError(e, "begin transaction".to_string()))
+}
+
+
+async fn commit_transaction(tx: Transaction<'static, Postgres>) -> Result<(), AppError> {
+    tx.commit().await
+        .map_err(|e| AppError::SqlxError(e, "commit transaction".to_string()))
+}
+
+
+fn stage_json_file(file_path: &Path, json_string: &str) -> Result<(), AppError> {
+
+    // The JSON is written to a temporary file, which only replaces any existing
+    // file when promoted (see below), so that an interrupted run can never leave
+    // a truncated study file, nor one that the database has not recorded.
+
+    let temp_path = get_staged_path(file_path);
+    let mut file = File::create(&temp_path)
+            .map_err(|e| AppError::IoWriteErrorWithPath(e, temp_path.clone()))?;
+    file.write_all(json_string.as_bytes())
+            .map_err(|e| AppError::IoWriteErrorWithPath(e, temp_path.clone()))?;
+    Ok(())
+}
+
+
+fn promote_json_file(file_path: &Path) -> Result<(), AppError> {
+    fs::rename(get_staged_path(file_path), file_path)
+            .map_err(|e| AppError::IoWriteErrorWithPath(e, file_path.to_path_buf()))
+}
+
+
+fn get_staged_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("json.tmp")
 }
 
 
@@ -142,4 +412,55 @@ fn get_file_path(json_path: &PathBuf, rec_summ: &WHOSummary) -> Result<PathBuf,
     let file_name = format!("{}.json", rec_summ.sd_sid);
 
     Ok([file_folder, PathBuf::from(&file_name)].iter().collect())
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_json_file_replaced_without_temp_file_left() {
+        let folder = std::env::temp_dir().join("dl_who_json_write_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let file_path = folder.join("NCT00000001.json");
+
+        stage_json_file(&file_path, "{\"a\": \"a much longer first version\"}").unwrap();
+        promote_json_file(&file_path).unwrap();
+        stage_json_file(&file_path, "{\"a\": \"b\"}").unwrap();
+        assert!(fs::read_to_string(&file_path).unwrap().contains("first version"));
+        promote_json_file(&file_path).unwrap();
+
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "{\"a\": \"b\"}");
+        assert!(!folder.join("NCT00000001.json.tmp").exists());
+        assert_eq!(fs::read_dir(&folder).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn check_processed_records_skipped() {
+        let folder = std::env::temp_dir().join("dl_who_skip_test");
+        fs::create_dir_all(&folder).unwrap();
+        let file_path = folder.join("ICTRPFullExport.csv");
+        fs::write(&file_path, "NCT1,\"a\nb\"\nNCT2,c,extra\nNCT3,d\nNCT4,e\n").unwrap();
+
+        let mut csv_rdr = ReaderBuilder::new().has_headers(false).from_path(&file_path).unwrap();
+        let mut record = ByteRecord::new();
+        for _ in 0..3 {
+            let _ = csv_rdr.read_byte_record(&mut record);
+        }
+        let chunk_res = DownloadResult { num_checked: 3, ..DownloadResult::new() };
+        let mut checkpoint = Checkpoint::new(1, &file_path).unwrap();
+        checkpoint.advance(&chunk_res, csv_rdr.position());
+
+        let mut csv_rdr = ReaderBuilder::new().has_headers(false).from_path(&file_path).unwrap();
+        skip_processed_records(&mut csv_rdr, &checkpoint).unwrap();
+        csv_rdr.read_byte_record(&mut record).unwrap();
+        assert_eq!(&record[0], b"NCT4");
+        assert_eq!(checkpoint.line_offset, 5);
+
+        checkpoint.byte_offset += 1;
+        let mut csv_rdr = ReaderBuilder::new().has_headers(false).from_path(&file_path).unwrap();
+        assert!(skip_processed_records(&mut csv_rdr, &checkpoint).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+/**********************************************************************************
+The records of a file are processed by a pipeline of threads, so that the parsing
+and transformation of the records, which is CPU bound, is spread across the cores
+available and overlaps with the database writes. A reader thread reads the csv
+records and passes them, numbered, to a pool of worker threads, each of which
+deserialises a record into a WHOLine, summarises and processes it (see processor.rs)
+and works out where its json file is to go. The processed lines are returned by
+the pipeline in the order in which they were read, to the (single) task that writes
+the summary records to the database in batches (see summary_buffer.rs), and which
+queues the json files to a pool of writers (see json_writer.rs). The records are
+therefore stored, and counted, exactly as if each had been processed in turn. The
+stages are connected by bounded channels, and the number of records in the pipeline
+at any one time is limited (to MAX_RECORDS_IN_FLIGHT), so that the memory used does
+not depend on the size of the file. The csv records of the studies processed are 
+passed back to the reader to be reused, and the WHOLines borrow their fields, so 
+that only the values kept are copied. As the reader runs ahead of the database writes
+the pipeline keeps the position in the file after the last record it returned, for
+use in checkpoints (see checkpoint.rs).
+***********************************************************************************/
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::Read;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver as SyncReceiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use csv::{ByteRecord, ErrorKind, Position, Reader};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use crate::err::AppError;
+use super::file_models::{WHORecord, WHOSummary};
+use super::processor;
+use super::rejects::RejectReason;
+use super::summary_buffer::{JsonPars, JsonPaths};
+use super::versions;
+use super::who_helper::writes_json;
+use super::{deserialise_line, get_file_path};
+
+pub const MAX_RECORDS_IN_FLIGHT: usize = 2000;
+const WORK_QUEUE_SIZE: usize = 500;
+const LINE_QUEUE_SIZE: usize = 500;
+
+
+// A study ready to be stored, with the paths of its json file and any snapshot.
+
+pub struct ProcessedStudy {
+    pub rec_summ: WHOSummary,
+    pub rec: WHORecord,
+    pub json: Option<JsonPaths>,
+}
+
+
+pub enum LineOutcome {
+    Study(Box<ProcessedStudy>),
+    Rejected(ByteRecord, RejectReason),
+    Unprocessed,        // some sort of problem occured - should have been logged
+}
+
+
+// A record as read, with the position of the record following it
+// and, if it could not be read as a whole row, the reason why.
+
+struct RawLine {
+    record: ByteRecord,
+    next_position: Position,
+    unreadable: Option<RejectReason>,
+}
+
+
+struct ProcessedLine {
+    next_position: Position,
+    outcome: Result<LineOutcome, AppError>,
+}
+
+
+pub struct LinePipeline {
+    lines: Receiver<(u64, ProcessedLine)>,
+    waiting: HashMap<u64, ProcessedLine>,     // lines processed before an earlier line
+    next_seq: u64,
+    permits: SyncReceiver<()>,                // one for each record in the pipeline
+    position: Position,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl LinePipeline {
+
+    pub fn start<R: Read + Send + 'static>(csv_rdr: Reader<R>, json_pars: &JsonPars, dl_id: i32,
+                                           num_workers: usize) -> Self {
+
+        let (work_tx, work_rx) = sync_channel(WORK_QUEUE_SIZE);
+        let (lines_tx, lines_rx) = channel(LINE_QUEUE_SIZE);
+        let (permits_tx, permits_rx) = sync_channel(MAX_RECORDS_IN_FLIGHT);
+        let (recycled_tx, recycled_rx) = sync_channel(MAX_RECORDS_IN_FLIGHT);
+        let position = csv_rdr.position().clone();
+
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        for _ in 0..num_workers.max(1) {
+            let work = Arc::clone(&work_rx);
+            let lines = lines_tx.clone();
+            let recycled = recycled_tx.clone();
+            let json_pars = json_pars.clone();
+            thread::spawn(move || process_lines(work, lines, recycled, json_pars, dl_id));
+        }
+        let reader = thread::spawn(move || read_lines(csv_rdr, work_tx, lines_tx, permits_tx, recycled_rx));
+
+        LinePipeline {
+            lines: lines_rx,
+            waiting: HashMap::new(),
+            next_seq: 0,
+            permits: permits_rx,
+            position,
+            reader: Some(reader),
+        }
+    }
+
+    // Returns the outcome of processing the next record, or None at the end of the data.
+
+    pub async fn next(&mut self) -> Option<Result<LineOutcome, AppError>> {
+        loop {
+            if let Some(line) = self.waiting.remove(&self.next_seq) {
+                self.next_seq += 1;
+                self.position = line.next_position;
+                let _ = self.permits.try_recv();     // allows the reader to read another record
+                return Some(line.outcome);
+            }
+            match self.lines.recv().await {
+                Some((seq, line)) => { self.waiting.insert(seq, line); },
+                None => return self.check_reader(),
+            }
+        }
+    }
+
+    // The position in the file after the last record returned.
+
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+
+    fn check_reader(&mut self) -> Option<Result<LineOutcome, AppError>> {
+
+        // The channel closes once the reader and the workers have all stopped,
+        // which (unless the reader has failed) is at the end of the data.
+
+        match self.reader.take().map(|h| h.join()) {
+            Some(Err(p)) => Some(Err(AppError::ProcessingThreadError("csv reader".to_string(), get_panic_message(p)))),
+            _ => None,
+        }
+    }
+}
+
+
+pub fn get_worker_count() -> usize {
+
+    // One core is left for the reader and the database writes.
+
+    thread::available_parallelism().map_or(1, |n| n.get().saturating_sub(1)).max(1)
+}
+
+
+fn read_lines<R: Read>(mut csv_rdr: Reader<R>, work: SyncSender<(u64, RawLine)>,
+                       lines: Sender<(u64, ProcessedLine)>, permits: SyncSender<()>,
+                       recycled: SyncReceiver<ByteRecord>) {
+
+    // Each of the loop's sends fails if the pipeline has been dropped,
+    // e.g. because of an error, in which case the reader stops.
+
+    let mut seq = 0;
+    loop {
+        if permits.send(()).is_err() {
+            return;
+        }
+        let mut record = recycled.try_recv().unwrap_or_default();
+        let unreadable = match csv_rdr.read_byte_record(&mut record) {
+            Ok(false) => return,   // end of the data reached
+            Ok(true) => None,
+            Err(e) if matches!(e.kind(), ErrorKind::UnequalLengths { .. }) => Some(RejectReason::ParseError(e.to_string())),
+            Err(e) => {
+                let line = ProcessedLine { next_position: csv_rdr.position().clone(),
+                                           outcome: Err(AppError::CsvError(e, (seq + 1).to_string())) };
+                let _ = lines.blocking_send((seq, line));
+                return;
+            },
+        };
+        let raw = RawLine { record, next_position: csv_rdr.position().clone(), unreadable };
+        if work.send((seq, raw)).is_err() {
+            return;
+        }
+        seq += 1;
+    }
+}
+
+
+fn process_lines(work: Arc<Mutex<SyncReceiver<(u64, RawLine)>>>, lines: Sender<(u64, ProcessedLine)>,
+                 recycled: SyncSender<ByteRecord>, json_pars: JsonPars, dl_id: i32) {
+
+    // A panic while processing a record is returned as an error, in the
+    // record's place, rather than leaving a gap in the lines returned.
+    // The records of rejected rows are kept, for the rejects log.
+
+    loop {
+        let (seq, raw) = match work.lock().map(|w| w.recv()) {
+            Ok(Ok(item)) => item,
+            _ => return,     // no more records
+        };
+        let outcome = catch_unwind(AssertUnwindSafe(|| process_record(raw.record, raw.unreadable, &recycled, &json_pars, dl_id)))
+            .unwrap_or_else(|p| Err(AppError::ProcessingThreadError("record processing worker".to_string(), get_panic_message(p))));
+        if lines.blocking_send((seq, ProcessedLine { next_position: raw.next_position, outcome })).is_err() {
+            return;
+        }
+    }
+}
+
+
+fn process_record(record: ByteRecord, unreadable: Option<RejectReason>, recycled: &SyncSender<ByteRecord>,
+                  json_pars: &JsonPars, dl_id: i32) -> Result<LineOutcome, AppError> {
+
+    if let Some(reason) = unreadable {
+        return Ok(LineOutcome::Rejected(record, reason));
+    }
+
+    // Obtain the full record from deserialisation, and construct the summary record.
+
+    let who_line = match deserialise_line(&record) {
+        Ok(w) => w,
+        Err(reason) => return Ok(LineOutcome::Rejected(record, reason)),
+    };
+    let rec_summ = match processor::summarise_line(&who_line, dl_id) {
+        Ok(r) => r,
+        Err(reason) => return Ok(LineOutcome::Rejected(record, reason)),
+    };
+
+    // Process the whole line to get a full WHO record, used to identify changes to the
+    // study (through its content hash), to record its versions and, for most sources,
+    // written as a json file. The json file (and any snapshot) is only written when
+    // the record's batch is stored, and then only if the study is new or has changed.
+
+    let rec = processor::process_line(who_line, &rec_summ);
+    let _ = recycled.try_send(record);     // dropped if the reader has enough records already
+    let rec = match rec {
+        Some(rec) => rec,
+        None => return Ok(LineOutcome::Unprocessed),
+    };
+    let json = if writes_json(rec_summ.sid_type_id) {   // file production not necessary for some sources (e.g. CTG, ISRCTN)
+        let snapshot_path = match json_pars.snapshots {
+            true => Some(versions::get_snapshot_path(&json_pars.json_path, &rec_summ, &rec.record_date)?),
+            false => None,
+        };
+        Some(JsonPaths { path: get_file_path(&json_pars.json_path, &rec_summ)?, snapshot_path })
+    } else {
+        None
+    };
+
+    Ok(LineOutcome::Study(Box::new(ProcessedStudy { rec_summ, rec, json })))
+}
+
+
+fn get_panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(p) => p.downcast::<&str>().map_or("unknown cause".to_string(), |s| s.to_string()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use csv::ReaderBuilder;
+    use super::super::test_support::make_row;
+
+    // A csv line with the trial id and title given, and empty strings elsewhere.
+
+    fn csv_row(sd_sid: &str, title: &str) -> String {
+        make_row(&[(0, sd_sid), (3, title)]) + "\n"
+    }
+
+    fn start_pipeline(data: String, num_workers: usize, folder: &str) -> LinePipeline {
+        let json_path = std::env::temp_dir().join(folder);
+        let _ = fs::remove_dir_all(&json_path);
+        let json_pars = JsonPars { json_path, snapshots: false };
+        let csv_rdr = ReaderBuilder::new().has_headers(false).from_reader(std::io::Cursor::new(data.into_bytes()));
+        LinePipeline::start(csv_rdr, &json_pars, 1, num_workers)
+    }
+
+    #[tokio::test]
+    async fn check_lines_returned_in_order() {
+        let mut data = String::new();
+        for i in 0..(MAX_RECORDS_IN_FLIGHT + 500) {
+            match i % 100 {
+                7 => data.push_str("XYZ123,a\n"),                            // unequal length
+                13 => data.push_str(&csv_row("XYZ123", "unknown source")),
+                _ => data.push_str(&csv_row(&format!("ACTRN1262400{:04}", i), &format!("study {}", i))),
+            }
+        }
+
+        let mut lines = start_pipeline(data, 4, "dl_who_pipeline_test");
+        let mut i = 0;
+        let mut num_rejected = 0;
+        while let Some(outcome) = lines.next().await {
+            match outcome.unwrap() {
+                LineOutcome::Study(s) => {
+                    assert_eq!(s.rec_summ.sd_sid, format!("ACTRN1262400{:04}", i));
+                    assert!(s.json.unwrap().path.ends_with(format!("ACTRN1262400{:04}.json", i)));
+                },
+                LineOutcome::Rejected(record, reason) => {
+                    assert_eq!(record.position().unwrap().line(), i as u64 + 1);
+                    assert_eq!(reason.code(), if i % 100 == 7 { "PARSE_ERROR" } else { "UNKNOWN_ID" });
+                    num_rejected += 1;
+                },
+                LineOutcome::Unprocessed => panic!("line {} not processed", i),
+            }
+            i += 1;
+        }
+        assert_eq!(i, MAX_RECORDS_IN_FLIGHT + 500);
+        assert_eq!(num_rejected, 50);
+        assert_eq!(lines.position().record(), i as u64);
+    }
+
+    #[tokio::test]
+    async fn check_position_after_each_line() {
+        let data = csv_row("ACTRN12624000001", "a") + &csv_row("ACTRN12624000002", "b\nc");
+        let first_len = csv_row("ACTRN12624000001", "a").len() as u64;
+        let mut lines = start_pipeline(data.clone(), 2, "dl_who_pipeline_position_test");
+
+        assert_eq!(lines.position().byte(), 0);
+        lines.next().await.unwrap().unwrap();
+        assert_eq!((lines.position().byte(), lines.position().line()), (first_len, 2));
+        lines.next().await.unwrap().unwrap();
+        assert_eq!((lines.position().byte(), lines.position().line()), (data.len() as u64, 4));
+        assert!(lines.next().await.is_none());
+    }
+}
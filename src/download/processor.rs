@@ -1,38 +1,43 @@
-use std::sync::LazyLock;
-use regex::Regex;
-use log::error;
 use chrono::NaiveDate;
 use std::collections::HashSet;
 
-use super::who_helper::{get_db_name, get_sid_type_id, get_type, get_status, 
-    get_conditions, split_and_dedup_countries, add_study_purpose,
+use super::who_helper::{get_type, get_status, 
+    get_conditions, add_study_purpose,
     add_int_study_features, add_obs_study_features, add_eu_design_features,
     add_masking, add_phase, add_eu_phase, split_ids, split_secids, process_sponsor_name};
-use super::gen_helper::{StringExtensions, DateExtensions};
+use super::gen_helper::{StringExtensions, DateExtensions, DateOrder, DatePrecision, PartialDate};
 use super::file_models::{WHOLine, WHORecord, WhoStudyFeature, SecondaryId, WHOSummary};
+use super::rejects::RejectReason;
+use super::registries::catalogue;
+use super::ages::{AgeLimit, get_age_eligibility, get_euro_age_eligibility};
+use super::genders::get_gender_eligibility;
+use super::enrolment::get_enrolment;
+use super::countries::get_study_countries;
 
+const EU_AGE_GROUPS: &str = "Are the trial subjects under 18?";
 
 
-pub fn summarise_line(w: &WHOLine, dl_id: i32, line_number: i32) -> Option<WHOSummary>  {
 
-    let sid = w.trial_id.replace("/", "-").replace("\\", "-").replace(".", "-");
-    let mut sd_sid = sid.trim().to_string();
+pub fn get_sd_sid(trial_id: &str) -> String {
+    let sid = trial_id.replace("/", "-").replace("\\", "-").replace(".", "-");
+    sid.trim().to_string()
+}
+
+
+pub fn summarise_line(w: &WHOLine, dl_id: i32) -> Result<WHOSummary, RejectReason>  {
+
+    let mut sd_sid = get_sd_sid(w.trial_id);
     
     if sd_sid == "" || sd_sid == "null" || sd_sid == "NULL" {        // Seems to happen, or has happened in the past, with one Dutch trial.
-        error!("Well that's weird - no study id on line {}!", line_number);
-        return None;
-    }
-
-    let sid_type_id = get_sid_type_id(&sd_sid);
-    if sid_type_id == 0
-    {
-        error!("Well that's weird - can't match the study id's {} source on line {}!", sd_sid, line_number);
-        return None;
+        return Err(RejectReason::EmptyId);
     }
 
-    if sid_type_id == 123 {
-        sd_sid = sd_sid[0..19].to_string(); // lose country specific suffix
-    }
+    let registry = match catalogue().find_by_trial_id(&sd_sid) {
+        Some(r) => r,
+        None => return Err(RejectReason::UnknownSource(sd_sid)),
+    };
+    let sid_type_id = registry.sid_type_id;
+    sd_sid = registry.normalise_id(&sd_sid);
    
     let mut title = w.pub_title.replace_unicodes();
     if title.is_none() {
@@ -118,58 +123,29 @@ pub fn summarise_line(w: &WHOLine, dl_id: i32, line_number: i32) -> Option<WHOSu
     (reg_ids, oth_ids) = split_secids(&secids);
     
    
-    let date_last_rev = get_naive_date (&w.last_updated);
+    let date_last_rev = get_naive_date (w.last_updated);
     
-    let reg_year: i32;
-    let date_reg = w.date_registration.as_iso_date();
-    (reg_year, _ , _) = split_iso_date(&date_reg);
+    // The years are used even if the month, or the day, is not known.
 
-    let enrol_year: i32;
-    let date_enrolment = w.date_enrollement.as_iso_date();
-    (enrol_year, _, _) = split_iso_date(&date_enrolment);
+    let get_year = |d: Option<PartialDate>| d.map_or(0, |d| d.year);
+    let reg_year = get_year(w.date_registration.as_partial_date(registry.date_order));
+    let enrol_year = get_year(w.date_enrollement.as_partial_date(registry.date_order));
 
     
-    let mut table_name = get_db_name(sid_type_id);
-    let mut suffix: &str;
+    let table_name = registry.name.clone();   // partitioned by reg_year in the database
 
-    if sid_type_id == 120 {
-        if reg_year < 2010 {
-            suffix = "_lt_2010";
-        }
-        else if reg_year < 2015 {
-            suffix = "_2010_14";
-        }
-        else if reg_year < 2020 {
-            suffix = "_2015_19";
-        }
-        else if reg_year < 2025 {
-            suffix = "_2020_24";
-        }
-        else {
-            suffix = "_2025_29";
-        }
-        table_name = table_name + suffix;
-    }
+    let countries = w.countries.tidy().and_then(|c| get_study_countries(sid_type_id, &c)).unwrap_or_default();
+    let as_option = |v: Vec<String>| if v.is_empty() { None } else { Some(v) };
 
-    if sid_type_id == 118 || sid_type_id == 121 
-        || sid_type_id == 127 {
-        if reg_year < 2020 {
-            suffix = "_lt_2020";
-        }
-        else {
-            suffix = "_ge_2020";
-        }
-        table_name = table_name + suffix;
-    }
-    
+    // The EU CTR lists the age groups included at the end of the inclusion criteria,
+    // rather than giving minimum and maximum ages.
 
-    let country_list = w.countries.tidy();
-    let countries = match country_list {
-        Some(c) => {split_and_dedup_countries(sid_type_id, &c)}
-        None => None,
+    let age_eligibility = match w.inclusion_criteria.find(EU_AGE_GROUPS) {
+        Some(pos) if sid_type_id == 123 => Some(get_euro_age_eligibility(&w.inclusion_criteria[pos..])),
+        _ => get_age_eligibility(w.age_min, w.age_max),
     };
 
-    Some(WHOSummary {
+    Ok(WHOSummary {
         sid_type_id: sid_type_id, 
         sd_sid: sd_sid, 
         title: title,
@@ -187,7 +163,11 @@ pub fn summarise_line(w: &WHOLine, dl_id: i32, line_number: i32) -> Option<WHOSu
         enrol_year: enrol_year,
         results_yes_no: w.results_yes_no.tidy(),
         table_name: table_name,
-        country_list: countries,
+        country_list: as_option(countries.names),
+        country_codes: as_option(countries.codes),
+        countries_unresolved: as_option(countries.unresolved),
+        age_eligibility,
+        enrolment: get_enrolment(w.target_size, w.type_enrolment, w.results_actual_enrollment),
         date_last_rev_in_who: date_last_rev,  // assumed to be always present
         dl_id: dl_id,
     })
@@ -198,6 +178,15 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
 
     let sid_type_id = summ.sid_type_id;
     let study_type_id = summ.study_type_id;
+    let registry = catalogue().get(sid_type_id);
+    let date_order = registry.map_or(DateOrder::default(), |r| r.date_order);
+    let date = |s: &str| get_date_and_precision(s, date_order);
+
+    let (date_registration, date_registration_precision) = date(w.date_registration);
+    let (date_enrolment, date_enrolment_precision) = date(w.date_enrollement);
+    let (results_date_posted, results_date_posted_precision) = date(w.results_date_posted);
+    let (results_date_first_pub, results_date_first_pub_precision) = date(w.results_date_first_pub);
+    let (results_date_completed, results_date_completed_precision) = date(w.results_date_completed);
 
     let design_orig = w.study_design.tidy();
     let phase_orig = w.phase.tidy();
@@ -270,87 +259,17 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
     };
 
 
-    let mut agemin = w.age_min.tidy();
-    let mut agemin_units: Option<String> = None;
-    let mut agemax = w.age_max.tidy();
-    let mut agemax_units: Option<String> = None;
-
-
-    if sid_type_id != 123 {
-
-        static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+").unwrap());
-
-        (agemin, agemin_units)  =  match w.age_min.tidy() {
-
-            Some(am) => {
-                if RE.is_match(&am) {
-                    let caps = RE.captures(&am).unwrap();
-                    let min = &caps[0];
-                    (Some(min.to_string()), am.get_time_units())
-                }
-                else {
-                    (None, None)
-                }
-            }, 
-
-            None => (None, None), 
-        };
-
-        (agemax, agemax_units)  =  match w.age_max.tidy() {
-
-            Some(am) => {
-                if RE.is_match(&am) {
-                    let caps = RE.captures(&am).unwrap();
-                    let max = &caps[0];
-                    (Some(max.to_string()), am.get_time_units())
-                }
-                else {
-                    (None, None)
-                }
-            }, 
-            None => (None, None), 
-        };
-    }
+    let age_limit = |a: &Option<AgeLimit>| match a {
+        Some(a) => (Some(a.value_string()), Some(a.unit.name().to_string())),
+        None => (None, None),
+    };
+    let ages = &summ.age_eligibility;
+    let (agemin, agemin_units) = age_limit(&ages.as_ref().and_then(|a| a.min.clone()));
+    let (agemax, agemax_units) = age_limit(&ages.as_ref().and_then(|a| a.max.clone()));
     
 
-    let gender = match w.gender.tidy() {
-
-       Some(g) => {
-            let glow = g.to_lowercase();
-            if glow.contains("both")
-            {
-                Some("Both".to_string())
-            }
-            else
-            {
-                let f = glow.contains("female") || glow.contains("women") || glow == "f";
-                let mut gen2 = glow.clone();
-                if f {
-                    gen2 = glow.replace("female", "").replace("women", "")
-                }
-                let m = gen2.contains("male") || glow.contains("men") || glow == "m";
-                
-                if m && f
-                {
-                    Some("Both".to_string())
-                }
-                else if m {
-                    Some("Male".to_string())
-                }
-                else if f {
-                    Some("Female".to_string())
-                }
-                else if glow == "-" {
-                    None
-                }
-                else // still no match...
-                {
-                    Some(format!("?? Unable to classify ({})", g))
-                }
-        }
-    },
-        None => None,
-    };
+    let gender_orig = w.gender.tidy();
+    let gender = gender_orig.as_deref().and_then(|g| get_gender_eligibility(g, registry));
 
 
     let inc_crit = match  w.inclusion_criteria.tidy() {
@@ -363,9 +282,8 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
 
             let mut crit = s.clone();
             if sid_type_id == 123  {
-               if let Some(pos) = s.find("Are the trial subjects under 18?")
+               if let Some(pos) = s.find(EU_AGE_GROUPS)
                {
-                    (agemin, agemin_units, agemax, agemax_units) = get_euro_ages(&s[pos..].to_string());
                      crit = s[..pos].to_string();
                }
             }
@@ -437,9 +355,12 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
         study_type_orig: summ.study_type.to_owned(),
         study_type_id: summ.study_type_id,
 
-        date_registration: w.date_registration.as_iso_date(),
-        date_enrolment: w.date_enrollement.as_iso_date(),
+        date_registration,
+        date_registration_precision,
+        date_enrolment,
+        date_enrolment_precision,
         target_size: w.target_size.tidy(),
+        enrolment: summ.enrolment.to_owned(),
         study_status_orig: summ.study_status.to_owned(),
         study_status_id: summ.study_status_id,
         primary_sponsor: w.primary_sponsor.tidy(),
@@ -451,7 +372,9 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
         agemin_units:agemin_units,
         agemax: agemax,
         agemax_units: agemax_units,
-        gender: gender,
+        age_eligibility: summ.age_eligibility.to_owned(),
+        gender_orig,
+        gender,
         inclusion_criteria: inc_crit,
         exclusion_criteria: exc_crit,
         primary_outcome: w.primary_outcome.replace_tags_and_unicodes(),
@@ -467,16 +390,21 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
         results_yes_no: w.results_yes_no.tidy(),       
         results_url_link: w.results_url_link.tidy(),
         results_summary: w.results_summary.tidy(),
-        results_date_posted: w.results_date_posted.as_iso_date(),
-        results_date_first_pub: w.results_date_first_pub.as_iso_date(),
+        results_date_posted,
+        results_date_posted_precision,
+        results_date_first_pub,
+        results_date_first_pub_precision,
         results_url_protocol: w.results_url_protocol.tidy(),
-        results_date_completed: w.results_date_completed.as_iso_date(),
+        results_date_completed,
+        results_date_completed_precision,
 
         ipd_plan: ipd_plan,
         ipd_description:ipd_description,
         design_string: design_orig,
         phase_string: phase_orig,
         country_list: summ.country_list.to_owned(),
+        country_codes: summ.country_codes.to_owned(),
+        countries_unresolved: summ.countries_unresolved.to_owned(),
         secondary_ids: summ.sec_ids.to_owned(),
         study_features: study_features,
         condition_list: conditions,
@@ -485,7 +413,7 @@ pub fn process_line(w: WHOLine, summ: &WHOSummary) -> Option<WHORecord>  {
 }
 
 
-fn get_naive_date (dt: &String) -> Option<NaiveDate> {
+fn get_naive_date (dt: &str) -> Option<NaiveDate> {
 
    match dt.as_iso_date()
    {
@@ -500,67 +428,11 @@ fn get_naive_date (dt: &String) -> Option<NaiveDate> {
 }
 
 
-fn split_iso_date (dt: &Option<String>) -> (i32, i32, i32) {
+// A date as an ISO string, only as precise as it is known, with that precision.
 
-    match dt {
-        Some(d) => {
-            if d.len() != 10 {
-                println!("Odd iso date: {}", d);
-            }
-            let year: i32 = d[0..4].parse().unwrap_or(0);
-            let month: i32 = d[5..7].parse().unwrap_or(0);
-            let day: i32 = d[8..].parse().unwrap_or(0);
-            if year != 0 && month != 0 && day != 0 {
-                (year, month, day)           
-            }
-            else {
-                (0, 0, 0)      
-            }
-         },
-         None => (0, 0, 0),     
-    }
-}
-
-
-fn get_euro_ages(age_string: &String) -> (Option<String>, Option<String>, Option<String>, Option<String>) {
-            
-    let mut agemin= None;
-    let mut agemin_units: Option<String> = None;
-    let mut agemax= None;
-    let mut agemax_units: Option<String> = None;
-
-    let children: bool = if age_string.contains("Are the trial subjects under 18? yes") {true} else {false};
-    let adult: bool = if age_string.contains("F.1.2 Adults (18-64 years) yes") {true} else {false};
-    let aged: bool = if age_string.contains("F.1.3 Elderly (>=65 years) yes") {true} else {false};
-     
-    if children {
-        if !adult {
-            agemax = Some("17".to_string());
-            agemax_units = Some("Years".to_string());
-        }
-        else {   // adult 
-            if !aged {
-                agemax = Some("64".to_string());
-                agemax_units = Some("Years".to_string());
-            }
-        }
+fn get_date_and_precision(date_string: &str, order: DateOrder) -> (Option<String>, Option<DatePrecision>) {
+    match date_string.as_partial_date(order) {
+        Some(d) => (Some(d.as_iso()), Some(d.precision)),
+        None => (None, None),
     }
-    else {     // no children
-        if adult {
-            agemin = Some("18".to_string());
-            agemin_units = Some("Years".to_string());
-
-            if !aged {
-                agemax = Some("64".to_string());
-                agemax_units = Some("Years".to_string());
-            }
-        }
-        else {       // aged only
-            agemin = Some("65".to_string());
-            agemin_units = Some("Years".to_string());
-        }
-    }
-
-    (agemin, agemin_units, agemax, agemax_units) 
-
 }
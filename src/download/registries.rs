@@ -0,0 +1,312 @@
+/**********************************************************************************
+The registry catalogue holds what the program needs to know about each trial
+registry in the WHO data - the prefixes of its trial ids, the name used for its
+dat table and json folder, how that table is partitioned and the folder split by
+year, whether json files are written for it at all, the order of the day and
+month in its numeric dates, and its own spellings of gender eligibility. The
+default catalogue is the registries.toml file bundled with the program. An
+alternative file can be named in the config file (registries_file), so that a
+registry newly added to the ICTRP data can be processed without any change to the
+code. The catalogue in use is held in a static variable, set once at startup.
+***********************************************************************************/
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use serde::Deserialize;
+use crate::err::AppError;
+use super::gen_helper::DateOrder;
+use super::genders::GenderEligibility;
+
+const DEFAULT_CATALOGUE: &str = include_str!("registries.toml");
+
+pub static CATALOGUE: OnceLock<RegistryCatalogue> = OnceLock::new();
+
+
+// A range partition of a registry's dat table. Years before the
+// registry's partition_start all go into a single partition, which
+// has no lower bound (and so also holds records with no year).
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct YearPartition {
+    pub name: String,
+    pub from_year: Option<i32>,
+    pub to_year: i32,
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Registry {
+    pub sid_type_id: i32,
+    pub name: String,
+    pub id_prefixes: Vec<String>,
+    #[serde(default)]
+    pub sec_id_prefixes: Vec<String>,
+    #[serde(default = "default_true")]
+    pub write_json: bool,
+    #[serde(default)]
+    pub json_split_by_year: bool,
+    pub max_id_length: Option<usize>,
+    #[serde(default = "default_partition_start")]
+    pub partition_start: i32,
+    #[serde(default = "default_partition_span")]
+    pub partition_span: i32,
+    #[serde(default)]
+    pub legacy_tables: Vec<String>,
+    #[serde(default)]
+    pub date_order: DateOrder,
+    #[serde(default)]
+    pub gender_values: HashMap<String, GenderEligibility>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_partition_start() -> i32 {
+    2000
+}
+
+fn default_partition_span() -> i32 {
+    5
+}
+
+impl Registry {
+
+    // Returns the partition of the registry's dat table that holds 
+    // studies registered in the given year, e.g. ctg_2015_19.
+
+    pub fn partition_for_year(&self, reg_year: i32) -> YearPartition {
+        if reg_year < self.partition_start {
+            return YearPartition {
+                name: format!("{}_lt_{}", self.name, self.partition_start),
+                from_year: None,
+                to_year: self.partition_start,
+            };
+        }
+        let from_year = self.partition_start + ((reg_year - self.partition_start) / self.partition_span) * self.partition_span;
+        let to_year = from_year + self.partition_span;
+        let name = if self.partition_span == 1 {
+            format!("{}_{}", self.name, from_year)
+        } else {
+            format!("{}_{}_{:02}", self.name, from_year, (to_year - 1) % 100)
+        };
+        YearPartition { name, from_year: Some(from_year), to_year }
+    }
+
+    pub fn get_gender_value(&self, gender: &str) -> Option<GenderEligibility> {
+        let lgender = gender.trim().to_lowercase();
+        self.gender_values.iter()
+            .find(|(v, _)| v.to_lowercase() == lgender)
+            .map(|(_, g)| *g)
+    }
+
+    pub fn normalise_id(&self, sd_sid: &str) -> String {
+        match self.max_id_length {
+            Some(n) => sd_sid.chars().take(n).collect(),   // e.g. lose country specific suffix
+            _ => sd_sid.to_string(),
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct RegistryCatalogue {
+    pub registries: Vec<Registry>,
+}
+
+impl RegistryCatalogue {
+
+    pub fn from_toml(toml_string: &str, source: &str) -> Result<Self, AppError> {
+
+        let catalogue = toml::from_str::<RegistryCatalogue>(toml_string)
+            .map_err(|e| AppError::ConfigurationError(format!("Unable to parse registry catalogue ({}).", source),
+                                                      e.to_string()))?;
+        catalogue.check(source)?;
+        Ok(catalogue)
+    }
+
+    pub fn bundled() -> Self {
+        RegistryCatalogue::from_toml(DEFAULT_CATALOGUE, "bundled registries.toml")
+            .expect("the bundled registry catalogue should be valid")
+    }
+
+    pub fn get(&self, sid_type_id: i32) -> Option<&Registry> {
+        self.registries.iter().find(|r| r.sid_type_id == sid_type_id)
+    }
+
+    pub fn find_by_trial_id(&self, sd_sid: &str) -> Option<&Registry> {
+        let usid = sd_sid.to_uppercase();
+        self.registries.iter()
+            .find(|r| r.id_prefixes.iter().any(|p| usid.starts_with(&p.to_uppercase())))
+    }
+
+    pub fn find_by_sec_id(&self, sec_id: &str) -> Option<&Registry> {
+        let upid = sec_id.to_uppercase();
+        self.registries.iter()
+            .find(|r| r.sec_id_prefixes.iter().any(|p| upid.starts_with(&p.to_uppercase())))
+    }
+
+    fn check(&self, source: &str) -> Result<(), AppError> {
+
+        let problem = |detail: String| Result::Err(AppError::ConfigurationError(
+                            format!("Invalid registry catalogue ({}).", source), detail));
+
+        let mut ids = HashSet::new();
+        for r in &self.registries {
+            if !ids.insert(r.sid_type_id) {
+                return problem(format!("Registry id {} is listed more than once.", r.sid_type_id));
+            }
+            if r.name.trim().is_empty() {
+                return problem(format!("Registry {} has no name.", r.sid_type_id));
+            }
+            if r.id_prefixes.iter().all(|p| p.trim().is_empty()) {
+                return problem(format!("Registry {} ({}) has no id prefixes.", r.sid_type_id, r.name));
+            }
+            if r.partition_span < 1 {
+                return problem(format!("The partition span of registry {} ({}) must be at least 1.", r.sid_type_id, r.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+pub fn load_catalogue_file(file_path: &Path) -> Result<usize, AppError> {
+
+    // Replaces the bundled catalogue with that in the file. Must be called
+    // before any data is processed, as the catalogue can only be set once.
+
+    let toml_string = fs::read_to_string(file_path)
+        .map_err(|e| AppError::IoReadErrorWithPath(e, file_path.to_owned()))?;
+    let catalogue = RegistryCatalogue::from_toml(&toml_string, &file_path.display().to_string())?;
+    let num_registries = catalogue.registries.len();
+    if CATALOGUE.set(catalogue).is_err() {
+        return Result::Err(AppError::InconsistentProgramParameter(
+                    "The registry catalogue has already been set".to_string()));
+    }
+    Ok(num_registries)
+}
+
+
+pub fn catalogue() -> &'static RegistryCatalogue {
+    CATALOGUE.get_or_init(RegistryCatalogue::bundled)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_bundled_catalogue_matches_trial_ids() {
+        let cat = RegistryCatalogue::bundled();
+        let id = |s: &str| cat.find_by_trial_id(s).map_or(0, |r| r.sid_type_id);
+        assert_eq!(id("NCT01234567"), 120);
+        assert_eq!(id("ChiCTR2400000001"), 118);
+        assert_eq!(id("CTRI/2024/01/000001"), 121);
+        assert_eq!(id("jRCT1234567890"), 0);
+        assert_eq!(id("JPRN-jRCT1234567890"), 127);
+        assert_eq!(id("EUCTR2020-000001-01-GB"), 123);
+        assert_eq!(id("ISRCTN12345678"), 126);
+        assert_eq!(id("ACTRN12624000001"), 116);
+        assert_eq!(id("NL-OMON12345"), 132);
+        assert_eq!(id("PER-001-24"), 129);
+        assert_eq!(id("ITMCTR2024000001"), 134);
+        assert_eq!(id("XYZ123"), 0);
+        assert_eq!(cat.registries.len(), 20);
+    }
+
+    #[test]
+    fn check_bundled_catalogue_settings() {
+        let cat = RegistryCatalogue::bundled();
+        assert_eq!(cat.get(131).unwrap().name, "thctr");
+        assert!(!cat.get(120).unwrap().write_json);
+        assert!(!cat.get(126).unwrap().write_json);
+        assert!(cat.get(116).unwrap().write_json);
+        assert!(cat.get(132).unwrap().json_split_by_year);
+        assert!(!cat.get(117).unwrap().json_split_by_year);
+        assert!(cat.get(999).is_none());
+        assert_eq!(cat.find_by_sec_id("ChiMCTR2000001").unwrap().sid_type_id, 134);
+        assert_eq!(cat.find_by_sec_id("KCT0001234").unwrap().sid_type_id, 119);
+        assert!(cat.find_by_sec_id("NCT01234567").is_none());
+        assert_eq!(cat.get(120).unwrap().date_order, DateOrder::DayFirst);
+        assert_eq!(cat.get(116).unwrap().date_order, DateOrder::DayFirst);
+    }
+
+    #[test]
+    fn check_partitions_by_year() {
+        let cat = RegistryCatalogue::bundled();
+        let ctg = cat.get(120).unwrap();
+        assert_eq!(ctg.partition_for_year(0), YearPartition { name: "ctg_lt_2010".to_string(), from_year: None, to_year: 2010 });
+        assert_eq!(ctg.partition_for_year(2009).name, "ctg_lt_2010");
+        assert_eq!(ctg.partition_for_year(2010), YearPartition { name: "ctg_2010_14".to_string(), from_year: Some(2010), to_year: 2015 });
+        assert_eq!(ctg.partition_for_year(2019).name, "ctg_2015_19");
+        assert_eq!(ctg.partition_for_year(2031), YearPartition { name: "ctg_2030_34".to_string(), from_year: Some(2030), to_year: 2035 });
+        assert_eq!(ctg.partition_for_year(2098).name, "ctg_2095_99");
+        assert_eq!(ctg.partition_for_year(2100).name, "ctg_2100_04");
+        assert_eq!(cat.get(127).unwrap().partition_for_year(2019).name, "jprn_lt_2020");
+        assert_eq!(cat.get(127).unwrap().partition_for_year(2020).name, "jprn_2020_24");
+        assert_eq!(cat.get(116).unwrap().partition_for_year(2020).name, "anzctr_2020_24");
+        assert_eq!(cat.get(116).unwrap().partition_for_year(1999).name, "anzctr_lt_2000");
+    }
+
+    #[test]
+    fn check_ids_normalised() {
+        let cat = RegistryCatalogue::bundled();
+        assert_eq!(cat.get(123).unwrap().normalise_id("2020-000001-01-GB"), "2020-000001-01-GB");
+        assert_eq!(cat.get(123).unwrap().normalise_id("EUCTR2020-000001-01-GB"), "EUCTR2020-000001-01");
+        assert_eq!(cat.get(116).unwrap().normalise_id("ACTRN12624000001"), "ACTRN12624000001");
+    }
+
+    #[test]
+    fn check_new_registry_needs_no_code() {
+        let cat = RegistryCatalogue::from_toml(r#"
+[[registries]]
+sid_type_id = 140
+name = "newctr"
+id_prefixes = ["NEWCTR", "NCTR"]
+json_split_by_year = true
+partition_span = 1
+date_order = "month_first"
+
+[registries.gender_values]
+"Hommes et Femmes" = "all"
+"#, "test").unwrap();
+        let r = cat.find_by_trial_id("nctr-2025-001").unwrap();
+        assert_eq!(r.name, "newctr");
+        assert!(r.write_json);
+        assert_eq!(r.date_order, DateOrder::MonthFirst);
+        assert_eq!(r.partition_for_year(2025).name, "newctr_2025");
+        assert_eq!(r.get_gender_value("hommes et femmes"), Some(GenderEligibility::All));
+        assert_eq!(r.get_gender_value("Femmes"), None);
+        assert!(cat.find_by_trial_id("NCT01234567").is_none());
+    }
+
+    #[test]
+    fn check_invalid_catalogues_rejected() {
+        let duplicate = r#"
+[[registries]]
+sid_type_id = 140
+name = "a"
+id_prefixes = ["A"]
+
+[[registries]]
+sid_type_id = 140
+name = "b"
+id_prefixes = ["B"]
+"#;
+        assert!(RegistryCatalogue::from_toml(duplicate, "test").is_err());
+
+        let no_span = r#"
+[[registries]]
+sid_type_id = 140
+name = "a"
+id_prefixes = ["A"]
+partition_span = 0
+"#;
+        assert!(RegistryCatalogue::from_toml(no_span, "test").is_err());
+        assert!(RegistryCatalogue::from_toml("[[registries]]\nsid_type_id = 140\n", "test").is_err());
+    }
+}
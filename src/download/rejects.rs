@@ -0,0 +1,289 @@
+/**********************************************************************************
+Rows that cannot be used - because they cannot be parsed into a WHOLine, or
+because their trial id is missing or cannot be matched to a registry - are
+quarantined rather than stopping the import. Each is written, with its line
+number, byte offset, raw text and the reason it was rejected, to a rejects
+file (one per source file, in the 'rejects' sub-folder of the log folder), and
+is also recorded in der.who_file_rejects. Processing of the file continues
+unless the number of rejected rows exceeds the maximum given in the config
+file (max_rejects).
+***********************************************************************************/
+
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use csv::{ByteRecord, Terminator, Writer, WriterBuilder};
+use log::warn;
+use crate::err::AppError;
+
+
+pub enum RejectReason {
+    ParseError(String),
+    EmptyId,
+    UnknownSource(String),
+}
+
+impl RejectReason {
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::ParseError(_) => "PARSE_ERROR",
+            RejectReason::EmptyId => "EMPTY_ID",
+            RejectReason::UnknownSource(_) => "UNKNOWN_ID",
+        }
+    }
+
+    pub fn details(&self) -> String {
+        match self {
+            RejectReason::ParseError(e) => e.clone(),
+            RejectReason::EmptyId => "No study id found".to_string(),
+            RejectReason::UnknownSource(sid) => format!("Unable to match the source of study id {}", sid),
+        }
+    }
+}
+
+
+pub struct RejectedRow {
+    pub line_number: i64,
+    pub byte_offset: i64,
+    pub reason_code: String,
+    pub error_text: String,
+    pub raw_text: String,
+}
+
+
+pub struct RejectPars {
+    pub rejects_folder: PathBuf,
+    pub max_rejects: i32,
+}
+
+
+pub struct RejectsLog {
+    rejects_file_path: PathBuf,
+    source_path: PathBuf,
+    max_rejects: i32,
+    writer: Option<Writer<File>>,
+    unstored: Vec<RejectedRow>,
+    appending: bool,
+    pub num_rejected: i32,
+}
+
+impl RejectsLog {
+
+    pub fn new(source_path: &Path, pars: &RejectPars) -> Self {
+        let source_name = source_path.file_stem().and_then(|s| s.to_str()).unwrap_or("unknown");
+        RejectsLog {
+            rejects_file_path: pars.rejects_folder.join(format!("{} rejects.csv", source_name)),
+            source_path: source_path.to_owned(),
+            max_rejects: pars.max_rejects,
+            writer: None,
+            unstored: Vec::new(),
+            appending: false,
+            num_rejected: 0,
+        }
+    }
+
+    // Used when an interrupted import is resumed - rows are added to the
+    // existing rejects file, and the rows it already holds count towards
+    // the maximum allowed. Rows from the chunk that was being processed when
+    // the import stopped (from line_offset on) are first removed from the
+    // file, as that chunk is processed again.
+
+    pub fn continue_from(&mut self, num_rejected: i32, line_offset: i64) -> Result<(), AppError> {
+        self.appending = true;
+        self.num_rejected = num_rejected;
+        if self.rejects_file_path.exists() {
+            self.truncate_from(line_offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, record: &ByteRecord, reason: RejectReason) -> Result<(), AppError> {
+
+        let (line_number, byte_offset) = match record.position() {
+            Some(p) => (p.line() as i64, p.byte() as i64),
+            None => (0, 0),
+        };
+        let row = RejectedRow {
+            line_number,
+            byte_offset,
+            reason_code: reason.code().to_string(),
+            error_text: reason.details(),
+            raw_text: get_raw_text(record),
+        };
+        warn!("Row at line {} rejected ({}): {}", row.line_number, row.reason_code, row.error_text);
+
+        self.write_row(&row)?;
+        self.unstored.push(row);
+        self.num_rejected += 1;
+
+        if self.num_rejected > self.max_rejects {
+            return Err(AppError::RejectThresholdExceeded(self.num_rejected, self.source_path.clone()));
+        }
+        Ok(())
+    }
+
+    fn truncate_from(&mut self, line_offset: i64) -> Result<(), AppError> {
+
+        let path = &self.rejects_file_path;
+        let mut rdr = csv::Reader::from_path(path)
+            .map_err(|e| AppError::CsvError(e, "rejects file".to_string()))?;
+        let header = rdr.byte_headers()
+            .map_err(|e| AppError::CsvError(e, "rejects file header".to_string()))?.clone();
+        let mut num_rows = 0;
+        let mut kept = Vec::new();
+        for r in rdr.byte_records() {
+            let r = r.map_err(|e| AppError::CsvError(e, "rejects file".to_string()))?;
+            num_rows += 1;
+            let line_number = std::str::from_utf8(&r[0]).ok().and_then(|n| n.parse::<i64>().ok());
+            if line_number.is_some_and(|n| n < line_offset) {
+                kept.push(r);
+            }
+        }
+        if kept.len() == num_rows {
+            return Ok(());
+        }
+
+        let file = File::create(path)
+            .map_err(|e| AppError::IoWriteErrorWithPath(e, path.clone()))?;
+        let mut w = Writer::from_writer(file);
+        for r in std::iter::once(&header).chain(kept.iter()) {
+            w.write_byte_record(r)
+                .map_err(|e| AppError::CsvError(e, "rejects file".to_string()))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn take_unstored(&mut self) -> Vec<RejectedRow> {
+        std::mem::take(&mut self.unstored)
+    }
+
+    fn write_row(&mut self, row: &RejectedRow) -> Result<(), AppError> {
+
+        // The rejects file is only created if a row is rejected, and replaces any
+        // rejects file left by an earlier run on the same file (unless continuing it).
+
+        if self.writer.is_none() {
+            if let Some(folder) = self.rejects_file_path.parent() {
+                fs::create_dir_all(folder)?;
+            }
+            if self.appending && self.rejects_file_path.exists() {
+                let file = OpenOptions::new().append(true).open(&self.rejects_file_path)
+                    .map_err(|e| AppError::IoWriteErrorWithPath(e, self.rejects_file_path.clone()))?;
+                self.writer = Some(Writer::from_writer(file));
+            }
+            else {
+                let file = File::create(&self.rejects_file_path)
+                    .map_err(|e| AppError::IoWriteErrorWithPath(e, self.rejects_file_path.clone()))?;
+                let mut w = Writer::from_writer(file);
+                w.write_record(["line_number", "byte_offset", "reason_code", "error", "raw_text"])
+                    .map_err(|e| AppError::CsvError(e, "rejects file header".to_string()))?;
+                self.writer = Some(w);
+            }
+        }
+
+        if let Some(w) = self.writer.as_mut() {
+            w.write_record([&row.line_number.to_string(), &row.byte_offset.to_string(),
+                            &row.reason_code, &row.error_text, &row.raw_text])
+                .map_err(|e| AppError::CsvError(e, row.line_number.to_string()))?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+
+pub fn get_raw_text(record: &ByteRecord) -> String {
+
+    // Reconstructs the row as it would appear in the csv file.
+
+    let mut w = WriterBuilder::new()
+        .flexible(true)
+        .terminator(Terminator::Any(b'\n'))
+        .from_writer(vec![]);
+    let _ = w.write_byte_record(record);
+    let bytes = w.into_inner().unwrap_or_default();
+    String::from_utf8_lossy(&bytes).trim_end_matches('\n').to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::ReaderBuilder;
+
+    fn read_first_record(data: &str) -> ByteRecord {
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(data.as_bytes());
+        let mut record = ByteRecord::new();
+        rdr.read_byte_record(&mut record).unwrap();
+        record
+    }
+
+    #[test]
+    fn check_reason_codes() {
+        assert_eq!(RejectReason::ParseError("bad".to_string()).code(), "PARSE_ERROR");
+        assert_eq!(RejectReason::EmptyId.code(), "EMPTY_ID");
+        assert_eq!(RejectReason::UnknownSource("XYZ123".to_string()).code(), "UNKNOWN_ID");
+        assert_eq!(RejectReason::UnknownSource("XYZ123".to_string()).details(),
+                    "Unable to match the source of study id XYZ123");
+    }
+
+    #[test]
+    fn check_raw_text_reconstructed() {
+        let record = read_first_record("XYZ123,\"a, b\",\"line one\nline two\"\n");
+        assert_eq!(get_raw_text(&record), "XYZ123,\"a, b\",\"line one\nline two\"");
+    }
+
+    #[test]
+    fn check_rejects_written_and_threshold_applied() {
+        let folder = std::env::temp_dir().join("dl_who_rejects_test");
+        let _ = fs::remove_dir_all(&folder);
+        let pars = RejectPars { rejects_folder: folder.clone(), max_rejects: 1 };
+        let mut rejects = RejectsLog::new(Path::new("/data/20250224 ICTRP.csv"), &pars);
+
+        let record = read_first_record("XYZ123,a\n");
+        assert!(rejects.add(&record, RejectReason::UnknownSource("XYZ123".to_string())).is_ok());
+        assert!(rejects.add(&record, RejectReason::EmptyId).is_err());
+        assert_eq!(rejects.num_rejected, 2);
+
+        let unstored = rejects.take_unstored();
+        assert_eq!(unstored.len(), 2);
+        assert_eq!(unstored[0].line_number, 1);
+        assert_eq!(unstored[1].reason_code, "EMPTY_ID");
+        assert_eq!(rejects.take_unstored().len(), 0);
+
+        let contents = fs::read_to_string(folder.join("20250224 ICTRP rejects.csv")).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+        assert!(contents.lines().nth(1).unwrap().starts_with("1,0,UNKNOWN_ID,"));
+    }
+
+    #[test]
+    fn check_rejects_continued_after_resuming() {
+        let folder = std::env::temp_dir().join("dl_who_rejects_resume_test");
+        let _ = fs::remove_dir_all(&folder);
+        let pars = RejectPars { rejects_folder: folder.clone(), max_rejects: 3 };
+        let mut rdr = ReaderBuilder::new().has_headers(false).flexible(true)
+            .from_reader("XYZ1,a\nNCT1,b\nXYZ3,\"c\nc\"\nNCT4,d\nXYZ5,e\n".as_bytes());
+        let records: Vec<ByteRecord> = rdr.byte_records().map(|r| r.unwrap()).collect();
+        let reject = |rejects: &mut RejectsLog, i: usize| rejects.add(&records[i], RejectReason::EmptyId);
+
+        // The first chunk (lines 1 to 4) is committed with two rejects, and the
+        // second (from line 5) fails after its reject, so the import is resumed
+        // from the checkpoint after the first chunk and the second processed again.
+
+        let mut rejects = RejectsLog::new(Path::new("/data/ICTRPFullExport.csv"), &pars);
+        reject(&mut rejects, 0).unwrap();
+        reject(&mut rejects, 2).unwrap();
+        reject(&mut rejects, 4).unwrap();
+
+        let mut resumed = RejectsLog::new(Path::new("/data/ICTRPFullExport.csv"), &pars);
+        resumed.continue_from(2, 5).unwrap();
+        assert!(reject(&mut resumed, 4).is_ok());
+        assert_eq!(resumed.num_rejected, 3);
+
+        let mut rdr = csv::Reader::from_path(folder.join("ICTRPFullExport rejects.csv")).unwrap();
+        let lines: Vec<String> = rdr.records().map(|r| r.unwrap()[0].to_string()).collect();
+        assert_eq!(lines, vec!["1", "3", "6"]);
+        assert_eq!(rdr.headers().unwrap().get(0), Some("line_number"));
+    }
+}
@@ -0,0 +1,250 @@
+/**********************************************************************************
+WHO weekly files are shipped as zip archives with names such as
+'ICTRPWeek24February2025.zip', each containing a single csv file. Historically
+these were unzipped and renamed by hand to '20250224 ICTRP.csv' so that they
+could be ordered by name. The functions here allow either form of name (and
+either a zip or a csv file) to be used directly, by deriving the file's date
+from its name, and by streaming the csv data out of a zip without extracting it.
+***********************************************************************************/
+
+use std::sync::LazyLock;
+use regex::Regex;
+use chrono::NaiveDate;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use zip::ZipArchive;
+use sha2::{Digest, Sha256};
+use crate::err::AppError;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub file_date: NaiveDate,
+    pub file_hash: String,
+}
+
+
+pub fn parse_file_date(file_name: &str) -> Option<NaiveDate> {
+
+    // Renamed files, e.g. '20250224 ICTRP.csv'.
+
+    static RE_ISO: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?<date>\d{8}) ICTRP").unwrap());
+    if let Some(c) = RE_ISO.captures(file_name) {
+        return NaiveDate::parse_from_str(&c["date"], "%Y%m%d").ok();
+    }
+
+    // Native WHO names, e.g. 'ICTRPWeek24February2025.zip' (or .csv, if unzipped).
+
+    static RE_WHO: LazyLock<Regex> = LazyLock::new(||
+                Regex::new(r"(?i)^ICTRPWeek(?<day>\d{1,2})(?<month>[a-z]+)(?<year>\d{4})").unwrap());
+    if let Some(c) = RE_WHO.captures(file_name) {
+        let day: u32 = c["day"].parse().ok()?;
+        let year: i32 = c["year"].parse().ok()?;
+        let month = get_month_number(&c["month"])?;
+        return NaiveDate::from_ymd_opt(year, month, day);
+    }
+
+    None
+}
+
+
+fn get_month_number(month: &str) -> Option<u32> {
+
+    // Full month names are expected, but three letter abbreviations are also accepted.
+
+    const MONTHS: [&str; 12] = ["january", "february", "march", "april", "may", "june", "july",
+                                "august", "september", "october", "november", "december"];
+    let m = month.to_lowercase();
+    MONTHS.iter()
+        .position(|name| *name == m || (m.len() == 3 && name.starts_with(&m)))
+        .map(|i| i as u32 + 1)
+}
+
+
+pub fn is_zip_file(file_path: &Path) -> bool {
+    file_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+
+pub fn is_source_file(file_path: &Path) -> bool {
+    file_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv") || ext.eq_ignore_ascii_case("zip"))
+}
+
+
+pub fn get_file_hash(file_path: &Path) -> Result<String, AppError> {
+
+    // The SHA-256 hash of the file's contents (as stored, i.e. of the zip
+    // archive rather than the csv within it), as a lower case hex string.
+
+    let mut file = File::open(file_path)
+                .map_err(|e| AppError::IoReadErrorWithPath(e, file_path.to_owned()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+                .map_err(|e| AppError::IoReadErrorWithPath(e, file_path.to_owned()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+
+pub fn open_source_reader(file_path: &Path) -> Result<Box<dyn Read + Send>, AppError> {
+
+    // Returns a reader over the csv data, whether this is a plain csv
+    // file or the (first) csv entry within a zip archive. In the latter case
+    // the data is decompressed as it is read - nothing is written to disk.
+
+    let file = File::open(file_path)
+                .map_err(|e| AppError::IoReadErrorWithPath(e, file_path.to_owned()))?;
+
+    if !is_zip_file(file_path) {
+        return Ok(Box::new(BufReader::new(file)));
+    }
+
+    let mut archive = ZipArchive::new(BufReader::new(file))
+                .map_err(|e| AppError::ZipError(e, file_path.to_owned()))?;
+    let entry_index = (0..archive.len())
+                .find(|i| archive.name_for_index(*i)
+                    .is_some_and(|n| n.to_lowercase().ends_with(".csv")))
+                .ok_or_else(|| AppError::FileSystemError(
+                    "Unable to find a csv file within the zip archive".to_string(),
+                    format!("Path provided was {:?}", file_path)))?;
+
+    // Check the entry can be opened before handing it over to the
+    // decompressing thread, so that errors are reported against the file.
+
+    archive.by_index(entry_index)
+                .map_err(|e| AppError::ZipError(e, file_path.to_owned()))?;
+
+    Ok(Box::new(ZipEntryReader::new(archive, entry_index)))
+}
+
+
+// A zip entry borrows its parent archive, so it cannot be returned directly
+// from the function above. Instead a separate thread owns the archive,
+// decompresses the entry and passes the data back in blocks over a bounded
+// channel, so that memory use stays flat however large the entry is.
+
+const ZIP_BLOCK_SIZE: usize = 256 * 1024;
+
+struct ZipEntryReader {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    offset: usize,
+}
+
+impl ZipEntryReader {
+    fn new<R: Read + Seek + Send + 'static>(mut archive: ZipArchive<R>, entry_index: usize) -> Self {
+
+        let (sender, receiver) = sync_channel::<std::io::Result<Vec<u8>>>(4);
+        thread::spawn(move || {
+            let mut entry = match archive.by_index(entry_index) {
+                Ok(e) => e,
+                Err(e) => {
+                    let _ = sender.send(Err(e.into()));
+                    return;
+                }
+            };
+            loop {
+                let mut block = vec![0; ZIP_BLOCK_SIZE];
+                match entry.read(&mut block) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        block.truncate(n);
+                        if sender.send(Ok(block)).is_err() {
+                            break;   // reader has been dropped
+                        }
+                    },
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        break;
+                    },
+                }
+            }
+        });
+
+        ZipEntryReader { receiver, block: Vec::new(), offset: 0 }
+    }
+}
+
+impl Read for ZipEntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.offset >= self.block.len() {
+            match self.receiver.recv() {
+                Ok(Ok(block)) => {
+                    self.block = block;
+                    self.offset = 0;
+                },
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(0),   // sender finished - end of entry
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.offset);
+        buf[..n].copy_from_slice(&self.block[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    #[test]
+    fn check_dates_from_native_who_names() {
+        assert_eq!(parse_file_date("ICTRPWeek24February2025.zip"), NaiveDate::from_ymd_opt(2025, 2, 24));
+        assert_eq!(parse_file_date("ICTRPWeek3March2025.csv"), NaiveDate::from_ymd_opt(2025, 3, 3));
+        assert_eq!(parse_file_date("ICTRPWeek06Jan2025.zip"), NaiveDate::from_ymd_opt(2025, 1, 6));
+    }
+
+    #[test]
+    fn check_dates_from_renamed_files() {
+        assert_eq!(parse_file_date("20250224 ICTRP.csv"), NaiveDate::from_ymd_opt(2025, 2, 24));
+        assert_eq!(parse_file_date("20250106 ICTRP.zip"), NaiveDate::from_ymd_opt(2025, 1, 6));
+    }
+
+    #[test]
+    fn check_unrecognised_names_give_no_date() {
+        assert_eq!(parse_file_date("ICTRPFullExport 001.csv"), None);
+        assert_eq!(parse_file_date("ICTRPWeek31February2025.zip"), None);
+        assert_eq!(parse_file_date("ICTRPWeek24Febtember2025.zip"), None);
+        assert_eq!(parse_file_date("notes.csv"), None);
+    }
+
+    #[test]
+    fn check_csv_streamed_from_zip() {
+        let folder = std::env::temp_dir().join("dl_who_zip_test");
+        fs_setup(&folder);
+        let zip_path = folder.join("ICTRPWeek24February2025.zip");
+
+        let content = "\"NCT00000001\",\"a\"\n\"NCT00000002\",\"b\"\n".repeat(50000);
+        let mut zw = ZipWriter::new(File::create(&zip_path).unwrap());
+        zw.start_file("ICTRPWeek24February2025.csv", SimpleFileOptions::default()).unwrap();
+        zw.write_all(content.as_bytes()).unwrap();
+        zw.finish().unwrap();
+
+        let mut output = String::new();
+        open_source_reader(&zip_path).unwrap().read_to_string(&mut output).unwrap();
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn check_file_hash() {
+        let folder = std::env::temp_dir().join("dl_who_hash_test");
+        fs_setup(&folder);
+        let file_path = folder.join("20250224 ICTRP.csv");
+        std::fs::write(&file_path, "abc").unwrap();
+        assert_eq!(get_file_hash(&file_path).unwrap(),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    fn fs_setup(folder: &Path) {
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder).unwrap();
+    }
+}
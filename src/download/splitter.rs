@@ -0,0 +1,115 @@
+/**********************************************************************************
+Splits a WHO full export file (a single csv file of over 5 GB, or the zip 
+archive containing it) into a series of numbered smaller csv files, each of 
+FULL_EXPORT_CHUNK_SIZE records, written to the same folder as the full export.
+This replaces the PowerShell script previously used for the same purpose. It is 
+not needed to process the full export (DL type 502 streams through the file in 
+chunks directly) but the split files are easier to inspect, or to re-process 
+individually as single files.
+***********************************************************************************/
+
+use std::path::{Path, PathBuf};
+use csv::{ByteRecord, ReaderBuilder, WriterBuilder, Writer};
+use std::fs::File;
+use std::io::BufWriter;
+use log::info;
+use crate::err::AppError;
+use super::source_file::open_source_reader;
+
+
+pub fn get_split_file_name(file_stem: &str, file_num: usize) -> String {
+    format!("{}{:0>3}.csv", file_stem, file_num)
+}
+
+
+// The name a chunk of a full export is recorded under when the export is imported
+// directly - that of the split file, with the id of the import, as the same names
+// are generated for every full export and the bookkeeping rows of a file are
+// replaced when a file with the same name is imported again.
+
+pub fn get_chunk_file_name(file_stem: &str, dl_id: i32, chunk_num: usize) -> String {
+    format!("{}{:0>3} dl{}.csv", file_stem, chunk_num, dl_id)
+}
+
+
+pub fn split_full_file(file_path: &Path, file_stem: &str, records_per_file: usize) -> Result<Vec<PathBuf>, AppError> {
+
+    let source_reader = open_source_reader(file_path)?;
+    let mut csv_rdr = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(source_reader);
+    let dest_folder = file_path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+    let mut split_files = Vec::<PathBuf>::new();
+    let mut writer: Option<Writer<BufWriter<File>>> = None;
+    let mut record = ByteRecord::new();
+    let mut record_count = 0;
+
+    // Records are written back out exactly as parsed, so that 
+    // processing the split files gives the same results as processing
+    // the full file (the split is on records rather than lines, so
+    // line breaks embedded within fields cannot cause problems).
+
+    while csv_rdr.read_byte_record(&mut record)
+                .map_err(|e| AppError::CsvError(e, (record_count + 1).to_string()))? {
+
+        if record_count % records_per_file == 0 {
+            if let Some(mut w) = writer.take() {
+                w.flush()?;
+            }
+            let split_path = dest_folder.join(get_split_file_name(file_stem, split_files.len() + 1));
+            info!("Creating file {:?}", split_path);
+            let file = File::create(&split_path)
+                .map_err(|e| AppError::IoWriteErrorWithPath(e, split_path.clone()))?;
+            writer = Some(WriterBuilder::new().flexible(true).from_writer(BufWriter::new(file)));
+            split_files.push(split_path);
+        }
+
+        if let Some(w) = writer.as_mut() {
+            w.write_byte_record(&record)
+                .map_err(|e| AppError::CsvError(e, (record_count + 1).to_string()))?;
+        }
+        record_count += 1;
+    }
+
+    if let Some(mut w) = writer.take() {
+        w.flush()?;
+    }
+
+    info!("{} records split into {} files", record_count, split_files.len());
+    Ok(split_files)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn check_split_file_names() {
+        assert_eq!(get_split_file_name("ICTRPFullExport ", 1), "ICTRPFullExport 001.csv");
+        assert_eq!(get_split_file_name("ICTRPFullExport", 22), "ICTRPFullExport022.csv");
+        assert_eq!(get_chunk_file_name("ICTRPFullExport ", 12, 1), "ICTRPFullExport 001 dl12.csv");
+        assert_ne!(get_chunk_file_name("ICTRPFullExport ", 13, 1), get_chunk_file_name("ICTRPFullExport ", 12, 1));
+    }
+
+    #[test]
+    fn check_records_are_split_without_loss() {
+        let folder = std::env::temp_dir().join("dl_who_split_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let full_path = folder.join("ICTRP-full.csv");
+        let content = "\"NCT00000001\",\"line one\nline two\"\n\"NCT00000002\",\"b\"\n\"NCT00000003\",\"c\"\n";
+        fs::write(&full_path, content).unwrap();
+
+        let files = split_full_file(&full_path, "Part ", 2).unwrap();
+
+        assert_eq!(files, vec![folder.join("Part 001.csv"), folder.join("Part 002.csv")]);
+        let part1 = fs::read_to_string(&files[0]).unwrap();
+        let part2 = fs::read_to_string(&files[1]).unwrap();
+        assert_eq!(part1, "NCT00000001,\"line one\nline two\"\nNCT00000002,b\n");
+        assert_eq!(part2, "NCT00000003,c\n");
+    }
+}
@@ -0,0 +1,392 @@
+/**********************************************************************************
+WHO summary records are not written to the database one at a time. Instead they
+are buffered, separately for each of the dat tables they are destined for, and
+each table's buffer is written in a single set based statement when it reaches
+SUMMARY_BATCH_SIZE records, or when the buffer is finished at the end of a file.
+If the same study appears more than once before its batch is written only the
+later record is kept (as it would have overwritten the earlier one anyway) but
+it is still counted as an update, so that the added and updated counts are the
+same as if each record had been stored individually (or as unchanged, if it has
+the same content). Before a batch is written the partitions it needs (the dat 
+tables being partitioned by reg_year) are created if necessary - once for each 
+partition per buffer. The content hash of each study in the batch is then compared
+with that already stored, and only new or changed studies have their dat rows
+and json files written. The json file of an unchanged study is only rewritten if
+it is missing, but the study is still recorded as seen in the download (so that
+a full export can identify the studies it no longer includes - see missing.rs). 
+Every study in the batch is also listed against the download in dat.study_downloads,
+so that the studies of an import can be compared with another source (see diff.rs).
+A version of each new or changed study is also recorded (see versions.rs), and, 
+if json snapshots are required, a dated copy of its json file. The json files are
+queued to a pool of writer threads (see json_writer.rs), and finishing the buffer
+waits until they have all been staged, returning them to be promoted once the
+transaction has been committed.
+***********************************************************************************/
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use sqlx::PgConnection;
+use crate::err::AppError;
+use super::file_models::{hash_content, WHORecord, WHOSummary};
+use super::data_access::{store_who_summaries, create_partition, get_content_hashes, mark_studies_seen, store_study_downloads};
+use super::json_writer::{JsonFile, JsonWriter, StagedJsonFiles, JSON_WRITER_COUNT};
+use super::registries::catalogue;
+use super::versions::{record_versions, FieldMap};
+
+pub const SUMMARY_BATCH_SIZE: usize = 1000;
+
+
+#[derive(Clone)]
+pub struct JsonPars {
+    pub json_path: PathBuf,
+    pub snapshots: bool,     // keep a dated copy of each version's json file
+}
+
+
+// Where a study's json file, and any snapshot of it, are to be written.
+
+pub struct JsonPaths {
+    pub path: PathBuf,
+    pub snapshot_path: Option<PathBuf>,
+}
+
+
+pub struct StoredSummary {
+    pub rec: WHOSummary,
+    pub local_path: Option<String>,
+    pub content_hash: String,
+    pub fields: FieldMap,                // the content fields of the full record
+    pub record_date: Option<String>,
+    json: Option<JsonFile>,
+}
+
+
+// The content hash of a study already stored, and whether it has been
+// flagged as missing from a full export (see missing.rs).
+
+pub struct StoredHash {
+    pub content_hash: Option<String>,
+    pub not_in_full_export: bool,
+}
+
+
+#[derive(Debug, Default, PartialEq)]
+pub struct StoreCounts {
+    pub added: i32,
+    pub updated: i32,
+    pub unchanged: i32,
+}
+
+impl StoreCounts {
+    pub fn add(&mut self, other: StoreCounts) {
+        self.added += other.added;
+        self.updated += other.updated;
+        self.unchanged += other.unchanged;
+    }
+}
+
+
+#[derive(Default)]
+struct PendingTable {
+    recs: Vec<StoredSummary>,
+    index: HashMap<String, usize>,   // sd_sid -> position in recs
+}
+
+
+pub struct SummaryBuffer {
+    batch_size: usize,
+    source_file: String,             // recorded with each study version
+    tables: HashMap<String, PendingTable>,
+    partitions: HashSet<String>,     // partitions known to exist
+    json_writer: JsonWriter,
+}
+
+impl SummaryBuffer {
+
+    pub fn new(batch_size: usize, source_file: &str) -> Self {
+        SummaryBuffer {
+            batch_size,
+            source_file: source_file.to_string(),
+            tables: HashMap::new(),
+            partitions: HashSet::new(),
+            json_writer: JsonWriter::new(JSON_WRITER_COUNT),
+        }
+    }
+
+    // Adds the record to the buffer for its table, writing that table's buffer to the
+    // database if it is now full. Returns the counts from any records written.
+
+    pub async fn add(&mut self, rec_summ: WHOSummary, rec: WHORecord, json: Option<JsonPaths>, 
+                        conn: &mut PgConnection) -> Result<StoreCounts, AppError> {
+
+        let mut counts = StoreCounts::default();
+        let table_name = rec_summ.table_name.clone();
+        match self.push(rec_summ, rec, json) {   // replaced a record already in the buffer?
+            Some(true) => counts.unchanged += 1,
+            Some(false) => counts.updated += 1,
+            None => {},
+        }
+        if self.tables.get(&table_name).is_some_and(|t| t.recs.len() >= self.batch_size) {
+            counts.add(self.flush_table(&table_name, conn).await?);
+        }
+        Ok(counts)
+    }
+
+    // Writes all the records still buffered, and waits for their json files to be
+    // staged. The staged files are returned, to be promoted after the commit.
+
+    pub async fn finish(mut self, conn: &mut PgConnection) -> Result<(StoreCounts, StagedJsonFiles), AppError> {
+
+        let mut counts = StoreCounts::default();
+        let table_names: Vec<String> = self.tables.keys().cloned().collect();
+        for table_name in table_names {
+            counts.add(self.flush_table(&table_name, conn).await?);
+        }
+        let json_files = self.json_writer.finish()?;
+        Ok((counts, json_files))
+    }
+
+    async fn flush_table(&mut self, table_name: &str, conn: &mut PgConnection) -> Result<StoreCounts, AppError> {
+        match self.tables.remove(table_name) {
+            Some(t) if !t.recs.is_empty() => {
+                self.ensure_partitions(&t.recs, conn).await?;
+                let sd_sids: Vec<String> = t.recs.iter().map(|r| r.rec.sd_sid.clone()).collect();
+                store_study_downloads(&sd_sids, t.recs[0].rec.dl_id, conn).await?;
+                let stored_hashes = get_content_hashes(table_name, &sd_sids, conn).await?;
+                let (mut changed, mut unchanged) = split_by_content(t.recs, &stored_hashes);
+
+                for s in changed.iter_mut() {
+                    if let Some(j) = s.json.take() {
+                        self.json_writer.write(j, false)?;
+                    }
+                }
+                for s in unchanged.iter_mut() {
+                    if let Some(j) = s.json.take() {
+                        self.json_writer.write(j, true)?;
+                    }
+                }
+                if let Some(s) = unchanged.first() {
+                    let sd_sids: Vec<String> = unchanged.iter().map(|r| r.rec.sd_sid.clone()).collect();
+                    mark_studies_seen(table_name, &sd_sids, s.rec.dl_id, conn).await?;
+                }
+                let mut counts = StoreCounts::default();
+                if !changed.is_empty() {
+                    record_versions(&changed, &self.source_file, conn).await?;
+                    counts = store_who_summaries(table_name, changed, conn).await?;
+                }
+                counts.unchanged += unchanged.len() as i32;
+                Ok(counts)
+            },
+            _ => Ok(StoreCounts::default()),
+        }
+    }
+
+    async fn ensure_partitions(&mut self, recs: &[StoredSummary], conn: &mut PgConnection) -> Result<(), AppError> {
+        for r in recs {
+            if let Some(registry) = catalogue().get(r.rec.sid_type_id) {
+                let partition = registry.partition_for_year(r.rec.reg_year);
+                if !self.partitions.contains(&partition.name) {
+                    create_partition(&registry.name, &partition, conn).await?;
+                    self.partitions.insert(partition.name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // If the record replaced one for the same study already in the buffer, returns
+    // whether the content of the record replaced was the same (i.e. had the same hash).
+
+    fn push(&mut self, rec_summ: WHOSummary, rec: WHORecord, json: Option<JsonPaths>) -> Option<bool> {
+
+        let fields = rec.content_fields();
+        let content_hash = hash_content(&fields);
+        let record_date = rec.record_date.clone();
+        let local_path = json.as_ref()
+            .map(|j| j.path.to_str().unwrap().replace("\\\\", "/").replace("\\", "/"));   // to support Windows
+        let json = json.map(|paths| JsonFile { paths, rec });
+        let summary = StoredSummary { rec: rec_summ, local_path, content_hash, fields, record_date, json };
+
+        let table = self.tables.entry(summary.rec.table_name.clone()).or_default();
+        match table.index.get(&summary.rec.sd_sid) {
+            Some(&i) => {
+                let replaced = std::mem::replace(&mut table.recs[i], summary);
+                Some(replaced.content_hash == table.recs[i].content_hash)
+            },
+            None => {
+                table.index.insert(summary.rec.sd_sid.clone(), table.recs.len());
+                table.recs.push(summary);
+                None
+            },
+        }
+    }
+
+    #[cfg(test)]
+    fn pending(&self, table_name: &str) -> usize {
+        self.tables.get(table_name).map_or(0, |t| t.recs.len())
+    }
+}
+
+
+// Splits the records into those that are new or have changed, and those with 
+// the same content hash as the stored version of the study. A study flagged as
+// not in a full export is treated as changed, even if its content is the same,
+// so that it is stored again and its json file (which may have been archived)
+// is rewritten in the usual place.
+
+fn split_by_content(recs: Vec<StoredSummary>, stored_hashes: &HashMap<String, StoredHash>) 
+                    -> (Vec<StoredSummary>, Vec<StoredSummary>) {
+    recs.into_iter().partition(|r| {
+        match stored_hashes.get(&r.rec.sd_sid) {
+            Some(StoredHash { content_hash: Some(h), not_in_full_export: false }) => *h != r.content_hash,
+            _ => true,   // new, stored before content hashes were, or missing from a full export
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support::make_record;
+
+    fn summary(sd_sid: &str, table_name: &str, title: &str) -> WHOSummary {
+        WHOSummary {
+            sid_type_id: 100, sd_sid: sd_sid.to_string(), title: Some(title.to_string()),
+            study_type: None, study_type_id: 0, study_status: None, study_status_id: 0,
+            sponsor_name: None, sponsor_processed: None,
+            sec_ids: None, reg_sec_ids: None, oth_sec_ids: None,
+            reg_year: 2024, enrol_year: 2024, results_yes_no: None, country_list: None,
+            country_codes: None, countries_unresolved: None,
+            age_eligibility: None, enrolment: None,
+            table_name: table_name.to_string(), date_last_rev_in_who: None, remote_url: None, dl_id: 1,
+        }
+    }
+
+    fn content_hash(rec: &WHORecord) -> String {
+        hash_content(&rec.content_fields())
+    }
+
+    #[test]
+    fn check_records_buffered_by_table() {
+        let mut buffer = SummaryBuffer::new(SUMMARY_BATCH_SIZE, "test.csv");
+        let json = JsonPaths { path: PathBuf::from("/json/chictr/x.json"), snapshot_path: None };
+        assert!(buffer.push(summary("ACTRN12624000001", "anzctr", "a"), make_record(&[(0, "ACTRN12624000001")]), None).is_none());
+        assert!(buffer.push(summary("ChiCTR2400000001", "chictr", "b"), make_record(&[(0, "ChiCTR2400000001")]), Some(json)).is_none());
+        assert!(buffer.push(summary("ACTRN12624000002", "anzctr", "c"), make_record(&[(0, "ACTRN12624000002")]), None).is_none());
+        assert_eq!(buffer.pending("anzctr"), 2);
+        assert_eq!(buffer.pending("chictr"), 1);
+        assert_eq!(buffer.tables["chictr"].recs[0].local_path, Some("/json/chictr/x.json".to_string()));
+        assert_eq!(buffer.tables["anzctr"].recs[0].local_path, None);
+        assert_eq!(buffer.tables["anzctr"].recs[1].fields["sd_sid"], "ACTRN12624000002");
+    }
+
+    #[test]
+    fn check_later_duplicate_replaces_earlier() {
+        let mut buffer = SummaryBuffer::new(SUMMARY_BATCH_SIZE, "test.csv");
+        let rec = |title: &str| make_record(&[(0, "ACTRN12624000001"), (3, title)]);
+        assert!(buffer.push(summary("ACTRN12624000001", "anzctr", "first"), rec("first"), None).is_none());
+        assert_eq!(buffer.push(summary("ACTRN12624000001", "anzctr", "second"), rec("second"), None), Some(false));
+        assert_eq!(buffer.push(summary("ACTRN12624000001", "anzctr", "second"), rec("second"), None), Some(true));
+        assert_eq!(buffer.pending("anzctr"), 1);
+        assert_eq!(buffer.tables["anzctr"].recs[0].rec.title, Some("second".to_string()));
+        assert_eq!(buffer.tables["anzctr"].recs[0].content_hash, content_hash(&rec("second")));
+    }
+
+    #[test]
+    fn check_content_hash_ignores_volatile_fields() {
+        let base = [(0, "ACTRN12624000001"), (1, "2025-01-06"), (3, "A study"), (21, "2024-01-15")];
+        let hash = content_hash(&make_record(&base));
+        assert_eq!(hash.len(), 64);
+        assert_eq!(content_hash(&make_record(&base)), hash);
+
+        let mut refreshed = base;
+        refreshed[1] = (1, "2025-02-24");    // last_updated
+        assert_eq!(content_hash(&make_record(&refreshed)), hash);
+
+        let mut retitled = base;
+        retitled[2] = (3, "A revised study");
+        assert_ne!(content_hash(&make_record(&retitled)), hash);
+    }
+
+    #[test]
+    fn check_content_hash_ignores_parsed_fields() {
+        let mut rec = make_record(&[(0, "ACTRN12624000001"), (3, "A study"), (31, "18 Years"), (32, "65 Years"),
+                                   (33, "Both"), (23, "120"), (28, "Australia;Yugoslavia")]);
+        assert!(rec.age_eligibility.is_some() && rec.gender.is_some() && rec.enrolment.is_some());
+        assert!(rec.country_codes.is_some() && rec.countries_unresolved.is_some());
+        let hash = content_hash(&rec);
+        rec.age_eligibility = None;
+        rec.gender = None;
+        rec.enrolment = None;
+        rec.country_codes = None;
+        rec.countries_unresolved = None;
+        assert_eq!(content_hash(&rec), hash);
+    }
+
+    #[test]
+    fn check_content_hash_changes_with_gender_given() {
+        let rec = |gender: &str| make_record(&[(0, "ACTRN12624000001"), (3, "A study"), (33, gender)]);
+        assert_eq!(rec("Both").gender, rec("Both males and females").gender);
+        assert_ne!(content_hash(&rec("Both")), content_hash(&rec("Both males and females")));
+        assert_ne!(content_hash(&rec("Both")), content_hash(&rec("Female")));
+    }
+
+    #[test]
+    fn check_content_hash_changes_with_partial_dates() {
+        let rec = |enrolment: &str| make_record(&[(0, "ACTRN12624000001"), (3, "A study"), (22, enrolment)]);
+        assert_eq!(content_hash(&rec("2024")), content_hash(&rec("2024")));
+        assert_ne!(content_hash(&rec("2024")), content_hash(&rec("2025")));
+        assert_ne!(content_hash(&rec("2024")), content_hash(&rec("")));
+        assert_ne!(content_hash(&rec("03/2024")), content_hash(&rec("04/2024")));
+        assert_ne!(content_hash(&rec("March 2024")), content_hash(&rec("15 March 2024")));
+    }
+
+    #[test]
+    fn check_records_split_by_content() {
+        let stored_summary = |sd_sid: &str, hash: &str| StoredSummary {
+            rec: summary(sd_sid, "anzctr", "a"), local_path: None, content_hash: hash.to_string(), 
+            fields: FieldMap::new(), record_date: None, json: None,
+        };
+        let recs = vec![stored_summary("A1", "same"), stored_summary("A2", "new hash"), 
+                        stored_summary("A3", "h"), stored_summary("A4", "h")];
+        let stored = |hash: Option<&str>| StoredHash { content_hash: hash.map(String::from), not_in_full_export: false };
+        let stored_hashes = HashMap::from([
+            ("A1".to_string(), stored(Some("same"))),
+            ("A2".to_string(), stored(Some("old hash"))),
+            ("A3".to_string(), stored(None)),      // stored before hashes were
+        ]);
+        let (changed, unchanged) = split_by_content(recs, &stored_hashes);
+        let sids = |v: &Vec<StoredSummary>| v.iter().map(|r| r.rec.sd_sid.clone()).collect::<Vec<String>>();
+        assert_eq!(sids(&changed), vec!["A2", "A3", "A4"]);
+        assert_eq!(sids(&unchanged), vec!["A1"]);
+    }
+
+    #[test]
+    fn check_study_back_in_full_export_stored_again() {
+
+        // A study flagged as not in a full export (its json file perhaps archived) that
+        // reappears unchanged is stored again, so that its json file is rewritten.
+
+        let rec = |sd_sid: &str| make_record(&[(0, sd_sid), (3, "A study")]);
+        let mut buffer = SummaryBuffer::new(SUMMARY_BATCH_SIZE, "test.csv");
+        for sd_sid in ["ACTRN12624000001", "ACTRN12624000002"] {
+            let json = JsonPaths { path: PathBuf::from(format!("/json/anzctr/{}.json", sd_sid)), snapshot_path: None };
+            buffer.push(summary(sd_sid, "anzctr", "A study"), rec(sd_sid), Some(json));
+        }
+        let hash = content_hash(&rec("ACTRN12624000001"));
+        let stored_hashes = HashMap::from([
+            ("ACTRN12624000001".to_string(), StoredHash { content_hash: Some(hash.clone()), not_in_full_export: true }),
+            ("ACTRN12624000002".to_string(), StoredHash { content_hash: Some(content_hash(&rec("ACTRN12624000002"))), 
+                                                          not_in_full_export: false }),
+        ]);
+        let recs = buffer.tables.remove("anzctr").unwrap().recs;
+        let (changed, unchanged) = split_by_content(recs, &stored_hashes);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].rec.sd_sid, "ACTRN12624000001");
+        assert_eq!(changed[0].content_hash, hash);
+        assert_eq!(changed[0].local_path.as_deref(), Some("/json/anzctr/ACTRN12624000001.json"));
+        assert!(changed[0].json.is_some());
+        assert_eq!(unchanged[0].rec.sd_sid, "ACTRN12624000002");
+    }
+}
@@ -0,0 +1,36 @@
+/**********************************************************************************
+Helpers shared by the tests of the download modules. Rather than reading sample
+files, the tests construct csv rows holding just the values they need (and, where
+required, the WHO records that processing those rows produces).
+***********************************************************************************/
+
+use csv::{ByteRecord, ReaderBuilder};
+use super::file_models::{WHOLine, WHORecord};
+use super::processor;
+
+const NUM_FIELDS: usize = 63;
+
+
+// Constructs a csv row with the given values at the given field positions
+// (see the WHOLine struct) and empty strings elsewhere, without a line ending.
+
+pub fn make_row(values: &[(usize, &str)]) -> String {
+    let mut fields = vec![String::new(); NUM_FIELDS];
+    for (i, v) in values {
+        fields[*i] = format!("\"{}\"", v);
+    }
+    fields.join(",")
+}
+
+
+// Constructs the full WHO record produced by processing such a row.
+
+pub fn make_record(values: &[(usize, &str)]) -> WHORecord {
+    let data = make_row(values) + "\n";
+    let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(data.as_bytes());
+    let mut record = ByteRecord::new();
+    rdr.read_byte_record(&mut record).unwrap();
+    let line: WHOLine = record.deserialize(None).unwrap();
+    let summ = processor::summarise_line(&line, 1).ok().unwrap();
+    processor::process_line(line, &summ).unwrap()
+}
@@ -0,0 +1,84 @@
+/**********************************************************************************
+The values of a file that could not be classified or resolved - genders (see
+genders.rs) and countries (see countries.rs) - counted by field, registry and value.
+The report is logged once each file (or full export) has been processed, listing
+the most common values of each field with the number of records they were found in,
+so that the spellings concerned can be added to the registry catalogue or to the
+country dictionary.
+***********************************************************************************/
+
+use std::collections::BTreeMap;
+use log::{info, warn};
+use super::who_helper::get_db_name;
+
+const MAX_LOGGED: usize = 20;
+
+pub const GENDER: &str = "gender";
+pub const COUNTRY: &str = "country";
+
+
+#[derive(Debug, Default)]
+pub struct UnclassifiedValues {
+    values: BTreeMap<(&'static str, i32, String), i32>,
+}
+
+impl UnclassifiedValues {
+
+    pub fn new() -> Self {
+        UnclassifiedValues::default()
+    }
+
+    pub fn add(&mut self, field: &'static str, sid_type_id: i32, value: &str) {
+        *self.values.entry((field, sid_type_id, value.to_string())).or_default() += 1;
+    }
+
+    pub fn num_records(&self, field: &str) -> i32 {
+        self.values.iter().filter(|(k, _)| k.0 == field).map(|(_, n)| n).sum()
+    }
+
+    pub fn log(&self, description: &str) {
+        for field in [GENDER, COUNTRY] {
+            let values = self.most_common(field);
+            if values.is_empty() {
+                continue;
+            }
+            let num_distinct = self.values.keys().filter(|k| k.0 == field).count();
+            warn!("{} {} values in {} could not be classified, with {} distinct values",
+                        self.num_records(field), field, description, num_distinct);
+            for ((_, sid_type_id, value), n) in values {
+                info!("    {}: '{}' ({})", get_db_name(*sid_type_id), value, n);
+            }
+            if num_distinct > MAX_LOGGED {
+                info!("    and {} other values", num_distinct - MAX_LOGGED);
+            }
+        }
+    }
+
+    fn most_common(&self, field: &str) -> Vec<(&(&'static str, i32, String), &i32)> {
+        let mut values: Vec<_> = self.values.iter().filter(|(k, _)| k.0 == field).collect();
+        values.sort_by(|a, b| b.1.cmp(a.1));
+        values.truncate(MAX_LOGGED);
+        values
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_unclassified_values_counted() {
+        let mut values = UnclassifiedValues::new();
+        values.add(GENDER, 118, "x");
+        values.add(GENDER, 118, "y");
+        values.add(GENDER, 118, "y");
+        values.add(GENDER, 116, "y");
+        values.add(COUNTRY, 118, "y");
+        assert_eq!(values.num_records(GENDER), 4);
+        assert_eq!(values.num_records(COUNTRY), 1);
+        let common = values.most_common(GENDER);
+        assert_eq!(common[0], (&(GENDER, 118, "y".to_string()), &2));
+        assert_eq!(common.len(), 3);
+    }
+}
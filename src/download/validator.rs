@@ -0,0 +1,280 @@
+/**********************************************************************************
+Validation (a 'dry run') passes each row of a file through the same
+deserialisation, summarise_line and process_line functions as an import, but
+writes nothing to the database, the json store or a rejects file. Instead a
+report is produced, giving the number of records from each registry (as in
+der.who_file_contents) and the numbers of rejected rows, unmapped study types
+(999) and statuses (99), genders that could not be classified, countries that
+could not be resolved and dates that could not be parsed, each with a few sample
+values. It allows the behaviour of a new WHO file to be checked before the file
+is imported.
+***********************************************************************************/
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use chrono::NaiveDate;
+use csv::{ByteRecord, Reader, ReaderBuilder};
+use log::info;
+use crate::err::AppError;
+use super::file_models::WHOLine;
+use super::gen_helper::{StringExtensions, DateExtensions, DateOrder, DatePrecision};
+use super::processor;
+use super::read_next_line;
+use super::registries::catalogue;
+use super::rejects::RejectReason;
+use super::source_file::open_source_reader;
+use super::who_helper::get_db_name;
+
+pub const MAX_SAMPLES: usize = 5;
+
+
+#[derive(Debug, Default)]
+pub struct IssueTally {
+    pub count: i32,
+    pub samples: Vec<String>,    // up to MAX_SAMPLES distinct values
+}
+
+impl IssueTally {
+    pub fn add(&mut self, sample: String) {
+        self.count += 1;
+        if self.samples.len() < MAX_SAMPLES && !self.samples.contains(&sample) {
+            self.samples.push(sample);
+        }
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub num_checked: i32,
+    pub num_valid: i32,
+    pub source_tots: BTreeMap<i32, i32>,
+    pub parse_errors: IssueTally,
+    pub empty_ids: IssueTally,
+    pub unknown_ids: IssueTally,
+    pub unmapped_types: IssueTally,
+    pub unmapped_statuses: IssueTally,
+    pub unclassified_genders: IssueTally,
+    pub unresolved_countries: IssueTally,
+    pub unparseable_dates: IssueTally,
+}
+
+impl ValidationReport {
+
+    fn add_reject(&mut self, record: &ByteRecord, reason: RejectReason) {
+        let line_number = record.position().map_or(0, |p| p.line());
+        match reason {
+            RejectReason::ParseError(e) => self.parse_errors.add(format!("line {}: {}", line_number, e)),
+            RejectReason::EmptyId => self.empty_ids.add(format!("line {}", line_number)),
+            RejectReason::UnknownSource(sid) => self.unknown_ids.add(sid),
+        }
+    }
+
+    fn check_dates(&mut self, w: &WHOLine, order: DateOrder) {
+        let dates = [("last_updated", w.last_updated), ("date_registration", w.date_registration), 
+                     ("date_enrolment", w.date_enrollement), ("results_date_posted", w.results_date_posted), 
+                     ("results_date_first_pub", w.results_date_first_pub), ("results_date_completed", w.results_date_completed)];
+        for (field, value) in dates {
+            if is_unparseable_date(value, order) {
+                self.unparseable_dates.add(format!("{}: {}", field, value.trim()));
+            }
+        }
+    }
+
+    pub fn log_report(&self, file_path: &Path) {
+
+        info!("");
+        info!("Validation of {:?}", file_path);
+        info!("{} records checked, {} valid, {} rejected", self.num_checked, self.num_valid, 
+                        self.num_checked - self.num_valid);
+        info!("");
+        info!("Records by registry:");
+        for (sid_type_id, n) in &self.source_tots {
+            info!("    {} ({}): {}", get_db_name(*sid_type_id), sid_type_id, n);
+        }
+        info!("");
+        log_tally("Rows that could not be parsed", &self.parse_errors);
+        log_tally("Rows with an empty trial id", &self.empty_ids);
+        log_tally("Rows with an unrecognised trial id", &self.unknown_ids);
+        log_tally("Unmapped study types (999)", &self.unmapped_types);
+        log_tally("Unmapped study statuses (99)", &self.unmapped_statuses);
+        log_tally("Genders that could not be classified", &self.unclassified_genders);
+        log_tally("Countries that could not be resolved", &self.unresolved_countries);
+        log_tally("Dates that could not be parsed", &self.unparseable_dates);
+        info!("---------------------------------------------------");
+    }
+}
+
+
+pub fn validate_file(file_path: &Path) -> Result<ValidationReport, AppError> {
+
+    let source_reader = open_source_reader(file_path)?;
+    let mut csv_rdr = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(source_reader);
+    info!("");
+    info!("Validating file {:?} - no data will be stored", file_path);
+
+    validate_records(&mut csv_rdr)
+}
+
+
+fn validate_records<R: Read>(csv_rdr: &mut Reader<R>) -> Result<ValidationReport, AppError> {
+
+    let mut report = ValidationReport::default();
+    let mut record = ByteRecord::new();
+
+    while let Some(line_result) = read_next_line(csv_rdr, &mut record, report.num_checked + 1)? {
+
+        report.num_checked += 1;
+        if report.num_checked % 10000 == 0 {
+            info!("{} records checked", report.num_checked);
+        }
+
+        let who_line = match line_result {
+            Ok(w) => w,
+            Err(reason) => {
+                report.add_reject(&record, reason);
+                continue;
+            }
+        };
+
+        let summ = match processor::summarise_line(&who_line, 0) {
+            Ok(s) => s,
+            Err(reason) => {
+                report.add_reject(&record, reason);
+                continue;
+            }
+        };
+
+        report.num_valid += 1;
+        report.source_tots.entry(summ.sid_type_id).and_modify(|n| *n += 1).or_insert(1);
+
+        if summ.study_type_id == 999 {
+            report.unmapped_types.add(summ.study_type.clone().unwrap_or_default());
+        }
+        if summ.study_status_id == 99 {
+            report.unmapped_statuses.add(summ.study_status.clone().unwrap_or_default());
+        }
+        let date_order = catalogue().get(summ.sid_type_id).map_or(DateOrder::default(), |r| r.date_order);
+        report.check_dates(&who_line, date_order);
+
+        // The full record is generated for every source (even those for which 
+        // no json file is written during an import) so that all rows are checked.
+
+        if let Some(rec) = processor::process_line(who_line, &summ) {
+            if let Some(g) = rec.unclassified_gender() {
+                report.unclassified_genders.add(g.to_string());
+            }
+            for c in rec.countries_unresolved.iter().flatten() {
+                report.unresolved_countries.add(c.to_string());
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+
+fn is_unparseable_date(value: &str, order: DateOrder) -> bool {
+
+    // A date is unparseable if it is present but cannot be converted to a valid
+    // ISO date, or to a month or a year. The 1900-01-01 values used by some
+    // registries to mean 'no date' are also converted to None, but are not counted.
+
+    if value.tidy().is_none() || value.contains("1900") {
+        return false;
+    }
+    match value.as_partial_date(order) {
+        Some(d) if d.precision == DatePrecision::Day => NaiveDate::parse_from_str(&d.as_iso(), "%Y-%m-%d").is_err(),
+        Some(_) => false,
+        None => true,
+    }
+}
+
+
+pub fn log_tally(description: &str, tally: &IssueTally) {
+    info!("{}: {}", description, tally.count);
+    for sample in &tally.samples {
+        info!("    e.g. {}", sample);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support::make_row;
+
+    fn validate_rows(rows: &[String]) -> ValidationReport {
+        let data = rows.join("\n") + "\n";
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(data.as_bytes());
+        validate_records(&mut rdr).unwrap()
+    }
+
+    #[test]
+    fn check_valid_rows_counted_by_registry() {
+        let report = validate_rows(&[
+            make_row(&[(0, "ACTRN12624000001"), (18, "Interventional"), (21, "2024-01-15"), (24, "Recruiting"), (28, "Australia;New Zealand"), (33, "Both")]),
+            make_row(&[(0, "ACTRN12624000002"), (18, "Interventional"), (21, "15/01/2024"), (24, "Recruiting"), (33, "Female")]),
+            make_row(&[(0, "ChiCTR2400000001"), (18, "Observational study"), (21, "2024-01-15"), (24, "Completed")]),
+        ]);
+        assert_eq!(report.num_checked, 3);
+        assert_eq!(report.num_valid, 3);
+        assert_eq!(report.source_tots.len(), 2);
+        assert_eq!(report.source_tots.values().sum::<i32>(), 3);
+        assert_eq!(report.unparseable_dates.count, 0);
+        assert_eq!(report.unclassified_genders.count, 0);
+        assert_eq!(report.unresolved_countries.count, 0);
+    }
+
+    #[test]
+    fn check_issues_recorded_with_samples() {
+        let report = validate_rows(&[
+            make_row(&[(0, "XYZ123"), (18, "Interventional")]),
+            make_row(&[(0, ""), (18, "Interventional")]),
+            "ACTRN12624000003,too,few,fields".to_string(),
+            make_row(&[(0, "ACTRN12624000004"), (18, "Odd type"), (21, "sometime in 2024"), (24, "Odd status"), (28, "Australia;Yugoslavia"), (33, "Healthy volunteers")]),
+            make_row(&[(0, "ACTRN12624000005"), (18, "Odd type"), (21, "1900-01-01"), (24, "Odd status"), (46, "NULL")]),
+        ]);
+        assert_eq!(report.num_checked, 5);
+        assert_eq!(report.num_valid, 2);
+        assert_eq!(report.unknown_ids.count, 1);
+        assert_eq!(report.unknown_ids.samples, vec!["XYZ123".to_string()]);
+        assert_eq!(report.empty_ids.count, 1);
+        assert_eq!(report.parse_errors.count, 1);
+        assert!(report.parse_errors.samples[0].starts_with("line 3:"));
+
+        assert_eq!(report.unmapped_types.count, 2);
+        assert_eq!(report.unmapped_types.samples, vec!["Odd type".to_string()]);
+        assert_eq!(report.unmapped_statuses.count, 2);
+        assert_eq!(report.unclassified_genders.count, 1);
+        assert_eq!(report.unclassified_genders.samples, vec!["Healthy volunteers".to_string()]);
+        assert_eq!(report.unresolved_countries.samples, vec!["yugoslavia".to_string()]);
+        assert_eq!(report.unparseable_dates.count, 1);
+        assert_eq!(report.unparseable_dates.samples, vec!["date_registration: sometime in 2024".to_string()]);
+    }
+
+    #[test]
+    fn check_samples_limited_and_distinct() {
+        let mut tally = IssueTally::default();
+        for i in 0..10 {
+            tally.add(format!("value {}", i % 7));
+        }
+        tally.add("value 0".to_string());
+        assert_eq!(tally.count, 11);
+        assert_eq!(tally.samples.len(), MAX_SAMPLES);
+        assert_eq!(tally.samples[0], "value 0");
+    }
+
+    #[test]
+    fn check_partial_dates_not_unparseable() {
+        assert!(!is_unparseable_date("March 2020", DateOrder::DayFirst));
+        assert!(!is_unparseable_date("2020-03", DateOrder::DayFirst));
+        assert!(!is_unparseable_date("2020", DateOrder::DayFirst));
+        assert!(!is_unparseable_date("03/05/2020", DateOrder::MonthFirst));
+        assert!(is_unparseable_date("2020-02-30", DateOrder::DayFirst));
+        assert!(is_unparseable_date("sometime in 2024", DateOrder::DayFirst));
+    }
+}
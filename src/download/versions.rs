@@ -0,0 +1,281 @@
+/**********************************************************************************
+Each time a study is stored as new, or because its content has changed, a version
+record is added to dat.study_versions, keyed by the study id and the download (dl_id).
+Rather than a full copy of the record each version holds only the fields whose
+values differ from those of the previous version (with null for a field that no
+longer has a value), so the first version of a study holds all of its non-null
+fields and the state of the study at any version can be rebuilt by applying the
+changes of each version in turn. The history subcommand uses the versions to show
+how a study's status, dates, countries and sponsor have changed across the files
+imported. If json_snapshots is set in the config file each version's json file is
+also kept, in a versions folder within the json data folder, dated by the date the
+record was last updated in the WHO data.
+***********************************************************************************/
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{NaiveDate, Utc};
+use serde_json::{Map, Value};
+use sqlx::{PgConnection, Pool, Postgres};
+use log::info;
+use crate::err::AppError;
+use super::data_access::{get_version_changes, store_study_versions, fetch_study_versions};
+use super::file_models::WHOSummary;
+use super::processor::get_sd_sid;
+use super::registries::catalogue;
+use super::summary_buffer::StoredSummary;
+use super::who_helper::get_db_name;
+
+pub type FieldMap = Map<String, Value>;
+
+// The fields shown by default by the history subcommand.
+
+pub const TRACKED_FIELDS: [&str; 13] = ["study_status_orig", "study_status_id", "date_registration",
+    "date_enrolment", "results_date_completed", "results_date_posted", "results_yes_no",
+    "target_size", "results_actual_enrollment", "country_list", "primary_sponsor",
+    "secondary_sponsors", "source_support"];
+
+
+pub struct VersionRow {
+    pub sd_sid: String,
+    pub sid_type_id: i32,
+    pub dl_id: i32,
+    pub record_date: Option<NaiveDate>,
+    pub content_hash: String,
+    pub changed_fields: Vec<String>,
+    pub changes: String,     // the changes as a json object
+    pub source_file: String,
+}
+
+
+pub struct StudyVersion {
+    pub dl_id: i32,
+    pub record_date: Option<NaiveDate>,
+    pub source_file: Option<String>,
+    pub changes: FieldMap,
+}
+
+
+// Returns the fields whose values differ from those in the previous state,
+// with their new values. A field missing from either is treated as null.
+
+pub fn get_changes(previous: &FieldMap, current: &FieldMap) -> FieldMap {
+    let mut changes = FieldMap::new();
+    for (k, v) in current {
+        if previous.get(k).unwrap_or(&Value::Null) != v {
+            changes.insert(k.clone(), v.clone());
+        }
+    }
+    for (k, v) in previous {
+        if !v.is_null() && !current.contains_key(k) {
+            changes.insert(k.clone(), Value::Null);
+        }
+    }
+    changes
+}
+
+
+// Applies a version's changes to the state built up from the earlier versions.
+
+pub fn apply_changes(state: &mut FieldMap, changes: &FieldMap) {
+    for (k, v) in changes {
+        if v.is_null() {
+            state.remove(k);
+        } else {
+            state.insert(k.clone(), v.clone());
+        }
+    }
+}
+
+
+// Records a version of each of the studies (all new or changed) that differs from its latest
+// stored version. Versions of a study from the same download are merged. Returns the number recorded.
+
+pub async fn record_versions(recs: &[StoredSummary], source_file: &str, conn: &mut PgConnection) -> Result<usize, AppError> {
+
+    let sd_sids: Vec<String> = recs.iter().map(|r| r.rec.sd_sid.clone()).collect();
+    let mut states: HashMap<String, FieldMap> = HashMap::new();
+    for (sd_sid, changes) in get_version_changes(&sd_sids, conn).await? {
+        let changes: FieldMap = serde_json::from_str(&changes)?;
+        apply_changes(states.entry(sd_sid).or_default(), &changes);
+    }
+
+    let empty_state = FieldMap::new();
+    let mut rows = Vec::new();
+    for r in recs {
+        let changes = get_changes(states.get(&r.rec.sd_sid).unwrap_or(&empty_state), &r.fields);
+        if changes.is_empty() {
+            continue;   // e.g. a study stored before content hashes were, but already versioned
+        }
+        rows.push(VersionRow {
+            sd_sid: r.rec.sd_sid.clone(),
+            sid_type_id: r.rec.sid_type_id,
+            dl_id: r.rec.dl_id,
+            record_date: r.record_date.as_deref().and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()),
+            content_hash: r.content_hash.clone(),
+            changed_fields: changes.keys().cloned().collect(),
+            changes: Value::Object(changes).to_string(),
+            source_file: source_file.to_string(),
+        });
+    }
+
+    let num_rows = rows.len();
+    if num_rows > 0 {
+        store_study_versions(rows, conn).await?;
+    }
+    Ok(num_rows)
+}
+
+
+pub fn get_snapshot_path(json_path: &Path, rec_summ: &WHOSummary, record_date: &Option<String>) -> Result<PathBuf, AppError> {
+
+    // e.g. <json folder>/versions/anzctr/ACTRN12624000001_2025-02-24.json
+
+    let folder = json_path.join("versions").join(get_db_name(rec_summ.sid_type_id));
+    if !folder.exists() {
+        fs::create_dir_all(&folder)?;
+    }
+    let date = match record_date {
+        Some(d) => d.clone(),
+        None => Utc::now().date_naive().to_string(),
+    };
+    Ok(folder.join(format!("{}_{}.json", rec_summ.sd_sid, date)))
+}
+
+
+// Describes each version of a study, as lines of text. The first version shows the initial values
+// of the fields, later ones how each field changed. Unless all_fields is true only the tracked fields
+// are included, and versions in which none of them changed are shown only as a count.
+
+pub fn describe_history(versions: &[StudyVersion], all_fields: bool) -> Vec<String> {
+
+    let mut lines = Vec::new();
+    let mut state = FieldMap::new();
+    let mut untracked_versions = 0;
+
+    for (i, v) in versions.iter().enumerate() {
+        let mut fields: Vec<&String> = v.changes.keys()
+            .filter(|k| all_fields || TRACKED_FIELDS.contains(&k.as_str())).collect();
+        if !all_fields {
+            fields.sort_by_key(|k| TRACKED_FIELDS.iter().position(|t| t == k));
+        }
+
+        if i > 0 && fields.is_empty() {
+            untracked_versions += 1;
+        } else {
+            let record_date = v.record_date.map_or("-".to_string(), |d| d.to_string());
+            let source = v.source_file.as_deref().map_or("", |f| f.rsplit('/').next().unwrap_or(f));
+            lines.push(format!("dl {}, updated in WHO {}, {}", v.dl_id, record_date, source));
+            for k in fields {
+                let new_value = display_value(v.changes.get(k));
+                if i == 0 {
+                    lines.push(format!("    {}: {}", k, new_value));
+                } else {
+                    lines.push(format!("    {}: {} -> {}", k, display_value(state.get(k)), new_value));
+                }
+            }
+        }
+        apply_changes(&mut state, &v.changes);
+    }
+
+    if untracked_versions > 0 {
+        lines.push(format!("({} other versions with changes to other fields only - use --all to show them)", untracked_versions));
+    }
+    lines
+}
+
+
+fn display_value(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => "(none)".to_string(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(a)) => a.iter()
+            .map(|v| match v { Value::String(s) => s.clone(), other => other.to_string() })
+            .collect::<Vec<String>>().join(", "),
+        Some(other) => other.to_string(),
+    }
+}
+
+
+pub async fn show_study_history(trial_id: &str, all_fields: bool, pool: &Pool<Postgres>) -> Result<usize, AppError> {
+
+    // The id is normalised as it is when the study is imported, so
+    // that e.g. 'CTRI/2024/01/000001' can be used for CTRI-2024-01-000001.
+
+    let mut sd_sid = get_sd_sid(trial_id);
+    if let Some(registry) = catalogue().find_by_trial_id(&sd_sid) {
+        sd_sid = registry.normalise_id(&sd_sid);
+    }
+
+    let versions = fetch_study_versions(&sd_sid, pool).await?;
+    if versions.is_empty() {
+        info!("No versions of {} have been recorded", sd_sid);
+        return Ok(0);
+    }
+    info!("{} versions of {} recorded", versions.len(), sd_sid);
+    for line in describe_history(&versions, all_fields) {
+        info!("{}", line);
+    }
+    Ok(versions.len())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field_map(value: Value) -> FieldMap {
+        match value {
+            Value::Object(m) => m,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn check_changes_identified_and_applied() {
+        let v1 = field_map(json!({"study_status_orig": "Recruiting", "target_size": "100", "title": null}));
+        let v2 = field_map(json!({"study_status_orig": "Completed", "country_list": ["Japan"], "title": null}));
+
+        let first = get_changes(&FieldMap::new(), &v1);
+        assert_eq!(first, field_map(json!({"study_status_orig": "Recruiting", "target_size": "100"})));
+
+        let second = get_changes(&v1, &v2);
+        assert_eq!(second, field_map(json!({"study_status_orig": "Completed", "country_list": ["Japan"], "target_size": null})));
+        assert!(get_changes(&v2, &v2).is_empty());
+
+        let mut state = FieldMap::new();
+        apply_changes(&mut state, &first);
+        apply_changes(&mut state, &second);
+        assert_eq!(state, field_map(json!({"study_status_orig": "Completed", "country_list": ["Japan"]})));
+    }
+
+    #[test]
+    fn check_history_described() {
+        let versions = vec![
+            StudyVersion { dl_id: 1, record_date: NaiveDate::from_ymd_opt(2025, 1, 6), source_file: Some("/data/20250106 ICTRP.csv".to_string()),
+                changes: field_map(json!({"study_status_orig": "Recruiting", "primary_sponsor": "Uni A", "interventions": "x"})) },
+            StudyVersion { dl_id: 2, record_date: None, source_file: None,
+                changes: field_map(json!({"interventions": "y"})) },
+            StudyVersion { dl_id: 3, record_date: NaiveDate::from_ymd_opt(2025, 3, 3), source_file: Some("20250303 ICTRP.csv".to_string()),
+                changes: field_map(json!({"study_status_orig": "Completed", "country_list": ["Japan", "Korea"]})) },
+        ];
+
+        let lines = describe_history(&versions, false);
+        assert_eq!(lines, vec![
+            "dl 1, updated in WHO 2025-01-06, 20250106 ICTRP.csv",
+            "    study_status_orig: Recruiting",
+            "    primary_sponsor: Uni A",
+            "dl 3, updated in WHO 2025-03-03, 20250303 ICTRP.csv",
+            "    study_status_orig: Recruiting -> Completed",
+            "    country_list: (none) -> Japan, Korea",
+            "(1 other versions with changes to other fields only - use --all to show them)",
+        ]);
+
+        let lines = describe_history(&versions, true);
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[4], "dl 2, updated in WHO -, ");
+        assert_eq!(lines[5], "    interventions: x -> y");
+    }
+}
@@ -3,88 +3,24 @@ use super::file_models::{MeddraCondition, SecIdBase,
 use std::sync::LazyLock;
 use regex::Regex;
 use std::collections::HashSet;
+use super::registries::catalogue;
+use super::countries::get_country;
 
 
-pub fn get_sid_type_id(sd_sid: &String) -> i32 {
-    let usid = sd_sid.to_uppercase();
-    match usid {
-        _ if usid.starts_with("NCT") => 120,
-        _ if usid.starts_with("CHICTR") => 118,
-        _ if usid.starts_with("CTRI") => 121,
-        _ if usid.starts_with("JPRN") => 127,
-        _ if usid.starts_with("EUCTR") => 123,
-        _ if usid.starts_with("ISRCTN") => 126,
-        _ if usid.starts_with("ACTRN") => 116,
-        _ if usid.starts_with("DRKS") => 124,
-        _ if usid.starts_with("IRCT") => 125,
-        _ if usid.starts_with("KCT") =>  119,
-        _ if usid.starts_with("NL-OMON") => 132,
-        _ if usid.starts_with("CTIS") => 135,
-        _ if usid.starts_with("RBR") => 117, 
-        _ if usid.starts_with("RPCEC") => 122,
-        _ if usid.starts_with("PACTR") => 128,
-        _ if usid.starts_with("PER") => 129,
-        _ if usid.starts_with("SLCTR") => 130,
-        _ if usid.starts_with("TCTR") => 131,
-        _ if usid.starts_with("LBCTR") => 133,
-        _ if usid.starts_with("ITMCTR") => 134,
-        _ => 0
-    }
-}
-
+// The registry details are taken from the registry catalogue (see registries.rs).
 
 pub fn get_db_name (sid_type_id: i32) -> String {
-    let db_name = match sid_type_id {
-        120 => "ctg",
-        116 => "anzctr",
-        117 => "rebec",
-        118 => "chictr",
-        119 => "cris",
-        121 => "ctri",
-        122 => "rpcec",
-        123 => "euctr",
-        124 => "drks",
-        125 => "irct",
-        126 => "isrctn",
-        127 => "jprn",
-        128 => "pactr",
-        129 => "rpuec",
-        130 => "slctr",
-        131 => "thctr",
-        132 => "nntr",
-        135 => "ctis",
-        133 => "lebctr",
-        134 => "itmctr",
-        _ => ""
-    };
-    db_name.to_string()
+    catalogue().get(sid_type_id).map_or("".to_string(), |r| r.name.clone())
 }
 
 
 pub fn split_by_year (sid_type_id: i32) -> bool {
-    
-    match sid_type_id {
-        116 => true,
-        117 => false,
-        118 => true,
-        119 => false,
-        121 => true,
-        122 => false,
-        123 => true,
-        124 => true,
-        125 => true,
-        127 => true,
-        128 => false,
-        129 => false,
-        130 => false,
-        131 => false,
-        132 => true,
-        135 => true,
-        133 => false,
-        134 => false,
-        _ => false
-    }
+    catalogue().get(sid_type_id).is_some_and(|r| r.json_split_by_year)
+}
+
 
+pub fn writes_json (sid_type_id: i32) -> bool {
+    catalogue().get(sid_type_id).is_some_and(|r| r.write_json)
 }
 
 
@@ -348,7 +284,7 @@ pub fn get_conditions(condition_list: &String, sid_type_id: i32) -> (Option<Vec<
 }
 
 
-pub fn split_and_dedup_countries(sid_type_id: i32, country_list: &String) -> Option<Vec<String>> {
+pub fn split_and_dedup_countries(sid_type_id: i32, country_list: &str) -> Vec<String> {
 
     // country list known to be non-null and already 'tidied'.
 
@@ -397,24 +333,45 @@ pub fn split_and_dedup_countries(sid_type_id: i32, country_list: &String) -> Opt
             this_c = this_c.replace("tanzania,", "tanzania - ");
             this_c = this_c.replace("islands,", "islands - ");
 
-            if this_c.contains(',') {
-                let added_strings: Vec<&str> = this_c.split(',').collect();
-                for ac in added_strings {
-                    let act = ac.trim();
-                    if add_country_name(act, &out_strings) {
-                        out_strings.push(act.to_string());
+            if this_c.contains(',') && get_country(&this_c).is_none() {
+                for ac in join_country_names(this_c.split(',').map(|s| s.trim()).collect()) {
+                    if add_country_name(&ac, &out_strings) {
+                        out_strings.push(ac);
                     }
                 }
                 this_c_consumed = true;   // countries have been added from this string
             }
         }
 
-        if !this_c_consumed && add_country_name(&this_c, &out_strings) {
+        if !this_c_consumed && !this_c.is_empty() && add_country_name(&this_c, &out_strings) {
             out_strings.push(this_c.to_string());
         }
     }
 
-    return Some(out_strings);
+    out_strings
+}
+
+
+fn join_country_names(parts: Vec<&str>) -> Vec<String> {
+
+    // Parts of a comma separated list are joined again where together they make up
+    // the name of a country in the dictionary (e.g. 'bonaire, sint eustatius and saba'),
+    // taking the longest name possible at each point.
+
+    let mut names = Vec::<String>::new();
+    let mut i = 0;
+    while i < parts.len() {
+        let mut j = parts.len();
+        while j > i + 1 && get_country(&parts[i..j].join(", ")).is_none() {
+            j -= 1;
+        }
+        let name = parts[i..j].join(", ");
+        if !name.is_empty() {
+            names.push(name);
+        }
+        i = j;
+    }
+    names
 }
 
 
@@ -1090,21 +1047,7 @@ pub fn get_sec_id_details(sec_id: &str) -> SecIdBase {
     if let Some(id) = contains_rpuec(sec_id) {id } 
     else 
     {
-        let upid = sec_id.to_uppercase();
-        let sec_id_type_id = match upid {
-        _ if upid.starts_with("CHICTR") => 118,
-        _ if upid.starts_with("IRCT") => 125,
-        _ if upid.starts_with("KCT") =>  119,
-        _ if upid.starts_with("RBR") => 117, 
-        _ if upid.starts_with("RPCEC") => 122,
-        _ if upid.starts_with("PACTR") => 128,
-        _ if upid.starts_with("SLCTR") => 130,
-        _ if upid.starts_with("TCTR") => 131,
-        _ if upid.starts_with("LBCTR") => 133,
-        _ if upid.starts_with("ITMCTR") => 134,
-        _ if upid.starts_with("CHIMCTR") => 134,
-        _ => 0
-        };
+        let sec_id_type_id = catalogue().find_by_sec_id(sec_id).map_or(0, |r| r.sid_type_id as usize);
 
         if sec_id_type_id > 0 {
             SecIdBase{
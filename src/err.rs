@@ -56,6 +56,15 @@ pub enum AppError {
     #[error("Error during CSV read operation: {0:?}")]
     CsvError(#[source] csv::Error, String),
 
+    #[error("Too many rejected rows ({0}) in file {1:?}")]
+    RejectThresholdExceeded(i32, std::path::PathBuf,),
+
+    #[error("Error when reading zip archive {1:?}")]
+    ZipError(#[source] zip::result::ZipError, std::path::PathBuf,),
+
+    #[error("A processing thread failed: {0:?}")]
+    ProcessingThreadError(String, String),
+
     #[error("Error reading user input: {0:?}")]
     UserInputError (#[from] std::io::Error),
 }
@@ -111,6 +120,16 @@ pub fn report_error(e: AppError) -> () {
         AppError::RegexError(e, d) => print_error(e.to_string(), d, "REGEX ISSUE"),
         
         AppError::CsvError(e, ln) => print_error (e.to_string(), format!("Error at file line {}", ln), "CSV ERROR"),
+
+        AppError::RejectThresholdExceeded(n, p) => print_error (
+                format!("{} rows of the file have been rejected, more than the maximum allowed (max_rejects in the config file).", n), 
+                "Path was: ".to_string() + p.to_str().unwrap(), "TOO MANY REJECTED ROWS"),
+
+        AppError::ZipError(e, p) => print_error (e.to_string(), 
+                "Path was: ".to_string() + p.to_str().unwrap(), "ZIP ARCHIVE PROBLEM"),
+
+        AppError::ProcessingThreadError(t, d) => print_error (
+                format!("The {} stopped unexpectedly.", t), d, "PROCESSING THREAD ERROR"),
    
         AppError::UserInputError(e) => print_simple_error (e.to_string(), "USER INPUT ERROR"),
 
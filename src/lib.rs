@@ -3,18 +3,42 @@ pub mod err;
 mod download;
 mod aggregate;
 
-use setup::cli_reader;
+// Entry points for the benchmarks (see benches/), which are
+// not otherwise part of the library's interface.
+
+#[doc(hidden)]
+pub mod bench_support {
+    pub use crate::download::gen_helper::{StringExtensions, DateExtensions};
+    pub use crate::download::process_record;
+}
+
+use setup::{cli_reader, config_reader, Action};
 use err::AppError;
 use std::ffi::OsString;
 use std::fs;
 use std::path::PathBuf;
-use download::data_access::{get_next_download_id, update_dl_event_record};
+use download::data_access::{get_next_download_id, update_dl_event_record, prepare_dat_tables, get_imported_files};
+use setup::migrations::{migrate_db, MON_MIGRATIONS, SRC_MIGRATIONS};
+use download::source_file::get_file_hash;
+use log::info;
+use download::rejects::RejectPars;
+use download::summary_buffer::JsonPars;
+use download::diff::DiffSource;
+use download::checkpoint::{Checkpoint, get_checkpoint_to_resume};
+
+// The counts from processing a file (or files). Of the studies downloaded, 
+// those added are new, those updated have changed since they were last 
+// stored, and those unchanged have not been rewritten.
 
 #[derive(Clone)]
 pub struct DownloadResult {
     pub num_checked: i32,
     pub num_downloaded: i32,
     pub num_added: i32,
+    pub num_updated: i32,
+    pub num_unchanged: i32,
+    pub num_rejected: i32,
+    pub num_not_in_export: i32,
 }
 
 impl DownloadResult {
@@ -23,6 +47,10 @@ impl DownloadResult {
         num_checked: 0,
         num_downloaded: 0,
         num_added: 0,
+        num_updated: 0,
+        num_unchanged: 0,
+        num_rejected: 0,
+        num_not_in_export: 0,
         }
    }
 
@@ -31,6 +59,10 @@ impl DownloadResult {
             num_checked: self.num_checked + other.num_checked,
             num_downloaded: self.num_downloaded + other.num_downloaded,
             num_added: self.num_added + other.num_added,
+            num_updated: self.num_updated + other.num_updated,
+            num_unchanged: self.num_unchanged + other.num_unchanged,
+            num_rejected: self.num_rejected + other.num_rejected,
+            num_not_in_export: self.num_not_in_export + other.num_not_in_export,
         }
     }
 }
@@ -42,28 +74,145 @@ pub async fn download(args: Vec<OsString>) -> Result<(), AppError> {
     // they are read using the CLAP based CLI reader.
 
     let cli_pars = cli_reader::fetch_valid_arguments(args)?;
-    let config_file = PathBuf::from("./app_config.toml");
-    let config_string: String = fs::read_to_string(&config_file)
-                    .map_err(|e| AppError::IoReadErrorWithPath(e, config_file))?;
+    let config_file = config_reader::find_config_file(cli_pars.config_file.as_deref(), &config_reader::env_lookup)?;
+    let config_string: String = match &config_file {
+        Some(f) => fs::read_to_string(f)
+                    .map_err(|e| AppError::IoReadErrorWithPath(e, f.clone()))?,
+        None => "".to_string(),
+    };
     
     let params = setup::get_params(cli_pars, &config_string)?;
 
     setup::establish_log(&params)?;
-    let mon_pool = setup::get_mon_db_pool().await?;  // pool for the monitoring db
+
+    match &config_file {
+        Some(f) => info!("Using config file {:?}", f),
+        None => info!("No config file found - configuration values supplied by DL_WHO_ environment variables"),
+    }
+
+    if !params.registries_file.as_os_str().is_empty() {
+
+        // Use the registry catalogue in the designated file rather than the bundled one.
+
+        let n = download::registries::load_catalogue_file(&params.registries_file)?;
+        info!("{} registries read from catalogue file {:?}", n, params.registries_file);
+    }
+
+    if params.action == Action::Split {
+
+        // Only split the full export file into its numbered files - no database access is needed.
+
+        download::splitter::split_full_file(&params.full_file_path, &params.full_file_stem, 
+                                            download::FULL_EXPORT_CHUNK_SIZE)?;
+        return Ok(());
+    }
+
+    if params.action == Action::Validate {
+
+        // Process the target file without storing anything, and report on its contents.
+
+        let file_path = params.csv_data_path.join(&params.target);
+        let report = download::validator::validate_file(&file_path)?;
+        report.log_report(&file_path);
+        return Ok(());
+    }
+
+    if params.action == Action::Diff {
+
+        // Compare the two files, or the data stored after two imports. The
+        // database is only used if one of the sources is an import.
+
+        let from = DiffSource::from_arg(&params.diff_from, &params.csv_data_path);
+        let to = DiffSource::from_arg(&params.diff_to, &params.csv_data_path);
+        let pool = match from.needs_db() || to.needs_db() {
+            true => {
+                let pool = setup::get_src_db_pool().await?;
+                migrate_db(&SRC_MIGRATIONS, &pool).await?;
+                Some(pool)
+            },
+            false => None,
+        };
+        let report = download::diff::compare_sources(&from, &to, pool.as_ref()).await?;
+        report.log_report(&from, &to);
+        let file_path = report.write_studies(&params.log_folder_path.join("diffs"), &from, &to)?;
+        info!("{} studies added, removed or changed listed in {:?}", report.studies.len(), file_path);
+        return Ok(());
+    }
+
     let src_pool = setup::get_src_db_pool().await?;  // pool for the source specific db
+    migrate_db(&SRC_MIGRATIONS, &src_pool).await?;   // applies any not yet applied
+
+    if params.action == Action::Migrate {
 
-    if params.doing_agg_only {
+        // Create or update the tables in both databases, including 
+        // the dat table for each registry in the catalogue.
+
+        let mon_pool = setup::get_mon_db_pool().await?;
+        let n = migrate_db(&MON_MIGRATIONS, &mon_pool).await?;
+        info!("{} migrations applied to the monitoring database", n);
+        prepare_dat_tables(&src_pool).await?;
+        info!("Source database tables ready for {} registries", download::registries::catalogue().registries.len());
+    }
+    else if params.action == Action::Link || params.action == Action::Aggregate {
         
-        // do the data aggregation process using the data already in the DB
-        // rather than download data from files
-        aggregate::identify_linked_studies(&src_pool).await?;
-        aggregate::aggregate_who_data(&src_pool).await?;
+        // do the linking, and if required the data aggregation process, using 
+        // the data already in the DB rather than download data from files
+        // (optionally excluding the studies not in the latest full export)
+        prepare_dat_tables(&src_pool).await?;
+        aggregate::identify_linked_studies(params.exclude_missing, &src_pool).await?;
+        if params.action == Action::Aggregate {
+            aggregate::aggregate_who_data(params.exclude_missing, &src_pool).await?;
+        }
+    }
+    else if params.action == Action::Export {
 
+        // write the data grids created by aggregation to csv files
+
+        let n = aggregate::export_data_grids(&params.export_folder, &src_pool).await?;
+        info!("{} data grids exported to {:?}", n, params.export_folder);
+    }
+    else if params.action == Action::History {
+
+        // show how the study has changed across the files imported
+
+        download::versions::show_study_history(&params.study_id, params.all_fields, &src_pool).await?;
+    }
+    else if params.action == Action::Inspect {
+
+        // list the files in the data folder and whether each has been imported
+
+        let imported_files = get_imported_files(&src_pool).await?;
+        let statuses = setup::get_file_statuses(&params.csv_data_path, &params.last_file_imported, &imported_files)?;
+        info!("{} dated csv or zip files found in {:?}", statuses.len(), params.csv_data_path);
+        for (f, status) in statuses {
+            info!("{:<40} {}", f.file_name, status.description());
+        }
     }
     else {
 
-        let json_path = params.json_data_path;
-        let dl_id = get_next_download_id(&mon_pool).await?;
+        if !(501..=503).contains(&params.dl_type) {
+            return Result::Err(AppError::InconsistentProgramParameter(
+                        format!("Unrecognised download type: {}", params.dl_type)));
+        }
+
+        let mon_pool = setup::get_mon_db_pool().await?;  // pool for the monitoring db
+        migrate_db(&MON_MIGRATIONS, &mon_pool).await?;
+        prepare_dat_tables(&src_pool).await?;
+        let json_pars = JsonPars {
+            json_path: params.json_data_path,
+            snapshots: params.json_snapshots,
+        };
+        let reject_pars = RejectPars {
+            rejects_folder: params.log_folder_path.join("rejects"),
+            max_rejects: params.max_rejects,
+        };
+
+        // A resumed full export import continues as the same import, from its checkpoint.
+
+        let (dl_id, mut checkpoint) = match params.resume_dl_id {
+            Some(id) => (id, Some(get_checkpoint_to_resume(id, &params.full_file_path, &mon_pool).await?)),
+            None => (get_next_download_id(&mon_pool).await?, None),
+        };
         let mut dl_res = DownloadResult::new();
         
         match params.dl_type {
@@ -76,31 +225,37 @@ pub async fn download(args: Vec<OsString>) -> Result<(), AppError> {
 
                 // first need a routine that can identify the files and return them 
                 // as a vector of file names, in the correct order, if any...
+                // Files already imported, and unchanged since, are identified from the database.
 
-                let files_to_process = setup::get_files_to_process(&source_folder, &last_file)?;  // **** Ordering to check ****
+                let imported_files = get_imported_files(&src_pool).await?;
+                let files_to_process = setup::get_files_to_process(&source_folder, &last_file, &imported_files)?;  // ordered by file date
+                if files_to_process.is_empty() {
+                    info!("No new or changed files found in {:?}", source_folder);
+                }
 
-                if files_to_process.len() > 0 {
-                    for f in files_to_process {
-                        let file_path: PathBuf = [&source_folder, &PathBuf:: from(f)].iter().collect();
-                        let res = download::process_single_file(&file_path, &json_path, dl_id, &src_pool).await?;
-                        dl_res = dl_res.add(res);
-                    }
+                for f in files_to_process {
+                    let res = download::process_single_file(&f.path, &f.file_hash, &json_pars, &reject_pars, dl_id, &src_pool).await?;
+                    dl_res = dl_res.add(res);
                 }
             },
 
             502 => {
-                // Processing of a full data download (20+ files) (was type 103).
-                
-                let source_folder = params.csv_full_path;
-                let file_num = params.full_file_num + 1;
-                let file_stem = params.full_file_stem;
-
-                for i in 1..file_num {
-                    let file_name = file_stem.clone() + &(format!("{:0>3}", i) + ".csv");
-                    let file_path: PathBuf = [&source_folder, &PathBuf:: from(file_name)].iter().collect();
-                    let res = download::process_single_file(&file_path, &json_path, dl_id, &src_pool).await?;
-                    dl_res = dl_res.add(res);
-                }
+                // Processing of a full data download (was type 103).
+                // The full export file (csv or zip) is read directly, in chunks.
+
+                let checkpoint = match &mut checkpoint {
+                    Some(c) => c,
+                    none => none.insert(Checkpoint::new(dl_id, &params.full_file_path)?),
+                };
+                dl_res = download::process_full_export_file(&params.full_file_path, &params.full_file_stem, 
+                                                            &json_pars, &reject_pars, checkpoint, &src_pool, &mon_pool).await?;
+
+                // Studies already stored but not in the export are then flagged, and 
+                // listed in the monitoring database (with their json files archived 
+                // if an archive folder has been given).
+
+                dl_res.num_not_in_export = download::missing::flag_studies_not_in_export(dl_id, &json_pars.json_path, 
+                                                            &params.json_archive_path, &src_pool, &mon_pool).await?;
             },
 
             503 => {
@@ -109,19 +264,21 @@ pub async fn download(args: Vec<OsString>) -> Result<(), AppError> {
                 let source_folder = params.csv_data_path;
                 let file_name = params.target;
                 let file_path: PathBuf = [source_folder, PathBuf:: from(file_name)].iter().collect();
-                dl_res = download::process_single_file(&file_path, &json_path, dl_id, &src_pool).await?;
+                let file_hash = get_file_hash(&file_path)?;
+                dl_res = download::process_single_file(&file_path, &file_hash, &json_pars, &reject_pars, dl_id, &src_pool).await?;
             },
             
-            _ => {    // shouldn't do anything except report weird dl type code
- 
-                
-                
-            }
+            _ => {}    // other values already excluded above
         }
 
         // Update dl event record with res details
 
         update_dl_event_record (dl_id, params.dl_type, dl_res, &mon_pool).await?;
+
+        if let Some(mut c) = checkpoint {
+            c.completed = true;   // the import can no longer be resumed
+            c.save(&mon_pool).await?;
+        }
     }
 
     Ok(())  
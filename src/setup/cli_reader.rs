@@ -1,156 +1,443 @@
-use clap::{command, Arg, ArgMatches};
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command};
 use crate::err::AppError;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
+// The different operations the program can carry out, each
+// corresponding to one of the (top level) subcommands.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Import,
+    Split,
+    Link,
+    Aggregate,
+    Export,
+    Inspect,
+    Validate,
+    Migrate,
+    History,
+    Diff,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Import => "import",
+            Action::Split => "full file split",
+            Action::Link => "linking",
+            Action::Aggregate => "aggregation",
+            Action::Export => "export",
+            Action::Inspect => "inspection",
+            Action::Validate => "validation",
+            Action::Migrate => "database migration",
+            Action::History => "study history",
+            Action::Diff => "comparison",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CliPars {
+    pub action: Action,
     pub dl_type: i32,
     pub target_file: String,
-    pub doing_agg_only: bool,
+    pub export_folder: PathBuf,
+    pub study_id: String,
+    pub all_fields: bool,
+    pub diff_from: String,
+    pub diff_to: String,
+    pub exclude_missing: bool,
+    pub resume_dl_id: Option<i32>,
+    pub config_file: Option<PathBuf>,
 }
 
 pub fn fetch_valid_arguments(args: Vec<OsString>) -> Result<CliPars, AppError>
 { 
     let parse_result = parse_args(args.to_vec())?;
 
-    // These parameters guaranteed to unwrap OK as all have a default value;
-
-    let dl_type_as_string = parse_result.get_one::<String>("dl_type").unwrap();
-    let dl_type: i32 = dl_type_as_string.parse().unwrap_or_else(|_| 0);
-
-    let target_file = parse_result.get_one::<String>("file").unwrap();
-    let a_flag = parse_result.get_flag("a_flag");
+    let mut cli_args = CliPars {
+        action: Action::Import,
+        dl_type: 0,
+        target_file: "".to_string(),
+        export_folder: PathBuf::from(""),
+        study_id: "".to_string(),
+        all_fields: false,
+        diff_from: "".to_string(),
+        diff_to: "".to_string(),
+        exclude_missing: false,
+        resume_dl_id: None,
+        config_file: parse_result.get_one::<PathBuf>("config").cloned(),
+    };
 
-    // If the a (aggregate) flag is set blankj toe other parameters
+    // A subcommand is required, so one of these arms must match. The import
+    // types map onto the DL type codes used in the evs.dl_events table 
+    // (and those codes can also be used as aliases for the import types).
 
-    let cli_args = if a_flag {
-        CliPars {
-            dl_type: 0,
-            target_file: "".to_string(),
-            doing_agg_only: true,
-        }
+    match parse_result.subcommand() {
+        Some(("import", import_matches)) => {
+            match import_matches.subcommand() {
+                Some(("incremental", _)) => cli_args.dl_type = 501,
+                Some(("full", full_matches)) => {
+                    cli_args.dl_type = 502;
+                    cli_args.resume_dl_id = full_matches.get_one::<i32>("resume").copied();
+                },
+                Some(("file", file_matches)) => {
+                    cli_args.dl_type = 503;
+                    if let Some(p) = file_matches.get_one::<PathBuf>("path") {
+                        cli_args.target_file = p.to_string_lossy().to_string();
+                    }
+                },
+                _ => {},
+            }
+        },
+        Some(("split", _)) => cli_args.action = Action::Split,
+        Some(("link", link_matches)) => {
+            cli_args.action = Action::Link;
+            cli_args.exclude_missing = link_matches.get_flag("exclude_missing");
+        },
+        Some(("aggregate", aggregate_matches)) => {
+            cli_args.action = Action::Aggregate;
+            cli_args.exclude_missing = aggregate_matches.get_flag("exclude_missing");
+        },
+        Some(("export", export_matches)) => {
+            cli_args.action = Action::Export;
+            cli_args.export_folder = export_matches.get_one::<PathBuf>("folder").unwrap().clone();  // required
+        },
+        Some(("inspect", _)) => cli_args.action = Action::Inspect,
+        Some(("validate", validate_matches)) => {
+            cli_args.action = Action::Validate;
+            if let Some(p) = validate_matches.get_one::<PathBuf>("path") {
+                cli_args.target_file = p.to_string_lossy().to_string();
+            }
+        },
+        Some(("migrate", _)) => cli_args.action = Action::Migrate,
+        Some(("history", history_matches)) => {
+            cli_args.action = Action::History;
+            cli_args.study_id = history_matches.get_one::<String>("study_id").unwrap().clone();  // required
+            cli_args.all_fields = history_matches.get_flag("all");
+        },
+        Some(("diff", diff_matches)) => {
+            cli_args.action = Action::Diff;
+            cli_args.diff_from = diff_matches.get_one::<String>("from").unwrap().clone();  // required
+            cli_args.diff_to = diff_matches.get_one::<String>("to").unwrap().clone();  // required
+        },
+        _ => {},
     }
-    else {
-        CliPars {
-            dl_type: dl_type,
-            target_file: target_file.clone(),
-            doing_agg_only: false,
-        }
-    };
 
     Ok(cli_args)
 }
 
 
-pub fn config_file_exists()-> bool {
-    let config_path = PathBuf::from("./app_config.toml");
-    let res = match config_path.try_exists() {
-        Ok(true) => true,
-        Ok(false) => false, 
-        Err(_e) => false,           
-    };
-    res
-}
-
-
 fn parse_args(args: Vec<OsString>) -> Result<ArgMatches, clap::Error> {
 
     command!()
-        .about("Imports data from ROR json file (v2) and imports it into a database")
+        .about("Imports WHO ICTRP csv data into a database, and links and aggregates the study data imported")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
-             Arg::new("dl_type")
-            .short('t')
-            .long("type")
-            .help("An integer indicating the type of download required")
-            .default_value("501")
+            Arg::new("config")
+            .long("config")
+            .short('c')
+            .global(true)
+            .value_parser(value_parser!(PathBuf))
+            .help("The config file to use. If absent DL_WHO_CONFIG, ./app_config.toml and then the XDG config folders are checked")
         )
-        .arg(
-            Arg::new("file")
-           .short('f')
-           .long("file")
-           .required(false)
-           .help("A string with the target file name")
-           .default_value("")
+        .subcommand(
+            Command::new("import")
+            .about("Imports WHO csv data, creating or updating the study summary records and json files")
+            .subcommand_required(true)
+            .arg_required_else_help(true)
+            .subcommand(
+                Command::new("incremental")
+                .visible_alias("501")
+                .about("Imports the files in the csv data folder that have not yet been imported, or have changed since, in date order")
+            )
+            .subcommand(
+                Command::new("full")
+                .visible_alias("502")
+                .about("Imports the full export file, in chunks of 50,000 records")
+                .arg(
+                    Arg::new("resume")
+                    .long("resume")
+                    .value_name("DL_ID")
+                    .value_parser(value_parser!(i32))
+                    .help("Resumes an interrupted full export import, identified by its download id, from its last checkpoint")
+                )
+            )
+            .subcommand(
+                Command::new("file")
+                .visible_alias("503")
+                .about("Imports a single designated file")
+                .arg(
+                    Arg::new("path")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf))
+                    .help("The file to import, as a name within the csv data folder or as a full path. If absent the target_file in the config file is used")
+                )
+            )
         )
-        .arg(
-            Arg::new("a_flag")
-           .short('a')
-           .long("agg")
-           .required(false)
-           .help("A flag signifying database data needs to be integrated")
-           .action(clap::ArgAction::SetTrue)
+        .subcommand(
+            Command::new("split")
+            .about("Splits the full export file into numbered files of 50,000 records, without importing any data")
+        )
+        .subcommand(
+            Command::new("link")
+            .about("Identifies studies registered in more than one registry, using the secondary ids of the data already imported")
+            .arg(exclude_missing_arg())
+        )
+        .subcommand(
+            Command::new("aggregate")
+            .about("Links studies (as for 'link') and then aggregates the data already imported into summary statistics and grids")
+            .arg(exclude_missing_arg())
+        )
+        .subcommand(
+            Command::new("export")
+            .about("Exports the aggregated data grids as csv files")
+            .arg(
+                Arg::new("folder")
+                .required(true)
+                .value_parser(value_parser!(PathBuf))
+                .help("The folder in which to create the csv files (created if necessary)")
+            )
+        )
+        .subcommand(
+            Command::new("inspect")
+            .about("Lists the files in the csv data folder, showing whether each has been imported, without importing any data")
+        )
+        .subcommand(
+            Command::new("validate")
+            .visible_alias("dry-run")
+            .about("Processes a single file as an import would, but only reports on its contents, without using the database or writing json files")
+            .arg(
+                Arg::new("path")
+                .required(false)
+                .value_parser(value_parser!(PathBuf))
+                .help("The file to validate, as a name within the csv data folder or as a full path. If absent the target_file in the config file is used")
+            )
+        )
+        .subcommand(
+            Command::new("migrate")
+            .visible_alias("init-db")
+            .about("Creates or updates the tables in the monitoring and source databases, applying any migrations not yet applied")
+        )
+        .subcommand(
+            Command::new("history")
+            .about("Shows how a study's status, dates, countries and sponsors have changed across the files imported")
+            .arg(
+                Arg::new("study_id")
+                .required(true)
+                .help("The trial id of the study, as in the WHO data")
+            )
+            .arg(
+                Arg::new("all")
+                .long("all")
+                .short('a')
+                .action(ArgAction::SetTrue)
+                .help("Show the changes to all fields, not only to the status, dates, countries and sponsors")
+            )
+        )
+        .subcommand(
+            Command::new("diff")
+            .about("Compares two WHO files, or the data stored after two imports, listing the trial ids added or removed and the fields changed")
+            .arg(
+                Arg::new("from")
+                .required(true)
+                .help("The earlier file, as a name within the csv data folder or as a full path, or the id (dl_id) of an earlier import")
+            )
+            .arg(
+                Arg::new("to")
+                .required(true)
+                .help("The later file, or the id (dl_id) of a later import")
+            )
         )
     .try_get_matches_from(args)
 }
 
 
+fn exclude_missing_arg() -> Arg {
+    Arg::new("exclude_missing")
+    .long("exclude-missing")
+    .action(ArgAction::SetTrue)
+    .help("Exclude the studies that were not in the latest full export")
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn check_cli_no_explicit_params() {
-        let target = "dummy target";
-        let args: Vec<&str> = vec![target];
+    fn get_pars(args: Vec<&str>) -> Result<CliPars, AppError> {
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        fetch_valid_arguments(test_args)
+    }
 
-        let res = fetch_valid_arguments(test_args).unwrap();
-
-        assert_eq!(res.target_file, "");
-        assert_eq!(res.dl_type, 501);
-        assert_eq!(res.doing_agg_only, false);
-        
+    #[test]
+    #[should_panic]
+    fn check_cli_no_explicit_params_panics() {
+        let _res = get_pars(vec!["dummy target"]).unwrap();
     }
   
     #[test]
-    fn check_cli_with_a_dl_type() {
-        let target = "dummy target";
-        let args : Vec<&str> = vec![target, "-t", "502"];
-        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+    fn check_cli_with_import_types() {
+        let res = get_pars(vec!["dummy target", "import", "incremental"]).unwrap();
+        assert_eq!(res.action, Action::Import);
+        assert_eq!(res.target_file, "");
+        assert_eq!(res.dl_type, 501);
 
-        let res = fetch_valid_arguments(test_args).unwrap();
+        let res = get_pars(vec!["dummy target", "import", "full"]).unwrap();
+        assert_eq!(res.action, Action::Import);
+        assert_eq!(res.dl_type, 502);
+        assert_eq!(res.resume_dl_id, None);
+    }
 
-        assert_eq!(res.target_file, "");
+    #[test]
+    fn check_cli_with_resumed_full_import() {
+        let res = get_pars(vec!["dummy target", "import", "full", "--resume", "12"]).unwrap();
         assert_eq!(res.dl_type, 502);
+        assert_eq!(res.resume_dl_id, Some(12));
+
+        assert!(get_pars(vec!["dummy target", "import", "full", "--resume", "latest"]).is_err());
+        assert!(get_pars(vec!["dummy target", "import", "incremental", "--resume", "12"]).is_err());
     }
 
     #[test]
-    fn check_cli_with_target() {
-        let target = "dummy target";
-        let args : Vec<&str> = vec![target, "-f", "dummy file.csv"];
-        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+    fn check_cli_with_numeric_aliases() {
+        let res = get_pars(vec!["dummy target", "import", "501"]).unwrap();
+        assert_eq!(res.dl_type, 501);
 
-        let res = fetch_valid_arguments(test_args).unwrap();
+        let res = get_pars(vec!["dummy target", "import", "502"]).unwrap();
+        assert_eq!(res.dl_type, 502);
 
+        let res = get_pars(vec!["dummy target", "import", "503", "dummy file.csv"]).unwrap();
+        assert_eq!(res.dl_type, 503);
         assert_eq!(res.target_file, "dummy file.csv");
-        assert_eq!(res.dl_type, 501);
     }
 
     #[test]
-    fn check_cli_with_dl_type_and_target() {
-        let target = "dummy target";
-        let args : Vec<&str> = vec![target, "-t", "503", "-f", "dummy file.csv"];
-        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
-
-        let res = fetch_valid_arguments(test_args).unwrap();
+    #[should_panic]
+    fn check_cli_with_unknown_import_type_panics() {
+        let _res = get_pars(vec!["dummy target", "import", "504"]).unwrap();
+    }
 
+    #[test]
+    fn check_cli_with_import_file() {
+        let res = get_pars(vec!["dummy target", "import", "file", "dummy file.csv"]).unwrap();
+        assert_eq!(res.action, Action::Import);
         assert_eq!(res.target_file, "dummy file.csv");
         assert_eq!(res.dl_type, 503);
+
+        let res = get_pars(vec!["dummy target", "import", "file"]).unwrap();
+        assert_eq!(res.target_file, "");
+        assert_eq!(res.dl_type, 503);
     }
 
     #[test]
-    fn check_cli_with_a_flag() {
-        let target = "dummy target";
-        let args : Vec<&str> = vec![target, "-a"];
-        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
-
-        let res = fetch_valid_arguments(test_args).unwrap();
+    fn check_cli_with_link_and_aggregate() {
+        let res = get_pars(vec!["dummy target", "link"]).unwrap();
+        assert_eq!(res.action, Action::Link);
+        assert_eq!(res.dl_type, 0);
 
+        let res = get_pars(vec!["dummy target", "aggregate"]).unwrap();
+        assert_eq!(res.action, Action::Aggregate);
         assert_eq!(res.target_file, "");
         assert_eq!(res.dl_type, 0);
-        assert_eq!(res.doing_agg_only, true);
     }
    
-}
+    #[test]
+    fn check_cli_with_split_subcommand() {
+        let res = get_pars(vec!["dummy target", "split"]).unwrap();
+        assert_eq!(res.dl_type, 0);
+        assert_eq!(res.action, Action::Split);
+    }
+
+    #[test]
+    fn check_cli_with_export_and_inspect() {
+        let res = get_pars(vec!["dummy target", "export", "/home/steve/Data/grids"]).unwrap();
+        assert_eq!(res.action, Action::Export);
+        assert_eq!(res.export_folder, PathBuf::from("/home/steve/Data/grids"));
+
+        let res = get_pars(vec!["dummy target", "inspect"]).unwrap();
+        assert_eq!(res.action, Action::Inspect);
+        assert_eq!(res.dl_type, 0);
+    }
+
+    #[test]
+    fn check_cli_with_validate() {
+        let res = get_pars(vec!["dummy target", "validate", "20250224 ICTRP.csv"]).unwrap();
+        assert_eq!(res.action, Action::Validate);
+        assert_eq!(res.target_file, "20250224 ICTRP.csv");
+        assert_eq!(res.dl_type, 0);
+
+        let res = get_pars(vec!["dummy target", "dry-run"]).unwrap();
+        assert_eq!(res.action, Action::Validate);
+        assert_eq!(res.target_file, "");
+    }
+
+    #[test]
+    fn check_cli_with_migrate() {
+        let res = get_pars(vec!["dummy target", "migrate"]).unwrap();
+        assert_eq!(res.action, Action::Migrate);
+        assert_eq!(res.dl_type, 0);
+
+        let res = get_pars(vec!["dummy target", "init-db", "-c", "./test_config.toml"]).unwrap();
+        assert_eq!(res.action, Action::Migrate);
+        assert_eq!(res.config_file, Some(PathBuf::from("./test_config.toml")));
+    }
 
+    #[test]
+    fn check_cli_with_exclude_missing() {
+        let res = get_pars(vec!["dummy target", "aggregate", "--exclude-missing"]).unwrap();
+        assert_eq!(res.action, Action::Aggregate);
+        assert!(res.exclude_missing);
+
+        let res = get_pars(vec!["dummy target", "link", "--exclude-missing"]).unwrap();
+        assert_eq!(res.action, Action::Link);
+        assert!(res.exclude_missing);
+
+        let res = get_pars(vec!["dummy target", "link"]).unwrap();
+        assert!(!res.exclude_missing);
+    }
+
+    #[test]
+    fn check_cli_with_history() {
+        let res = get_pars(vec!["dummy target", "history", "ACTRN12624000001"]).unwrap();
+        assert_eq!(res.action, Action::History);
+        assert_eq!(res.study_id, "ACTRN12624000001");
+        assert!(!res.all_fields);
+
+        let res = get_pars(vec!["dummy target", "history", "NCT01234567", "--all"]).unwrap();
+        assert_eq!(res.study_id, "NCT01234567");
+        assert!(res.all_fields);
+    }
+
+    #[test]
+    fn check_cli_with_diff() {
+        let res = get_pars(vec!["dummy target", "diff", "20250217 ICTRP.csv", "20250224 ICTRP.csv"]).unwrap();
+        assert_eq!(res.action, Action::Diff);
+        assert_eq!(res.diff_from, "20250217 ICTRP.csv");
+        assert_eq!(res.diff_to, "20250224 ICTRP.csv");
+
+        let res = get_pars(vec!["dummy target", "diff", "9", "10"]).unwrap();
+        assert_eq!(res.diff_from, "9");
+        assert_eq!(res.diff_to, "10");
+    }
+
+    #[test]
+    #[should_panic]
+    fn check_cli_diff_with_one_source_panics() {
+        let _res = get_pars(vec!["dummy target", "diff", "20250224 ICTRP.csv"]).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn check_cli_history_without_study_panics() {
+        let _res = get_pars(vec!["dummy target", "history"]).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn check_cli_export_without_folder_panics() {
+        let _res = get_pars(vec!["dummy target", "export"]).unwrap();
+    }
+}
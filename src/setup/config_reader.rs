@@ -3,7 +3,8 @@ use std::sync::OnceLock;
 use toml;
 use serde::Deserialize;
 use crate::err::AppError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::fs;
 
 
 #[derive(Debug, Deserialize)]
@@ -13,28 +14,32 @@ pub struct TomlConfig {
     pub database: Option<TomlDBPars>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct TomlDataPars {
     pub full_file_stem: Option<String>,
-    pub full_file_num: Option<String>,
+    pub full_file_name: Option<String>,
     pub last_file_imported: Option<String>,
     pub target_file: Option<String>,
+    pub max_rejects: Option<String>,
+    pub registries_file: Option<String>,
+    pub json_snapshots: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct TomlFolderPars {
     pub csv_data_path: Option<String>,
     pub csv_full_path: Option<String>,
     pub json_data_path: Option<String>,
     pub log_folder_path: Option<String>,
-
+    pub json_archive_path: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct TomlDBPars {
     pub db_host: Option<String>,
     pub db_user: Option<String>,
     pub db_password: Option<String>,
+    pub db_password_file: Option<String>,
     pub db_port: Option<String>,
     pub mon_db_name: Option<String>,
     pub src_db_name: Option<String>,
@@ -49,9 +54,12 @@ pub struct Config {
 
 pub struct DataPars {
     pub full_file_stem: String,
-    pub full_file_num: i32,
+    pub full_file_name: String,
     pub last_file_imported: String,
     pub target_file: String,
+    pub max_rejects: i32,
+    pub registries_file: String,
+    pub json_snapshots: bool,
 }
 
 pub struct FolderPars {
@@ -59,6 +67,7 @@ pub struct FolderPars {
     pub csv_full_path: PathBuf,
     pub json_data_path: PathBuf,
     pub log_folder_path: PathBuf,
+    pub json_archive_path: PathBuf,
 }
 
 #[derive(Debug, Clone)]
@@ -73,33 +82,115 @@ pub struct DBPars {
 
 pub static DB_PARS: OnceLock<DBPars> = OnceLock::new();
 
-pub fn populate_config_vars(config_string: &String) -> Result<Config, AppError> {
+// Environment variables are read through a lookup function, rather than directly,
+// so that the tests can supply their own values without changing the environment.
+
+pub type EnvLookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+pub const ENV_PREFIX: &str = "DL_WHO_";
+pub const CONFIG_FILE_NAME: &str = "app_config.toml";
+pub const CONFIG_FOLDER_NAME: &str = "dl_who";
+
+const DATA_KEYS: [&str; 7] = ["full_file_stem", "full_file_name", "last_file_imported", "target_file", "max_rejects", 
+                              "registries_file", "json_snapshots"];
+const FOLDER_KEYS: [&str; 5] = ["csv_data_path", "csv_full_path", "json_data_path", "log_folder_path", "json_archive_path"];
+const DB_KEYS: [&str; 7] = ["db_host", "db_user", "db_password", "db_password_file", "db_port", "mon_db_name", "src_db_name"];
+
+
+pub fn env_lookup(key: &str) -> Option<String> {
+    std::env::var(key).ok()
+}
+
+
+pub fn find_config_file(cli_path: Option<&Path>, lookup: EnvLookup) -> Result<Option<PathBuf>, AppError> {
+
+    // A config file given on the command line, or by the DL_WHO_CONFIG environment
+    // variable, must exist. Otherwise the first of ./app_config.toml, 
+    // $XDG_CONFIG_HOME/dl_who/app_config.toml (by default ~/.config/dl_who/...) and
+    // dl_who/app_config.toml within each of the $XDG_CONFIG_DIRS (by default /etc/xdg)
+    // is used. If none of these exist all the values must come from the environment.
+
+    let explicit_path = match cli_path {
+        Some(p) => Some(p.to_owned()),
+        None => lookup(&format!("{}CONFIG", ENV_PREFIX)).filter(|p| !p.trim().is_empty()).map(PathBuf::from),
+    };
+    if let Some(p) = explicit_path {
+        if p.is_file() {
+            return Ok(Some(p));
+        }
+        return Result::Err(AppError::ConfigurationError("Unable to find the designated config file.".to_string(),
+                        format!("No file found at {:?}.", p)));
+    }
+
+    Ok(get_config_search_path(lookup).into_iter().find(|p| p.is_file()))
+}
 
-    let toml_config = toml::from_str::<TomlConfig>(&config_string)
+
+pub fn get_config_search_path(lookup: EnvLookup) -> Vec<PathBuf> {
+
+    let mut paths = vec![PathBuf::from(".").join(CONFIG_FILE_NAME)];
+
+    let config_home = match lookup("XDG_CONFIG_HOME").filter(|p| !p.trim().is_empty()) {
+        Some(p) => Some(PathBuf::from(p)),
+        None => lookup("HOME").filter(|p| !p.trim().is_empty()).map(|h| PathBuf::from(h).join(".config")),
+    };
+    if let Some(p) = config_home {
+        paths.push(p.join(CONFIG_FOLDER_NAME).join(CONFIG_FILE_NAME));
+    }
+
+    let config_dirs = lookup("XDG_CONFIG_DIRS").filter(|p| !p.trim().is_empty())
+                        .unwrap_or_else(|| "/etc/xdg".to_string());
+    for d in config_dirs.split(':').filter(|d| !d.is_empty()) {
+        paths.push(PathBuf::from(d).join(CONFIG_FOLDER_NAME).join(CONFIG_FILE_NAME));
+    }
+
+    paths
+}
+
+
+pub fn populate_config_vars(config_string: &str) -> Result<Config, AppError> {
+    populate_config_vars_with_env(config_string, &env_lookup)
+}
+
+
+pub fn populate_config_vars_with_env(config_string: &str, lookup: EnvLookup) -> Result<Config, AppError> {
+
+    // Any value in the config file can be overridden by an environment variable, named
+    // by upper casing the key and adding the DL_WHO_ prefix, e.g. DL_WHO_DB_HOST. 
+    // A section can therefore be missing from the file if it is supplied by the environment.
+
+    let toml_config = toml::from_str::<TomlConfig>(config_string)
         .map_err(|_| {AppError::ConfigurationError("Unable to parse config file.".to_string(),
                                        "File (app_config.toml) may be malformed.".to_string())})?;
 
-    let toml_data_details = match toml_config.data {
+    let mut toml_data_details = match toml_config.data {
         Some(d) => d,
+        None if env_has_any(&DATA_KEYS, lookup) => TomlDataPars::default(),
         None => {return Result::Err(AppError::ConfigurationError("Missing or misspelt configuration section.".to_string(),
         "Cannot find a section called '[data]'.".to_string()))},
     };
 
-    let toml_database = match toml_config.database {
+    let mut toml_database = match toml_config.database {
         Some(d) => d,
+        None if env_has_any(&DB_KEYS, lookup) => TomlDBPars::default(),
         None => {return Result::Err(AppError::ConfigurationError("Missing or misspelt configuration section.".to_string(),
             "Cannot find a section called '[database]'.".to_string()))},
     };
 
-    let toml_folders = match toml_config.folders {
+    let mut toml_folders = match toml_config.folders {
         Some(f) => f,
+        None if env_has_any(&FOLDER_KEYS, lookup) => TomlFolderPars::default(),
         None => {return Result::Err(AppError::ConfigurationError("Missing or misspelt configuration section.".to_string(),
            "Cannot find a section called '[folders]'.".to_string()))},
     };
+
+    apply_data_overrides(&mut toml_data_details, lookup);
+    apply_folder_overrides(&mut toml_folders, lookup);
+    apply_db_overrides(&mut toml_database, lookup);
        
     let config_folders = verify_folder_parameters(toml_folders)?;
     let config_data_dets = verify_data_parameters(toml_data_details)?;
-    let config_db_pars = verify_db_parameters(toml_database)?;
+    let config_db_pars = verify_db_parameters(toml_database, lookup)?;
 
     match DB_PARS.set(config_db_pars.clone())
     {
@@ -114,22 +205,84 @@ pub fn populate_config_vars(config_string: &String) -> Result<Config, AppError>
     })
 }
 
+
+fn env_key(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+fn env_has_any(keys: &[&str], lookup: EnvLookup) -> bool {
+    keys.iter().any(|k| lookup(&env_key(k)).is_some())
+}
+
+fn override_value(value: &mut Option<String>, key: &str, lookup: EnvLookup) {
+    if let Some(v) = lookup(&env_key(key)) {
+        *value = Some(v);
+    }
+}
+
+fn apply_data_overrides(pars: &mut TomlDataPars, lookup: EnvLookup) {
+    override_value(&mut pars.full_file_stem, "full_file_stem", lookup);
+    override_value(&mut pars.full_file_name, "full_file_name", lookup);
+    override_value(&mut pars.last_file_imported, "last_file_imported", lookup);
+    override_value(&mut pars.target_file, "target_file", lookup);
+    override_value(&mut pars.max_rejects, "max_rejects", lookup);
+    override_value(&mut pars.registries_file, "registries_file", lookup);
+    override_value(&mut pars.json_snapshots, "json_snapshots", lookup);
+}
+
+fn apply_folder_overrides(pars: &mut TomlFolderPars, lookup: EnvLookup) {
+    override_value(&mut pars.csv_data_path, "csv_data_path", lookup);
+    override_value(&mut pars.csv_full_path, "csv_full_path", lookup);
+    override_value(&mut pars.json_data_path, "json_data_path", lookup);
+    override_value(&mut pars.log_folder_path, "log_folder_path", lookup);
+    override_value(&mut pars.json_archive_path, "json_archive_path", lookup);
+}
+
+fn apply_db_overrides(pars: &mut TomlDBPars, lookup: EnvLookup) {
+
+    // A password, or password file, from the environment replaces 
+    // both of the password settings in the config file.
+
+    if env_has_any(&["db_password", "db_password_file"], lookup) {
+        pars.db_password = None;
+        pars.db_password_file = None;
+    }
+    override_value(&mut pars.db_host, "db_host", lookup);
+    override_value(&mut pars.db_user, "db_user", lookup);
+    override_value(&mut pars.db_password, "db_password", lookup);
+    override_value(&mut pars.db_password_file, "db_password_file", lookup);
+    override_value(&mut pars.db_port, "db_port", lookup);
+    override_value(&mut pars.mon_db_name, "mon_db_name", lookup);
+    override_value(&mut pars.src_db_name, "src_db_name", lookup);
+}
+
+
 fn verify_data_parameters(toml_data_pars: TomlDataPars) -> Result<DataPars, AppError> {
 
     let full_file_stem = check_defaulted_string (toml_data_pars.full_file_stem, "full DL file stem", "ICTRPFullExport ", "ICTRPFullExport ");
 
-    let full_file_num_as_string = check_defaulted_string (toml_data_pars.full_file_num, "full DL file num", "zero", "0");
-    let full_file_num: i32 = full_file_num_as_string.parse().unwrap_or_else(|_| 0);
+    let full_file_name = check_defaulted_string (toml_data_pars.full_file_name, "full DL file name", "empty string", "");
     
     let last_file_imported = check_defaulted_string (toml_data_pars.last_file_imported, "last file imported", "empty string", "");
 
     let target_file = check_defaulted_string (toml_data_pars.target_file, "single target file", "empty string", "");
+
+    let max_rejects_as_string = check_defaulted_string (toml_data_pars.max_rejects, "max rejected rows per file", "100", "100");
+    let max_rejects: i32 = max_rejects_as_string.parse().unwrap_or_else(|_| 100);
+
+    let registries_file = check_defaulted_string (toml_data_pars.registries_file, "registry catalogue file", "the bundled catalogue", "");
+
+    let json_snapshots_as_string = check_defaulted_string (toml_data_pars.json_snapshots, "keep json snapshots", "false", "false");
+    let json_snapshots = json_snapshots_as_string.trim().eq_ignore_ascii_case("true");
         
     Ok(DataPars {
         full_file_stem,
-        full_file_num,
+        full_file_name,
         last_file_imported,
         target_file,
+        max_rejects,
+        registries_file,
+        json_snapshots,
     })
 }
 
@@ -143,22 +296,23 @@ fn verify_folder_parameters(toml_folders: TomlFolderPars) -> Result<FolderPars,
 
     let log_folder_path_string = check_essential_string (toml_folders.log_folder_path, "log folder", "log_folder_path")?;
 
+    let json_archive_path_string = check_defaulted_string (toml_folders.json_archive_path, "json archive folder", "empty string", "");
+
     Ok(FolderPars {
         csv_data_path: PathBuf::from(csv_data_path_string),
         csv_full_path: PathBuf::from(csv_full_path_string),
         json_data_path: PathBuf::from(json_data_path_string),
         log_folder_path: PathBuf::from(log_folder_path_string),
+        json_archive_path: PathBuf::from(json_archive_path_string),
     })
 }
 
-fn verify_db_parameters(toml_database: TomlDBPars) -> Result<DBPars, AppError> {
+fn verify_db_parameters(toml_database: TomlDBPars, lookup: EnvLookup) -> Result<DBPars, AppError> {
 
-    // Check user name and password first as there are no defaults for these values.
-    // They must therefore be present.
+    // Check user name first as there is no default for this value.
+    // It must therefore be present.
 
     let db_user = check_essential_string (toml_database.db_user, "database user name", "db_user")?; 
-
-    let db_password = check_essential_string (toml_database.db_password, "database user password", "db_password")?;
        
     let db_host = check_defaulted_string (toml_database.db_host, "DB host", "localhost", "localhost");
             
@@ -168,6 +322,32 @@ fn verify_db_parameters(toml_database: TomlDBPars) -> Result<DBPars, AppError> {
     let mon_db_name = check_defaulted_string (toml_database.mon_db_name, "Mon DB name", "mon", "mon");
     let src_db_name = check_defaulted_string (toml_database.src_db_name, "Src DB name", "who", "who");
 
+    // The password must then be found, either directly, from a designated
+    // file, or from a matching entry in the file named by PGPASSFILE.
+
+    let db_password = match non_empty(toml_database.db_password_file) {
+        Some(f) => read_password_file(&PathBuf::from(f))?,
+        None => match non_empty(toml_database.db_password) {
+            Some(p) => p,
+            None => {
+                let pgpass = match non_empty(lookup("PGPASSFILE")) {
+                    Some(f) => {
+                        let pgpass_path = PathBuf::from(f);
+                        let contents = fs::read_to_string(&pgpass_path)
+                                .map_err(|e| AppError::IoReadErrorWithPath(e, pgpass_path))?;
+                        find_pgpass_password(&contents, &db_host, db_port, &db_user, &[&src_db_name, &mon_db_name])
+                    },
+                    None => None,
+                };
+                match pgpass {
+                    Some(p) => p,
+                    None => return Result::Err(AppError::ConfigurationError("Essential configuration value missing or misspelt.".to_string(),
+                        "Cannot find a value for database user password (db_password, db_password_file, or an entry in the PGPASSFILE).".to_string())),
+                }
+            },
+        },
+    };
+
     Ok(DBPars {
         db_host,
         db_user,
@@ -179,6 +359,66 @@ fn verify_db_parameters(toml_database: TomlDBPars) -> Result<DBPars, AppError> {
 }
 
 
+fn non_empty(s: Option<String>) -> Option<String> {
+    s.filter(|s| !s.trim().is_empty())
+}
+
+
+fn read_password_file(file_path: &PathBuf) -> Result<String, AppError> {
+
+    let contents = fs::read_to_string(file_path)
+            .map_err(|e| AppError::IoReadErrorWithPath(e, file_path.clone()))?;
+    let password = contents.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Result::Err(AppError::ConfigurationError("Empty database password file.".to_string(),
+                        format!("No password found in {:?}.", file_path)));
+    }
+    Ok(password)
+}
+
+
+pub fn find_pgpass_password(contents: &str, host: &str, port: usize, user: &str, db_names: &[&str]) -> Option<String> {
+
+    // Each line of a .pgpass file is hostname:port:database:username:password,
+    // where any of the first four can be *, and : or \ in a field are escaped by a \.
+    // The password is taken from the first line that matches.
+
+    let port_string = port.to_string();
+    for line in contents.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = split_pgpass_line(line);
+        if fields.len() != 5 {
+            continue;
+        }
+        let matches = |field: &str, value: &str| field == "*" || field == value;
+        if matches(&fields[0], host) && matches(&fields[1], &port_string) 
+            && db_names.iter().any(|d| matches(&fields[2], d)) && matches(&fields[3], user) {
+            return Some(fields[4].clone());
+        }
+    }
+    None
+}
+
+
+fn split_pgpass_line(line: &str) -> Vec<String> {
+
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => if let Some(next) = chars.next() { field.push(next) },
+            ':' => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+
 fn check_essential_string (src_name: Option<String>, value_name: &str, config_name: &str) -> Result<String, AppError> {
  
     let s = match src_name {
@@ -262,15 +502,19 @@ mod tests {
         let config = r#"
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "dummy test ICTRP.csv"
+max_rejects = "250"
+registries_file = "/home/steve/Data/who_registries.toml"
+json_snapshots = "true"
 
 [folders]
 csv_data_path="/home/steve/Data/MDR source data/WHO/data"
 csv_full_path="/home/steve/Data/MDR source data/WHO/data/Full export 2025-02"
 json_data_path="/home/steve/Data/MDR json files/who"
 log_folder_path="/home/steve/Data/MDR_Logs/who"
+json_archive_path="/home/steve/Data/MDR json archive/who"
 
 [database]
 db_host="localhost"
@@ -287,11 +531,15 @@ src_db_name="who"
         assert_eq!(res.folders.csv_full_path, PathBuf::from("/home/steve/Data/MDR source data/WHO/data/Full export 2025-02"));
         assert_eq!(res.folders.json_data_path, PathBuf::from("/home/steve/Data/MDR json files/who"));
         assert_eq!(res.folders.log_folder_path, PathBuf::from("/home/steve/Data/MDR_Logs/who"));
+        assert_eq!(res.folders.json_archive_path, PathBuf::from("/home/steve/Data/MDR json archive/who"));
 
         assert_eq!(res.data_details.full_file_stem, "ICTRPFullExport ");
-        assert_eq!(res.data_details.full_file_num, 22);
+        assert_eq!(res.data_details.full_file_name, "ICTRP-full-1074705.csv");
         assert_eq!(res.data_details.last_file_imported, "20250106 ICTRP.csv");
         assert_eq!(res.data_details.target_file, "dummy test ICTRP.csv");
+        assert_eq!(res.data_details.max_rejects, 250);
+        assert_eq!(res.data_details.registries_file, "/home/steve/Data/who_registries.toml");
+        assert!(res.data_details.json_snapshots);
 
         assert_eq!(res.db_pars.db_host, "localhost");
         assert_eq!(res.db_pars.db_user, "user_name");
@@ -309,7 +557,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -337,7 +585,7 @@ src_db_name="who"
         assert_eq!(res.folders.log_folder_path, PathBuf::from("E:\\MDR\\MDR Logs"));
 
         assert_eq!(res.data_details.full_file_stem, "ICTRPFullExport ");
-        assert_eq!(res.data_details.full_file_num, 22);
+        assert_eq!(res.data_details.full_file_name, "ICTRP-full-1074705.csv");
         assert_eq!(res.data_details.last_file_imported, "20250106 ICTRP.csv");
         assert_eq!(res.data_details.target_file, "20250210 ICTRP.csv");
 
@@ -357,7 +605,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "dummy test ICTRP.csv"
 
@@ -394,7 +642,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -431,7 +679,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -462,7 +710,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -514,11 +762,15 @@ src_db_name="who"
         assert_eq!(res.folders.csv_full_path, PathBuf::from("/home/steve/Data/MDR source data/WHO/data/Full export 2025-02"));
         assert_eq!(res.folders.json_data_path, PathBuf::from("/home/steve/Data/MDR json files/who"));
         assert_eq!(res.folders.log_folder_path, PathBuf::from("/home/steve/Data/MDR logs/who"));
+        assert_eq!(res.folders.json_archive_path, PathBuf::from(""));
 
         assert_eq!(res.data_details.full_file_stem, "ICTRPFullExport ");
-        assert_eq!(res.data_details.full_file_num, 0);
+        assert_eq!(res.data_details.full_file_name, "");
         assert_eq!(res.data_details.last_file_imported, "");
         assert_eq!(res.data_details.target_file, "");
+        assert_eq!(res.data_details.max_rejects, 100);
+        assert_eq!(res.data_details.registries_file, "");
+        assert!(!res.data_details.json_snapshots);
 
         assert_eq!(res.db_pars.db_host, "localhost");
         assert_eq!(res.db_pars.db_user, "user_name");
@@ -538,7 +790,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -567,7 +819,7 @@ src_db_name="who"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -601,7 +853,7 @@ db_password="password"
 
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -630,6 +882,153 @@ src_db_name="who"
         assert_eq!(res.db_pars.src_db_name, "who");
     }
 
-}
-  
 
+    // Precedence of environment variables, password sources and config file locations.
+    // The environment is supplied by a lookup over a fixed set of values.
+
+    use std::collections::HashMap;
+
+    const BASE_CONFIG: &str = r#"
+[data]
+full_file_stem = "ICTRPFullExport "
+last_file_imported = "20250106 ICTRP.csv"
+max_rejects = "250"
+
+[folders]
+csv_data_path="/home/steve/Data/MDR source data/WHO/data"
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR_Logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+"#;
+
+    fn env_from(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> + use<> {
+        let vars: HashMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |k: &str| vars.get(k).cloned()
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join("dl_who_config_test");
+        fs::create_dir_all(&folder).unwrap();
+        let file_path = folder.join(name);
+        fs::write(&file_path, contents).unwrap();
+        file_path
+    }
+
+    #[test]
+    fn check_no_env_values_leaves_config_values() {
+        let res = populate_config_vars_with_env(BASE_CONFIG, &env_from(&[])).unwrap();
+        assert_eq!(res.db_pars.db_host, "localhost");
+        assert_eq!(res.db_pars.db_password, "password");
+        assert_eq!(res.data_details.max_rejects, 250);
+        assert_eq!(res.folders.csv_full_path, PathBuf::from("/home/steve/Data/MDR source data/WHO/data"));
+    }
+
+    #[test]
+    fn check_env_values_override_config_values() {
+        let env = env_from(&[("DL_WHO_DB_HOST", "db.example.org"), ("DL_WHO_DB_PORT", "5433"),
+                             ("DL_WHO_MAX_REJECTS", "10"), ("DL_WHO_CSV_FULL_PATH", "/data/full"),
+                             ("DL_WHO_LAST_FILE_IMPORTED", ""), ("DL_WHO_JSON_SNAPSHOTS", "TRUE")]);
+        let res = populate_config_vars_with_env(BASE_CONFIG, &env).unwrap();
+        assert_eq!(res.db_pars.db_host, "db.example.org");
+        assert_eq!(res.db_pars.db_port, 5433);
+        assert_eq!(res.db_pars.db_user, "user_name");
+        assert_eq!(res.data_details.max_rejects, 10);
+        assert_eq!(res.data_details.last_file_imported, "");
+        assert!(res.data_details.json_snapshots);
+        assert_eq!(res.folders.csv_full_path, PathBuf::from("/data/full"));
+        assert_eq!(res.folders.csv_data_path, PathBuf::from("/home/steve/Data/MDR source data/WHO/data"));
+    }
+
+    #[test]
+    fn check_env_can_supply_whole_config() {
+        let env = env_from(&[("DL_WHO_TARGET_FILE", "20250224 ICTRP.csv"), ("DL_WHO_JSON_DATA_PATH", "/data/json"),
+                             ("DL_WHO_LOG_FOLDER_PATH", "/data/logs"), ("DL_WHO_DB_USER", "who_user"),
+                             ("DL_WHO_DB_PASSWORD", "secret")]);
+        let res = populate_config_vars_with_env("", &env).unwrap();
+        assert_eq!(res.data_details.target_file, "20250224 ICTRP.csv");
+        assert_eq!(res.data_details.full_file_stem, "ICTRPFullExport ");
+        assert_eq!(res.folders.json_data_path, PathBuf::from("/data/json"));
+        assert_eq!(res.db_pars.db_user, "who_user");
+        assert_eq!(res.db_pars.db_password, "secret");
+        assert_eq!(res.db_pars.src_db_name, "who");
+    }
+
+    #[test]
+    fn check_missing_section_without_env_values_fails() {
+        let env = env_from(&[("DL_WHO_DB_USER", "who_user"), ("DL_WHO_DB_PASSWORD", "secret")]);
+        assert!(populate_config_vars_with_env("", &env).is_err());
+    }
+
+    #[test]
+    fn check_password_file_used_before_config_password() {
+        let pw_file = temp_file("password_1", "file_secret\n");
+        let config = format!("{}db_password_file=\"{}\"\n", BASE_CONFIG, pw_file.display());
+        let res = populate_config_vars_with_env(&config, &env_from(&[])).unwrap();
+        assert_eq!(res.db_pars.db_password, "file_secret");
+    }
+
+    #[test]
+    fn check_env_password_replaces_config_password_file() {
+        let config = format!("{}db_password_file=\"/no/such/file\"\n", BASE_CONFIG);
+        let env = env_from(&[("DL_WHO_DB_PASSWORD", "env_secret")]);
+        let res = populate_config_vars_with_env(&config, &env).unwrap();
+        assert_eq!(res.db_pars.db_password, "env_secret");
+
+        let pw_file = temp_file("password_2", "env_file_secret");
+        let env = env_from(&[("DL_WHO_DB_PASSWORD_FILE", pw_file.to_str().unwrap())]);
+        let res = populate_config_vars_with_env(BASE_CONFIG, &env).unwrap();
+        assert_eq!(res.db_pars.db_password, "env_file_secret");
+    }
+
+    #[test]
+    fn check_pgpassfile_used_if_no_password_given() {
+        let pgpass = temp_file("pgpass_1", "# comment\nother:5432:*:user_name:wrong\nlocalhost:5432:who:user_name:pgpass_secret\n");
+        let config = BASE_CONFIG.replace("db_password=\"password\"", "");
+        let env = env_from(&[("PGPASSFILE", pgpass.to_str().unwrap())]);
+        let res = populate_config_vars_with_env(&config, &env).unwrap();
+        assert_eq!(res.db_pars.db_password, "pgpass_secret");
+
+        let env = env_from(&[("PGPASSFILE", pgpass.to_str().unwrap()), ("DL_WHO_DB_USER", "someone_else")]);
+        assert!(populate_config_vars_with_env(&config, &env).is_err());
+        assert!(populate_config_vars_with_env(&config, &env_from(&[])).is_err());
+    }
+
+    #[test]
+    fn check_pgpass_matching() {
+        let contents = "*:*:*:admin:a\\:b\\\\c\nlocalhost:5433:mon:user_name:mon_pw\nlocalhost:*:*:user_name:any_pw\n";
+        assert_eq!(find_pgpass_password(contents, "db.example.org", 5432, "admin", &["who"]), Some("a:b\\c".to_string()));
+        assert_eq!(find_pgpass_password(contents, "localhost", 5433, "user_name", &["who", "mon"]), Some("mon_pw".to_string()));
+        assert_eq!(find_pgpass_password(contents, "localhost", 5432, "user_name", &["who", "mon"]), Some("any_pw".to_string()));
+        assert_eq!(find_pgpass_password(contents, "remote", 5432, "user_name", &["who"]), None);
+    }
+
+    #[test]
+    fn check_config_search_path() {
+        let env = env_from(&[("HOME", "/home/steve")]);
+        assert_eq!(get_config_search_path(&env), vec![PathBuf::from("./app_config.toml"),
+                    PathBuf::from("/home/steve/.config/dl_who/app_config.toml"),
+                    PathBuf::from("/etc/xdg/dl_who/app_config.toml")]);
+
+        let env = env_from(&[("HOME", "/home/steve"), ("XDG_CONFIG_HOME", "/cfg"), ("XDG_CONFIG_DIRS", "/etc/a:/etc/b")]);
+        assert_eq!(get_config_search_path(&env), vec![PathBuf::from("./app_config.toml"),
+                    PathBuf::from("/cfg/dl_who/app_config.toml"),
+                    PathBuf::from("/etc/a/dl_who/app_config.toml"),
+                    PathBuf::from("/etc/b/dl_who/app_config.toml")]);
+    }
+
+    #[test]
+    fn check_explicit_config_file_precedence() {
+        let cli_file = temp_file("cli_config.toml", BASE_CONFIG);
+        let env_file = temp_file("env_config.toml", BASE_CONFIG);
+        let env = env_from(&[("DL_WHO_CONFIG", env_file.to_str().unwrap())]);
+
+        assert_eq!(find_config_file(Some(&cli_file), &env).unwrap(), Some(cli_file.clone()));
+        assert_eq!(find_config_file(None, &env).unwrap(), Some(env_file));
+        assert!(find_config_file(Some(Path::new("/no/such/config.toml")), &env).is_err());
+        assert!(find_config_file(None, &env_from(&[("DL_WHO_CONFIG", "/no/such/config.toml")])).is_err());
+    }
+}
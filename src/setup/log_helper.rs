@@ -8,7 +8,7 @@
 use chrono::Local;
 use std::path::PathBuf;
 use crate::err::AppError;
-use crate::setup::InitParams;
+use crate::setup::{Action, InitParams};
 
 use log::{info, LevelFilter};
 use log4rs::{
@@ -21,19 +21,19 @@ use log4rs::{
 };
 
 
-pub fn setup_log (log_folder: &PathBuf, dl_type: i32) -> Result<log4rs::Handle, AppError> {
-    let log_file_path = get_log_file_path(log_folder, dl_type);
+pub fn setup_log (log_folder: &PathBuf, dl_type: i32, action: Action) -> Result<log4rs::Handle, AppError> {
+    let log_file_path = get_log_file_path(log_folder, dl_type, action);
     config_log (&log_file_path)
 }
 
-fn get_log_file_path(log_folder: &PathBuf, dl_type: i32) -> PathBuf {
+fn get_log_file_path(log_folder: &PathBuf, dl_type: i32, action: Action) -> PathBuf {
 
     let datetime_string = Local::now().format("%m-%d %H%M%S").to_string();
-    let log_file_name = if dl_type == 0 {
-        format!("WHO DL - aggregation {}", datetime_string)
+    let log_file_name = if action == Action::Import {
+        format!("WHO DL ({}) {}", dl_type, datetime_string)
     }
     else {
-        format!("WHO DL ({}) {}", dl_type, datetime_string)
+        format!("WHO DL - {} {}", action.name(), datetime_string)
     };
     [log_folder, &PathBuf::from(&log_file_name)].iter().collect()
 }
@@ -83,20 +83,63 @@ pub fn log_startup_params (ip : &InitParams) {
     info!("");
     info!("************************************");
     info!("");
-    info!("download type: {}", ip.dl_type.to_string());
-
-    if ip.dl_type == 0 {
-        info!("** data aggregation **");
-    }
-    else
-    {
-        info!("full DL file stem: {}", ip.full_file_stem);
-        info!("full DL file num: {}", ip.full_file_num.to_string());
-        info!("(previous) last file imported: {}", ip.last_file_imported);
-        info!("target file: {:?}", ip.target);
-        info!("csv data path: {:?}", ip.csv_data_path);
-        info!("csv full data path: {:?}", ip.csv_full_path);
-        info!("json data parth: {:?}", ip.json_data_path);
+    match ip.action {
+        Action::Import => {
+            info!("download type: {}", ip.dl_type.to_string());
+            if let Some(dl_id) = ip.resume_dl_id {
+                info!("resuming import: {}", dl_id);
+            }
+            info!("full DL file stem: {}", ip.full_file_stem);
+            info!("full export file: {:?}", ip.full_file_path);
+            info!("(previous) last file imported: {}", ip.last_file_imported);
+            info!("target file: {:?}", ip.target);
+            info!("max rejected rows per file: {}", ip.max_rejects);
+            info!("registry catalogue file: {:?}", ip.registries_file);
+            info!("csv data path: {:?}", ip.csv_data_path);
+            info!("csv full data path: {:?}", ip.csv_full_path);
+            info!("json data parth: {:?}", ip.json_data_path);
+            info!("keep json snapshots: {}", ip.json_snapshots);
+            info!("json archive path: {:?}", ip.json_archive_path);
+        },
+        Action::Split => {
+            info!("** full export file split **");
+            info!("full DL file stem: {}", ip.full_file_stem);
+            info!("full export file: {:?}", ip.full_file_path);
+        },
+        Action::Inspect => {
+            info!("** file inspection **");
+            info!("(previous) last file imported: {}", ip.last_file_imported);
+            info!("csv data path: {:?}", ip.csv_data_path);
+        },
+        Action::Export => {
+            info!("** data grid export **");
+            info!("export folder: {:?}", ip.export_folder);
+        },
+        Action::Validate => {
+            info!("** file validation (dry run) **");
+            info!("target file: {:?}", ip.target);
+            info!("registry catalogue file: {:?}", ip.registries_file);
+            info!("csv data path: {:?}", ip.csv_data_path);
+        },
+        Action::Migrate => {
+            info!("** database migration **");
+            info!("registry catalogue file: {:?}", ip.registries_file);
+        },
+        Action::Diff => {
+            info!("** comparison **");
+            info!("from: {}", ip.diff_from);
+            info!("to: {}", ip.diff_to);
+            info!("csv data path: {:?}", ip.csv_data_path);
+        },
+        Action::History => {
+            info!("** study history **");
+            info!("study id: {}", ip.study_id);
+            info!("all fields: {}", ip.all_fields);
+        },
+        Action::Link | Action::Aggregate => {
+            info!("** data {} **", ip.action.name());
+            info!("exclude studies not in the latest full export: {}", ip.exclude_missing);
+        },
     }
     info!("log folder path: {:?}", ip.log_folder_path);
 
@@ -110,4 +153,4 @@ pub fn write_config (config_string: &String) {
     info!("New file is:");
     info!("{}", config_string);
     info!("");
-}
\ No newline at end of file
+}
@@ -0,0 +1,166 @@
+/**********************************************************************************
+The database schema is created and updated by versioned migrations, held as sql
+files under setup/migrations and built into the program. There are two sets - one
+for the monitoring (mon) database and one for the WHO source (who) database. The
+migrations applied to each database are recorded in a dl_who_migrations table,
+in the evs schema of the mon database and the met schema of the who database,
+together with a checksum of each file. Any migrations not yet applied are run, in
+version order and each in its own transaction, by the 'migrate' (or 'init-db')
+command, and at the start of any run that uses the database. Migrations should
+therefore never be edited once released - a change requires a new migration.
+***********************************************************************************/
+
+use sqlx::{Pool, Postgres};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use log::{info, warn};
+use crate::err::AppError;
+
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+
+pub struct MigrationSet {
+    pub db_label: &'static str,
+    pub schema: &'static str,    // holds the dl_who_migrations table
+    pub migrations: &'static [Migration],
+}
+
+
+pub static MON_MIGRATIONS: MigrationSet = MigrationSet {
+    db_label: "monitoring",
+    schema: "evs",
+    migrations: &[
+        Migration { version: 1, name: "create_dl_events", sql: include_str!("migrations/mon/0001_create_dl_events.sql") },
+        Migration { version: 2, name: "add_studies_not_in_export", sql: include_str!("migrations/mon/0002_add_studies_not_in_export.sql") },
+        Migration { version: 3, name: "create_dl_checkpoints", sql: include_str!("migrations/mon/0003_create_dl_checkpoints.sql") },
+    ],
+};
+
+pub static SRC_MIGRATIONS: MigrationSet = MigrationSet {
+    db_label: "source",
+    schema: "met",
+    migrations: &[
+        Migration { version: 1, name: "create_schemas", sql: include_str!("migrations/who/0001_create_schemas.sql") },
+        Migration { version: 2, name: "create_file_tables", sql: include_str!("migrations/who/0002_create_file_tables.sql") },
+        Migration { version: 3, name: "create_met_tables", sql: include_str!("migrations/who/0003_create_met_tables.sql") },
+        Migration { version: 4, name: "add_file_change_counts", sql: include_str!("migrations/who/0004_add_file_change_counts.sql") },
+        Migration { version: 5, name: "create_study_versions", sql: include_str!("migrations/who/0005_create_study_versions.sql") },
+        Migration { version: 6, name: "create_study_downloads", sql: include_str!("migrations/who/0006_create_study_downloads.sql") },
+    ],
+};
+
+
+impl MigrationSet {
+
+    // Returns the migrations not yet applied, given the versions (and
+    // checksums) already recorded in the database, in version order.
+
+    pub fn pending(&self, applied: &HashMap<i32, String>) -> Vec<&Migration> {
+        let mut pending: Vec<&Migration> = self.migrations.iter()
+                    .filter(|m| !applied.contains_key(&m.version)).collect();
+        pending.sort_by_key(|m| m.version);
+        pending
+    }
+
+    // Returns the versions of any applied migrations that have since been changed.
+
+    pub fn changed(&self, applied: &HashMap<i32, String>) -> Vec<i32> {
+        self.migrations.iter()
+            .filter(|m| applied.get(&m.version).is_some_and(|c| *c != m.checksum()))
+            .map(|m| m.version).collect()
+    }
+}
+
+
+pub async fn migrate_db(set: &MigrationSet, pool: &Pool<Postgres>) -> Result<usize, AppError> {
+
+    // Applies any pending migrations and returns the number applied.
+
+    let sql = format!(r#"SET client_min_messages TO WARNING;
+                create schema if not exists {};
+                create table if not exists {}.dl_who_migrations (
+                      version       int           not null primary key
+                    , name          varchar       not null
+                    , checksum      varchar       not null
+                    , applied_at    timestamptz   not null default now()
+                );
+                SET client_min_messages TO NOTICE;"#, set.schema, set.schema);
+    sqlx::raw_sql(&sql).execute(pool).await.map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+
+    let sql = format!("select version, checksum from {}.dl_who_migrations", set.schema);
+    let rows: Vec<(i32, String)> = sqlx::query_as(&sql).fetch_all(pool).await
+                    .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+    let applied: HashMap<i32, String> = rows.into_iter().collect();
+
+    for v in set.changed(&applied) {
+        warn!("Migration {} of the {} database has been changed since it was applied", v, set.db_label);
+    }
+
+    let pending = set.pending(&applied);
+    for m in &pending {
+        let mut tx = pool.begin().await
+                    .map_err(|e| AppError::SqlxError(e, "begin transaction".to_string()))?;
+        sqlx::raw_sql(m.sql).execute(&mut *tx).await
+                    .map_err(|e| AppError::SqlxError(e, m.sql.to_string()))?;
+        let sql = format!("insert into {}.dl_who_migrations (version, name, checksum) values ($1, $2, $3)", set.schema);
+        sqlx::query(&sql).bind(m.version).bind(m.name).bind(m.checksum()).execute(&mut *tx).await
+                    .map_err(|e| AppError::SqlxError(e, sql.clone()))?;
+        tx.commit().await
+                    .map_err(|e| AppError::SqlxError(e, "commit transaction".to_string()))?;
+        info!("Migration {:04}_{} applied to the {} database", m.version, m.name, set.db_label);
+    }
+    Ok(pending.len())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_migration_versions_are_unique_and_ordered() {
+        for set in [&MON_MIGRATIONS, &SRC_MIGRATIONS] {
+            let versions: Vec<i32> = set.migrations.iter().map(|m| m.version).collect();
+            let expected: Vec<i32> = (1..=versions.len() as i32).collect();
+            assert_eq!(versions, expected);
+            assert!(set.migrations.iter().all(|m| !m.sql.trim().is_empty()));
+        }
+    }
+
+    #[test]
+    fn check_migrations_create_required_tables() {
+        let all_sql = |set: &MigrationSet| set.migrations.iter().map(|m| m.sql).collect::<Vec<&str>>().join("\n");
+        let mon = all_sql(&MON_MIGRATIONS);
+        for t in ["evs.dl_events", "evs.studies_not_in_export", "evs.dl_checkpoints"] {
+            assert!(mon.contains(&format!("create table if not exists {}", t)), "{} not created", t);
+        }
+        let src = all_sql(&SRC_MIGRATIONS);
+        for t in ["der.who_file_dls", "der.who_file_contents", "der.who_file_rejects", "met.tables"] {
+            assert!(src.contains(&format!("create table if not exists {}", t)), "{} not created", t);
+        }
+    }
+
+    #[test]
+    fn check_pending_and_changed_migrations() {
+        let mut applied = HashMap::new();
+        assert_eq!(SRC_MIGRATIONS.pending(&applied).len(), SRC_MIGRATIONS.migrations.len());
+
+        applied.insert(1, SRC_MIGRATIONS.migrations[0].checksum());
+        applied.insert(2, "not the checksum".to_string());
+        let pending: Vec<i32> = SRC_MIGRATIONS.pending(&applied).iter().map(|m| m.version).collect();
+        assert_eq!(pending[0], 3);
+        assert_eq!(pending.len(), SRC_MIGRATIONS.migrations.len() - 2);
+        assert_eq!(SRC_MIGRATIONS.changed(&applied), vec![2]);
+    }
+}
@@ -0,0 +1,15 @@
+-- The download events table, in the monitoring database. Each run of an 
+-- import is recorded as a single event, with its type (501, 502 or 503).
+
+create schema if not exists evs;
+
+create table if not exists evs.dl_events (
+      id                        int           not null primary key
+    , source_id                 int           not null
+    , type_id                   int           null
+    , time_started              timestamptz   null
+    , time_ended                timestamptz   null
+    , num_records_checked       int           null
+    , num_records_downloaded    int           null
+    , num_records_added         int           null
+);
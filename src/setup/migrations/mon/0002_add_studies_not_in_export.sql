@@ -0,0 +1,14 @@
+-- The studies in the source database that were not in a full export (DL type 502),
+-- recorded for each full export import, with the import in which each was last seen
+-- and, if its json file was archived, where that file was moved to.
+
+alter table evs.dl_events add column if not exists num_records_not_in_export int null;
+
+create table if not exists evs.studies_not_in_export (
+      dl_id                     int           not null
+    , sd_sid                    varchar       not null
+    , sid_type_id               int           not null
+    , last_seen_dl_id           int           null
+    , json_archive_path         varchar       null
+    , primary key (dl_id, sd_sid)
+);
@@ -0,0 +1,22 @@
+-- The progress of a full export import (DL type 502), checkpointed after each chunk 
+-- is committed, so that an interrupted run can be resumed (import full --resume). 
+-- Positions are those of the first record after the last committed chunk, and the
+-- counts are the running totals of the import to that point.
+
+create table if not exists evs.dl_checkpoints (
+      dl_id                     int           not null primary key
+    , file_path                 varchar       not null
+    , file_size                 bigint        not null
+    , chunk_num                 int           not null
+    , record_offset             bigint        not null
+    , line_offset               bigint        not null
+    , byte_offset               bigint        not null
+    , num_checked               int           not null
+    , num_downloaded            int           not null
+    , num_added                 int           not null
+    , num_updated               int           not null
+    , num_unchanged             int           not null
+    , num_rejected              int           not null
+    , completed                 boolean       not null default false
+    , time_saved                timestamptz   null
+);
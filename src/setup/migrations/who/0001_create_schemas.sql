@@ -0,0 +1,9 @@
+-- The schemas of the WHO source database. The dat tables themselves are created
+-- (as partitioned tables) from the registry catalogue, as that can change without
+-- a new version of the program. The tables in sec, and the aggregation tables in
+-- der, are recreated each time linking or aggregation is carried out.
+
+create schema if not exists dat;
+create schema if not exists der;
+create schema if not exists met;
+create schema if not exists sec;
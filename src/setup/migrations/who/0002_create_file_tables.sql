@@ -0,0 +1,34 @@
+-- The tables recording the import of each WHO csv file, its contents 
+-- (the number of studies from each registry) and any rows rejected.
+-- file_hash may be missing from tables created by earlier versions.
+
+create table if not exists der.who_file_dls (
+      id                int           generated always as identity primary key
+    , dl_id             int           not null
+    , file_path         varchar       not null
+    , file_hash         varchar       null
+    , date_dl           date          null
+    , num_checked       int           null
+    , num_downloaded    int           null
+    , num_added         int           null
+);
+
+alter table der.who_file_dls add column if not exists file_hash varchar null;
+
+create table if not exists der.who_file_contents (
+      file_path         varchar       not null
+    , source_id         int           not null
+    , num_found         int           null
+);
+
+create table if not exists der.who_file_rejects (
+      id                int           generated always as identity primary key
+    , dl_id             int           not null
+    , file_path         varchar       not null
+    , line_number       bigint        not null
+    , byte_offset       bigint        not null
+    , reason_code       varchar       not null
+    , error_text        varchar       null
+    , raw_text          varchar       null
+    , rejected_at       timestamptz   not null default now()
+);
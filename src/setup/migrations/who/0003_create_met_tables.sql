@@ -0,0 +1,8 @@
+-- The list of registries, used when setting up the aggregation grids. 
+-- Rows are added for any registry in the catalogue not already listed.
+
+create table if not exists met.tables (
+      sid_type_id       int           not null
+    , sid_type_name     varchar       not null
+    , table_name        varchar       not null
+);
@@ -0,0 +1,5 @@
+-- The numbers of changed and unchanged studies in each file, now that
+-- studies whose content has not changed are no longer rewritten.
+
+alter table der.who_file_dls add column if not exists num_updated int null;
+alter table der.who_file_dls add column if not exists num_unchanged int null;
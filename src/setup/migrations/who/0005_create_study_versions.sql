@@ -0,0 +1,15 @@
+-- The versions of each study, one for each download in which the study was new 
+-- or had changed. Each holds only the fields that changed (see versions.rs).
+
+create table if not exists dat.study_versions (
+      sd_sid            varchar       not null
+    , dl_id             int           not null
+    , sid_type_id       int           not null
+    , record_date       date          null
+    , content_hash      varchar       not null
+    , changed_fields    varchar[]     null
+    , changes           jsonb         not null
+    , source_file       varchar       null
+    , recorded_at       timestamptz   not null default now()
+    , primary key (sd_sid, dl_id)
+);
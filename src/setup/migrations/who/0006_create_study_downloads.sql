@@ -0,0 +1,9 @@
+-- The studies in each download, whether new, changed or unchanged, so that
+-- the data stored after an import can be compared with another source
+-- without including studies that were not in it (see diff.rs).
+
+create table if not exists dat.study_downloads (
+      dl_id             int           not null
+    , sd_sid            varchar       not null
+    , primary key (dl_id, sd_sid)
+);
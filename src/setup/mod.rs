@@ -15,29 +15,44 @@ The module also provides a database connection pool on demand.
 pub mod cli_reader;
 pub mod config_reader;
 pub mod log_helper;
+pub mod migrations;
 
 use std::fs;
 use std::sync::OnceLock;
 use crate::err::AppError;
 use sqlx::postgres::{PgPoolOptions, PgConnectOptions, PgPool};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use sqlx::ConnectOptions;
 use config_reader::{Config, DBPars};
 use cli_reader::CliPars;
-use std::ffi::OsStr;
+pub use cli_reader::Action;
+use crate::download::source_file::{SourceFile, parse_file_date, is_source_file, is_zip_file, get_file_hash};
+use std::collections::HashMap;
+use log::warn;
 
 pub struct InitParams {
     pub dl_type: i32,
     pub full_file_stem: String,
-    pub full_file_num: i32,
+    pub full_file_path: PathBuf,
     pub last_file_imported: String,
     pub target: String,
+    pub max_rejects: i32,
+    pub registries_file: PathBuf,
+    pub json_snapshots: bool,
     pub csv_data_path: PathBuf,
     pub csv_full_path: PathBuf,
     pub json_data_path: PathBuf,
     pub log_folder_path: PathBuf,
-    pub doing_agg_only: bool,
+    pub json_archive_path: PathBuf,
+    pub export_folder: PathBuf,
+    pub study_id: String,
+    pub all_fields: bool,
+    pub diff_from: String,
+    pub diff_to: String,
+    pub exclude_missing: bool,
+    pub resume_dl_id: Option<i32>,
+    pub action: Action,
 }
 
 pub static LOG_RUNNING: OnceLock<bool> = OnceLock::new();
@@ -52,22 +67,35 @@ pub fn get_params(cli_pars: CliPars, config_string: &String) -> Result<InitParam
     let empty_pb = PathBuf::from("");
     let empty_str = "".to_string();
     
-    if cli_pars.doing_agg_only {
+    if matches!(cli_pars.action, Action::Link | Action::Aggregate | Action::Export | Action::Migrate | Action::History) {
 
-        // File related parameters become irrelevant (apart from the log file)
+        // File related parameters become irrelevant (apart from the log file,
+        // the registry catalogue used to create the dat tables, and, if 
+        // exporting, the folder for the exported files, or if showing 
+        // a study's history, the study id)
 
         Ok(InitParams {
             dl_type: 0,
             full_file_stem: "".to_string(),
-            full_file_num: 0,
+            full_file_path: empty_pb.clone(),
             last_file_imported: "".to_string(),
             target: "".to_string(),
+            max_rejects: 0,
+            registries_file: PathBuf::from(&data_pars.registries_file),
+            json_snapshots: false,
             csv_data_path: empty_pb.clone(),
             csv_full_path: empty_pb.clone(),
             json_data_path: empty_pb.clone(),
             log_folder_path: folder_pars.log_folder_path,
-            doing_agg_only: true,
-
+            json_archive_path: empty_pb.clone(),
+            export_folder: cli_pars.export_folder,
+            study_id: cli_pars.study_id,
+            all_fields: cli_pars.all_fields,
+            diff_from: "".to_string(),
+            diff_to: "".to_string(),
+            exclude_missing: cli_pars.exclude_missing,
+            resume_dl_id: None,
+            action: cli_pars.action,
         })
 
     }
@@ -77,13 +105,13 @@ pub fn get_params(cli_pars: CliPars, config_string: &String) -> Result<InitParam
         let mut target = cli_pars.target_file;
 
         let full_file_stem = data_pars.full_file_stem;
-        let full_file_num = data_pars.full_file_num;
+        let mut full_file_path = empty_pb.clone();
         let last_file_imported = data_pars.last_file_imported;
         let csv_data_path = folder_pars.csv_data_path;
         let csv_full_path = folder_pars.csv_full_path;
 
         let json_data_path = folder_pars.json_data_path;  // already checked as present
-        if !folder_exists(&json_data_path) {
+        if cli_pars.action == Action::Import && !folder_exists(&json_data_path) {
             fs::create_dir_all(&json_data_path)?;
         }
 
@@ -96,10 +124,11 @@ pub fn get_params(cli_pars: CliPars, config_string: &String) -> Result<InitParam
                 target = data_pars.target_file;  // otherwise use the config file
         }
 
-        if dl_type == 501 {
+        if dl_type == 501 || cli_pars.action == Action::Inspect {
             
             // DEFAULT download for WHO data
             // To process any files in the data folder not yet processed, in the correct order
+            // (or, if inspecting, to list the files and show whether they have been processed)
 
             // Needs csv_data_path to exist (<> "")  
 
@@ -112,22 +141,19 @@ pub fn get_params(cli_pars: CliPars, config_string: &String) -> Result<InitParam
                         format!("Path provided was {:?}", csv_data_path)));
             }
 
-            // Needs last_file_imported to exist (<> "")
+            // last_file_imported is optional - files already imported are identified from the 
+            // der.who_file_dls table - but if given only files dated after it are considered.
 
-            if last_file_imported == empty_str  { 
-                return Result::Err(AppError::MissingProgramParameter("csv_data_path".to_string()));
-            }
-            
             // The processing needs to get the files from examining the folder - does not need explicit targets at this stage
-            // to identify files not yet downloaded and dated after the last file downloaded date, ordered by date
+            // to identify files not yet downloaded (or changed since being downloaded), ordered by date
             // errors on accessing the individual file paths will need to be dealt with there...
 
         }
 
-        if dl_type == 502 {
+        if dl_type == 502 || cli_pars.action == Action::Split {
             
-            // Use full data download 
-            // To process files in the full data folder in the correct order
+            // Use full data download (or split it into numbered files)
+            // To process the full export file, in chunks, in the correct order
 
             // need csv_full_path to exist (<> "")  
             
@@ -141,23 +167,18 @@ pub fn get_params(cli_pars: CliPars, config_string: &String) -> Result<InitParam
             }
 
             // need full_file_stem to exist (but this has a default)
-            // need full_file_num to exist  (is > 0)
+            // need the full export file itself - either named in the config 
+            // file or the only csv or zip file in the folder
 
-            if full_file_num == 0  { 
-                return Result::Err(AppError::MissingProgramParameter("full_file_num".to_string()));
-            }
-
-
-            // The processing could get the files from a loop - does not need explicit targets at this stage
-            // errors on accessing the individual file paths will need to be dealt with there...
+            full_file_path = get_full_export_file(&csv_full_path, &data_pars.full_file_name, &full_file_stem)?;
 
         }
 
 
-        if dl_type == 503 {
+        if dl_type == 503 || cli_pars.action == Action::Validate {
             
             // Single file download 
-            // To process the designated target file only
+            // To process (or validate) the designated target file only
 
             // Needs csv_data_path to exist (<> "")  
 
@@ -178,22 +199,70 @@ pub fn get_params(cli_pars: CliPars, config_string: &String) -> Result<InitParam
             
         }
 
+        if cli_pars.action == Action::Diff {
+
+            // Files to be compared that are not given as full paths (rather than 
+            // as import ids) are looked for in the csv data folder, which must exist.
+
+            let needs_folder = [&cli_pars.diff_from, &cli_pars.diff_to].iter()
+                .any(|a| a.trim().parse::<i32>().is_err() && Path::new(a.as_str()).is_relative());
+            if needs_folder && csv_data_path == empty_pb { 
+                return Result::Err(AppError::MissingProgramParameter("csv_data_path".to_string()));
+            }
+        }
+
         Ok(InitParams {
             dl_type,
             full_file_stem,
-            full_file_num,
+            full_file_path,
             last_file_imported: last_file_imported,
             target: target,
+            max_rejects: data_pars.max_rejects,
+            registries_file: PathBuf::from(&data_pars.registries_file),
+            json_snapshots: data_pars.json_snapshots,
             csv_data_path: csv_data_path,
             csv_full_path: csv_full_path,
             json_data_path: json_data_path,
             log_folder_path: log_folder_path,
-            doing_agg_only: cli_pars.doing_agg_only,
-
+            json_archive_path: folder_pars.json_archive_path,
+            export_folder: empty_pb.clone(),
+            study_id: "".to_string(),
+            all_fields: false,
+            diff_from: cli_pars.diff_from,
+            diff_to: cli_pars.diff_to,
+            exclude_missing: false,
+            resume_dl_id: cli_pars.resume_dl_id,
+            action: cli_pars.action,
         })
     }
 }
 
+fn get_full_export_file(csv_full_path: &Path, full_file_name: &str, full_file_stem: &str) -> Result<PathBuf, AppError> {
+
+    if !full_file_name.is_empty() {
+        return Ok(csv_full_path.join(full_file_name));
+    }
+
+    // Otherwise look for the single csv or zip file in the folder, ignoring 
+    // any files generated by splitting the export, i.e. those starting
+    // with the full file stem.
+
+    let candidates = fs::read_dir(csv_full_path)?
+             .filter_map(|res| res.ok())
+             .map(|dir_entry| dir_entry.path())
+             .filter(|path| is_source_file(path))
+             .filter(|path| path.file_name().and_then(|f| f.to_str())
+                     .is_some_and(|f| !f.starts_with(full_file_stem)))
+             .collect::<Vec<_>>();
+
+    match candidates.len() {
+        1 => Ok(candidates[0].clone()),
+        0 => Result::Err(AppError::MissingProgramParameter("full_file_name".to_string())),
+        _ => Result::Err(AppError::InconsistentProgramParameter(
+                format!("More than one possible full export file found in {:?} - use full_file_name to specify which", csv_full_path))),
+    }
+}
+
 fn folder_exists(folder_name: &PathBuf) -> bool {
     let res = match folder_name.try_exists() {
         Ok(true) => true,
@@ -263,7 +332,7 @@ pub fn fetch_db_pars() -> Result<DBPars, AppError> {
 pub fn establish_log(params: &InitParams) -> Result<(), AppError> {
 
     if !log_set_up() {  // can be called more than once in context of integration tests
-        log_helper::setup_log(&params.log_folder_path, params.dl_type)?;
+        log_helper::setup_log(&params.log_folder_path, params.dl_type, params.action)?;
         LOG_RUNNING.set(true).unwrap(); // should always work
         log_helper::log_startup_params(&params);
     }
@@ -277,49 +346,122 @@ pub fn log_set_up() -> bool {
     }
 }
 
-pub fn get_files_to_process(data_folder: &PathBuf, last_file: &String) -> Result<Vec<String>, AppError> {
+// The status of each (dated) csv or zip file in the csv data folder, with respect to 
+// the files already imported, as listed by get_file_statuses.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    New,
+    Changed,
+    Imported,
+    SameDateAsOther,
+    BeforeLastFile,
+}
+
+impl FileStatus {
+    pub fn description(&self) -> &'static str {
+        match self {
+            FileStatus::New => "not yet imported",
+            FileStatus::Changed => "changed since imported",
+            FileStatus::Imported => "imported",
+            FileStatus::SameDateAsOther => "ignored - another file has the same date",
+            FileStatus::BeforeLastFile => "ignored - not after the last file imported (as set in config)",
+        }
+    }
+}
+
+
+pub fn get_files_to_process(data_folder: &PathBuf, last_file: &str, imported_files: &HashMap<String, Option<String>>) 
+                                -> Result<Vec<SourceFile>, AppError> {
+
+    // Files to be processed are those not yet imported, or changed since being
+    // imported, in date order.
+
+    let mut files_to_process = Vec::<SourceFile>::new();
+    for (f, status) in get_file_statuses(data_folder, last_file, imported_files)? {
+        match status {
+            FileStatus::New => files_to_process.push(f),
+            FileStatus::Changed => {
+                warn!("{} has changed since it was imported - it will be processed again", f.file_name);
+                files_to_process.push(f);
+            },
+            FileStatus::SameDateAsOther => warn!("{} ignored, as another file has the same date", f.file_name),
+            _ => {},
+        }
+    }
+    Ok(files_to_process)
+}
+
+
+pub fn get_file_statuses(data_folder: &PathBuf, last_file: &str, imported_files: &HashMap<String, Option<String>>) 
+                                -> Result<Vec<(SourceFile, FileStatus)>, AppError> {
     
-    let last_file_as_buf = PathBuf::from(last_file);
-    let last_file_as_osstr: &OsStr = last_file_as_buf.as_os_str();
+    // If given, the date of the last file imported is derived from its name, which may be 
+    // in the original WHO form (e.g. 'ICTRPWeek24February2025.zip') or in the 
+    // renamed form previously required (e.g. '20250224 ICTRP.csv').
 
-    // Get list of csv files in the source folder.
+    let last_date = if last_file.is_empty() {
+        None
+    }
+    else {
+        match parse_file_date(last_file) {
+            Some(d) => Some(d),
+            None => return Result::Err(AppError::ConfigurationError(
+                        "Unable to derive a date from the last file imported.".to_string(),
+                        format!("File name provided was '{}'.", last_file))),
+        }
+    };
+
+    // Get list of csv and zip files in the source folder.
     // 1) Filter out all those directory entries which couldn't be read.
     // 2) Map the directory entries to paths
-    // 3) Filter out all paths with extensions other than `csv`
+    // 3) Filter out all paths with extensions other than `csv` or `zip`,
+    //    and those whose names do not provide a date.
 
-    let csv_paths = std::fs::read_dir(data_folder)?              // Read_dir provides a list of Result<DirEntry, Error>
-             .filter_map(|res| res.ok())       // The list now just the valid DirEntries (.ok generates an option
-                                                                                            // ) but filter_map includes only those with a Some() value
-             .map(|dir_entry| dir_entry.path())     // Mapped across to the paths included in the DirEntries
+    let mut files = std::fs::read_dir(data_folder)?   // Read_dir provides a list of Result<DirEntry, Error>
+             .filter_map(|res| res.ok())
+             .map(|dir_entry| dir_entry.path())
+             .filter(|path| is_source_file(path))
              .filter_map(|path| {
-                if path.extension().map_or(false, |ext| ext == "csv") {     //  filter_map filters on Some()) values as 
-                                                                            //  generated by the closure. In the closure, the map_or function
-                                                                            //  generates true if the path has a .csv extension,
-                                                                            //  false otherwise, which determines if the if branch 
-                                                                            //  is followed (for Some(path)) or the else.       
-                    Some(path)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();      
- 
-    // Generates a collection of PathBufs.
-    // Iterate over and compare with the last_file.
-    
-    let files = csv_paths.iter()
-            .filter_map(|p| p.file_name())
-            .filter_map(|f| 
-                     if f >  last_file_as_osstr {
-                        Some(f)
-                     }
-                    else {
-                        None
-                    })
-            .map(|f| f.to_str().unwrap().to_string())   // assumes utf-8 characters
-            .collect::<Vec<_>>();
+                let file_name = path.file_name()?.to_str()?.to_string();   // assumes utf-8 characters
+                let file_date = parse_file_date(&file_name)?;
+                Some(SourceFile { path, file_name, file_date, file_hash: "".to_string() })
+             })
+             .collect::<Vec<_>>();
+
+    // Order by the date derived from the file name. If both a zip and an 
+    // unzipped csv version of the same week's file are present only one is 
+    // processed - the csv file, as it is assumed to have been deliberately extracted.
+
+    files.sort_by(|a, b| a.file_date.cmp(&b.file_date)
+                    .then_with(|| is_zip_file(&a.path).cmp(&is_zip_file(&b.path))));
+
+    // Compare the remainder with the files already imported, to find those not yet 
+    // imported or with contents changed since. Files recorded without a hash were 
+    // imported before hashes were stored, and are assumed to be unchanged.
+
+    let mut statuses = Vec::<(SourceFile, FileStatus)>::new();
+    let mut previous_date = None;
+    for mut f in files {
+        let status = if last_date.is_some_and(|d| f.file_date <= d) {
+            FileStatus::BeforeLastFile
+        }
+        else if previous_date == Some(f.file_date) {
+            FileStatus::SameDateAsOther
+        }
+        else {
+            previous_date = Some(f.file_date);
+            f.file_hash = get_file_hash(&f.path)?;
+            match imported_files.get(&f.file_name) {
+                None => FileStatus::New,
+                Some(Some(h)) if *h != f.file_hash => FileStatus::Changed,
+                Some(_) => FileStatus::Imported,
+            }
+        };
+        statuses.push((f, status));
+    }
    
-    Ok(files)
+    Ok(statuses)
 }
 
 
@@ -335,7 +477,7 @@ mod tests {
         let config = r#"
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "dummy test ICTRP.csv"
 
@@ -357,14 +499,14 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target"];
+        let args : Vec<&str> = vec!["dummy target", "import", "incremental"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
         let res = get_params(cli_pars, &config_string).unwrap();
         
         assert_eq!(res.dl_type, 501);
-        assert_eq!(res.doing_agg_only, false);
+        assert_eq!(res.action, Action::Import);
 
         assert_eq!(res.csv_data_path, PathBuf::from("/home/steve/Data/MDR source data/WHO"));
         assert_eq!(res.csv_full_path, PathBuf::from("/home/steve/Data/MDR source data/WHO/Full export 2025-02"));
@@ -372,7 +514,7 @@ src_db_name="who"
         assert_eq!(res.log_folder_path, PathBuf::from("/home/steve/Data/MDR_Logs/who"));
         
         assert_eq!(res.full_file_stem, "ICTRPFullExport ");
-        assert_eq!(res.full_file_num, 22);
+        assert_eq!(res.full_file_path, PathBuf::from(""));
         assert_eq!(res.last_file_imported, "20250106 ICTRP.csv");
         assert_eq!(res.target, "dummy test ICTRP.csv");
     }
@@ -382,7 +524,7 @@ src_db_name="who"
         let config = r#"
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "20250210 ICTRP.csv"
 
@@ -404,7 +546,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "503", "-f", "dummy who file.csv"];
+        let args : Vec<&str> = vec!["dummy target", "import", "file", "dummy who file.csv"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -417,7 +559,7 @@ src_db_name="who"
         assert_eq!(res.log_folder_path, PathBuf::from("/home/steve/Data/MDR_Logs/who"));
 
         assert_eq!(res.full_file_stem, "ICTRPFullExport ");
-        assert_eq!(res.full_file_num, 22);
+        assert_eq!(res.full_file_path, PathBuf::from(""));
         assert_eq!(res.last_file_imported, "20250106 ICTRP.csv");
         assert_eq!(res.target, "dummy who file.csv");
     }
@@ -445,7 +587,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "501"];
+        let args : Vec<&str> = vec!["dummy target", "import", "incremental"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -481,7 +623,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "501"];
+        let args : Vec<&str> = vec!["dummy target", "import", "incremental"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -489,8 +631,7 @@ src_db_name="who"
     }
 
     #[test]
-    #[should_panic]
-    fn check_501_no_last_file_panics() {
+    fn check_501_no_last_file_accepted() {
 
         let config = r#"
 [data]
@@ -511,11 +652,13 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "501"];
+        let args : Vec<&str> = vec!["dummy target", "import", "incremental"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
-        let _res = get_params(cli_pars, &config_string).unwrap();
+        let res = get_params(cli_pars, &config_string).unwrap();
+        assert_eq!(res.dl_type, 501);
+        assert_eq!(res.last_file_imported, "");
     }
 
     #[test]
@@ -523,7 +666,7 @@ src_db_name="who"
 
         let config = r#"
 [data]
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 
 [folders]
 csv_full_path="/home/steve/Data/MDR source data/WHO/Full Export 2025-02"
@@ -541,7 +684,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "502"];
+        let args : Vec<&str> = vec!["dummy target", "import", "full"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -552,7 +695,8 @@ src_db_name="who"
         assert_eq!(res.json_data_path, PathBuf::from("/home/steve/Data/MDR json files/who"));
         assert_eq!(res.log_folder_path, PathBuf::from("/home/steve/Data/MDR logs/who"));
         assert_eq!(res.full_file_stem, "ICTRPFullExport ");
-        assert_eq!(res.full_file_num, 22);
+        assert_eq!(res.full_file_path, PathBuf::from("/home/steve/Data/MDR source data/WHO/Full Export 2025-02/ICTRP-full-1074705.csv"));
+        assert_eq!(res.resume_dl_id, None);
     }
 
     #[test]
@@ -561,7 +705,7 @@ src_db_name="who"
 
         let config = r#"
 [data]
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 
 [folders]
 json_data_path="/home/steve/Data/MDR json files/who"
@@ -578,7 +722,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "502"];
+        let args : Vec<&str> = vec!["dummy target", "import", "full"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -586,32 +730,36 @@ src_db_name="who"
     }
 
     #[test]
-    #[should_panic]
-    fn check_502_no_full_file_num_panics() {
+    fn check_full_export_file_found_when_not_named() {
 
-        let config = r#"
-[data]
+        let folder = std::env::temp_dir().join("dl_who_full_export_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        for f in ["ICTRP-full-1074705.zip", "ICTRPFullExport 001.csv", "ICTRPFullExport 002.csv", "notes.txt"] {
+            fs::write(folder.join(f), "").unwrap();
+        }
 
-[folders]
-json_data_path="/home/steve/Data/MDR json files/who"
-log_folder_path="/home/steve/Data/MDR_Logs/who"
+        let res = get_full_export_file(&folder, "", "ICTRPFullExport ").unwrap();
+        assert_eq!(res, folder.join("ICTRP-full-1074705.zip"));
 
-[database]
-db_host="localhost"
-db_user="user_name"
-db_password="password"
-db_port="5432"
-mon_db_name="mon"
-src_db_name="who"
-            "#;
-        let config_string = config.to_string();
-        config_reader::populate_config_vars(&config_string).unwrap();
+        let res = get_full_export_file(&folder, "other name.csv", "ICTRPFullExport ").unwrap();
+        assert_eq!(res, folder.join("other name.csv"));
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "502"];
-        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
-        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+        fs::write(folder.join("ICTRP-full-1074800.csv"), "").unwrap();
+        let res = get_full_export_file(&folder, "", "ICTRPFullExport ");
+        assert!(res.is_err());
+    }
 
-        let _res = get_params(cli_pars, &config_string).unwrap();
+    #[test]
+    #[should_panic]
+    fn check_502_no_full_export_file_panics() {
+
+        let folder = std::env::temp_dir().join("dl_who_no_full_export_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("ICTRPFullExport 001.csv"), "").unwrap();
+
+        let _res = get_full_export_file(&folder, "", "ICTRPFullExport ").unwrap();
     }
 
     #[test]
@@ -637,7 +785,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "503"];
+        let args : Vec<&str> = vec!["dummy target", "import", "file"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -673,7 +821,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "503"];
+        let args : Vec<&str> = vec!["dummy target", "import", "file"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -703,7 +851,7 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-t", "503"];
+        let args : Vec<&str> = vec!["dummy target", "import", "file"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
@@ -712,12 +860,12 @@ src_db_name="who"
 
 
      #[test]
-    fn check_a_flag_gives_correct_params() {
+    fn check_aggregate_gives_correct_params() {
 
         let config = r#"
 [data]
 full_file_stem = "ICTRPFullExport "
-full_file_num = "22"
+full_file_name = "ICTRP-full-1074705.csv"
 last_file_imported = "20250106 ICTRP.csv"
 target_file = "dummy test ICTRP.csv"
 
@@ -739,14 +887,14 @@ src_db_name="who"
         let config_string = config.to_string();
         config_reader::populate_config_vars(&config_string).unwrap();
 
-        let args : Vec<&str> = vec!["dummy target", "-a"];
+        let args : Vec<&str> = vec!["dummy target", "aggregate"];
         let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
         let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
 
         let res = get_params(cli_pars, &config_string).unwrap();
         
         assert_eq!(res.dl_type, 0);
-        assert_eq!(res.doing_agg_only, true);
+        assert_eq!(res.action, Action::Aggregate);
 
         assert_eq!(res.csv_data_path, PathBuf::from(""));
         assert_eq!(res.csv_full_path, PathBuf::from(""));
@@ -754,8 +902,260 @@ src_db_name="who"
         assert_eq!(res.log_folder_path, PathBuf::from("/home/steve/Data/MDR logs/who"));
         
         assert_eq!(res.full_file_stem, "".to_string());
-        assert_eq!(res.full_file_num, 0);
+        assert_eq!(res.full_file_path, PathBuf::from(""));
         assert_eq!(res.last_file_imported, "".to_string());
         assert_eq!(res.target, "".to_string());
     }
+
+
+    #[test]
+    fn check_export_gives_correct_params() {
+
+        let config = r#"
+[data]
+
+[folders]
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+db_port="5432"
+mon_db_name="mon"
+src_db_name="who"
+        "#;
+
+        let config_string = config.to_string();
+        config_reader::populate_config_vars(&config_string).unwrap();
+
+        let args : Vec<&str> = vec!["dummy target", "export", "/home/steve/Data/MDR grids"];
+        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+
+        let res = get_params(cli_pars, &config_string).unwrap();
+        
+        assert_eq!(res.dl_type, 0);
+        assert_eq!(res.action, Action::Export);
+        assert_eq!(res.export_folder, PathBuf::from("/home/steve/Data/MDR grids"));
+        assert_eq!(res.log_folder_path, PathBuf::from("/home/steve/Data/MDR logs/who"));
+    }
+
+
+    #[test]
+    fn check_history_gives_correct_params() {
+
+        let config = r#"
+[data]
+
+[folders]
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+db_port="5432"
+mon_db_name="mon"
+src_db_name="who"
+        "#;
+
+        let config_string = config.to_string();
+        config_reader::populate_config_vars(&config_string).unwrap();
+
+        let args : Vec<&str> = vec!["dummy target", "history", "ChiCTR2400000001", "--all"];
+        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+
+        let res = get_params(cli_pars, &config_string).unwrap();
+
+        assert_eq!(res.dl_type, 0);
+        assert_eq!(res.action, Action::History);
+        assert_eq!(res.study_id, "ChiCTR2400000001");
+        assert!(res.all_fields);
+        assert_eq!(res.csv_data_path, PathBuf::from(""));
+    }
+
+
+    #[test]
+    fn check_diff_of_imports_needs_no_csv_folder() {
+
+        let config = r#"
+[data]
+
+[folders]
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+db_port="5432"
+mon_db_name="mon"
+src_db_name="who"
+        "#;
+
+        let config_string = config.to_string();
+        config_reader::populate_config_vars(&config_string).unwrap();
+
+        let args : Vec<&str> = vec!["dummy target", "diff", "9", "/data/full/ICTRPFullExport.zip"];
+        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+
+        let res = get_params(cli_pars, &config_string).unwrap();
+
+        assert_eq!(res.action, Action::Diff);
+        assert_eq!(res.diff_from, "9");
+        assert_eq!(res.diff_to, "/data/full/ICTRPFullExport.zip");
+    }
+
+
+    #[test]
+    #[should_panic]
+    fn check_diff_of_file_names_without_csv_folder_panics() {
+
+        let config = r#"
+[data]
+
+[folders]
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+db_port="5432"
+mon_db_name="mon"
+src_db_name="who"
+        "#;
+
+        let config_string = config.to_string();
+        config_reader::populate_config_vars(&config_string).unwrap();
+
+        let args : Vec<&str> = vec!["dummy target", "diff", "20250217 ICTRP.csv", "20250224 ICTRP.csv"];
+        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+
+        let _res = get_params(cli_pars, &config_string).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn check_inspect_no_csv_folder_panics() {
+
+        let config = r#"
+[data]
+
+[folders]
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+db_port="5432"
+mon_db_name="mon"
+src_db_name="who"
+        "#;
+
+        let config_string = config.to_string();
+        config_reader::populate_config_vars(&config_string).unwrap();
+
+        let args : Vec<&str> = vec!["dummy target", "inspect"];
+        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+
+        let _res = get_params(cli_pars, &config_string).unwrap();
+    }
+
+
+    #[test]
+    #[should_panic]
+    fn check_validate_without_target_panics() {
+
+        let config = r#"
+[data]
+
+[folders]
+csv_data_path="/home/steve/Data/MDR source data/WHO/data"
+json_data_path="/home/steve/Data/MDR json files/who"
+log_folder_path="/home/steve/Data/MDR logs/who"
+
+[database]
+db_host="localhost"
+db_user="user_name"
+db_password="password"
+db_port="5432"
+mon_db_name="mon"
+src_db_name="who"
+        "#;
+
+        let config_string = config.to_string();
+        config_reader::populate_config_vars(&config_string).unwrap();
+
+        let args : Vec<&str> = vec!["dummy target", "validate"];
+        let test_args = args.iter().map(|x| x.to_string().into()).collect::<Vec<OsString>>();
+        let cli_pars = cli_reader::fetch_valid_arguments(test_args).unwrap();
+
+        let _res = get_params(cli_pars, &config_string).unwrap();
+    }
+
+
+    #[test]
+    fn check_files_to_process_are_filtered_and_date_ordered() {
+
+        let folder = std::env::temp_dir().join("dl_who_files_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        for f in ["ICTRPWeek3March2025.zip", "ICTRPWeek24February2025.zip", "20250224 ICTRP.csv",
+                  "20250106 ICTRP.csv", "ICTRPWeek10February2025.zip", "notes.txt", "ICTRPFullExport 001.csv"] {
+            fs::write(folder.join(f), "").unwrap();
+        }
+
+        let no_imports = HashMap::new();
+        let res = get_files_to_process(&folder, "20250106 ICTRP.csv", &no_imports).unwrap();
+        let names: Vec<&str> = res.iter().map(|f| f.file_name.as_str()).collect();
+        assert_eq!(names, vec!["ICTRPWeek10February2025.zip", "20250224 ICTRP.csv", "ICTRPWeek3March2025.zip"]);
+
+        let res = get_files_to_process(&folder, "ICTRPWeek24February2025.zip", &no_imports).unwrap();
+        let names: Vec<&str> = res.iter().map(|f| f.file_name.as_str()).collect();
+        assert_eq!(names, vec!["ICTRPWeek3March2025.zip"]);
+
+        let res = get_files_to_process(&folder, "", &no_imports).unwrap();
+        assert_eq!(res.len(), 4);
+
+        let res = get_file_statuses(&folder, "20250106 ICTRP.csv", &no_imports).unwrap();
+        let statuses: Vec<(&str, FileStatus)> = res.iter().map(|(f, s)| (f.file_name.as_str(), *s)).collect();
+        assert_eq!(statuses, vec![("20250106 ICTRP.csv", FileStatus::BeforeLastFile), 
+                                  ("ICTRPWeek10February2025.zip", FileStatus::New),
+                                  ("20250224 ICTRP.csv", FileStatus::New),
+                                  ("ICTRPWeek24February2025.zip", FileStatus::SameDateAsOther),
+                                  ("ICTRPWeek3March2025.zip", FileStatus::New)]);
+    }
+
+    #[test]
+    fn check_imported_files_skipped_unless_changed() {
+
+        let folder = std::env::temp_dir().join("dl_who_imported_files_test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        for f in ["20250106 ICTRP.csv", "20250113 ICTRP.csv", "20250120 ICTRP.csv", "20250127 ICTRP.csv"] {
+            fs::write(folder.join(f), f).unwrap();
+        }
+
+        let mut imported = HashMap::new();
+        imported.insert("20250106 ICTRP.csv".to_string(), Some(get_file_hash(&folder.join("20250106 ICTRP.csv")).unwrap()));
+        imported.insert("20250113 ICTRP.csv".to_string(), Some("hash of an earlier version".to_string()));
+        imported.insert("20250120 ICTRP.csv".to_string(), None);
+
+        let res = get_files_to_process(&folder, "", &imported).unwrap();
+        let names: Vec<&str> = res.iter().map(|f| f.file_name.as_str()).collect();
+        assert_eq!(names, vec!["20250113 ICTRP.csv", "20250127 ICTRP.csv"]);
+        assert_eq!(res[1].file_hash, get_file_hash(&folder.join("20250127 ICTRP.csv")).unwrap());
+    }
 }